@@ -1,11 +1,24 @@
+use quietdrop_core::accounts::is_valid_account_name;
+use quietdrop_core::authentication::{CredentialStore, FileCredentialStore};
 use quietdrop_core::client;
-use quietdrop_core::encryption::generate_keypair;
+use quietdrop_core::encryption::Identity;
+use quietdrop_core::error::QuietDropError;
+use quietdrop_core::keystore::{create_private_dir, write_private_file, Keystore, ServerKeyFiles};
 use quietdrop_core::message::{get_input, Message, MessageType};
-use quietdrop_core::server;
+use quietdrop_core::outbox::{Outbox, OutboxStatus, RetryPolicy, OUTBOX_FILE};
+use quietdrop_core::protocol::{ErrorCode, SessionToken};
+use quietdrop_core::server::{self, ServerConfig};
+use quietdrop_core::sessions::{SavedSession, SESSION_TOKEN_FILE};
+use quietdrop_core::storage::Storage;
+use quietdrop_core::tls::{ClientTls, ServerTls};
+use quietdrop_core::transfer::{
+    IncomingFiles, OutgoingFiles, TransferDirection, TransferProgress, TRANSFERS_DIR,
+};
 use sodiumoxide::crypto::box_;
 use std::env;
-use std::fs::File;
-use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::runtime::Runtime;
 
 fn main() {
@@ -14,71 +27,1014 @@ fn main() {
 
     let args: Vec<String> = env::args().collect();
     if args.len() < 2 {
-        eprintln!("Usage: quietdrop <server|client>");
+        eprintln!(
+            "Usage: quietdrop <server|rotate-keys|client|listen|outbox|logout|\
+            change-password|reset-password> [--key-dir DIR] \
+            [--tls-cert FILE [--tls-key FILE]] [--downloads DIR]"
+        );
         std::process::exit(1);
     }
 
     let rt = Runtime::new().expect("Failed to create Tokio runtime");
 
-    let server_public_key_bytes;
-    let server_secret_key_bytes;
+    // where the server keeps its keypair and clients find its public key
+    let server_keys = ServerKeyFiles::new(key_dir(&args));
 
     match args[1].as_str() {
         "server" => {
-            // you basically generate server keypair this way
-            let (public_key, secret_key) = generate_keypair();
+            // load the server keypair, generating it only on first run
+            let (_, server_secret_key) = match server_keys.load_or_generate() {
+                Ok((keypair, true)) => {
+                    println!(
+                        "Generated a new server keypair; share {} with clients",
+                        server_keys.public_key_path().display()
+                    );
+                    keypair
+                }
+                Ok((keypair, false)) => keypair,
+                Err(e) => {
+                    eprintln!("Unable to load server keys: {}", e);
+                    std::process::exit(1);
+                }
+            };
 
-            server_public_key_bytes = public_key.as_ref().to_vec();
-            server_secret_key_bytes = secret_key.as_ref().to_vec();
+            // accounts, keys and queued messages survive restarts in a
+            // database next to the server keys
+            let storage = Storage::open(key_dir(&args).join("quietdrop.db")).unwrap_or_else(|e| {
+                eprintln!("Unable to open the server database: {}", e);
+                std::process::exit(1);
+            });
 
-            // and then you basically save server's keys to files for later use
-            let _ = File::create("server_public_key.key")
-                .and_then(|mut file| file.write_all(&server_public_key_bytes));
-            let _ = File::create("server_secret_key.key")
-                .and_then(|mut file| file.write_all(&server_secret_key_bytes));
+            // servers set up before the database keep their account file
+            let accounts_file = key_dir(&args).join("accounts.json");
+            let credentials = accounts_file.exists().then(|| {
+                let store = FileCredentialStore::open(&accounts_file).unwrap_or_else(|e| {
+                    eprintln!("Unable to open the account store: {}", e);
+                    std::process::exit(1);
+                });
+                Arc::new(store) as Arc<dyn CredentialStore>
+            });
 
-            let server_secret_key = box_::SecretKey::from_slice(&server_secret_key_bytes).unwrap();
+            let config = ServerConfig {
+                tls: server_tls(&args),
+                storage: Arc::new(storage),
+                credentials,
+                ..ServerConfig::default()
+            };
 
             println!("\n>>> Now listening for incoming messages...\n");
 
             // and then you run the server using its secret key this way
-            rt.block_on(server::run_server("127.0.0.1:8080", &server_secret_key))
-                .expect("Server failed to run");
+            rt.block_on(server::run_server_with_config(
+                "127.0.0.1:8080",
+                &server_secret_key,
+                config,
+            ))
+            .expect("Server failed to run");
         }
+        "rotate-keys" => match server_keys.rotate() {
+            Ok(_) => println!(
+                "Rotated server keys; previous keys kept as .old. \
+                    Clients need the new {}",
+                server_keys.public_key_path().display()
+            ),
+            Err(e) => {
+                eprintln!("Unable to rotate server keys: {}", e);
+                std::process::exit(1);
+            }
+        },
         "client" => {
-            // now here, you basically generat the client keypair
-            let (public_key, secret_key) = generate_keypair();
+            // the server's public key is pinned: only the real server can
+            // complete the connection handshake or answer key lookups
+            let server_public_key = read_server_public_key(&server_keys);
+
+            println!("\n");
+            let name = read_name();
+
+            // your long-term keys live in an encrypted keystore
+            let identity = load_identity(&name);
+
+            // messages wait here until the server accepts them
+            let outbox = open_outbox(&name);
+
+            let recipient = get_input("Enter the recipient: ");
+
+            let sender = Sender {
+                rt: &rt,
+                args: &args,
+                name: &name,
+                identity: &identity,
+                server_public_key: &server_public_key,
+            };
+            let mut client = match open_session(&sender) {
+                Ok(client) => client,
+                Err(e) => {
+                    eprintln!("Unable to reach the server: {}", e);
+                    queue_offline(&sender, &outbox, &recipient, &e);
+                    return;
+                }
+            };
+
+            // send anything left over from an earlier run first
+            deliver_outbox(&sender, &mut client, &outbox);
+
+            // pick up files that were on their way in or out when we last quit
+            let mut transfers = open_transfers(&args, &name, &identity);
+            resume_transfers(&sender, &mut client, &mut transfers);
 
-            // then read server's keys from files
-            // from the file you saved --> server's public key
-            let mut file =
-                File::open("server_public_key.key").expect("Unable to open the key file");
+            // messages are sealed to the recipient's key, not the server's
+            let recipient_public_key =
+                match rt.block_on(client.lookup_key(&recipient, &server_public_key)) {
+                    Ok(Some(key)) => key,
+                    Ok(None) => {
+                        eprintln!("{} has not published a key yet.", recipient);
+                        std::process::exit(1);
+                    }
+                    Err(e) => {
+                        eprintln!("Unable to look up {}'s key: {}", recipient, e);
+                        std::process::exit(1);
+                    }
+                };
+            // kept so messages can still be queued on a run without the server
+            save_contact_key(&name, &recipient, &recipient_public_key);
 
-            let mut server_public_key_bytes = Vec::new();
-            file.read_to_end(&mut server_public_key_bytes)
-                .expect("Unable to read the key file");
+            println!("Connected. Enter /file PATH to send a file, or an empty message to quit.");
+            loop {
+                let msg_str = get_input("Enter your message: ");
+                if msg_str.is_empty() {
+                    break;
+                }
 
-            let server_public_key = box_::PublicKey::from_slice(&server_public_key_bytes).unwrap();
+                if let Some(path) = msg_str.strip_prefix("/file ") {
+                    send_file(
+                        &sender,
+                        &mut client,
+                        &mut transfers,
+                        Path::new(path.trim()),
+                        &recipient,
+                        &recipient_public_key,
+                    );
+                    continue;
+                }
+
+                queue_message(
+                    &sender,
+                    &outbox,
+                    &recipient,
+                    &recipient_public_key,
+                    &msg_str,
+                );
+                deliver_outbox(&sender, &mut client, &outbox);
+
+                // Show anything that arrived for us in the meantime
+                loop {
+                    match rt.block_on(client.try_recv_message(Duration::from_millis(50))) {
+                        Ok(Some(incoming)) => {
+                            print_incoming(&mut client, &incoming, &sender, &mut transfers)
+                        }
+                        Ok(None) => break,
+                        Err(e) => {
+                            eprintln!("Unable to receive messages: {}", e);
+                            break;
+                        }
+                    }
+                }
+                print_delivery_receipts(&mut client);
+                continue_transfers(&sender, &mut client, &mut transfers);
+            }
+
+            if let Err(e) = rt.block_on(client.close()) {
+                eprintln!("Unable to close the connection: {}", e);
+            }
+        }
+        "listen" => {
+            let server_public_key = read_server_public_key(&server_keys);
+
+            println!("\n");
+            let name = read_name();
+            let identity = load_identity(&name);
+
+            let reader = Sender {
+                rt: &rt,
+                args: &args,
+                name: &name,
+                identity: &identity,
+                server_public_key: &server_public_key,
+            };
+            let mut client = open_session(&reader).unwrap_or_else(|e| {
+                eprintln!("Unable to reach the server: {}", e);
+                std::process::exit(1);
+            });
+
+            let mut transfers = open_transfers(&args, &name, &identity);
+            resume_transfers(&reader, &mut client, &mut transfers);
+            println!("\n>>> Waiting for messages to {}...\n", name);
+            loop {
+                match rt.block_on(client.recv_message()) {
+                    Ok(Some(incoming)) => {
+                        print_incoming(&mut client, &incoming, &reader, &mut transfers);
+                        print_delivery_receipts(&mut client);
+                        continue_transfers(&reader, &mut client, &mut transfers);
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        eprintln!("Lost the connection to the server: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            println!("Server closed the connection.");
+        }
+        "outbox" => {
+            println!("\n");
+            let name = read_name();
+            let outbox = open_outbox(&name);
+            let entries = outbox.entries();
+            if entries.is_empty() {
+                println!("The outbox is empty.");
+            }
+            for entry in entries {
+                let status = match &entry.status {
+                    OutboxStatus::Pending => format!(
+                        "pending, {} failed attempt(s), next try at {}",
+                        entry.attempts, entry.next_attempt
+                    ),
+                    OutboxStatus::Sent { message_id } => format!("sent as {}", message_id),
+                    OutboxStatus::Failed { reason } => format!("failed: {}", reason),
+                };
+                println!(
+                    "{} to {} ({}): {}",
+                    entry.id, entry.message.recipient, entry.message.timestamp, status
+                );
+                if let Some(error) = &entry.last_error {
+                    println!("    last error: {}", error);
+                }
+            }
+        }
+        "logout" => {
+            let server_public_key = read_server_public_key(&server_keys);
 
             println!("\n");
-            let name = get_input("Enter your name: ");
-            let msg_str = get_input("Enter your message: ");
-
-            let mut msg = Message {
-                timestamp: chrono::Utc::now(),
-                message_type: MessageType::Text,
-                sender: name.clone(),
-                recipient: "Bob".to_owned(),
-                content: vec![],
-                public_key,
+            let name = read_name();
+            let saved_session = saved_session(&name);
+            let token = match saved_session.token() {
+                Ok(Some(token)) => token,
+                Ok(None) => {
+                    println!("{} is not logged in.", name);
+                    return;
+                }
+                Err(e) => {
+                    eprintln!("Unable to read the saved session: {}", e);
+                    std::process::exit(1);
+                }
             };
-            msg.encrypt_content(&msg_str, &server_public_key, &secret_key);
-            rt.block_on(client::send_message(&msg, "127.0.0.1:8080"))
-                .expect("Client failed to send message");
+
+            // revoking a session needs no long-term keys, so a throwaway
+            // identity carries the connection
+            let mut client = connect(&rt, &args, &server_public_key, &Identity::generate());
+            let result = rt.block_on(async {
+                match client.resume(&token).await {
+                    Ok(_) => client.logout().await?,
+                    // already expired or revoked on the server
+                    Err(QuietDropError::Server {
+                        code: ErrorCode::InvalidSession,
+                        ..
+                    }) => {}
+                    Err(e) => return Err(e),
+                }
+                client.close().await
+            });
+            if let Err(e) = result {
+                eprintln!("Unable to log out: {}", e);
+                std::process::exit(1);
+            }
+            if let Err(e) = saved_session.clear() {
+                eprintln!("Unable to delete the saved session: {}", e);
+                std::process::exit(1);
+            }
+            println!("Logged out {}.", name);
+        }
+        "change-password" => {
+            let server_public_key = read_server_public_key(&server_keys);
+
+            println!("\n");
+            let name = read_name();
+            let old_password = get_input("Enter your current account password: ");
+            let new_password = read_new_password();
+
+            let mut client = connect(&rt, &args, &server_public_key, &Identity::generate());
+            let session = rt
+                .block_on(async {
+                    client.login(&name, &old_password).await?;
+                    let session = client.change_password(&old_password, &new_password).await?;
+                    client.close().await?;
+                    Ok::<_, QuietDropError>(session)
+                })
+                .unwrap_or_else(|e| {
+                    eprintln!("Unable to change the password: {}", e);
+                    std::process::exit(1);
+                });
+            println!("Password changed; {} is logged out everywhere else.", name);
+
+            if let Err(e) = saved_session(&name).save(&session.token) {
+                eprintln!("Unable to save the session token: {}", e);
+            }
+            // a keystore protected by the old password follows it to the new one
+            let keystore = Keystore::new(keystore_dir(&name));
+            if keystore.exists() && keystore.load(&old_password).is_ok() {
+                match keystore.change_passphrase(&old_password, &new_password) {
+                    Ok(()) => println!("Your keystore passphrase was changed to match."),
+                    Err(e) => eprintln!("Unable to re-encrypt your keystore: {}", e),
+                }
+            }
+        }
+        "reset-password" => {
+            let server_public_key = read_server_public_key(&server_keys);
+
+            println!("\n");
+            let name = read_name();
+            let recovery_code = get_input("Enter your recovery code: ");
+            let new_password = read_new_password();
+
+            let mut client = connect(&rt, &args, &server_public_key, &Identity::generate());
+            let credentials = rt
+                .block_on(async {
+                    let credentials = client
+                        .reset_password(&name, &recovery_code, &new_password)
+                        .await?;
+                    client.close().await?;
+                    Ok::<_, QuietDropError>(credentials)
+                })
+                .unwrap_or_else(|e| {
+                    eprintln!("Unable to reset the password: {}", e);
+                    std::process::exit(1);
+                });
+            println!("Password reset; {} is logged out everywhere else.", name);
+            print_recovery_code(&credentials.recovery_code);
+
+            if let Err(e) = saved_session(&name).save(&credentials.session.token) {
+                eprintln!("Unable to save the session token: {}", e);
+            }
+            // without the old password there is nothing to re-encrypt the keystore from
+            if Keystore::new(keystore_dir(&name)).exists() {
+                println!("Your keystore passphrase is unchanged.");
+            }
         }
         _ => {
-            eprintln!("Invalid argument. Use 'client' or 'server'.");
+            eprintln!(
+                "Invalid argument. Use 'server', 'rotate-keys', 'client', 'listen', 'outbox', \
+                'logout', 'change-password' or 'reset-password'."
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Where the keystore for `name` lives: `$QUIETDROP_HOME/<name>`, or
+/// `.quietdrop/<name>` in the current directory.
+fn keystore_dir(name: &str) -> PathBuf {
+    let base = env::var_os("QUIETDROP_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(".quietdrop"));
+    base.join(name)
+}
+
+/// The session token saved in `name`'s keystore directory.
+fn saved_session(name: &str) -> SavedSession {
+    SavedSession::new(keystore_dir(name).join(SESSION_TOKEN_FILE))
+}
+
+fn load_identity(name: &str) -> Identity {
+    let keystore = Keystore::new(keystore_dir(name));
+    let result = if keystore.exists() {
+        let mut attempts = 0;
+        loop {
+            let passphrase = get_input("Enter your keystore passphrase: ");
+            attempts += 1;
+            match keystore.load(&passphrase) {
+                Err(QuietDropError::Auth(_)) if attempts < 3 => {
+                    eprintln!("Wrong passphrase, try again.")
+                }
+                result => break result,
+            }
+        }
+    } else {
+        println!(
+            "No identity found for {}; creating one in {}",
+            name,
+            keystore.dir().display()
+        );
+        let passphrase = get_input("Choose a keystore passphrase: ");
+        if get_input("Repeat the passphrase: ") != passphrase {
+            eprintln!("Passphrases do not match.");
             std::process::exit(1);
         }
+        keystore.create(&passphrase)
+    };
+
+    result.unwrap_or_else(|e| {
+        eprintln!("Unable to open keystore: {}", e);
+        std::process::exit(1);
+    })
+}
+
+fn read_server_public_key(server_keys: &ServerKeyFiles) -> box_::PublicKey {
+    server_keys.public_key().unwrap_or_else(|e| {
+        eprintln!("Unable to load the server public key: {}", e);
+        std::process::exit(1);
+    })
+}
+
+/// The value following `flag` on the command line, if the flag is present.
+fn flag_value(args: &[String], flag: &str) -> Option<String> {
+    let pos = args.iter().position(|arg| arg == flag)?;
+    match args.get(pos + 1) {
+        Some(value) => Some(value.clone()),
+        None => {
+            eprintln!("{} needs a value", flag);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Where received files are saved: `--downloads DIR`, or `downloads` in the
+/// current directory.
+fn downloads_dir(args: &[String]) -> PathBuf {
+    PathBuf::from(flag_value(args, "--downloads").unwrap_or_else(|| "downloads".to_owned()))
+}
+
+/// The directory holding the server key files: `--key-dir DIR`, then
+/// `$QUIETDROP_KEY_DIR`, then the current directory.
+fn key_dir(args: &[String]) -> PathBuf {
+    if let Some(dir) = flag_value(args, "--key-dir") {
+        return PathBuf::from(dir);
+    }
+    env::var_os("QUIETDROP_KEY_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+/// The server's TLS certificate and key, if `--tls-cert` and `--tls-key` are
+/// given; without them the server speaks plain TCP.
+fn server_tls(args: &[String]) -> Option<ServerTls> {
+    match (
+        flag_value(args, "--tls-cert"),
+        flag_value(args, "--tls-key"),
+    ) {
+        (Some(cert), Some(key)) => Some(ServerTls::new(cert, key)),
+        (None, None) => None,
+        _ => {
+            eprintln!("--tls-cert and --tls-key must be given together");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Connects to the server, over TLS pinned to the certificate in
+/// `--tls-cert FILE` if one is given.
+fn connect(
+    rt: &Runtime,
+    args: &[String],
+    server_public_key: &box_::PublicKey,
+    identity: &Identity,
+) -> client::Client {
+    try_connect(rt, args, server_public_key, identity).unwrap_or_else(|e| {
+        eprintln!("Unable to reach the server: {}", e);
+        std::process::exit(1);
+    })
+}
+
+fn try_connect(
+    rt: &Runtime,
+    args: &[String],
+    server_public_key: &box_::PublicKey,
+    identity: &Identity,
+) -> Result<client::Client, QuietDropError> {
+    match flag_value(args, "--tls-cert") {
+        Some(cert) => {
+            let tls = ClientTls::pinned("localhost", Path::new(&cert)).unwrap_or_else(|e| {
+                eprintln!("Unable to load the server certificate: {}", e);
+                std::process::exit(1);
+            });
+            rt.block_on(client::Client::connect_tls(
+                "127.0.0.1:8080",
+                &tls,
+                server_public_key,
+                identity,
+            ))
+        }
+        None => rt.block_on(client::Client::connect(
+            "127.0.0.1:8080",
+            server_public_key,
+            identity,
+        )),
+    }
+}
+
+/// Connects as `sender`, logs in and identifies with its long-term keys.
+fn open_session(sender: &Sender) -> Result<client::Client, QuietDropError> {
+    let rt = sender.rt;
+    let mut client = try_connect(rt, sender.args, sender.server_public_key, sender.identity)?;
+    log_in(rt, &mut client, sender.name);
+    rt.block_on(client.identify(sender.name, sender.identity))?;
+    Ok(client)
+}
+
+/// Logs in to the account `name`, with the saved session token if it is
+/// still valid and otherwise with a password, offering to register the
+/// account if that fails. Saves the new session token for next time.
+fn log_in(rt: &Runtime, client: &mut client::Client, name: &str) {
+    let saved_session = saved_session(name);
+    if let Some(token) = saved_session.token().unwrap_or_else(|e| {
+        eprintln!("Ignoring the saved session: {}", e);
+        None
+    }) {
+        match rt.block_on(client.resume(&token)) {
+            Ok(_) => return,
+            Err(QuietDropError::Server {
+                code: ErrorCode::InvalidSession,
+                ..
+            }) => eprintln!("Your session has expired; please log in again."),
+            Err(e) => {
+                eprintln!("Unable to resume your session: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let session = log_in_with_password(rt, client, name);
+    if let Err(e) = saved_session.save(&session.token) {
+        eprintln!("Unable to save the session token: {}", e);
+    }
+}
+
+fn log_in_with_password(rt: &Runtime, client: &mut client::Client, name: &str) -> SessionToken {
+    let password = get_input("Enter your account password: ");
+    match rt.block_on(client.login(name, &password)) {
+        Ok(session) => return session,
+        Err(QuietDropError::Server {
+            code: ErrorCode::InvalidCredentials,
+            ..
+        }) => {}
+        Err(e) => {
+            eprintln!("Unable to log in: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    let answer = get_input(&format!(
+        "Could not log in as {}. Register it as a new account? [y/N] ",
+        name
+    ));
+    if !answer.eq_ignore_ascii_case("y") {
+        std::process::exit(1);
+    }
+    if get_input("Repeat the password: ") != password {
+        eprintln!("Passwords do not match.");
+        std::process::exit(1);
+    }
+    let credentials = rt
+        .block_on(client.register(name, &password))
+        .unwrap_or_else(|e| {
+            eprintln!("Unable to register {}: {}", name, e);
+            std::process::exit(1);
+        });
+    print_recovery_code(&credentials.recovery_code);
+    credentials.session
+}
+
+/// Asks for an account name. It names the keystore directory, so names the
+/// server would refuse are refused here before any path is built from them.
+fn read_name() -> String {
+    let name = get_input("Enter your name: ");
+    if !is_valid_account_name(&name) {
+        eprintln!("Invalid name: {}", name);
+        std::process::exit(1);
+    }
+    name
+}
+
+/// Asks for a new password twice.
+fn read_new_password() -> String {
+    let password = get_input("Choose a new account password: ");
+    if get_input("Repeat the new password: ") != password {
+        eprintln!("Passwords do not match.");
+        std::process::exit(1);
+    }
+    password
+}
+
+fn print_recovery_code(recovery_code: &str) {
+    println!(
+        "\nYour recovery code is {}\n\
+        Write it down and keep it safe: it is the only way to reset a forgotten \
+        password, and it is not shown again.\n",
+        recovery_code
+    );
+}
+
+/// Opens the outbox kept in `name`'s keystore directory.
+fn open_outbox(name: &str) -> Outbox {
+    let path = keystore_dir(name).join(OUTBOX_FILE);
+    Outbox::open(path, RetryPolicy::default()).unwrap_or_else(|e| {
+        eprintln!("Unable to open the outbox: {}", e);
+        std::process::exit(1);
+    })
+}
+
+/// What the client needs to reconnect, and to answer messages, on its own.
+struct Sender<'a> {
+    rt: &'a Runtime,
+    args: &'a [String],
+    name: &'a str,
+    identity: &'a Identity,
+    server_public_key: &'a box_::PublicKey,
+}
+
+/// Sends everything in the outbox, waiting out rate limits and retry delays.
+///
+/// While the server is unreachable this keeps reconnecting, backing off
+/// between attempts, until every message is sent or has failed. Quitting in
+/// the meantime loses nothing: the outbox is retried on the next run.
+fn deliver_outbox(sender: &Sender, client: &mut client::Client, outbox: &Outbox) {
+    let rt = sender.rt;
+    let mut connected = true;
+    while let Some(wait) = outbox.next_attempt_in() {
+        if !wait.is_zero() {
+            println!(
+                "{} message(s) waiting in the outbox; retrying in {} ms",
+                outbox.pending_count(),
+                wait.as_millis()
+            );
+            std::thread::sleep(wait);
+        }
+
+        if !connected {
+            match open_session(sender) {
+                Ok(reconnected) => {
+                    *client = reconnected;
+                    connected = true;
+                }
+                Err(e) => {
+                    eprintln!("Unable to reach the server: {}", e);
+                    if let Err(e) = outbox.record_failure(&e) {
+                        eprintln!("Unable to update the outbox: {}", e);
+                        std::process::exit(1);
+                    }
+                    report_outbox(outbox);
+                    continue;
+                }
+            }
+        }
+
+        if let Err(e) = rt.block_on(outbox.flush(client, sender.identity)) {
+            eprintln!("Lost the connection to the server: {}", e);
+            connected = false;
+        }
+        report_outbox(outbox);
+    }
+}
+
+/// Seals `text` to the recipient, signs it and leaves it in the outbox.
+fn queue_message(
+    sender: &Sender,
+    outbox: &Outbox,
+    recipient: &str,
+    recipient_public_key: &box_::PublicKey,
+    text: &str,
+) {
+    let identity = sender.identity;
+    let mut msg = Message {
+        id: Message::new_id(),
+        timestamp: chrono::Utc::now(),
+        message_type: MessageType::Text,
+        sender: sender.name.to_owned(),
+        recipient: recipient.to_owned(),
+        content: vec![],
+        public_key: *identity.public_key(),
+        signing_key: *identity.signing_key(),
+        signature: vec![],
+    };
+    msg.encrypt_content(text, recipient_public_key, identity.secret_key());
+    msg.sign(identity.signing_secret_key());
+    if let Err(e) = outbox.push(msg) {
+        eprintln!("Unable to save the message to the outbox: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Queues messages to `recipient` while the server cannot be reached, sealed
+/// to the key saved from an earlier lookup. The outbox sends them on the
+/// next run that connects.
+fn queue_offline(sender: &Sender, outbox: &Outbox, recipient: &str, error: &QuietDropError) {
+    if let Err(e) = outbox.record_failure(error) {
+        eprintln!("Unable to update the outbox: {}", e);
+        std::process::exit(1);
+    }
+    report_outbox(outbox);
+
+    let recipient_public_key = match contact_key(sender.name, recipient) {
+        Some(key) => key,
+        None => {
+            eprintln!(
+                "No saved key for {}; connect once to look it up before writing offline.",
+                recipient
+            );
+            std::process::exit(1);
+        }
+    };
+
+    println!(
+        "Offline. Messages wait in the outbox until the next run; enter an empty message to quit."
+    );
+    loop {
+        let msg_str = get_input("Enter your message: ");
+        if msg_str.is_empty() {
+            break;
+        }
+        if msg_str.starts_with("/file ") {
+            eprintln!("Files can only be sent while connected.");
+            continue;
+        }
+        queue_message(sender, outbox, recipient, &recipient_public_key, &msg_str);
+    }
+
+    let pending = outbox.pending_count();
+    if pending > 0 {
+        println!(
+            "{} message(s) waiting in the outbox for the next run.",
+            pending
+        );
+    }
+}
+
+/// Where `name` keeps the public key last looked up for `contact`, if
+/// `contact` is usable as a file name.
+fn contact_key_path(name: &str, contact: &str) -> Option<PathBuf> {
+    if contact.is_empty() || contact.contains(['/', '\\']) || contact.starts_with('.') {
+        return None;
+    }
+    Some(
+        keystore_dir(name)
+            .join("contacts")
+            .join(format!("{}.pub", contact)),
+    )
+}
+
+fn contact_key(name: &str, contact: &str) -> Option<box_::PublicKey> {
+    let bytes = std::fs::read(contact_key_path(name, contact)?).ok()?;
+    box_::PublicKey::from_slice(&bytes)
+}
+
+fn save_contact_key(name: &str, contact: &str, key: &box_::PublicKey) {
+    let path = match contact_key_path(name, contact) {
+        Some(path) => path,
+        None => return,
+    };
+    let bytes = key.as_ref();
+    if bytes.len() != box_::PUBLICKEYBYTES {
+        eprintln!(
+            "Not saving {}'s key: it is {} bytes, not {}",
+            contact,
+            bytes.len(),
+            box_::PUBLICKEYBYTES
+        );
+        return;
+    }
+    let saved = path
+        .parent()
+        .map_or(Ok(()), create_private_dir)
+        .and_then(|_| write_private_file(&path, bytes));
+    if let Err(e) = saved {
+        eprintln!("Unable to save {}'s key: {}", contact, e);
+    }
+}
+
+/// Prints what became of messages that are no longer pending and drops
+/// them from the outbox.
+fn report_outbox(outbox: &Outbox) {
+    let finished = outbox.remove_finished().unwrap_or_else(|e| {
+        eprintln!("Unable to update the outbox: {}", e);
+        std::process::exit(1);
+    });
+    for entry in finished {
+        if let OutboxStatus::Failed { reason } = entry.status {
+            eprintln!(
+                "Message to {} was not delivered: {}",
+                entry.message.recipient, reason
+            );
+        }
+    }
+}
+
+/// Files on their way in or out, kept on disk until they arrive.
+struct Transfers {
+    incoming: IncomingFiles,
+    outgoing: OutgoingFiles,
+}
+
+/// Opens the downloads directory and the uploads kept in `name`'s keystore
+/// directory, along with whatever was left unfinished in them.
+fn open_transfers(args: &[String], name: &str, identity: &Identity) -> Transfers {
+    let incoming = IncomingFiles::open(downloads_dir(args), name, identity);
+    let uploads_dir = keystore_dir(name).join(TRANSFERS_DIR);
+    let outgoing = OutgoingFiles::open(uploads_dir, name, identity);
+    match incoming.and_then(|incoming| Ok((incoming, outgoing?))) {
+        Ok((incoming, outgoing)) => Transfers { incoming, outgoing },
+        Err(e) => {
+            eprintln!("Unable to open unfinished file transfers: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Asks the senders of unfinished downloads to resend what is missing and
+/// sends the rest of unfinished uploads.
+fn resume_transfers(sender: &Sender, client: &mut client::Client, transfers: &mut Transfers) {
+    for progress in transfers
+        .incoming
+        .transfers()
+        .iter()
+        .chain(&transfers.outgoing.transfers())
+    {
+        print_progress(progress);
+    }
+    if let Err(e) = transfers.incoming.request_resume() {
+        eprintln!("Unable to resume downloads: {}", e);
+    }
+    continue_transfers(sender, client, transfers);
+}
+
+/// Sends the acknowledgements owed to the senders of files, then any upload
+/// chunks the server has not accepted yet, such as ones a recipient asked
+/// for again.
+fn continue_transfers(sender: &Sender, client: &mut client::Client, transfers: &mut Transfers) {
+    let rt = sender.rt;
+    for reply in transfers.incoming.take_replies() {
+        if let Err(e) = rt.block_on(client.send(&reply)) {
+            eprintln!(
+                "Unable to acknowledge a file from {}: {}",
+                reply.recipient, e
+            );
+        }
+    }
+    if !transfers.outgoing.has_unsent() {
+        return;
+    }
+    if let Err(e) = rt.block_on(transfers.outgoing.send_pending(client, print_progress)) {
+        eprintln!(
+            "Unable to send files: {}; they will resume from the last chunk sent",
+            e
+        );
+    }
+}
+
+/// Shows a message routed to us, or the read receipt it carries, and tells
+/// the sender we read it.
+fn print_incoming(
+    client: &mut client::Client,
+    msg: &Message,
+    reader: &Sender,
+    transfers: &mut Transfers,
+) {
+    match msg.message_type {
+        // chunks are checked against the offer they belong to instead
+        MessageType::FileChunk => {
+            receive_file(msg, transfers);
+            return;
+        }
+        // acknowledgements only count from the recipient of the file
+        MessageType::TransferAck => {
+            match transfers.outgoing.handle(msg) {
+                Ok(Some(progress)) if progress.is_complete() => print_progress(&progress),
+                Ok(_) => {}
+                Err(e) => eprintln!("\n## Ignoring a transfer acknowledgement: {}\n", e),
+            }
+            return;
+        }
+        _ => {}
+    }
+
+    let rt = reader.rt;
+    let content = match msg.decrypt_content(reader.identity.secret_key()) {
+        Ok(content) => content,
+        Err(e) => {
+            eprintln!(
+                "\n## Could not decrypt message from {}: {}\n",
+                msg.sender, e
+            );
+            return;
+        }
+    };
+
+    // the signature only counts if it was made with the key the sender published
+    let published = rt
+        .block_on(client.lookup_keys(&msg.sender, reader.server_public_key))
+        .ok();
+    let signing_key = published.as_ref().and_then(|record| record.signing_key);
+    if signing_key.as_ref() != Some(&msg.signing_key) {
+        eprintln!(
+            "\n## Dropping message claiming to be from {}: not signed with their published key\n",
+            msg.sender
+        );
+        return;
+    }
+    // warn if the key the message was sealed with is not the one the sender published
+    if published.and_then(|record| record.public_key).as_ref() != Some(&msg.public_key) {
+        eprintln!(
+            "!! Warning: {} sent this from a key they have not published",
+            msg.sender
+        );
+    }
+
+    match msg.message_type {
+        // a read receipt's content is the ID of the message that was read
+        MessageType::ReadReceipt => {
+            println!("## {} read message {}", msg.sender, content);
+            return;
+        }
+        MessageType::File => {
+            println!("\n## {} is sending a file...", msg.sender);
+            receive_file(msg, transfers);
+            return;
+        }
+        _ => {}
+    }
+    println!(
+        "\n## Message from {} at {}:\n{}\n",
+        msg.sender, msg.timestamp, content
+    );
+    if let Err(e) = rt.block_on(client.send_read_receipt(msg, reader.name, reader.identity)) {
+        eprintln!("Unable to send a read receipt to {}: {}", msg.sender, e);
+    }
+}
+
+/// Adds a file offer or chunk to its download, saying where the file went
+/// once it is complete.
+fn receive_file(msg: &Message, transfers: &mut Transfers) {
+    let result = transfers.incoming.handle(msg);
+    for progress in transfers.incoming.take_progress() {
+        print_progress(&progress);
+    }
+    match result {
+        Ok(Some(file)) => println!(
+            "\n## File from {}: {} ({} bytes, {}) saved to {}\n",
+            file.sender,
+            file.header.name,
+            file.header.size,
+            file.header.mime_type,
+            file.path.display()
+        ),
+        Ok(None) => {}
+        Err(e) => eprintln!("\n## Could not receive a file from {}: {}\n", msg.sender, e),
+    }
+}
+
+/// Encrypts the file at `path` and sends it chunk by chunk. Whatever is not
+/// sent by the time the connection drops is sent after the next reconnect.
+fn send_file(
+    sender: &Sender,
+    client: &mut client::Client,
+    transfers: &mut Transfers,
+    path: &Path,
+    recipient: &str,
+    recipient_public_key: &box_::PublicKey,
+) {
+    if let Err(e) = transfers
+        .outgoing
+        .start(path, recipient, recipient_public_key)
+    {
+        eprintln!("Unable to send {}: {}", path.display(), e);
+        return;
+    }
+    continue_transfers(sender, client, transfers);
+}
+
+fn print_progress(progress: &TransferProgress) {
+    let verb = match progress.direction {
+        TransferDirection::Upload if progress.is_complete() => "Delivered",
+        TransferDirection::Upload => "Sending",
+        TransferDirection::Download if progress.is_complete() => "Received",
+        TransferDirection::Download => "Receiving",
+    };
+    println!(
+        "{} {} ({}): {}/{} bytes, chunk {} of {}",
+        verb,
+        progress.name,
+        progress.peer,
+        progress.bytes_done(),
+        progress.size,
+        progress.chunks_done,
+        progress.chunk_count
+    );
+}
+
+/// Prints the delivery receipts that arrived for messages we sent.
+fn print_delivery_receipts(client: &mut client::Client) {
+    for receipt in client.take_delivery_receipts() {
+        println!(
+            "## Message {} delivered to {} at {}",
+            receipt.message_id, receipt.recipient, receipt.delivered_at
+        );
     }
 }
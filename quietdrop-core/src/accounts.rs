@@ -0,0 +1,169 @@
+use crate::authentication::{
+    hash_password_with, needs_rehash, verify_password, Credential, CredentialStore, PasswordPolicy,
+};
+use crate::error::Result;
+use std::sync::Arc;
+
+/// Shortest password the server accepts at registration.
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// Bytes of randomness in a recovery code.
+const RECOVERY_CODE_BYTES: usize = 16;
+
+/// Whether `name` can be an account name.
+///
+/// Clients keep each identity in a directory named after it, so a name must
+/// not be empty, contain a path separator or start with a dot.
+pub fn is_valid_account_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(['/', '\\']) && !name.starts_with('.')
+}
+
+/// Registered account names and their Argon2id password hashes.
+///
+/// Only PHC-format hashes are kept, in the given `CredentialStore`;
+/// passwords and recovery codes themselves are never stored. New hashes
+/// follow `policy`, and older password hashes are upgraded to it the next
+/// time their password is verified.
+pub struct Accounts {
+    store: Arc<dyn CredentialStore>,
+    policy: PasswordPolicy,
+    /// Checked against when a name is unknown, so a failed login takes as
+    /// long whether or not the account exists.
+    decoy_hash: String,
+}
+
+impl Accounts {
+    pub fn new(store: Arc<dyn CredentialStore>, policy: PasswordPolicy) -> Result<Self> {
+        let (decoy_hash, _) = hash_password_with("quietdrop-decoy-password", &policy)?;
+        Ok(Accounts {
+            store,
+            policy,
+            decoy_hash,
+        })
+    }
+
+    /// Creates an account for `name`, returning its one-time recovery code.
+    ///
+    /// Returns `None` without changing anything if the name is already taken.
+    pub fn register(&self, name: &str, password: &str) -> Result<Option<String>> {
+        if self.contains(name)? {
+            return Ok(None);
+        }
+        // Hashing is slow, so it happens before the store is touched
+        let (password_hash, _) = hash_password_with(password, &self.policy)?;
+        let (recovery_code, recovery_hash) = self.new_recovery_code()?;
+        let credential = Credential {
+            password_hash,
+            recovery_hash: Some(recovery_hash),
+        };
+        Ok(self
+            .store
+            .insert(name, &credential)?
+            .then_some(recovery_code))
+    }
+
+    /// Whether `password` is correct for the account `name`.
+    ///
+    /// Unknown names are reported as a wrong password. A correct password
+    /// whose hash was made under other parameters is rehashed under the
+    /// current policy.
+    pub fn verify(&self, name: &str, password: &str) -> Result<bool> {
+        let mut credential = match self.store.get(name)? {
+            Some(credential) => credential,
+            None => {
+                verify_password(&self.decoy_hash, "", password)?;
+                return Ok(false);
+            }
+        };
+        if !verify_password(&credential.password_hash, "", password)? {
+            return Ok(false);
+        }
+
+        if needs_rehash(&credential.password_hash, &self.policy)? {
+            // The login stands even if the upgrade fails; it is retried next time
+            let upgraded = hash_password_with(password, &self.policy).and_then(|(hash, _)| {
+                credential.password_hash = hash;
+                self.store.update(name, &credential)
+            });
+            if let Err(e) = upgraded {
+                eprintln!("## Could not upgrade the password hash for {}: {}", name, e);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Replaces the password of `name` if `old_password` is correct.
+    pub fn change_password(
+        &self,
+        name: &str,
+        old_password: &str,
+        new_password: &str,
+    ) -> Result<bool> {
+        if !self.verify(name, old_password)? {
+            return Ok(false);
+        }
+        let (password_hash, _) = hash_password_with(new_password, &self.policy)?;
+        match self.store.get(name)? {
+            Some(mut credential) => {
+                credential.password_hash = password_hash;
+                self.store.update(name, &credential)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Replaces the password of `name` if `recovery_code` is its current
+    /// recovery code.
+    ///
+    /// The code is used up: on success a new one is returned in its place.
+    /// Unknown names and wrong codes both give `None`.
+    pub fn reset_password(
+        &self,
+        name: &str,
+        recovery_code: &str,
+        new_password: &str,
+    ) -> Result<Option<String>> {
+        let recovery_code = normalize_recovery_code(recovery_code);
+        let mut credential = match self.store.get(name)? {
+            Some(credential) if credential.recovery_hash.is_some() => credential,
+            _ => {
+                verify_password(&self.decoy_hash, "", &recovery_code)?;
+                return Ok(None);
+            }
+        };
+        let recovery_hash = credential.recovery_hash.take().unwrap_or_default();
+        if !verify_password(&recovery_hash, "", &recovery_code)? {
+            return Ok(None);
+        }
+
+        let (password_hash, _) = hash_password_with(new_password, &self.policy)?;
+        let (new_code, new_recovery_hash) = self.new_recovery_code()?;
+        credential.password_hash = password_hash;
+        credential.recovery_hash = Some(new_recovery_hash);
+        Ok(self.store.update(name, &credential)?.then_some(new_code))
+    }
+
+    pub fn contains(&self, name: &str) -> Result<bool> {
+        Ok(self.store.get(name)?.is_some())
+    }
+
+    /// A fresh recovery code and its hash.
+    fn new_recovery_code(&self) -> Result<(String, String)> {
+        let mut bytes = [0u8; RECOVERY_CODE_BYTES];
+        sodiumoxide::randombytes::randombytes_into(&mut bytes);
+        let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let (hash, _) = hash_password_with(&hex, &self.policy)?;
+
+        // Grouped for reading aloud or writing down: 1a2b-3c4d-...
+        let groups: Vec<&str> = (0..hex.len()).step_by(4).map(|i| &hex[i..i + 4]).collect();
+        Ok((groups.join("-"), hash))
+    }
+}
+
+/// Recovery codes are compared without their dashes, spaces or case.
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
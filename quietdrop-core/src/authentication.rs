@@ -1,4 +1,5 @@
-use anyhow::{Error, Result}; // Import Result and Error from anyhow
+use crate::error::{QuietDropError, Result};
+use crate::keystore::write_private_file;
 use argon2::Algorithm;
 use argon2::Params;
 use argon2::Version;
@@ -8,44 +9,326 @@ use argon2::{
     Argon2, PasswordHasher, PasswordVerifier,
 };
 use rand::rngs::OsRng;
-use std::fs::File;
-use std::io::{Read, Write};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
 const SALT_FILE: &str = "salt.txt";
 
+#[deprecated(
+    note = "the PHC string from `hash_password` embeds its own salt; keep it in a `CredentialStore`"
+)]
 pub fn save_salt(salt: &SaltString) -> std::io::Result<()> {
     let mut file = File::create(SALT_FILE)?;
     file.write_all(salt.as_ref().as_bytes())?;
     Ok(())
 }
 
+#[deprecated(
+    note = "the PHC string from `hash_password` embeds its own salt; keep it in a `CredentialStore`"
+)]
 pub fn load_salt() -> Result<SaltString> {
     let mut file = File::open(SALT_FILE)?;
     let mut salt_str = String::new();
     file.read_to_string(&mut salt_str)?;
-    SaltString::new(&salt_str).map_err(|e| Error::msg(e.to_string()))
+    SaltString::new(&salt_str).map_err(auth_error)
 }
 
+/// Argon2id cost parameters for new password hashes and derived keys.
+///
+/// Hashes and keystores record the parameters they were made with, so
+/// raising the policy does not invalidate existing ones; `needs_rehash`
+/// tells when a hash should be replaced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PasswordPolicy {
+    /// Memory cost in KiB.
+    pub memory_kib: u32,
+    /// Number of passes over the memory.
+    pub iterations: u32,
+    /// Degree of parallelism (lanes).
+    pub parallelism: u32,
+}
+
+impl PasswordPolicy {
+    pub fn new(memory_kib: u32, iterations: u32, parallelism: u32) -> Self {
+        PasswordPolicy {
+            memory_kib,
+            iterations,
+            parallelism,
+        }
+    }
+
+    fn argon2(&self) -> Result<Argon2<'static>> {
+        let params = Params::new(self.memory_kib, self.iterations, self.parallelism, None)
+            .map_err(auth_error)?;
+        Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+    }
+}
+
+impl Default for PasswordPolicy {
+    /// 19 MiB of memory, 2 passes, 1 lane: the OWASP minimum for Argon2id.
+    fn default() -> Self {
+        PasswordPolicy::new(19 * 1024, 2, 1)
+    }
+}
+
+/// Hashes `password` with a fresh random salt under the default policy.
+///
+/// Returns the PHC string, which embeds the salt and parameters and is all
+/// that needs storing, and the salt on its own.
 pub fn hash_password(password: &str) -> Result<(String, String)> {
+    hash_password_with(password, &PasswordPolicy::default())
+}
+
+/// Like `hash_password`, under an explicit policy.
+pub fn hash_password_with(password: &str, policy: &PasswordPolicy) -> Result<(String, String)> {
     let salt = SaltString::generate(&mut OsRng);
-    let config = Params::new(3, 4096, 1, None).map_err(|e| Error::msg(e.to_string()))?;
 
-    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, config);
+    let argon2 = policy.argon2()?;
     let password_hash = argon2
         .hash_password(password.as_bytes(), &salt)
-        .map_err(|e| Error::msg(e.to_string()))?
+        .map_err(auth_error)?
         .to_string();
 
     Ok((password_hash, salt.as_str().to_string()))
 }
 
+/// Checks `password` against a PHC string from `hash_password`.
+///
+/// The salt and cost parameters are read from the PHC string; `_salt` is
+/// ignored and only kept for existing callers.
 pub fn verify_password(hashed_password: &str, _salt: &str, password: &str) -> Result<bool> {
-    let password_hash =
-        PasswordHash::new(hashed_password).map_err(|e| Error::msg(e.to_string()))?;
-    let config = Params::new(3, 4096, 1, None).map_err(|e| Error::msg(e.to_string()))?;
-    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, config);
+    let password_hash = PasswordHash::new(hashed_password).map_err(auth_error)?;
 
-    Ok(argon2
+    Ok(Argon2::default()
         .verify_password(password.as_bytes(), &password_hash)
         .is_ok())
 }
+
+/// Whether a PHC string was made with anything other than Argon2id under
+/// exactly `policy`, and so should be replaced after the next successful
+/// verify.
+pub fn needs_rehash(hashed_password: &str, policy: &PasswordPolicy) -> Result<bool> {
+    let password_hash = PasswordHash::new(hashed_password).map_err(auth_error)?;
+    if password_hash.algorithm != Algorithm::Argon2id.ident()
+        || password_hash.version != Some(Version::V0x13.into())
+    {
+        return Ok(true);
+    }
+    let params = Params::try_from(&password_hash).map_err(auth_error)?;
+    Ok(params.m_cost() != policy.memory_kib
+        || params.t_cost() != policy.iterations
+        || params.p_cost() != policy.parallelism)
+}
+
+/// Derives `output.len()` bytes of key material from a passphrase and salt,
+/// with the costs in `policy`. The policy must be stored with whatever the
+/// key protects, to derive the same key again.
+pub fn derive_key(
+    passphrase: &str,
+    salt: &[u8],
+    policy: &PasswordPolicy,
+    output: &mut [u8],
+) -> Result<()> {
+    let argon2 = policy.argon2()?;
+    argon2
+        .hash_password_into(passphrase.as_bytes(), salt, output)
+        .map_err(auth_error)
+}
+
+/// What the server keeps for one account.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Credential {
+    /// PHC hash of the password.
+    pub password_hash: String,
+    /// PHC hash of the one-time recovery code, until it is used.
+    #[serde(default)]
+    pub recovery_hash: Option<String>,
+}
+
+impl Credential {
+    pub fn new(password_hash: impl Into<String>) -> Self {
+        Credential {
+            password_hash: password_hash.into(),
+            recovery_hash: None,
+        }
+    }
+}
+
+/// Per-user password records, one `Credential` per account name.
+///
+/// Implementations must be safe to share between connections.
+pub trait CredentialStore: Send + Sync + fmt::Debug {
+    /// The credential stored for `name`, if the name is known.
+    fn get(&self, name: &str) -> Result<Option<Credential>>;
+
+    /// Stores a credential for a new `name`. Returns `false`, changing
+    /// nothing, if the name already has one.
+    fn insert(&self, name: &str, credential: &Credential) -> Result<bool>;
+
+    /// Replaces the credential for an existing `name`. Returns `false` if the
+    /// name is unknown.
+    fn update(&self, name: &str, credential: &Credential) -> Result<bool>;
+}
+
+/// Keeps credentials in memory only; they are gone when the process exits.
+#[derive(Default)]
+pub struct MemoryCredentialStore {
+    credentials: Mutex<HashMap<String, Credential>>,
+}
+
+impl CredentialStore for MemoryCredentialStore {
+    fn get(&self, name: &str) -> Result<Option<Credential>> {
+        Ok(self.credentials.lock().unwrap().get(name).cloned())
+    }
+
+    fn insert(&self, name: &str, credential: &Credential) -> Result<bool> {
+        let mut credentials = self.credentials.lock().unwrap();
+        if credentials.contains_key(name) {
+            return Ok(false);
+        }
+        credentials.insert(name.to_owned(), credential.clone());
+        Ok(true)
+    }
+
+    fn update(&self, name: &str, credential: &Credential) -> Result<bool> {
+        match self.credentials.lock().unwrap().get_mut(name) {
+            Some(stored) => {
+                *stored = credential.clone();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+impl fmt::Debug for MemoryCredentialStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryCredentialStore")
+            .field("accounts", &self.credentials.lock().unwrap().len())
+            .finish()
+    }
+}
+
+const CREDENTIAL_FILE_VERSION: u8 = 2;
+/// Version 1 files held only the password hash for each name.
+const CREDENTIAL_FILE_VERSION_HASH_ONLY: u8 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct CredentialFile<T> {
+    version: u8,
+    credentials: BTreeMap<String, T>,
+}
+
+#[derive(Deserialize)]
+struct FileVersion {
+    version: u8,
+}
+
+/// Keeps credentials in a JSON file, rewritten atomically with owner-only
+/// permissions on every change.
+pub struct FileCredentialStore {
+    path: PathBuf,
+    credentials: Mutex<BTreeMap<String, Credential>>,
+}
+
+impl FileCredentialStore {
+    /// Opens the store at `path`, starting empty if the file does not exist.
+    ///
+    /// A version 1 file is read as password hashes without recovery codes
+    /// and rewritten in the current format on the next change.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let credentials = match fs::read(&path) {
+            Ok(bytes) => match serde_json::from_slice::<FileVersion>(&bytes)?.version {
+                CREDENTIAL_FILE_VERSION => {
+                    serde_json::from_slice::<CredentialFile<Credential>>(&bytes)?.credentials
+                }
+                CREDENTIAL_FILE_VERSION_HASH_ONLY => {
+                    serde_json::from_slice::<CredentialFile<String>>(&bytes)?
+                        .credentials
+                        .into_iter()
+                        .map(|(name, hash)| (name, Credential::new(hash)))
+                        .collect()
+                }
+                version => {
+                    return Err(auth_error(format!(
+                        "{} has unsupported version {}",
+                        path.display(),
+                        version
+                    )))
+                }
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(FileCredentialStore {
+            path,
+            credentials: Mutex::new(credentials),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn save(&self, credentials: &BTreeMap<String, Credential>) -> Result<()> {
+        let file = CredentialFile {
+            version: CREDENTIAL_FILE_VERSION,
+            credentials: credentials.clone(),
+        };
+        write_private_file(&self.path, &serde_json::to_vec_pretty(&file)?)
+    }
+}
+
+impl CredentialStore for FileCredentialStore {
+    fn get(&self, name: &str) -> Result<Option<Credential>> {
+        Ok(self.credentials.lock().unwrap().get(name).cloned())
+    }
+
+    fn insert(&self, name: &str, credential: &Credential) -> Result<bool> {
+        let mut credentials = self.credentials.lock().unwrap();
+        if credentials.contains_key(name) {
+            return Ok(false);
+        }
+        credentials.insert(name.to_owned(), credential.clone());
+        if let Err(e) = self.save(&credentials) {
+            credentials.remove(name);
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    fn update(&self, name: &str, credential: &Credential) -> Result<bool> {
+        let mut credentials = self.credentials.lock().unwrap();
+        let previous = match credentials.insert(name.to_owned(), credential.clone()) {
+            Some(previous) => previous,
+            None => {
+                credentials.remove(name);
+                return Ok(false);
+            }
+        };
+        if let Err(e) = self.save(&credentials) {
+            credentials.insert(name.to_owned(), previous);
+            return Err(e);
+        }
+        Ok(true)
+    }
+}
+
+impl fmt::Debug for FileCredentialStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileCredentialStore")
+            .field("path", &self.path)
+            .field("accounts", &self.credentials.lock().unwrap().len())
+            .finish()
+    }
+}
+
+fn auth_error(e: impl std::fmt::Display) -> QuietDropError {
+    QuietDropError::Auth(e.to_string())
+}
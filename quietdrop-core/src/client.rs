@@ -1,25 +1,497 @@
+use crate::connection::{Connection, ConnectionConfig};
+use crate::directory::KeyRecord;
+use crate::encryption::{Identity, PublicKey, SecretKey};
+use crate::error::{QuietDropError, Result};
 use crate::message::Message;
-use serde_json;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use crate::protocol::{
+    self, Capability, DeliveryReceipt, ErrorCode, Packet, ServerResponse, SessionToken,
+    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use crate::tls::{BoxedStream, ClientTls};
+use crate::transport;
+use std::collections::VecDeque;
+use std::time::Duration;
 use tokio::net::TcpStream;
+use tokio::time::timeout;
 
-pub async fn send_message(
-    message: &Message,
-    server_addr: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut stream = TcpStream::connect(server_addr).await?;
+/// What the server hands out when an account is created or its password is
+/// reset: a session for later connections and the one-time recovery code the
+/// user must keep to reset the password again.
+#[derive(Debug, Clone)]
+pub struct AccountCredentials {
+    pub session: SessionToken,
+    pub recovery_code: String,
+}
+
+/// A persistent client connection to a QuietDrop server.
+///
+/// Messages routed to this client can arrive at any time, including while it is
+/// waiting for an acknowledgement; those are kept in an inbox and returned by
+/// `recv_message`. Messages whose signature does not verify are dropped.
+/// Delivery receipts for messages this client sent are collected the same way
+/// and handed out by `take_delivery_receipts`.
+///
+/// The connection is encrypted and authenticated in both directions: the
+/// server must prove it holds the secret key for the pinned
+/// `server_public_key`, and the client proves it holds its identity's key.
+pub struct Client {
+    connection: Connection<BoxedStream>,
+    inbox: VecDeque<Message>,
+    delivered: Vec<DeliveryReceipt>,
+    secret_key: Option<SecretKey>,
+    protocol_version: u16,
+    capabilities: Vec<Capability>,
+}
+
+impl Client {
+    pub async fn connect(
+        server_addr: &str,
+        server_public_key: &PublicKey,
+        identity: &Identity,
+    ) -> Result<Client> {
+        Client::connect_with_config(
+            server_addr,
+            server_public_key,
+            identity,
+            ConnectionConfig::default(),
+        )
+        .await
+    }
+
+    /// Connects, sets up the secure transport and performs the version
+    /// handshake.
+    ///
+    /// Fails with `Handshake` if the server does not hold the key pinned by
+    /// `server_public_key`, and with `IncompatibleVersion` if the server and
+    /// this build have no protocol version in common.
+    pub async fn connect_with_config(
+        server_addr: &str,
+        server_public_key: &PublicKey,
+        identity: &Identity,
+        config: ConnectionConfig,
+    ) -> Result<Client> {
+        let stream = TcpStream::connect(server_addr).await?;
+        Client::establish(Box::new(stream), server_public_key, identity, config).await
+    }
+
+    /// Like `connect`, but over TLS, for servers configured with a
+    /// certificate or sitting behind a TLS terminator.
+    ///
+    /// The transport handshake still runs inside TLS, so `server_public_key`
+    /// is checked as well as the certificate.
+    pub async fn connect_tls(
+        server_addr: &str,
+        tls: &ClientTls,
+        server_public_key: &PublicKey,
+        identity: &Identity,
+    ) -> Result<Client> {
+        let config = ConnectionConfig::default();
+        let stream = TcpStream::connect(server_addr).await?;
+        let stream = timeout(config.idle_timeout, tls.connect(stream))
+            .await
+            .map_err(|_| {
+                QuietDropError::Tls("Server did not complete the TLS handshake".to_owned())
+            })??;
+        Client::establish(stream, server_public_key, identity, config).await
+    }
+
+    async fn establish(
+        mut stream: BoxedStream,
+        server_public_key: &PublicKey,
+        identity: &Identity,
+        config: ConnectionConfig,
+    ) -> Result<Client> {
+        let handshake = transport::connect(&mut stream, server_public_key, identity.secret_key());
+        let channel = timeout(config.idle_timeout, handshake)
+            .await
+            .map_err(|_| {
+                QuietDropError::Handshake("Server did not complete the handshake".to_owned())
+            })??;
+        let mut client = Client {
+            connection: Connection::with_channel(stream, config, channel),
+            inbox: VecDeque::new(),
+            delivered: Vec::new(),
+            secret_key: None,
+            protocol_version: 0,
+            capabilities: Vec::new(),
+        };
+        client.handshake().await?;
+        Ok(client)
+    }
+
+    /// The protocol version agreed with the server.
+    pub fn protocol_version(&self) -> u16 {
+        self.protocol_version
+    }
+
+    /// Capabilities supported by both this build and the server.
+    pub fn capabilities(&self) -> &[Capability] {
+        &self.capabilities
+    }
+
+    async fn handshake(&mut self) -> Result<()> {
+        let name = concat!("quietdrop-core/", env!("CARGO_PKG_VERSION"));
+        self.connection.send(&protocol::hello(name)).await?;
+        match self.next_reply().await? {
+            ServerResponse::Welcome {
+                version,
+                capabilities,
+            } => {
+                if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+                    return Err(QuietDropError::IncompatibleVersion(format!(
+                        "Server chose protocol version {}, but this client supports {} to {}",
+                        version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+                    )));
+                }
+                self.protocol_version = version;
+                self.capabilities = capabilities;
+                Ok(())
+            }
+            other => Err(unexpected("Hello", other)),
+        }
+    }
+
+    /// Creates the account `name` on the server and logs this connection in
+    /// to it, returning a session token for later connections and the
+    /// account's recovery code.
+    ///
+    /// Fails with a `NameTaken` or `WeakPassword` server error if the name is
+    /// already registered or the password is too short.
+    pub async fn register(&mut self, name: &str, password: &str) -> Result<AccountCredentials> {
+        self.connection
+            .send(&Packet::Register {
+                name: name.to_owned(),
+                password: password.to_owned(),
+            })
+            .await?;
+        match self.next_reply().await? {
+            ServerResponse::Registered {
+                session,
+                recovery_code,
+            } => Ok(AccountCredentials {
+                session,
+                recovery_code,
+            }),
+            other => Err(unexpected("Register", other)),
+        }
+    }
+
+    /// Logs this connection in to the account `name`, returning a session
+    /// token for later connections.
+    ///
+    /// A wrong password or unknown name fails with an `InvalidCredentials`
+    /// server error.
+    pub async fn login(&mut self, name: &str, password: &str) -> Result<SessionToken> {
+        self.connection
+            .send(&Packet::Login {
+                name: name.to_owned(),
+                password: password.to_owned(),
+            })
+            .await?;
+        match self.next_reply().await? {
+            ServerResponse::LoggedIn { session } => Ok(session),
+            other => Err(unexpected("Login", other)),
+        }
+    }
+
+    /// Logs this connection in with a session token instead of a password.
+    ///
+    /// The returned token is the one presented, with its remaining lifetime.
+    /// An expired or revoked token fails with an `InvalidSession` server
+    /// error.
+    pub async fn resume(&mut self, token: &str) -> Result<SessionToken> {
+        self.connection
+            .send(&Packet::Resume {
+                token: token.to_owned(),
+            })
+            .await?;
+        match self.next_reply().await? {
+            ServerResponse::LoggedIn { session } => Ok(session),
+            other => Err(unexpected("Resume", other)),
+        }
+    }
+
+    /// Swaps this connection's session token for a new one with a fresh
+    /// expiry. The old token stops working.
+    pub async fn refresh_session(&mut self) -> Result<SessionToken> {
+        self.connection.send(&Packet::RefreshSession).await?;
+        match self.next_reply().await? {
+            ServerResponse::LoggedIn { session } => Ok(session),
+            other => Err(unexpected("RefreshSession", other)),
+        }
+    }
+
+    /// Revokes this connection's session token and logs it out.
+    pub async fn logout(&mut self) -> Result<()> {
+        self.connection.send(&Packet::Logout).await?;
+        match self.next_reply().await? {
+            ServerResponse::LoggedOut => {
+                self.secret_key = None;
+                Ok(())
+            }
+            other => Err(unexpected("Logout", other)),
+        }
+    }
+
+    /// Changes the logged-in account's password.
+    ///
+    /// Every session of the account, on any device, is revoked; the returned
+    /// token replaces them. A wrong `old_password` fails with an
+    /// `InvalidCredentials` server error.
+    pub async fn change_password(
+        &mut self,
+        old_password: &str,
+        new_password: &str,
+    ) -> Result<SessionToken> {
+        self.connection
+            .send(&Packet::ChangePassword {
+                old_password: old_password.to_owned(),
+                new_password: new_password.to_owned(),
+            })
+            .await?;
+        match self.next_reply().await? {
+            ServerResponse::PasswordChanged { session } => Ok(session),
+            other => Err(unexpected("ChangePassword", other)),
+        }
+    }
+
+    /// Sets a new password for `name` using its recovery code, and logs this
+    /// connection in.
+    ///
+    /// The recovery code only works once; the returned credentials hold its
+    /// replacement. Every earlier session of the account is revoked.
+    pub async fn reset_password(
+        &mut self,
+        name: &str,
+        recovery_code: &str,
+        new_password: &str,
+    ) -> Result<AccountCredentials> {
+        self.connection
+            .send(&Packet::ResetPassword {
+                name: name.to_owned(),
+                recovery_code: recovery_code.to_owned(),
+                new_password: new_password.to_owned(),
+            })
+            .await?;
+        match self.next_reply().await? {
+            ServerResponse::PasswordReset {
+                session,
+                recovery_code,
+            } => Ok(AccountCredentials {
+                session,
+                recovery_code,
+            }),
+            other => Err(unexpected("ResetPassword", other)),
+        }
+    }
+
+    /// Registers this connection to receive messages addressed to `name` and
+    /// publishes the public keys of `identity` for other users to encrypt to
+    /// and to verify signatures with.
+    ///
+    /// The connection must be logged in to the account `name`, and
+    /// `identity` must be the one the client connected with. Messages queued
+    /// on the server while `name` was offline are delivered right after this
+    /// call.
+    pub async fn identify(&mut self, name: &str, identity: &Identity) -> Result<()> {
+        self.connection
+            .send(&Packet::Identify {
+                name: name.to_owned(),
+                public_key: *identity.public_key(),
+                signing_key: *identity.signing_key(),
+            })
+            .await?;
+        match self.next_reply().await? {
+            ServerResponse::Identified => {
+                self.secret_key = Some(identity.secret_key().clone());
+                Ok(())
+            }
+            other => Err(unexpected("Identify", other)),
+        }
+    }
+
+    /// Asks the server for the public key published by `identity`.
+    ///
+    /// The answer is sealed by the server, so it is only accepted if it opens
+    /// with `server_public_key`. Requires a prior call to `identify`.
+    pub async fn lookup_key(
+        &mut self,
+        identity: &str,
+        server_public_key: &PublicKey,
+    ) -> Result<Option<PublicKey>> {
+        Ok(self
+            .lookup_keys(identity, server_public_key)
+            .await?
+            .public_key)
+    }
+
+    /// Like `lookup_key`, but returns the whole record including the signing
+    /// key, which receivers use to check who really sent a message.
+    pub async fn lookup_keys(
+        &mut self,
+        identity: &str,
+        server_public_key: &PublicKey,
+    ) -> Result<KeyRecord> {
+        let secret_key = self.secret_key.clone().ok_or_else(|| {
+            QuietDropError::Protocol("Client must identify before looking up keys".to_owned())
+        })?;
 
-    let msg_bytes = serde_json::to_vec(message)?;
-    stream.write_all(&msg_bytes).await?;
+        self.connection
+            .send(&Packet::LookupKey {
+                identity: identity.to_owned(),
+            })
+            .await?;
 
-    let mut buf = vec![0; 1024];
-    let n = stream.read(&mut buf).await?;
-    let response = String::from_utf8_lossy(&buf[..n]);
-    println!("Server response: {}", response);
+        match self.next_reply().await? {
+            ServerResponse::PublicKey { sealed, .. } => {
+                let record = KeyRecord::open(&sealed, server_public_key, &secret_key)?;
+                if record.identity != identity {
+                    return Err(QuietDropError::Protocol(
+                        "Server answered a key lookup for a different identity".to_owned(),
+                    ));
+                }
+                Ok(record)
+            }
+            other => Err(unexpected("LookupKey", other)),
+        }
+    }
+
+    /// Sends a message and waits for the server to acknowledge it.
+    ///
+    /// Returns the message's ID, which later receipts refer to. The server
+    /// refuses IDs that are not UUIDs; `Message::new_id` makes valid ones.
+    pub async fn send(&mut self, message: &Message) -> Result<String> {
+        self.connection
+            .send(&Packet::Message(message.clone()))
+            .await?;
+        match self.next_reply().await? {
+            ServerResponse::Ack { message_id } => Ok(message_id),
+            other => Err(unexpected("Message", other)),
+        }
+    }
+
+    /// Waits for the next message routed to this client.
+    ///
+    /// Returns `Ok(None)` when the server closes the connection.
+    pub async fn recv_message(&mut self) -> Result<Option<Message>> {
+        while let Some(message) = self.inbox.pop_front() {
+            if verified(&message) {
+                return Ok(Some(message));
+            }
+        }
+
+        loop {
+            match self.connection.recv().await? {
+                Some(Packet::Message(message)) => {
+                    if verified(&message) {
+                        return Ok(Some(message));
+                    }
+                }
+                Some(Packet::Delivered(receipt)) => self.delivered.push(receipt),
+                Some(other) => eprintln!("Ignoring unexpected packet: {:?}", other),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Like `recv_message`, but gives up after `wait` if nothing arrives.
+    pub async fn try_recv_message(&mut self, wait: Duration) -> Result<Option<Message>> {
+        match tokio::time::timeout(wait, self.recv_message()).await {
+            Ok(result) => result,
+            Err(_) => Ok(None),
+        }
+    }
 
-    if response != "Message received." {
-        return Err("Failed to receive proper acknowledgement from server".into());
+    /// Delivery receipts received since the last call, oldest first.
+    ///
+    /// Receipts are only read off the connection while the client waits for
+    /// something else, so call this after `recv_message` or a request.
+    pub fn take_delivery_receipts(&mut self) -> Vec<DeliveryReceipt> {
+        std::mem::take(&mut self.delivered)
     }
 
-    Ok(())
+    /// Tells the sender of `read` that it has been read, with a read receipt
+    /// sent as `reader`.
+    pub async fn send_read_receipt(
+        &mut self,
+        read: &Message,
+        reader: &str,
+        identity: &Identity,
+    ) -> Result<String> {
+        self.send(&Message::read_receipt(read, reader, identity))
+            .await
+    }
+
+    pub async fn close(mut self) -> Result<()> {
+        self.connection.close().await?;
+        Ok(())
+    }
+
+    /// Reads the server's reply to the last request, keeping any messages that
+    /// arrive in the meantime for `recv_message`.
+    ///
+    /// Error responses are turned into the matching `QuietDropError`.
+    async fn next_reply(&mut self) -> Result<ServerResponse> {
+        loop {
+            match self.connection.recv().await? {
+                Some(Packet::Message(message)) => self.inbox.push_back(message),
+                Some(Packet::Delivered(receipt)) => self.delivered.push(receipt),
+                Some(Packet::Response(response)) => {
+                    return match response {
+                        ServerResponse::Error {
+                            code: ErrorCode::IncompatibleVersion,
+                            reason,
+                        } => Err(QuietDropError::IncompatibleVersion(reason)),
+                        ServerResponse::Error { code, reason } => {
+                            Err(QuietDropError::Server { code, reason })
+                        }
+                        ServerResponse::RateLimited { retry_after } => {
+                            Err(QuietDropError::RateLimited { retry_after })
+                        }
+                        ServerResponse::AuthRequired => Err(QuietDropError::AuthRequired),
+                        response => Ok(response),
+                    }
+                }
+                Some(other) => {
+                    return Err(QuietDropError::Protocol(format!(
+                        "Expected a response from the server, got {:?}",
+                        other
+                    )))
+                }
+                None => return Err(QuietDropError::ConnectionClosed),
+            }
+        }
+    }
+}
+
+fn unexpected(request: &str, response: ServerResponse) -> QuietDropError {
+    QuietDropError::Protocol(format!(
+        "Unexpected response to {}: {:?}",
+        request, response
+    ))
+}
+
+fn verified(message: &Message) -> bool {
+    match message.verify_signature() {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("Dropping message from {}: {}", message.sender, e);
+            false
+        }
+    }
+}
+
+/// Opens a connection, logs in to the sender's account, sends a single
+/// message and closes the connection again.
+pub async fn send_message(
+    message: &Message,
+    server_addr: &str,
+    server_public_key: &PublicKey,
+    identity: &Identity,
+    password: &str,
+) -> Result<()> {
+    let mut client = Client::connect(server_addr, server_public_key, identity).await?;
+    client.login(&message.sender, password).await?;
+    client.send(message).await?;
+    client.close().await
 }
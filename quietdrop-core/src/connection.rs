@@ -0,0 +1,191 @@
+use crate::framing::{decode_frame, encode_frame, MAX_FRAME_SIZE};
+use crate::protocol::Packet;
+use crate::transport::SecureChannel;
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+
+/// Timing and size limits for a `Connection`.
+#[derive(Debug, Clone)]
+pub struct ConnectionConfig {
+    /// How long the connection may stay quiet before a `Ping` is sent.
+    pub heartbeat_interval: Duration,
+    /// How long the connection may stay quiet before it is considered dead.
+    pub idle_timeout: Duration,
+    /// Largest frame accepted from the peer.
+    pub max_frame_size: usize,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        ConnectionConfig {
+            heartbeat_interval: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(90),
+            max_frame_size: MAX_FRAME_SIZE,
+        }
+    }
+}
+
+/// A long-lived, framed connection that exchanges `Packet`s in both directions.
+///
+/// Heartbeats are handled inside `recv`: a quiet peer is sent a `Ping`, incoming
+/// `Ping`s are answered with `Pong`, and a peer that stays silent past the idle
+/// timeout is reported as `TimedOut`. Both `send` and `recv` keep their progress
+/// in internal buffers, so `recv` can safely be used inside `tokio::select!`.
+///
+/// A connection created with `with_channel` encrypts every frame with the
+/// channel's session keys.
+pub struct Connection<S> {
+    stream: S,
+    config: ConnectionConfig,
+    channel: Option<SecureChannel>,
+    read_buf: Vec<u8>,
+    write_buf: Vec<u8>,
+    last_activity: Instant,
+    awaiting_pong: bool,
+}
+
+impl<S> Connection<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(stream: S) -> Self {
+        Connection::with_config(stream, ConnectionConfig::default())
+    }
+
+    pub fn with_config(stream: S, config: ConnectionConfig) -> Self {
+        Connection::build(stream, config, None)
+    }
+
+    /// Wraps a stream on which a transport handshake has just completed.
+    pub fn with_channel(stream: S, config: ConnectionConfig, channel: SecureChannel) -> Self {
+        Connection::build(stream, config, Some(channel))
+    }
+
+    fn build(stream: S, config: ConnectionConfig, channel: Option<SecureChannel>) -> Self {
+        Connection {
+            stream,
+            config,
+            channel,
+            read_buf: Vec::new(),
+            write_buf: Vec::new(),
+            last_activity: Instant::now(),
+            awaiting_pong: false,
+        }
+    }
+
+    pub fn config(&self) -> &ConnectionConfig {
+        &self.config
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Whether frames on this connection are encrypted.
+    pub fn is_secure(&self) -> bool {
+        self.channel.is_some()
+    }
+
+    /// Sends a packet and waits until it has been written to the stream.
+    pub async fn send(&mut self, packet: &Packet) -> std::io::Result<()> {
+        self.queue(packet)?;
+        self.flush_pending().await
+    }
+
+    /// Waits for the next packet from the peer, answering heartbeats on the way.
+    ///
+    /// Returns `Ok(None)` when the peer closes the connection cleanly.
+    pub async fn recv(&mut self) -> std::io::Result<Option<Packet>> {
+        loop {
+            self.flush_pending().await?;
+
+            if let Some(frame) = decode_frame(&mut self.read_buf, self.config.max_frame_size)? {
+                self.last_activity = Instant::now();
+                self.awaiting_pong = false;
+
+                let frame = match &mut self.channel {
+                    Some(channel) => channel.open(&frame)?,
+                    None => frame,
+                };
+                let packet = Packet::from_bytes(&frame)
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+                match packet {
+                    Packet::Ping => self.queue(&Packet::Pong)?,
+                    Packet::Pong => {}
+                    packet => return Ok(Some(packet)),
+                }
+                continue;
+            }
+
+            let idle = self.last_activity.elapsed();
+            if idle >= self.config.idle_timeout {
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    "Peer did not respond to heartbeats",
+                ));
+            }
+            let wait = if self.awaiting_pong {
+                self.config.idle_timeout - idle
+            } else {
+                self.config.heartbeat_interval.saturating_sub(idle)
+            };
+
+            let mut chunk = [0u8; 8192];
+            match timeout(wait, self.stream.read(&mut chunk)).await {
+                Ok(Ok(0)) => {
+                    if self.read_buf.is_empty() {
+                        return Ok(None);
+                    }
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "Connection closed in the middle of a frame",
+                    ));
+                }
+                Ok(Ok(n)) => self.read_buf.extend_from_slice(&chunk[..n]),
+                Ok(Err(e)) => return Err(e),
+                Err(_) => {
+                    if !self.awaiting_pong {
+                        self.queue(&Packet::Ping)?;
+                        self.awaiting_pong = true;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Flushes pending output and shuts down the write side of the stream.
+    pub async fn close(&mut self) -> std::io::Result<()> {
+        self.flush_pending().await?;
+        self.stream.shutdown().await
+    }
+
+    fn queue(&mut self, packet: &Packet) -> std::io::Result<()> {
+        let bytes = packet
+            .to_bytes()
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        let bytes = match &mut self.channel {
+            Some(channel) => channel.seal(&bytes),
+            None => bytes,
+        };
+        encode_frame(&bytes, &mut self.write_buf)
+    }
+
+    async fn flush_pending(&mut self) -> std::io::Result<()> {
+        if self.write_buf.is_empty() {
+            return Ok(());
+        }
+        while !self.write_buf.is_empty() {
+            let n = self.stream.write(&self.write_buf).await?;
+            if n == 0 {
+                return Err(Error::new(
+                    ErrorKind::WriteZero,
+                    "Failed to write frame to the connection",
+                ));
+            }
+            self.write_buf.drain(..n);
+        }
+        self.stream.flush().await
+    }
+}
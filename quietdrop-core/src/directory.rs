@@ -0,0 +1,81 @@
+use crate::encryption::{decrypt_bytes, encrypt_bytes, PublicKey, SecretKey, SigningPublicKey};
+use crate::error::{QuietDropError, Result};
+use crate::storage::Storage;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// The answer to a key lookup, sealed by the server to the requesting client.
+///
+/// Sealing with the server's secret key lets the client check that the answer
+/// really came from the server whose public key it has on file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyRecord {
+    pub identity: String,
+    pub public_key: Option<PublicKey>,
+    pub signing_key: Option<SigningPublicKey>,
+}
+
+impl KeyRecord {
+    pub fn seal(&self, requester_public_key: &PublicKey, server_secret_key: &SecretKey) -> Vec<u8> {
+        let json = serde_json::to_vec(self).expect("key records always serialize");
+        encrypt_bytes(&json, requester_public_key, server_secret_key)
+    }
+
+    pub fn open(
+        sealed: &[u8],
+        server_public_key: &PublicKey,
+        requester_secret_key: &SecretKey,
+    ) -> Result<KeyRecord> {
+        let json = decrypt_bytes(sealed, server_public_key, requester_secret_key)?;
+        serde_json::from_slice(&json)
+            .map_err(|_| QuietDropError::Protocol("Invalid key record".to_owned()))
+    }
+}
+
+/// Public keys published by identities that have connected to the server.
+///
+/// The keys are kept in the server's `Storage`, so lookups keep working for
+/// identities that connected before a restart.
+pub struct KeyDirectory {
+    storage: Arc<Storage>,
+}
+
+impl KeyDirectory {
+    pub fn new(storage: Arc<Storage>) -> Self {
+        KeyDirectory { storage }
+    }
+
+    pub fn publish(
+        &self,
+        identity: &str,
+        public_key: PublicKey,
+        signing_key: SigningPublicKey,
+    ) -> Result<()> {
+        self.storage
+            .publish_keys(identity, &public_key, &signing_key)
+    }
+
+    pub fn lookup(&self, identity: &str) -> Result<Option<PublicKey>> {
+        Ok(self
+            .storage
+            .public_keys(identity)?
+            .map(|(public_key, _)| public_key))
+    }
+
+    pub fn lookup_signing_key(&self, identity: &str) -> Result<Option<SigningPublicKey>> {
+        Ok(self
+            .storage
+            .public_keys(identity)?
+            .map(|(_, signing_key)| signing_key))
+    }
+
+    /// Builds the answer to a lookup for `identity`.
+    pub fn record(&self, identity: &str) -> Result<KeyRecord> {
+        let keys = self.storage.public_keys(identity)?;
+        Ok(KeyRecord {
+            identity: identity.to_owned(),
+            public_key: keys.map(|(public_key, _)| public_key),
+            signing_key: keys.map(|(_, signing_key)| signing_key),
+        })
+    }
+}
@@ -1,35 +1,354 @@
+use crate::error::{QuietDropError, Result};
+use serde::{Deserialize, Serialize};
 use sodiumoxide::crypto::box_;
-use std::result::Result;
+use sodiumoxide::crypto::secretstream::{self, Header, Key, Pull, Push, Stream, Tag};
+use sodiumoxide::crypto::sign;
+use std::io::ErrorKind;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 pub type PublicKey = box_::PublicKey;
 pub type SecretKey = box_::SecretKey;
 pub type KeyPair = (PublicKey, SecretKey);
 
+pub type SigningPublicKey = sign::PublicKey;
+pub type SigningSecretKey = sign::SecretKey;
+pub type SigningKeyPair = (SigningPublicKey, SigningSecretKey);
+pub type Signature = sign::Signature;
+
+/// A user's long-term keys: one pair for encryption, one for signing.
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub keypair: KeyPair,
+    pub signing_keypair: SigningKeyPair,
+}
+
+impl Identity {
+    pub fn generate() -> Identity {
+        Identity {
+            keypair: generate_keypair(),
+            signing_keypair: generate_signing_keypair(),
+        }
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        &self.keypair.0
+    }
+
+    pub fn secret_key(&self) -> &SecretKey {
+        &self.keypair.1
+    }
+
+    pub fn signing_key(&self) -> &SigningPublicKey {
+        &self.signing_keypair.0
+    }
+
+    pub fn signing_secret_key(&self) -> &SigningSecretKey {
+        &self.signing_keypair.1
+    }
+}
+
 pub fn generate_keypair() -> KeyPair {
     box_::gen_keypair()
 }
 
+pub fn generate_signing_keypair() -> SigningKeyPair {
+    sign::gen_keypair()
+}
+
+/// Plaintext bytes in each frame of an encrypted stream.
+pub const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Bytes each sealed stream frame adds to its plaintext.
+pub const STREAM_FRAME_OVERHEAD: usize = secretstream::ABYTES;
+
+/// The stream key and header, sealed to the recipient at the front of an
+/// encrypted stream.
+const SEALED_STREAM_KEY_LEN: usize =
+    box_::NONCEBYTES + box_::MACBYTES + secretstream::KEYBYTES + secretstream::HEADERBYTES;
+
+/// Encrypts `message` from the holder of `secret_key` to the holder of
+/// `public_key`. A wrapper around `encrypt_bytes`.
 pub fn encrypt_message(message: &str, public_key: &PublicKey, secret_key: &SecretKey) -> Vec<u8> {
+    encrypt_bytes(message.as_bytes(), public_key, secret_key)
+}
+
+/// Decrypts what `encrypt_message` produced. A wrapper around
+/// `decrypt_bytes` that also requires the plaintext to be UTF-8.
+pub fn decrypt_message(
+    encrypted_data: &[u8],
+    public_key: &PublicKey,
+    secret_key: &SecretKey,
+) -> Result<String> {
+    let decrypted_msg = decrypt_bytes(encrypted_data, public_key, secret_key)?;
+    String::from_utf8(decrypted_msg).map_err(|_| QuietDropError::Crypto("Invalid UTF-8".to_owned()))
+}
+
+/// Encrypts and authenticates `plaintext` with `crypto_box`. The output is
+/// a fresh nonce followed by the ciphertext.
+pub fn encrypt_bytes(plaintext: &[u8], public_key: &PublicKey, secret_key: &SecretKey) -> Vec<u8> {
     let nonce = box_::gen_nonce();
-    let encrypted_msg = box_::seal(message.as_bytes(), &nonce, public_key, secret_key);
+    let encrypted_msg = box_::seal(plaintext, &nonce, public_key, secret_key);
 
     [nonce.as_ref(), encrypted_msg.as_slice()].concat()
 }
 
-pub fn decrypt_message(
+/// Decrypts what `encrypt_bytes` produced, failing if it was altered or not
+/// sealed between these two keys.
+pub fn decrypt_bytes(
     encrypted_data: &[u8],
     public_key: &PublicKey,
     secret_key: &SecretKey,
-) -> Result<String, &'static str> {
+) -> Result<Vec<u8>> {
     if encrypted_data.len() < box_::NONCEBYTES {
-        return Err("Encrypted data is too short");
+        return Err(QuietDropError::Crypto(
+            "Encrypted data is too short".to_owned(),
+        ));
     }
 
     let (nonce_bytes, encrypted_msg) = encrypted_data.split_at(box_::NONCEBYTES);
-    let nonce = box_::Nonce::from_slice(nonce_bytes).ok_or("Failed to construct nonce")?;
+    let nonce = box_::Nonce::from_slice(nonce_bytes)
+        .ok_or_else(|| QuietDropError::Crypto("Failed to construct nonce".to_owned()))?;
+
+    box_::open(encrypted_msg, &nonce, public_key, secret_key)
+        .map_err(|_| QuietDropError::Crypto("Decryption failed".to_owned()))
+}
+
+/// What opens one encrypted stream: a secretstream key and the header its
+/// first frame starts from.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StreamKey {
+    key: Vec<u8>,
+    header: Vec<u8>,
+}
 
-    let decrypted_msg = box_::open(encrypted_msg, &nonce, public_key, secret_key)
-        .map_err(|_| "Decryption failed")?;
+impl StreamKey {
+    /// Seals the key with `encrypt_bytes`, for the holder of `public_key`.
+    pub fn seal(&self, public_key: &PublicKey, secret_key: &SecretKey) -> Vec<u8> {
+        encrypt_bytes(
+            &[&self.key[..], &self.header].concat(),
+            public_key,
+            secret_key,
+        )
+    }
+
+    /// Opens what `seal` produced.
+    pub fn open(sealed: &[u8], public_key: &PublicKey, secret_key: &SecretKey) -> Result<Self> {
+        let stream_key = decrypt_bytes(sealed, public_key, secret_key)?;
+        if stream_key.len() != secretstream::KEYBYTES + secretstream::HEADERBYTES {
+            return Err(QuietDropError::Crypto("Malformed stream key".to_owned()));
+        }
+        let (key, header) = stream_key.split_at(secretstream::KEYBYTES);
+        Ok(StreamKey {
+            key: key.to_vec(),
+            header: header.to_vec(),
+        })
+    }
+}
 
-    String::from_utf8(decrypted_msg).map_err(|_| "Invalid UTF-8")
+/// The sending end of an encrypted stream, sealing frames in order.
+///
+/// Each frame may carry associated data, which is authenticated but not
+/// encrypted. The last frame is marked as such, so a stream cut short does
+/// not pass for a complete one.
+pub struct StreamEncryptor {
+    stream: Stream<Push>,
+}
+
+impl StreamEncryptor {
+    /// Starts a stream under a fresh key, which is returned for the
+    /// recipient.
+    pub fn new() -> Result<(StreamEncryptor, StreamKey)> {
+        let key = secretstream::gen_key();
+        let (stream, header) = Stream::init_push(&key).map_err(|_| {
+            QuietDropError::Crypto("Unable to start the encrypted stream".to_owned())
+        })?;
+        let stream_key = StreamKey {
+            key: key.as_ref().to_vec(),
+            header: header.as_ref().to_vec(),
+        };
+        Ok((StreamEncryptor { stream }, stream_key))
+    }
+
+    /// Seals the next frame.
+    pub fn push(
+        &mut self,
+        frame: &[u8],
+        associated_data: Option<&[u8]>,
+        last: bool,
+    ) -> Result<Vec<u8>> {
+        let tag = if last { Tag::Final } else { Tag::Message };
+        self.stream
+            .push(frame, associated_data, tag)
+            .map_err(|_| QuietDropError::Crypto("Unable to encrypt the stream".to_owned()))
+    }
+}
+
+/// The receiving end of an encrypted stream, opening frames in the order
+/// they were sealed.
+pub struct StreamDecryptor {
+    stream: Stream<Pull>,
+}
+
+impl StreamDecryptor {
+    pub fn new(stream_key: &StreamKey) -> Result<Self> {
+        let key = Key::from_slice(&stream_key.key)
+            .ok_or_else(|| QuietDropError::Crypto("Malformed stream key".to_owned()))?;
+        let header = Header::from_slice(&stream_key.header)
+            .ok_or_else(|| QuietDropError::Crypto("Malformed stream header".to_owned()))?;
+        let stream = Stream::init_pull(&header, &key).map_err(|_| {
+            QuietDropError::Crypto("Unable to open the encrypted stream".to_owned())
+        })?;
+        Ok(StreamDecryptor { stream })
+    }
+
+    /// Opens the next frame, returning its plaintext and whether it was the
+    /// last one. Fails if the frame was altered, reordered or sealed with
+    /// different associated data.
+    pub fn pull(
+        &mut self,
+        sealed: &[u8],
+        associated_data: Option<&[u8]>,
+    ) -> Result<(Vec<u8>, bool)> {
+        let (frame, tag) = self
+            .stream
+            .pull(sealed, associated_data)
+            .map_err(|_| QuietDropError::Crypto("Decryption failed".to_owned()))?;
+        Ok((frame, tag == Tag::Final))
+    }
+}
+
+/// Encrypts everything `reader` yields into `writer`, for payloads too
+/// large to hold in memory. Returns the number of plaintext bytes.
+///
+/// The stream starts with a fresh `StreamKey`, sealed with
+/// `encrypt_bytes`. Frames of up to `STREAM_CHUNK_SIZE` plaintext bytes
+/// follow, each as a 4-byte big-endian length and the ciphertext; the last
+/// frame is tagged as final, so a truncated stream does not decrypt.
+pub async fn encrypt_stream<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    public_key: &PublicKey,
+    secret_key: &SecretKey,
+) -> Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let (mut stream, stream_key) = StreamEncryptor::new()?;
+    writer
+        .write_all(&stream_key.seal(public_key, secret_key))
+        .await?;
+
+    // Read one frame ahead, so the last frame is known when it is sealed
+    let mut total = 0;
+    let mut frame = read_frame(reader).await?;
+    loop {
+        let next = if frame.len() == STREAM_CHUNK_SIZE {
+            read_frame(reader).await?
+        } else {
+            Vec::new()
+        };
+        let last = next.is_empty();
+        let sealed = stream.push(&frame, None, last)?;
+        writer
+            .write_all(&(sealed.len() as u32).to_be_bytes())
+            .await?;
+        writer.write_all(&sealed).await?;
+        total += frame.len() as u64;
+        if last {
+            break;
+        }
+        frame = next;
+    }
+    writer.flush().await?;
+    Ok(total)
+}
+
+/// Decrypts what `encrypt_stream` produced from `reader` into `writer`.
+/// Returns the number of plaintext bytes.
+///
+/// Frames are written as they are authenticated, so on failure `writer` may
+/// already hold part of the plaintext; callers should discard it.
+pub async fn decrypt_stream<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    public_key: &PublicKey,
+    secret_key: &SecretKey,
+) -> Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut sealed_key = [0; SEALED_STREAM_KEY_LEN];
+    read_exact_or_truncated(reader, &mut sealed_key).await?;
+    let stream_key = StreamKey::open(&sealed_key, public_key, secret_key)?;
+    let mut stream = StreamDecryptor::new(&stream_key)?;
+
+    let mut total = 0;
+    loop {
+        let mut len = [0; 4];
+        read_exact_or_truncated(reader, &mut len).await?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len > STREAM_CHUNK_SIZE + STREAM_FRAME_OVERHEAD {
+            return Err(QuietDropError::Crypto(
+                "Encrypted stream frame is too large".to_owned(),
+            ));
+        }
+        let mut sealed = vec![0; len];
+        read_exact_or_truncated(reader, &mut sealed).await?;
+        let (frame, last) = stream.pull(&sealed, None)?;
+        writer.write_all(&frame).await?;
+        total += frame.len() as u64;
+        if last {
+            break;
+        }
+    }
+    if reader.read(&mut [0; 1]).await? != 0 {
+        return Err(QuietDropError::Crypto(
+            "Unexpected data after the encrypted stream".to_owned(),
+        ));
+    }
+    writer.flush().await?;
+    Ok(total)
+}
+
+/// Up to `STREAM_CHUNK_SIZE` bytes, fewer only at the end of `reader`.
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut frame = Vec::with_capacity(STREAM_CHUNK_SIZE);
+    reader
+        .take(STREAM_CHUNK_SIZE as u64)
+        .read_to_end(&mut frame)
+        .await?;
+    Ok(frame)
+}
+
+async fn read_exact_or_truncated<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut [u8],
+) -> Result<()> {
+    match reader.read_exact(buf).await {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Err(QuietDropError::Crypto(
+            "Encrypted stream is truncated".to_owned(),
+        )),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn sign_bytes(data: &[u8], signing_secret_key: &SigningSecretKey) -> Vec<u8> {
+    sign::sign_detached(data, signing_secret_key)
+        .to_bytes()
+        .to_vec()
+}
+
+pub fn verify_bytes(signature: &[u8], data: &[u8], signing_key: &SigningPublicKey) -> Result<()> {
+    let signature = Signature::from_bytes(signature)
+        .map_err(|_| QuietDropError::InvalidSignature("Malformed signature".to_owned()))?;
+    if sign::verify_detached(&signature, data, signing_key) {
+        Ok(())
+    } else {
+        Err(QuietDropError::InvalidSignature(
+            "Signature does not match the signing key".to_owned(),
+        ))
+    }
 }
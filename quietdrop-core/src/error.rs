@@ -0,0 +1,67 @@
+use crate::protocol::ErrorCode;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Everything that can go wrong in quietdrop-core, grouped so callers can
+/// react to each kind of failure differently.
+#[derive(Debug, Error)]
+pub enum QuietDropError {
+    /// Reading or writing a socket or file failed.
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    /// Something could not be encrypted or decrypted.
+    #[error("Cryptographic error: {0}")]
+    Crypto(String),
+    /// A signature is missing, malformed or made with the wrong key.
+    #[error("Invalid signature: {0}")]
+    InvalidSignature(String),
+    /// Data could not be encoded or decoded.
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+    /// The peer sent something that does not fit the protocol.
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+    /// The peer closed the connection before answering.
+    #[error("Connection closed by the peer")]
+    ConnectionClosed,
+    /// A password or passphrase was wrong, or the caller is not allowed to do this.
+    #[error("Authentication failed: {0}")]
+    Auth(String),
+    /// A keystore or key file is missing, unreadable or inconsistent.
+    #[error("Keystore error: {0}")]
+    Keystore(String),
+    /// The server is throttling this client.
+    #[error("Rate limited by the server; retry after {} ms", .retry_after.as_millis())]
+    RateLimited { retry_after: Duration },
+    /// The server refused the request.
+    #[error("Server refused the request ({code}): {reason}")]
+    Server { code: ErrorCode, reason: String },
+    /// The secure transport could not be set up, or the peer could not prove
+    /// it holds the expected key.
+    #[error("Secure handshake failed: {0}")]
+    Handshake(String),
+    /// A TLS certificate or key could not be loaded or used.
+    #[error("TLS error: {0}")]
+    Tls(String),
+    /// The client and server have no protocol version in common.
+    #[error("Incompatible protocol version: {0}")]
+    IncompatibleVersion(String),
+    /// The server's database, or a client's outbox file, could not be opened,
+    /// migrated or queried.
+    #[error("Storage error: {0}")]
+    Storage(String),
+    /// A file could not be sent, or did not arrive intact.
+    #[error("File transfer error: {0}")]
+    Transfer(String),
+    /// The server requires the connection to log in, or identify, first.
+    #[error("The server requires this connection to log in or identify first")]
+    AuthRequired,
+}
+
+impl From<rusqlite::Error> for QuietDropError {
+    fn from(e: rusqlite::Error) -> Self {
+        QuietDropError::Storage(e.to_string())
+    }
+}
+
+pub type Result<T> = std::result::Result<T, QuietDropError>;
@@ -0,0 +1,113 @@
+use std::io::{Error, ErrorKind};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Size of the big-endian length prefix that precedes every frame.
+pub const LENGTH_PREFIX_SIZE: usize = 4;
+
+/// Largest payload a single frame may carry.
+pub const MAX_FRAME_SIZE: usize = 8 * 1024 * 1024;
+
+/// Writes `payload` as a single length-prefixed frame.
+pub async fn write_frame<W>(writer: &mut W, payload: &[u8]) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut frame = Vec::with_capacity(LENGTH_PREFIX_SIZE + payload.len());
+    encode_frame(payload, &mut frame)?;
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+/// Reads the next length-prefixed frame.
+///
+/// Returns `Ok(None)` when the peer closes the stream cleanly between frames.
+/// A stream that ends in the middle of a frame is reported as `UnexpectedEof`.
+pub async fn read_frame<R>(
+    reader: &mut R,
+    max_frame_size: usize,
+) -> std::io::Result<Option<Vec<u8>>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut len_buf = [0u8; LENGTH_PREFIX_SIZE];
+    let mut filled = 0;
+    while filled < LENGTH_PREFIX_SIZE {
+        let n = reader.read(&mut len_buf[filled..]).await?;
+        if n == 0 {
+            if filled == 0 {
+                return Ok(None);
+            }
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Connection closed in the middle of a frame header",
+            ));
+        }
+        filled += n;
+    }
+
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if len > max_frame_size {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Frame of {} bytes exceeds the maximum of {} bytes",
+                len, max_frame_size
+            ),
+        ));
+    }
+
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some(payload))
+}
+
+/// Appends `payload` to `buffer` as a length-prefixed frame.
+pub fn encode_frame(payload: &[u8], buffer: &mut Vec<u8>) -> std::io::Result<()> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Frame of {} bytes exceeds the maximum of {} bytes",
+                payload.len(),
+                MAX_FRAME_SIZE
+            ),
+        ));
+    }
+
+    buffer.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(payload);
+    Ok(())
+}
+
+/// Removes one complete frame from the front of `buffer`, if one is available.
+///
+/// Returns `Ok(None)` when more bytes are needed.
+pub fn decode_frame(
+    buffer: &mut Vec<u8>,
+    max_frame_size: usize,
+) -> std::io::Result<Option<Vec<u8>>> {
+    if buffer.len() < LENGTH_PREFIX_SIZE {
+        return Ok(None);
+    }
+
+    let mut len_buf = [0u8; LENGTH_PREFIX_SIZE];
+    len_buf.copy_from_slice(&buffer[..LENGTH_PREFIX_SIZE]);
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if len > max_frame_size {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Frame of {} bytes exceeds the maximum of {} bytes",
+                len, max_frame_size
+            ),
+        ));
+    }
+
+    if buffer.len() < LENGTH_PREFIX_SIZE + len {
+        return Ok(None);
+    }
+
+    let payload = buffer[LENGTH_PREFIX_SIZE..LENGTH_PREFIX_SIZE + len].to_vec();
+    buffer.drain(..LENGTH_PREFIX_SIZE + len);
+    Ok(Some(payload))
+}
@@ -0,0 +1,408 @@
+use crate::authentication::{derive_key, PasswordPolicy};
+use crate::encryption::{
+    generate_signing_keypair, Identity, KeyPair, PublicKey, SecretKey, SigningSecretKey,
+};
+use crate::error::{QuietDropError, Result};
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::{box_, secretbox, sign};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const PUBLIC_KEY_FILE: &str = "identity.pub";
+const SECRET_KEY_FILE: &str = "identity.key";
+const KEYSTORE_VERSION: u8 = 3;
+/// Version 2 keystores did not record their key derivation costs.
+const KEYSTORE_VERSION_FIXED_COSTS: u8 = 2;
+/// Version 1 keystores held only the encryption key.
+const KEYSTORE_VERSION_BOX_ONLY: u8 = 1;
+const SALT_LEN: usize = 16;
+
+/// The costs keystores before version 3 derived their key with: 4 MiB of
+/// memory, 3 passes, 1 lane.
+const FIXED_KEY_DERIVATION: PasswordPolicy = PasswordPolicy {
+    memory_kib: 4096,
+    iterations: 3,
+    parallelism: 1,
+};
+
+/// The secret keys as stored on disk, encrypted under a passphrase-derived key.
+///
+/// The plaintext is the encryption secret key followed by the signing secret key.
+#[derive(Serialize, Deserialize)]
+struct EncryptedSecretKey {
+    version: u8,
+    /// The Argon2id costs the key was derived with, from version 3 on.
+    #[serde(default)]
+    key_derivation: Option<PasswordPolicy>,
+    salt: Vec<u8>,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+/// A directory holding one long-term identity: an encryption and a signing keypair.
+///
+/// The encryption public key is stored in the clear; the secret keys are sealed with
+/// XSalsa20-Poly1305 under a key derived from the passphrase with Argon2id.
+/// The Argon2id costs are stored next to the ciphertext, and a keystore made with
+/// other costs than `PasswordPolicy::default()` is re-encrypted the next time it
+/// is unlocked. On Unix the directory is created `0700` and the key files `0600`.
+pub struct Keystore {
+    dir: PathBuf,
+}
+
+impl Keystore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Keystore { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn exists(&self) -> bool {
+        self.dir.join(SECRET_KEY_FILE).exists()
+    }
+
+    /// Generates and saves a new identity. Fails if the keystore already exists.
+    pub fn create(&self, passphrase: &str) -> Result<Identity> {
+        if self.exists() {
+            return Err(keystore_error(format!(
+                "A keystore already exists in {}",
+                self.dir.display()
+            )));
+        }
+        let identity = Identity::generate();
+        self.save(&identity, passphrase)?;
+        Ok(identity)
+    }
+
+    /// Loads the identity, creating it first if the keystore is empty.
+    pub fn load_or_create(&self, passphrase: &str) -> Result<Identity> {
+        if self.exists() {
+            self.load(passphrase)
+        } else {
+            self.create(passphrase)
+        }
+    }
+
+    /// Decrypts and returns the stored identity.
+    ///
+    /// A version 1 keystore has no signing key; one is generated and the
+    /// keystore is rewritten in the current format. So is a keystore whose
+    /// key was derived with outdated costs.
+    pub fn load(&self, passphrase: &str) -> Result<Identity> {
+        let path = self.dir.join(SECRET_KEY_FILE);
+        let bytes =
+            fs::read(&path).with_context(|| format!("Unable to read {}", path.display()))?;
+        let stored: EncryptedSecretKey = serde_json::from_slice(&bytes)
+            .with_context(|| format!("{} is not a valid keystore file", path.display()))?;
+        let (expected_len, key_derivation) = match stored.version {
+            KEYSTORE_VERSION => (
+                box_::SECRETKEYBYTES + sign::SECRETKEYBYTES,
+                stored.key_derivation.ok_or_else(|| {
+                    keystore_error("Keystore is missing its key derivation costs")
+                })?,
+            ),
+            KEYSTORE_VERSION_FIXED_COSTS => (
+                box_::SECRETKEYBYTES + sign::SECRETKEYBYTES,
+                FIXED_KEY_DERIVATION,
+            ),
+            KEYSTORE_VERSION_BOX_ONLY => (box_::SECRETKEYBYTES, FIXED_KEY_DERIVATION),
+            version => {
+                return Err(keystore_error(format!(
+                    "Unsupported keystore version {}",
+                    version
+                )))
+            }
+        };
+
+        let key = passphrase_key(passphrase, &stored.salt, &key_derivation)?;
+        let nonce = secretbox::Nonce::from_slice(&stored.nonce)
+            .ok_or_else(|| keystore_error("Keystore nonce is corrupted"))?;
+        let mut secret_bytes = secretbox::open(&stored.ciphertext, &nonce, &key).map_err(|_| {
+            QuietDropError::Auth("Wrong passphrase or corrupted keystore".to_owned())
+        })?;
+        if secret_bytes.len() != expected_len {
+            sodiumoxide::utils::memzero(&mut secret_bytes);
+            return Err(keystore_error("Keystore secret key is corrupted"));
+        }
+        let (box_bytes, sign_bytes) = secret_bytes.split_at(box_::SECRETKEYBYTES);
+        let secret_key = SecretKey::from_slice(box_bytes);
+        let signing_secret_key = if sign_bytes.is_empty() {
+            None
+        } else {
+            Some(SigningSecretKey::from_slice(sign_bytes))
+        };
+        sodiumoxide::utils::memzero(&mut secret_bytes);
+        let secret_key =
+            secret_key.ok_or_else(|| keystore_error("Keystore secret key is corrupted"))?;
+
+        let public_key = self.public_key()?;
+        if secret_key.public_key() != public_key {
+            return Err(keystore_error(
+                "Keystore public key does not match its secret key",
+            ));
+        }
+
+        match signing_secret_key {
+            Some(signing_secret_key) => {
+                let signing_secret_key = signing_secret_key
+                    .ok_or_else(|| keystore_error("Keystore signing key is corrupted"))?;
+                let identity = Identity {
+                    keypair: (public_key, secret_key),
+                    signing_keypair: (signing_secret_key.public_key(), signing_secret_key),
+                };
+                if stored.version != KEYSTORE_VERSION || key_derivation != PasswordPolicy::default()
+                {
+                    self.save(&identity, passphrase)?;
+                }
+                Ok(identity)
+            }
+            None => {
+                let identity = Identity {
+                    keypair: (public_key, secret_key),
+                    signing_keypair: generate_signing_keypair(),
+                };
+                self.save(&identity, passphrase)?;
+                Ok(identity)
+            }
+        }
+    }
+
+    /// Encrypts and writes `identity`, replacing any identity already stored.
+    pub fn save(&self, identity: &Identity, passphrase: &str) -> Result<()> {
+        let mut salt = vec![0u8; SALT_LEN];
+        sodiumoxide::randombytes::randombytes_into(&mut salt);
+        let key_derivation = PasswordPolicy::default();
+        let key = passphrase_key(passphrase, &salt, &key_derivation)?;
+        let nonce = secretbox::gen_nonce();
+        let mut secret_bytes = [
+            identity.secret_key().as_ref(),
+            identity.signing_secret_key().as_ref(),
+        ]
+        .concat();
+        let stored = EncryptedSecretKey {
+            version: KEYSTORE_VERSION,
+            key_derivation: Some(key_derivation),
+            salt,
+            nonce: nonce.as_ref().to_vec(),
+            ciphertext: secretbox::seal(&secret_bytes, &nonce, &key),
+        };
+        sodiumoxide::utils::memzero(&mut secret_bytes);
+
+        create_private_dir(&self.dir)?;
+        write_private_file(
+            &self.dir.join(PUBLIC_KEY_FILE),
+            identity.public_key().as_ref(),
+        )?;
+        write_private_file(
+            &self.dir.join(SECRET_KEY_FILE),
+            &serde_json::to_vec(&stored)?,
+        )
+    }
+
+    /// Re-encrypts the stored identity under a new passphrase.
+    pub fn change_passphrase(&self, old_passphrase: &str, new_passphrase: &str) -> Result<()> {
+        let identity = self.load(old_passphrase)?;
+        self.save(&identity, new_passphrase)
+    }
+
+    /// Reads the public key, which does not require the passphrase.
+    pub fn public_key(&self) -> Result<PublicKey> {
+        let path = self.dir.join(PUBLIC_KEY_FILE);
+        let bytes =
+            fs::read(&path).with_context(|| format!("Unable to read {}", path.display()))?;
+        PublicKey::from_slice(&bytes).ok_or_else(|| {
+            keystore_error(format!(
+                "{} does not contain a valid public key",
+                path.display()
+            ))
+        })
+    }
+}
+
+fn keystore_error(message: impl Into<String>) -> QuietDropError {
+    QuietDropError::Keystore(message.into())
+}
+
+/// Describes what was being done when an I/O or decoding step failed.
+trait Context<T> {
+    fn with_context(self, what: impl FnOnce() -> String) -> Result<T>;
+}
+
+impl<T, E: std::fmt::Display> Context<T> for std::result::Result<T, E> {
+    fn with_context(self, what: impl FnOnce() -> String) -> Result<T> {
+        self.map_err(|e| keystore_error(format!("{}: {}", what(), e)))
+    }
+}
+
+fn passphrase_key(
+    passphrase: &str,
+    salt: &[u8],
+    policy: &PasswordPolicy,
+) -> Result<secretbox::Key> {
+    let mut key_bytes = [0u8; secretbox::KEYBYTES];
+    derive_key(passphrase, salt, policy, &mut key_bytes)?;
+    let key = secretbox::Key::from_slice(&key_bytes).expect("key has the right length");
+    sodiumoxide::utils::memzero(&mut key_bytes);
+    Ok(key)
+}
+
+/// Creates `dir` (and its parents) and restricts it to the current user.
+pub fn create_private_dir(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("Unable to create {}", dir.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))
+            .with_context(|| format!("Unable to set permissions on {}", dir.display()))?;
+    }
+    Ok(())
+}
+
+/// Writes `bytes` to `path` atomically with owner-only permissions.
+///
+/// The bytes go to `<file name>.tmp` first, so files sharing a directory
+/// never share a temporary file.
+pub fn write_private_file(path: &Path, bytes: &[u8]) -> Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options
+        .open(&tmp_path)
+        .with_context(|| format!("Unable to create {}", tmp_path.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(bytes)
+        .and_then(|_| file.sync_all())
+        .with_context(|| format!("Unable to write {}", tmp_path.display()))?;
+    fs::rename(&tmp_path, path).with_context(|| format!("Unable to write {}", path.display()))?;
+    Ok(())
+}
+
+pub const SERVER_PUBLIC_KEY_FILE: &str = "server_public_key.key";
+pub const SERVER_SECRET_KEY_FILE: &str = "server_secret_key.key";
+
+/// The server's long-term keypair as two raw key files in one directory.
+///
+/// Keys are only generated on first run or by an explicit `rotate`; a missing
+/// half or an unreadable file is an error rather than a reason to start over,
+/// so existing clients never lose the key they pinned.
+pub struct ServerKeyFiles {
+    dir: PathBuf,
+}
+
+impl ServerKeyFiles {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        ServerKeyFiles { dir: dir.into() }
+    }
+
+    pub fn public_key_path(&self) -> PathBuf {
+        self.dir.join(SERVER_PUBLIC_KEY_FILE)
+    }
+
+    pub fn secret_key_path(&self) -> PathBuf {
+        self.dir.join(SERVER_SECRET_KEY_FILE)
+    }
+
+    /// Loads the keypair, generating it only if neither key file exists yet.
+    ///
+    /// The flag is `true` when a new keypair was generated.
+    pub fn load_or_generate(&self) -> Result<(KeyPair, bool)> {
+        let public_exists = self.public_key_path().exists();
+        let secret_exists = self.secret_key_path().exists();
+        match (public_exists, secret_exists) {
+            (false, false) => Ok((self.write_new_keypair()?, true)),
+            (true, true) => Ok((self.load()?, false)),
+            (true, false) => Err(keystore_error(format!(
+                "{} exists but {} is missing; restore it or rotate the keys",
+                self.public_key_path().display(),
+                self.secret_key_path().display()
+            ))),
+            (false, true) => Err(keystore_error(format!(
+                "{} exists but {} is missing; restore it or rotate the keys",
+                self.secret_key_path().display(),
+                self.public_key_path().display()
+            ))),
+        }
+    }
+
+    /// Loads the keypair and checks that both halves belong together.
+    pub fn load(&self) -> Result<KeyPair> {
+        let public_key = self.public_key()?;
+
+        let path = self.secret_key_path();
+        let bytes =
+            fs::read(&path).with_context(|| format!("Unable to read {}", path.display()))?;
+        let secret_key = SecretKey::from_slice(&bytes).ok_or_else(|| {
+            keystore_error(format!(
+                "{} is corrupted: expected {} bytes, found {}",
+                path.display(),
+                box_::SECRETKEYBYTES,
+                bytes.len()
+            ))
+        })?;
+
+        if secret_key.public_key() != public_key {
+            return Err(keystore_error(format!(
+                "{} does not match {}",
+                self.public_key_path().display(),
+                path.display()
+            )));
+        }
+        Ok((public_key, secret_key))
+    }
+
+    /// Reads only the public key, as clients do to pin the server.
+    pub fn public_key(&self) -> Result<PublicKey> {
+        read_server_public_key(&self.public_key_path())
+    }
+
+    /// Replaces the keypair with a new one, keeping the old files as `.old`.
+    pub fn rotate(&self) -> Result<KeyPair> {
+        for path in [self.public_key_path(), self.secret_key_path()] {
+            if path.exists() {
+                let backup = path.with_extension("key.old");
+                fs::rename(&path, &backup).with_context(|| {
+                    format!("Unable to move {} to {}", path.display(), backup.display())
+                })?;
+            }
+        }
+        self.write_new_keypair()
+    }
+
+    fn write_new_keypair(&self) -> Result<KeyPair> {
+        let keypair = box_::gen_keypair();
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Unable to create {}", self.dir.display()))?;
+        write_private_file(&self.secret_key_path(), keypair.1.as_ref())?;
+        fs::write(self.public_key_path(), keypair.0.as_ref())
+            .with_context(|| format!("Unable to write {}", self.public_key_path().display()))?;
+        Ok(keypair)
+    }
+}
+
+/// Reads a raw server public key file.
+pub fn read_server_public_key(path: &Path) -> Result<PublicKey> {
+    let bytes = fs::read(path).with_context(|| format!("Unable to read {}", path.display()))?;
+    PublicKey::from_slice(&bytes).ok_or_else(|| {
+        keystore_error(format!(
+            "{} is corrupted: expected {} bytes, found {}",
+            path.display(),
+            box_::PUBLICKEYBYTES,
+            bytes.len()
+        ))
+    })
+}
@@ -1,8 +1,25 @@
+pub mod accounts;
 pub mod authentication;
 pub mod client;
+pub mod connection;
+pub mod directory;
 pub mod encryption;
+pub mod error;
+pub mod framing;
+pub mod keystore;
 pub mod message;
+pub mod outbox;
+pub mod protocol;
+pub mod rate_limit;
+pub mod receipts;
+pub mod replay;
+pub mod routing;
 pub mod server;
+pub mod sessions;
+pub mod storage;
+pub mod tls;
+pub mod transfer;
+pub mod transport;
 
 pub fn initialize() {
     // Initialize sodiumoxide if needed
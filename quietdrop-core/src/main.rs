@@ -1,11 +1,15 @@
 use quietdrop_core::client;
-use quietdrop_core::encryption::generate_keypair;
+use quietdrop_core::encryption::{generate_keypair, Identity};
+use quietdrop_core::error::QuietDropError;
 use quietdrop_core::message::{get_input, Message, MessageType};
+use quietdrop_core::protocol::ErrorCode;
 use quietdrop_core::server;
+use quietdrop_core::storage::Storage;
 use sodiumoxide::crypto::box_;
 use std::env;
 use std::fs::File;
 use std::io::prelude::*;
+use std::sync::Arc;
 use tokio::runtime::Runtime;
 
 fn main() {
@@ -36,15 +40,21 @@ fn main() {
 
             let server_secret_key = box_::SecretKey::from_slice(&server_secret_key_bytes).unwrap();
 
+            let storage = Storage::open("quietdrop.db").expect("Unable to open the database");
+
             println!("\n>>> Now listening for incoming messages...\n");
 
             // and then you run the server using its secret key this way
-            rt.block_on(server::run_server("127.0.0.1:8080", &server_secret_key))
-                .expect("Server failed to run");
+            rt.block_on(server::run_server(
+                "127.0.0.1:8080",
+                &server_secret_key,
+                Arc::new(storage),
+            ))
+            .expect("Server failed to run");
         }
         "client" => {
-            // now here, you basically generat the client keypair
-            let (public_key, secret_key) = generate_keypair();
+            // now here, you basically generat the client keys
+            let identity = Identity::generate();
 
             // then read server's keys from files
             // from the file you saved --> server's public key
@@ -59,19 +69,43 @@ fn main() {
 
             println!("\n");
             let name = get_input("Enter your name: ");
+            let password = get_input("Enter your account password: ");
             let msg_str = get_input("Enter your message: ");
 
             let mut msg = Message {
+                id: Message::new_id(),
                 timestamp: chrono::Utc::now(),
                 message_type: MessageType::Text,
                 sender: name.clone(),
                 recipient: "Bob".to_owned(),
                 content: vec![],
-                public_key,
+                public_key: *identity.public_key(),
+                signing_key: *identity.signing_key(),
+                signature: vec![],
             };
-            msg.encrypt_content(&msg_str, &server_public_key, &secret_key);
-            rt.block_on(client::send_message(&msg, "127.0.0.1:8080"))
-                .expect("Client failed to send message");
+            msg.encrypt_content(&msg_str, &server_public_key, identity.secret_key());
+            msg.sign(identity.signing_secret_key());
+            rt.block_on(async {
+                let mut client =
+                    client::Client::connect("127.0.0.1:8080", &server_public_key, &identity)
+                        .await?;
+                // first use of a name creates the account
+                match client.register(&name, &password).await {
+                    Err(QuietDropError::Server {
+                        code: ErrorCode::NameTaken,
+                        ..
+                    }) => {
+                        client.login(&name, &password).await?;
+                    }
+                    result => println!(
+                        "Account created. Your recovery code is {}; keep it safe.",
+                        result?.recovery_code
+                    ),
+                }
+                client.send(&msg).await?;
+                client.close().await
+            })
+            .expect("Client failed to send message");
         }
         _ => {
             eprintln!("Invalid argument. Use 'client' or 'server'.");
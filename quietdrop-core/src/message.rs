@@ -1,33 +1,168 @@
 #![allow(dead_code)]
-use crate::encryption::{encrypt_message, PublicKey, SecretKey};
+use crate::encryption::{
+    decrypt_bytes, decrypt_message, encrypt_bytes, sign_bytes, verify_bytes, Identity, PublicKey,
+    SecretKey, SigningPublicKey, SigningSecretKey,
+};
+use crate::error::Result;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::io::{stdin, stdout, Write};
+use uuid::Uuid;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Domain separator for message signatures, so they cannot be replayed as
+/// signatures over anything else. Version 2 added the message ID.
+const SIGNATURE_CONTEXT: &[u8] = b"quietdrop-message-v2";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
+    /// Chosen by the sender with `Message::new_id`; receipts refer to it.
+    pub id: String,
     pub timestamp: DateTime<Utc>,
     pub message_type: MessageType,
     pub sender: String,
     pub recipient: String,
     pub content: Vec<u8>,
     pub public_key: PublicKey,
+    /// The sender's Ed25519 key; must match the key published for `sender`.
+    pub signing_key: SigningPublicKey,
+    /// Detached signature over the fields covered by `signed_bytes`.
+    pub signature: Vec<u8>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum MessageType {
     Text,
+    /// Offers a file: its name, size, type and hash, and the key its chunks
+    /// are encrypted with. See `transfer`.
     File,
+    /// Part of a file, encrypted with the key from its `File` offer rather
+    /// than sealed to the recipient.
+    FileChunk,
+    /// Tells a file's sender how many chunks arrived, or asks it to resend
+    /// from there. See `transfer`.
+    TransferAck,
+    /// Tells the original sender that a message was read. The content is
+    /// the ID of the read message, encrypted like any other content.
+    ReadReceipt,
 }
 
 impl Message {
+    /// A fresh, unique message ID.
+    pub fn new_id() -> String {
+        Uuid::new_v4().to_string()
+    }
+
+    /// A signed read receipt for `read`, from `reader` back to its sender.
+    ///
+    /// The receipt is end-to-end encrypted to the key `read` was sent with,
+    /// so the server only sees one more message between the two.
+    pub fn read_receipt(read: &Message, reader: &str, identity: &Identity) -> Message {
+        let mut receipt = Message {
+            id: Message::new_id(),
+            timestamp: Utc::now(),
+            message_type: MessageType::ReadReceipt,
+            sender: reader.to_owned(),
+            recipient: read.sender.clone(),
+            content: vec![],
+            public_key: *identity.public_key(),
+            signing_key: *identity.signing_key(),
+            signature: vec![],
+        };
+        receipt.encrypt_content(&read.id, &read.public_key, identity.secret_key());
+        receipt.sign(identity.signing_secret_key());
+        receipt
+    }
+
+    /// The ID of the message this read receipt acknowledges, or `None` if
+    /// this is not a read receipt.
+    pub fn read_message_id(&self, receiver_secret_key: &SecretKey) -> Result<Option<String>> {
+        if self.message_type != MessageType::ReadReceipt {
+            return Ok(None);
+        }
+        self.decrypt_content(receiver_secret_key).map(Some)
+    }
+
     pub fn encrypt_content(
         &mut self,
         plaintext: &str,
         receiver_public_key: &PublicKey,
         sender_secret_key: &SecretKey,
     ) {
-        self.content = encrypt_message(plaintext, receiver_public_key, sender_secret_key);
+        self.encrypt_content_bytes(plaintext.as_bytes(), receiver_public_key, sender_secret_key);
+    }
+
+    /// Like `encrypt_content`, for content that is not text.
+    pub fn encrypt_content_bytes(
+        &mut self,
+        plaintext: &[u8],
+        receiver_public_key: &PublicKey,
+        sender_secret_key: &SecretKey,
+    ) {
+        self.content = encrypt_bytes(plaintext, receiver_public_key, sender_secret_key);
+    }
+
+    /// Decrypts the content with the recipient's secret key and the sender's
+    /// public key carried in the message.
+    pub fn decrypt_content(&self, receiver_secret_key: &SecretKey) -> Result<String> {
+        decrypt_message(&self.content, &self.public_key, receiver_secret_key)
+    }
+
+    /// Like `decrypt_content`, for content that is not text.
+    pub fn decrypt_content_bytes(&self, receiver_secret_key: &SecretKey) -> Result<Vec<u8>> {
+        decrypt_bytes(&self.content, &self.public_key, receiver_secret_key)
+    }
+
+    /// Signs the message with the sender's signing key.
+    ///
+    /// Call this after `encrypt_content`; any later change to a signed field
+    /// invalidates the signature.
+    pub fn sign(&mut self, signing_secret_key: &SigningSecretKey) {
+        self.signature = sign_bytes(&self.signed_bytes(), signing_secret_key);
+    }
+
+    /// Checks the signature against the `signing_key` carried in the message.
+    ///
+    /// This only proves the message was not altered since it was signed by
+    /// that key; callers must still check the key belongs to `sender`.
+    pub fn verify_signature(&self) -> Result<()> {
+        verify_bytes(&self.signature, &self.signed_bytes(), &self.signing_key)
+    }
+
+    /// The bytes covered by the signature: every field except the signature,
+    /// each length-prefixed so field boundaries cannot be shifted.
+    fn signed_bytes(&self) -> Vec<u8> {
+        let message_type: &[u8] = match self.message_type {
+            MessageType::Text => b"text",
+            MessageType::File => b"file",
+            MessageType::FileChunk => b"file-chunk",
+            MessageType::TransferAck => b"transfer-ack",
+            MessageType::ReadReceipt => b"read-receipt",
+        };
+        let timestamp = [
+            self.timestamp.timestamp().to_be_bytes().as_slice(),
+            self.timestamp
+                .timestamp_subsec_nanos()
+                .to_be_bytes()
+                .as_slice(),
+        ]
+        .concat();
+
+        let mut bytes = Vec::new();
+        for field in [
+            SIGNATURE_CONTEXT,
+            self.id.as_bytes(),
+            &timestamp,
+            message_type,
+            self.sender.as_bytes(),
+            self.recipient.as_bytes(),
+            self.public_key.as_ref(),
+            self.signing_key.as_ref(),
+            &self.content,
+        ] {
+            bytes.extend_from_slice(&(field.len() as u64).to_be_bytes());
+            bytes.extend_from_slice(field);
+        }
+        bytes
     }
 }
 
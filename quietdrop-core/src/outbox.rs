@@ -0,0 +1,322 @@
+use crate::client::Client;
+use crate::encryption::Identity;
+use crate::error::{QuietDropError, Result};
+use crate::keystore::write_private_file;
+use crate::message::Message;
+use crate::protocol::ErrorCode;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+const OUTBOX_FILE_VERSION: u8 = 1;
+
+/// The file a client keeps its `Outbox` in, next to its keystore.
+pub const OUTBOX_FILE: &str = "outbox.json";
+
+/// How long to wait between attempts to send a message, and when to give up.
+///
+/// The wait starts at `initial_delay` and doubles after every failed attempt,
+/// up to `max_delay`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Failed attempts after which a message is marked as failed.
+    pub max_attempts: u32,
+}
+
+impl RetryPolicy {
+    pub fn new(initial_delay: Duration, max_delay: Duration, max_attempts: u32) -> Self {
+        RetryPolicy {
+            initial_delay,
+            max_delay,
+            max_attempts,
+        }
+    }
+
+    /// The wait after the `attempts`th failed attempt.
+    pub fn delay(&self, attempts: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+        self.initial_delay
+            .saturating_mul(factor)
+            .min(self.max_delay)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::new(Duration::from_secs(1), Duration::from_secs(60), 10)
+    }
+}
+
+/// Where an outgoing message stands.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OutboxStatus {
+    /// Waiting to be sent, or to be retried.
+    Pending,
+    /// Accepted by the server, which acknowledged it as `message_id`.
+    Sent { message_id: String },
+    /// Refused by the server, or out of attempts; it will not be retried.
+    Failed { reason: String },
+}
+
+/// One message in the outbox.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    /// The message's ID, which receipts refer to.
+    pub id: String,
+    pub message: Message,
+    pub status: OutboxStatus,
+    /// Failed attempts so far.
+    pub attempts: u32,
+    /// The earliest time the message will be tried again.
+    pub next_attempt: DateTime<Utc>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct OutboxFile {
+    version: u8,
+    entries: Vec<OutboxEntry>,
+}
+
+/// Outgoing messages kept on disk until the server accepts them.
+///
+/// Messages are stored as they are sent: encrypted to the recipient and
+/// signed. Sending failures that may clear up on their own, such as a lost
+/// connection or rate limiting, leave a message pending and back off
+/// according to the `RetryPolicy`; a refusal from the server fails it for
+/// good. Every attempt gets a fresh timestamp and signature, so a message
+/// can wait out an outage longer than the server's replay window.
+pub struct Outbox {
+    path: PathBuf,
+    policy: RetryPolicy,
+    entries: Mutex<Vec<OutboxEntry>>,
+}
+
+impl Outbox {
+    /// Opens the outbox at `path`, starting empty if the file does not exist.
+    /// The directory holding it must exist before anything is added.
+    pub fn open(path: impl Into<PathBuf>, policy: RetryPolicy) -> Result<Self> {
+        let path = path.into();
+        let entries = match fs::read(&path) {
+            Ok(bytes) => {
+                let file: OutboxFile = serde_json::from_slice(&bytes)?;
+                if file.version != OUTBOX_FILE_VERSION {
+                    return Err(QuietDropError::Storage(format!(
+                        "{} has unsupported version {}",
+                        path.display(),
+                        file.version
+                    )));
+                }
+                file.entries
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Outbox {
+            path,
+            policy,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Adds a message to be sent on the next `flush`, returning its ID.
+    pub fn push(&self, message: Message) -> Result<String> {
+        let id = message.id.clone();
+        let mut entries = self.entries.lock().unwrap();
+        entries.push(OutboxEntry {
+            id: id.clone(),
+            message,
+            status: OutboxStatus::Pending,
+            attempts: 0,
+            next_attempt: Utc::now(),
+            last_error: None,
+        });
+        if let Err(e) = self.save(&entries) {
+            entries.pop();
+            return Err(e);
+        }
+        Ok(id)
+    }
+
+    pub fn status(&self, id: &str) -> Option<OutboxStatus> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|entry| entry.id == id)
+            .map(|entry| entry.status.clone())
+    }
+
+    /// Every entry, oldest first.
+    pub fn entries(&self) -> Vec<OutboxEntry> {
+        self.entries.lock().unwrap().clone()
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|entry| entry.status == OutboxStatus::Pending)
+            .count()
+    }
+
+    /// How long until the next pending message is due, or `None` if nothing
+    /// is pending. Zero if one is already due.
+    pub fn next_attempt_in(&self) -> Option<Duration> {
+        let now = Utc::now();
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|entry| entry.status == OutboxStatus::Pending)
+            .map(|entry| (entry.next_attempt - now).to_std().unwrap_or_default())
+            .min()
+    }
+
+    /// Removes the sent and failed entries and returns them, so each
+    /// outcome is reported once.
+    pub fn remove_finished(&self) -> Result<Vec<OutboxEntry>> {
+        let mut entries = self.entries.lock().unwrap();
+        let (pending, finished): (Vec<_>, Vec<_>) = entries
+            .drain(..)
+            .partition(|entry| entry.status == OutboxStatus::Pending);
+        *entries = pending;
+        self.save(&entries)?;
+        Ok(finished)
+    }
+
+    /// Sends every pending message that is due over `client`, oldest first,
+    /// and returns how many the server accepted. Each message is stamped
+    /// with the current time and signed again with `identity` first.
+    ///
+    /// Stops at the first rate limit, leaving the rest for later. If the
+    /// connection fails, every due message is rescheduled and the error is
+    /// returned so the caller can reconnect. A message only counts as sent
+    /// once the server acknowledges its ID; the server does so again for a
+    /// retry of a message it already accepted. Flushes of the same outbox
+    /// must not run concurrently.
+    pub async fn flush(&self, client: &mut Client, identity: &Identity) -> Result<usize> {
+        let due = self.due();
+        let mut sent = 0;
+        for (index, (id, mut message)) in due.iter().cloned().enumerate() {
+            message.timestamp = Utc::now();
+            message.sign(identity.signing_secret_key());
+            match client.send(&message).await {
+                Ok(message_id) if message_id == message.id => {
+                    self.update(&id, |entry| {
+                        entry.status = OutboxStatus::Sent { message_id };
+                    })?;
+                    sent += 1;
+                }
+                Ok(message_id) => {
+                    let reason = format!(
+                        "The server acknowledged {} instead of {}",
+                        message_id, message.id
+                    );
+                    let policy = self.policy;
+                    self.update(&id, |entry| retry_later(entry, &policy, reason))?;
+                }
+                Err(QuietDropError::RateLimited { retry_after }) => {
+                    let next_attempt = Utc::now() + to_chrono(retry_after);
+                    self.update(&id, |entry| entry.next_attempt = next_attempt)?;
+                    return Ok(sent);
+                }
+                Err(QuietDropError::Server {
+                    code: ErrorCode::Internal,
+                    reason,
+                }) => {
+                    let policy = self.policy;
+                    self.update(&id, |entry| retry_later(entry, &policy, reason))?;
+                }
+                Err(QuietDropError::Server { reason, .. }) => {
+                    self.update(&id, |entry| {
+                        entry.status = OutboxStatus::Failed { reason };
+                    })?;
+                }
+                Err(e) => {
+                    let remaining: Vec<&str> =
+                        due[index..].iter().map(|(id, ..)| id.as_str()).collect();
+                    self.reschedule(&remaining, &e)?;
+                    return Err(e);
+                }
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Counts a failed attempt against every due message, for when no
+    /// connection to the server could be made at all.
+    pub fn record_failure(&self, error: &QuietDropError) -> Result<()> {
+        let due: Vec<String> = self.due().into_iter().map(|(id, ..)| id).collect();
+        let due: Vec<&str> = due.iter().map(String::as_str).collect();
+        self.reschedule(&due, error)
+    }
+
+    /// The pending messages whose next attempt has come, oldest first.
+    fn due(&self) -> Vec<(String, Message)> {
+        let now = Utc::now();
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|entry| entry.status == OutboxStatus::Pending && entry.next_attempt <= now)
+            .map(|entry| (entry.id.clone(), entry.message.clone()))
+            .collect()
+    }
+
+    fn reschedule(&self, ids: &[&str], error: &QuietDropError) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        for entry in entries
+            .iter_mut()
+            .filter(|entry| ids.contains(&entry.id.as_str()))
+        {
+            retry_later(entry, &self.policy, error.to_string());
+        }
+        self.save(&entries)
+    }
+
+    fn update(&self, id: &str, change: impl FnOnce(&mut OutboxEntry)) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.iter_mut().find(|entry| entry.id == id) {
+            change(entry);
+        }
+        self.save(&entries)
+    }
+
+    fn save(&self, entries: &[OutboxEntry]) -> Result<()> {
+        let file = OutboxFile {
+            version: OUTBOX_FILE_VERSION,
+            entries: entries.to_vec(),
+        };
+        write_private_file(&self.path, &serde_json::to_vec_pretty(&file)?)
+    }
+}
+
+/// Counts a failed attempt and schedules the next one, or gives up once the
+/// policy's attempts are used up.
+fn retry_later(entry: &mut OutboxEntry, policy: &RetryPolicy, reason: String) {
+    entry.attempts += 1;
+    if entry.attempts >= policy.max_attempts {
+        entry.status = OutboxStatus::Failed {
+            reason: format!("Gave up after {} attempts: {}", entry.attempts, reason),
+        };
+    } else {
+        entry.next_attempt = Utc::now() + to_chrono(policy.delay(entry.attempts));
+    }
+    entry.last_error = Some(reason);
+}
+
+fn to_chrono(duration: Duration) -> chrono::Duration {
+    chrono::Duration::from_std(duration).unwrap_or(chrono::Duration::MAX)
+}
@@ -0,0 +1,294 @@
+use crate::encryption::{PublicKey, SigningPublicKey};
+use crate::message::Message;
+use crate::replay::Rejection;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Duration;
+
+/// The newest protocol version this build speaks.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// The oldest protocol version this build still accepts.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Optional features a peer may support, announced during the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Capability {
+    /// `LookupKey` answered with sealed key records.
+    KeyDirectory,
+    /// Messages carry Ed25519 signatures.
+    SignedMessages,
+    /// Messages for offline recipients are queued.
+    OfflineDelivery,
+    /// Password accounts via `Register` and `Login`.
+    Accounts,
+    /// Session tokens via `Resume`, `RefreshSession` and `Logout`.
+    Sessions,
+    /// `Delivered` receipts for sent messages.
+    Receipts,
+    /// A capability added by a newer peer that this build does not know.
+    #[serde(other)]
+    Unknown,
+}
+
+/// Capabilities this build supports.
+pub const CAPABILITIES: &[Capability] = &[
+    Capability::KeyDirectory,
+    Capability::SignedMessages,
+    Capability::OfflineDelivery,
+    Capability::Accounts,
+    Capability::Sessions,
+    Capability::Receipts,
+];
+
+/// An opaque token that logs a new connection in without a password.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionToken {
+    pub token: String,
+    /// How long the token stays valid, counted from when it was sent.
+    pub expires_in: Duration,
+}
+
+/// The server's word that a message reached a connection open for its
+/// recipient. It says nothing about whether the recipient has read it; that
+/// comes, if the recipient chooses, as an encrypted read receipt message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeliveryReceipt {
+    pub message_id: String,
+    pub recipient: String,
+    pub delivered_at: DateTime<Utc>,
+}
+
+/// Everything that travels inside a frame on a QuietDrop connection.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Packet {
+    /// First packet from a client: the protocol versions and capabilities it
+    /// supports. The server answers with `Welcome`, or with an error before
+    /// hanging up.
+    Hello {
+        min_version: u16,
+        max_version: u16,
+        capabilities: Vec<Capability>,
+        /// Free-form client name and version, for the server's logs.
+        client: String,
+    },
+    /// Creates a password account for `name` and logs the connection in as it.
+    Register { name: String, password: String },
+    /// Logs the connection in to an existing account. A connection may only
+    /// identify as, and send messages from, the account it is logged in to.
+    Login { name: String, password: String },
+    /// Logs the connection in with a session token from an earlier `Register`,
+    /// `Login` or `RefreshSession`.
+    Resume { token: String },
+    /// Swaps the connection's session token for a new one with a fresh expiry.
+    RefreshSession,
+    /// Revokes the connection's session token and logs it out.
+    Logout,
+    /// Replaces the logged-in account's password. Every session of the
+    /// account is revoked and the connection gets a new one.
+    ChangePassword {
+        old_password: String,
+        new_password: String,
+    },
+    /// Replaces a forgotten password using the account's one-time recovery
+    /// code, then logs the connection in. Every session of the account is
+    /// revoked and a new recovery code replaces the used one.
+    ResetPassword {
+        name: String,
+        recovery_code: String,
+        new_password: String,
+    },
+    /// Registers the connection to receive messages addressed to `name` and
+    /// publishes the public key other users should encrypt to, along with the
+    /// key its messages will be signed with. `public_key` must be the key the
+    /// client authenticated with during the transport handshake.
+    Identify {
+        name: String,
+        public_key: PublicKey,
+        signing_key: SigningPublicKey,
+    },
+    /// Asks the server for the public key published by `identity`.
+    LookupKey { identity: String },
+    /// An encrypted chat message.
+    Message(Message),
+    /// Sent by the server to the sender of a message once it is delivered,
+    /// or when the sender next identifies if it was offline at the time.
+    Delivered(DeliveryReceipt),
+    /// The server's answer to the client's last request.
+    Response(ServerResponse),
+    /// Keepalive probe; the peer answers with `Pong`.
+    Ping,
+    /// Answer to a `Ping`.
+    Pong,
+}
+
+/// Every answer the server gives to a client request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ServerResponse {
+    /// Answer to `Hello`: the version both sides will speak and the
+    /// capabilities both support.
+    Welcome {
+        version: u16,
+        capabilities: Vec<Capability>,
+    },
+    /// The account was created and the connection is logged in to it.
+    /// `recovery_code` is shown to the user once and never sent again.
+    Registered {
+        session: SessionToken,
+        recovery_code: String,
+    },
+    /// The connection is now logged in to the account. Answers `Login`,
+    /// `Resume` and `RefreshSession`; after `Resume` the token is the one
+    /// presented.
+    LoggedIn { session: SessionToken },
+    /// The connection's session was revoked and it is no longer logged in.
+    LoggedOut,
+    /// The password was changed; `session` replaces every earlier session.
+    PasswordChanged { session: SessionToken },
+    /// The password was reset and the connection is logged in. The used
+    /// recovery code is replaced by `recovery_code`.
+    PasswordReset {
+        session: SessionToken,
+        recovery_code: String,
+    },
+    /// The connection is now registered for the name it identified as.
+    Identified,
+    /// The message was accepted and routed; `message_id` is the one the
+    /// sender gave it.
+    Ack { message_id: String },
+    /// Answer to `LookupKey`: a `KeyRecord` sealed to the requester.
+    PublicKey { identity: String, sealed: Vec<u8> },
+    /// The request was refused.
+    Error { code: ErrorCode, reason: String },
+    /// The request was dropped because the client is sending too fast; it may
+    /// try again after `retry_after`.
+    RateLimited { retry_after: Duration },
+    /// The request needs a connection that is logged in, and for some
+    /// requests identified, as the name involved.
+    AuthRequired,
+}
+
+/// Why the server refused a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// The client and server share no protocol version; the server closes the connection.
+    IncompatibleVersion,
+    /// The client sent something other than `Hello` first; the server closes the connection.
+    HandshakeRequired,
+    /// The packet could not be parsed; the server closes the connection.
+    MalformedPacket,
+    /// The client sent a packet the server does not accept from clients.
+    UnexpectedPacket,
+    /// The message signature is missing or does not verify.
+    InvalidSignature,
+    /// The message claims a sender other than the identified connection or
+    /// the owner of its signing key.
+    SenderMismatch,
+    /// `Identify` published a key other than the one the connection was
+    /// opened with.
+    KeyMismatch,
+    /// `Register` named an account that already exists.
+    NameTaken,
+    /// `Register` named an account with a name clients cannot store.
+    InvalidName,
+    /// `Login` or `ChangePassword` gave the wrong password, or
+    /// `ResetPassword` the wrong recovery code, or the account is unknown.
+    InvalidCredentials,
+    /// A new password is shorter than the server accepts.
+    WeakPassword,
+    /// The session token has expired, was revoked or was never issued.
+    InvalidSession,
+    /// The server failed to carry out a valid request.
+    Internal,
+    /// A message with the same nonce was already accepted.
+    Replayed,
+    /// The message timestamp is outside the accepted window.
+    Stale,
+    /// The message ID is not a UUID.
+    InvalidMessageId,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ErrorCode::IncompatibleVersion => "incompatible version",
+            ErrorCode::HandshakeRequired => "handshake required",
+            ErrorCode::MalformedPacket => "malformed packet",
+            ErrorCode::UnexpectedPacket => "unexpected packet",
+            ErrorCode::InvalidSignature => "invalid signature",
+            ErrorCode::SenderMismatch => "sender mismatch",
+            ErrorCode::KeyMismatch => "key mismatch",
+            ErrorCode::NameTaken => "name taken",
+            ErrorCode::InvalidName => "invalid name",
+            ErrorCode::InvalidCredentials => "invalid credentials",
+            ErrorCode::WeakPassword => "weak password",
+            ErrorCode::InvalidSession => "invalid session",
+            ErrorCode::Internal => "internal error",
+            ErrorCode::Replayed => "replayed",
+            ErrorCode::Stale => "stale",
+            ErrorCode::InvalidMessageId => "invalid message id",
+        };
+        f.write_str(name)
+    }
+}
+
+impl From<Rejection> for ErrorCode {
+    fn from(rejection: Rejection) -> Self {
+        match rejection {
+            Rejection::Replayed => ErrorCode::Replayed,
+            Rejection::Stale => ErrorCode::Stale,
+        }
+    }
+}
+
+impl ServerResponse {
+    pub fn error(code: ErrorCode, reason: impl Into<String>) -> Self {
+        ServerResponse::Error {
+            code,
+            reason: reason.into(),
+        }
+    }
+}
+
+/// The client's side of the handshake for this build.
+pub fn hello(client: &str) -> Packet {
+    Packet::Hello {
+        min_version: MIN_PROTOCOL_VERSION,
+        max_version: PROTOCOL_VERSION,
+        capabilities: CAPABILITIES.to_vec(),
+        client: client.to_owned(),
+    }
+}
+
+/// Picks the newest version both ranges contain and the capabilities both
+/// sides listed, or explains why the peers cannot talk to each other.
+pub fn negotiate(
+    min_version: u16,
+    max_version: u16,
+    capabilities: &[Capability],
+) -> Result<(u16, Vec<Capability>), String> {
+    let version = max_version.min(PROTOCOL_VERSION);
+    if min_version > max_version || version < min_version.max(MIN_PROTOCOL_VERSION) {
+        return Err(format!(
+            "Peer speaks protocol versions {} to {}, but this side supports {} to {}",
+            min_version, max_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        ));
+    }
+    let shared = CAPABILITIES
+        .iter()
+        .filter(|capability| capabilities.contains(capability))
+        .copied()
+        .collect();
+    Ok((version, shared))
+}
+
+impl Packet {
+    pub fn to_bytes(&self) -> serde_json::Result<Vec<u8>> {
+        serde_json::to_vec(self)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> serde_json::Result<Packet> {
+        serde_json::from_slice(bytes)
+    }
+}
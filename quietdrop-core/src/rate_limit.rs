@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How many requests a single key may make per time window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitPolicy {
+    pub max_requests: usize,
+    pub window: Duration,
+}
+
+impl RateLimitPolicy {
+    pub fn new(max_requests: usize, window: Duration) -> Self {
+        RateLimitPolicy {
+            max_requests,
+            window,
+        }
+    }
+}
+
+/// Fixed-window rate limiter keyed by peer address, identity or anything else.
+///
+/// Entries whose window has passed are swept out at most once per window, so
+/// keys that stop sending do not accumulate forever.
+pub struct RateLimiter<K> {
+    policy: RateLimitPolicy,
+    state: Mutex<LimiterState<K>>,
+}
+
+struct LimiterState<K> {
+    requests: HashMap<K, (usize, Instant)>,
+    last_sweep: Instant,
+}
+
+impl<K> RateLimiter<K>
+where
+    K: Eq + Hash,
+{
+    pub fn new(policy: RateLimitPolicy) -> Self {
+        RateLimiter {
+            policy,
+            state: Mutex::new(LimiterState {
+                requests: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn policy(&self) -> RateLimitPolicy {
+        self.policy
+    }
+
+    /// Counts one request for `key`.
+    ///
+    /// Returns how long the caller has to wait if the key is over its limit.
+    pub fn check(&self, key: K) -> Result<(), Duration> {
+        self.check_at(key, Instant::now())
+    }
+
+    /// Like `check`, with an explicit notion of the current time.
+    pub fn check_at(&self, key: K, now: Instant) -> Result<(), Duration> {
+        let window = self.policy.window;
+        let mut state = self.state.lock().unwrap();
+
+        if now.saturating_duration_since(state.last_sweep) >= window {
+            state
+                .requests
+                .retain(|_, (_, started)| now.saturating_duration_since(*started) < window);
+            state.last_sweep = now;
+        }
+
+        let (count, started) = state.requests.entry(key).or_insert((0, now));
+        let elapsed = now.saturating_duration_since(*started);
+        if elapsed >= window {
+            *count = 0;
+            *started = now;
+        }
+        if *count >= self.policy.max_requests {
+            Err(window.saturating_sub(now.saturating_duration_since(*started)))
+        } else {
+            *count += 1;
+            Ok(())
+        }
+    }
+
+    /// Number of keys currently tracked.
+    pub fn tracked_keys(&self) -> usize {
+        self.state.lock().unwrap().requests.len()
+    }
+}
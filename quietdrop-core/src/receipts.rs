@@ -0,0 +1,104 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Where a sent message stands, as far as its sender knows.
+///
+/// A message moves forward through `Pending`, `Sent`, `Delivered` and
+/// `Read`, possibly skipping steps, since receipts can overtake each other.
+/// It never moves back. `Failed` is only reachable from `Pending`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MessageStatus {
+    /// Not yet accepted by the server.
+    Pending,
+    /// The server refused it, or it ran out of attempts.
+    Failed,
+    /// Accepted by the server.
+    Sent,
+    /// Handed to a connection open for the recipient.
+    Delivered,
+    /// The recipient sent a read receipt.
+    Read,
+}
+
+/// Something that happened to a sent message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusEvent {
+    /// The server acknowledged the message.
+    Accepted,
+    /// A delivery receipt arrived.
+    Delivered,
+    /// A read receipt arrived.
+    Read,
+    /// Sending failed for good.
+    Failed,
+}
+
+impl MessageStatus {
+    /// The status after `event`. Events that would move the message
+    /// backwards, such as a delivery receipt arriving after a read receipt,
+    /// leave it unchanged.
+    pub fn apply(self, event: StatusEvent) -> MessageStatus {
+        let next = match event {
+            StatusEvent::Accepted => MessageStatus::Sent,
+            StatusEvent::Delivered => MessageStatus::Delivered,
+            StatusEvent::Read => MessageStatus::Read,
+            StatusEvent::Failed if self == MessageStatus::Pending => return MessageStatus::Failed,
+            StatusEvent::Failed => return self,
+        };
+        // A receipt proves the server accepted the message after all
+        if next.rank() > self.rank() {
+            next
+        } else {
+            self
+        }
+    }
+
+    fn rank(self) -> u8 {
+        match self {
+            MessageStatus::Pending | MessageStatus::Failed => 0,
+            MessageStatus::Sent => 1,
+            MessageStatus::Delivered => 2,
+            MessageStatus::Read => 3,
+        }
+    }
+}
+
+/// The status of every message a client is following, by message ID.
+#[derive(Debug, Default)]
+pub struct MessageTracker {
+    statuses: HashMap<String, MessageStatus>,
+}
+
+impl MessageTracker {
+    pub fn new() -> Self {
+        MessageTracker::default()
+    }
+
+    /// Starts following a message as `Pending`, unless it is already followed.
+    pub fn track(&mut self, message_id: &str) {
+        self.statuses
+            .entry(message_id.to_owned())
+            .or_insert(MessageStatus::Pending);
+    }
+
+    /// Applies `event` to a followed message and returns its new status.
+    /// Events for messages that are not followed are ignored.
+    pub fn apply(&mut self, message_id: &str, event: StatusEvent) -> Option<MessageStatus> {
+        let status = self.statuses.get_mut(message_id)?;
+        *status = status.apply(event);
+        Some(*status)
+    }
+
+    pub fn status(&self, message_id: &str) -> Option<MessageStatus> {
+        self.statuses.get(message_id).copied()
+    }
+
+    pub fn statuses(&self) -> &HashMap<String, MessageStatus> {
+        &self.statuses
+    }
+
+    /// Stops following a message.
+    pub fn forget(&mut self, message_id: &str) -> Option<MessageStatus> {
+        self.statuses.remove(message_id)
+    }
+}
@@ -0,0 +1,174 @@
+use crate::message::Message;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::box_;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+
+/// Why the server refused to relay a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Rejection {
+    /// A message with the same nonce was already accepted.
+    Replayed,
+    /// The timestamp is further from the server's clock than the window allows.
+    Stale,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::Replayed => write!(f, "message was already delivered"),
+            Rejection::Stale => write!(f, "message timestamp is outside the accepted window"),
+        }
+    }
+}
+
+/// What the replay cache made of a message it let through.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Admission {
+    /// The message has not been seen before. Hand the ticket back with
+    /// `accept` once the message is routed, or `forget` if it could not be.
+    New(PendingMessage),
+    /// The sender re-signed a message that was already accepted, because it
+    /// never got the acknowledgement. It must not be routed again.
+    Duplicate,
+}
+
+/// A message `check` let through that has not been routed yet.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PendingMessage {
+    key: Vec<u8>,
+    message_id: String,
+}
+
+/// Remembers recently accepted messages so captured ones cannot be resent.
+///
+/// Messages are identified by the nonce at the front of their ciphertext, which
+/// the signature covers. Only messages whose timestamp is within `window` of
+/// the server's clock are accepted, so a nonce only has to be remembered for
+/// that long before an old copy would be rejected as stale anyway. Expired
+/// nonces are swept out at most once per window.
+///
+/// A nonce only counts as used once its message is accepted, so a message
+/// the server failed to route can be sent again. A copy of an accepted
+/// message with the same ID but a fresh timestamp, which only its sender can
+/// sign, is reported as a `Duplicate` rather than a replay.
+pub struct ReplayCache {
+    window: Duration,
+    state: Mutex<ReplayState>,
+}
+
+struct ReplayState {
+    seen: HashMap<Vec<u8>, Seen>,
+    last_sweep: DateTime<Utc>,
+}
+
+struct Seen {
+    timestamp: DateTime<Utc>,
+    sender: String,
+    message_id: String,
+    accepted: bool,
+}
+
+impl ReplayCache {
+    pub fn new(window: std::time::Duration) -> Self {
+        ReplayCache {
+            window: Duration::from_std(window).unwrap_or(Duration::MAX),
+            state: Mutex::new(ReplayState {
+                seen: HashMap::new(),
+                last_sweep: Utc::now(),
+            }),
+        }
+    }
+
+    /// Lets `message` through if it is fresh and has not been seen before.
+    pub fn check(&self, message: &Message) -> Result<Admission, Rejection> {
+        self.check_at(message, Utc::now())
+    }
+
+    /// Like `check`, with an explicit notion of the current time.
+    pub fn check_at(&self, message: &Message, now: DateTime<Utc>) -> Result<Admission, Rejection> {
+        let age = now.signed_duration_since(message.timestamp);
+        if age > self.window || -age > self.window {
+            return Err(Rejection::Stale);
+        }
+
+        let window = self.window;
+        let mut state = self.state.lock().unwrap();
+
+        if now.signed_duration_since(state.last_sweep) >= window {
+            state
+                .seen
+                .retain(|_, seen| now.signed_duration_since(seen.timestamp) <= window);
+            state.last_sweep = now;
+        }
+
+        let key = replay_key(message);
+        // An expired nonce may still be here if the last sweep was recent
+        if let Some(seen) = state.seen.get(&key) {
+            if now.signed_duration_since(seen.timestamp) <= window {
+                let resigned = seen.accepted
+                    && seen.sender == message.sender
+                    && seen.message_id == message.id
+                    && seen.timestamp != message.timestamp;
+                return if resigned {
+                    Ok(Admission::Duplicate)
+                } else {
+                    Err(Rejection::Replayed)
+                };
+            }
+        }
+        state.seen.insert(
+            key.clone(),
+            Seen {
+                timestamp: message.timestamp,
+                sender: message.sender.clone(),
+                message_id: message.id.clone(),
+                accepted: false,
+            },
+        );
+        Ok(Admission::New(PendingMessage {
+            key,
+            message_id: message.id.clone(),
+        }))
+    }
+
+    /// Marks a message as accepted, so its nonce cannot be used again.
+    pub fn accept(&self, pending: PendingMessage) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(seen) = state.seen.get_mut(&pending.key) {
+            if seen.message_id == pending.message_id {
+                seen.accepted = true;
+            }
+        }
+    }
+
+    /// Frees the nonce of a message the server could not route, so the
+    /// sender can try again.
+    pub fn forget(&self, pending: PendingMessage) {
+        let mut state = self.state.lock().unwrap();
+        let unaccepted = state
+            .seen
+            .get(&pending.key)
+            .is_some_and(|seen| !seen.accepted && seen.message_id == pending.message_id);
+        if unaccepted {
+            state.seen.remove(&pending.key);
+        }
+    }
+
+    /// Number of messages currently remembered, including expired ones
+    /// that have not been swept out yet.
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn replay_key(message: &Message) -> Vec<u8> {
+    let len = message.content.len().min(box_::NONCEBYTES);
+    message.content[..len].to_vec()
+}
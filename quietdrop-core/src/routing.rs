@@ -0,0 +1,273 @@
+use crate::error::Result;
+use crate::message::{Message, MessageType};
+use crate::protocol::{DeliveryReceipt, Packet};
+use crate::storage::{ReceiptStatus, Storage};
+use chrono::Utc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// What happened to a routed message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// Handed to a connection that is currently open for the recipient,
+    /// which reports it with `Router::delivered` once it has written it.
+    Forwarded,
+    /// Stored until the recipient connects.
+    Queued,
+}
+
+/// Tracks which identities are connected and holds messages, and delivery
+/// receipts, for those who are not.
+///
+/// Held messages are kept in `storage`, so they survive a server restart.
+/// Each routed message gets a receipt there as it is queued and delivered.
+/// A message only counts as delivered once the recipient's connection has
+/// written it; a connection that closes first hands its unwritten messages
+/// back to `route`.
+/// Storage is only used from the blocking thread pool, never while the
+/// table of connections is locked.
+pub struct Router {
+    storage: Arc<Storage>,
+    state: Mutex<RouterState>,
+}
+
+#[derive(Default)]
+struct RouterState {
+    online: HashMap<String, (u64, UnboundedSender<Packet>)>,
+    next_registration: u64,
+}
+
+impl Router {
+    pub fn new(storage: Arc<Storage>) -> Self {
+        Router {
+            storage,
+            state: Mutex::new(RouterState::default()),
+        }
+    }
+
+    /// Registers a connection for `identity` and flushes any queued messages
+    /// and delivery receipts to it.
+    ///
+    /// A newer connection for the same identity replaces the older one. The returned
+    /// registration ID must be passed to `disconnect` when the connection ends.
+    pub async fn connect(&self, identity: &str, sender: UnboundedSender<Packet>) -> Result<u64> {
+        let registration = {
+            let mut state = self.state.lock().unwrap();
+            let registration = state.next_registration;
+            state.next_registration += 1;
+            state
+                .online
+                .insert(identity.to_owned(), (registration, sender));
+            registration
+        };
+        let flushed = match self.flush_receipts(identity).await {
+            Ok(()) => self.flush(identity).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = flushed {
+            self.disconnect(identity, registration);
+            return Err(e);
+        }
+        Ok(registration)
+    }
+
+    /// Removes the connection registered for `identity`, unless it has been replaced.
+    pub fn disconnect(&self, identity: &str, registration: u64) {
+        let mut state = self.state.lock().unwrap();
+        if matches!(state.online.get(identity), Some((current, _)) if *current == registration) {
+            state.online.remove(identity);
+        }
+    }
+
+    /// Forwards a message to its recipient's connection, or queues it if
+    /// they are offline.
+    pub async fn route(&self, message: Message) -> Result<Delivery> {
+        let recipient = message.recipient.clone();
+        let message = match self.hand_over(message) {
+            None => return Ok(Delivery::Forwarded),
+            Some(message) => message,
+        };
+
+        self.blocking(move |storage| {
+            storage.enqueue(&message.id, &message)?;
+            storage.record_receipt(
+                &message.sender,
+                &message.id,
+                &message.recipient,
+                ReceiptStatus::Queued,
+            )
+        })
+        .await?;
+        // The recipient may have connected while the message was being stored
+        if self.is_online(&recipient) {
+            self.flush(&recipient).await?;
+        }
+        Ok(Delivery::Queued)
+    }
+
+    /// Passes a delivery receipt to `sender`, or keeps it until they connect.
+    pub async fn send_receipt(&self, sender: &str, receipt: DeliveryReceipt) -> Result<()> {
+        let receipt = match self.hand_over_receipt(sender, receipt) {
+            None => return Ok(()),
+            Some(receipt) => receipt,
+        };
+
+        let owner = sender.to_owned();
+        self.blocking(move |storage| storage.enqueue_delivery_receipt(&owner, &receipt))
+            .await?;
+        // The sender may have connected while the receipt was being stored
+        if self.is_online(sender) {
+            self.flush_receipts(sender).await?;
+        }
+        Ok(())
+    }
+
+    /// Records that the recipient's connection wrote `message` to it.
+    ///
+    /// The sender gets a delivery receipt, right away if online and otherwise
+    /// when it next connects. Read receipts and file chunks do not get
+    /// delivery receipts of their own.
+    pub async fn delivered(&self, message: &Message) -> Result<()> {
+        let (sender, message_id, recipient) = (
+            message.sender.clone(),
+            message.id.clone(),
+            message.recipient.clone(),
+        );
+        self.blocking(move |storage| {
+            storage.record_receipt(&sender, &message_id, &recipient, ReceiptStatus::Delivered)
+        })
+        .await?;
+        match delivery_receipt(message) {
+            Some((sender, receipt)) => self.send_receipt(&sender, receipt).await,
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_online(&self, identity: &str) -> bool {
+        self.state.lock().unwrap().online.contains_key(identity)
+    }
+
+    pub async fn queued_count(&self, identity: &str) -> Result<usize> {
+        let identity = identity.to_owned();
+        self.blocking(move |storage| storage.queued_count(&identity))
+            .await
+    }
+
+    /// Hands everything queued for `identity` to its connection, keeping
+    /// what is left queued if the connection goes away.
+    async fn flush(&self, identity: &str) -> Result<()> {
+        let owner = identity.to_owned();
+        let mut queue = self
+            .blocking(move |storage| storage.take_queued(&owner))
+            .await?
+            .into_iter();
+        while let Some((message_id, message)) = queue.next() {
+            if let Some(message) = self.hand_over(message) {
+                let rest: Vec<_> = std::iter::once((message_id, message))
+                    .chain(queue)
+                    .collect();
+                return self
+                    .blocking(move |storage| {
+                        rest.iter().try_for_each(|(message_id, message)| {
+                            storage.enqueue(message_id, message)
+                        })
+                    })
+                    .await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Hands the delivery receipts kept for `sender` to its connection,
+    /// keeping what is left if the connection goes away.
+    async fn flush_receipts(&self, sender: &str) -> Result<()> {
+        let owner = sender.to_owned();
+        let mut receipts = self
+            .blocking(move |storage| storage.take_delivery_receipts(&owner))
+            .await?
+            .into_iter();
+        while let Some(receipt) = receipts.next() {
+            if let Some(receipt) = self.hand_over_receipt(sender, receipt) {
+                let owner = sender.to_owned();
+                let rest: Vec<_> = std::iter::once(receipt).chain(receipts).collect();
+                return self
+                    .blocking(move |storage| {
+                        rest.iter().try_for_each(|receipt| {
+                            storage.enqueue_delivery_receipt(&owner, receipt)
+                        })
+                    })
+                    .await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Passes `message` to the connection open for its recipient. Gives the
+    /// message back if there is none.
+    fn hand_over(&self, message: Message) -> Option<Message> {
+        let recipient = message.recipient.clone();
+        match self.send_packet(&recipient, Packet::Message(message))? {
+            Packet::Message(message) => Some(message),
+            _ => unreachable!("only messages are routed"),
+        }
+    }
+
+    /// Passes `receipt` to the connection open for `sender`. Gives the
+    /// receipt back if there is none.
+    fn hand_over_receipt(&self, sender: &str, receipt: DeliveryReceipt) -> Option<DeliveryReceipt> {
+        match self.send_packet(sender, Packet::Delivered(receipt))? {
+            Packet::Delivered(receipt) => Some(receipt),
+            _ => unreachable!("only receipts are sent here"),
+        }
+    }
+
+    /// Queues `packet` on the connection open for `identity`, forgetting the
+    /// connection if it has gone away. Gives the packet back if it could not
+    /// be queued.
+    fn send_packet(&self, identity: &str, packet: Packet) -> Option<Packet> {
+        let mut state = self.state.lock().unwrap();
+        let sent = match state.online.get(identity) {
+            Some((_, connection)) => connection.send(packet),
+            None => return Some(packet),
+        };
+        match sent {
+            Ok(()) => None,
+            Err(returned) => {
+                state.online.remove(identity);
+                Some(returned.0)
+            }
+        }
+    }
+
+    /// Runs `work` against storage on the blocking thread pool.
+    async fn blocking<T, F>(&self, work: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Storage) -> Result<T> + Send + 'static,
+    {
+        let storage = Arc::clone(&self.storage);
+        tokio::task::spawn_blocking(move || work(&storage))
+            .await
+            .map_err(std::io::Error::other)?
+    }
+}
+
+/// The receipt owed to the sender of `message` once it is delivered, with
+/// the sender's name. Read receipts are not acknowledged, and neither are
+/// file chunks or transfer acknowledgements; the file's offer stands for
+/// them.
+fn delivery_receipt(message: &Message) -> Option<(String, DeliveryReceipt)> {
+    if matches!(
+        message.message_type,
+        MessageType::ReadReceipt | MessageType::FileChunk | MessageType::TransferAck
+    ) {
+        return None;
+    }
+    let receipt = DeliveryReceipt {
+        message_id: message.id.clone(),
+        recipient: message.recipient.clone(),
+        delivered_at: Utc::now(),
+    };
+    Some((message.sender.clone(), receipt))
+}
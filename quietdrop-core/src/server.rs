@@ -1,104 +1,869 @@
-#![allow(dead_code)]
-use crate::encryption::{decrypt_message, SecretKey};
+use crate::accounts::{is_valid_account_name, Accounts, MIN_PASSWORD_LENGTH};
+use crate::authentication::{CredentialStore, PasswordPolicy};
+use crate::connection::{Connection, ConnectionConfig};
+use crate::directory::KeyDirectory;
+use crate::encryption::{PublicKey, SecretKey};
+use crate::error::Result;
 use crate::message::Message;
-use serde_json;
+use crate::protocol::{negotiate, ErrorCode, Packet, ServerResponse, SessionToken};
+use crate::rate_limit::{RateLimitPolicy, RateLimiter};
+use crate::replay::{Admission, ReplayCache};
+use crate::routing::{Delivery, Router};
+use crate::sessions::Sessions;
+use crate::storage::Storage;
+use crate::tls::{BoxedStream, ServerTls};
+use crate::transport;
 use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::time::Instant;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::net::TcpListener;
 use tokio::spawn;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use uuid::Uuid;
 
-struct RateLimiter {
-    requests: HashMap<SocketAddr, (usize, Instant)>,
-    time_frame: std::time::Duration,
-    request_limit: usize,
+/// Tunable server behaviour.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// How far a message timestamp may be from the server's clock, in either
+    /// direction, before the message is rejected as stale.
+    pub replay_window: Duration,
+    /// Limit on requests from one peer IP address, across all its connections.
+    pub peer_rate_limit: Option<RateLimitPolicy>,
+    /// Limit on messages sent by one account, across all connections.
+    pub identity_rate_limit: Option<RateLimitPolicy>,
+    /// How long a new connection has to complete the transport handshake.
+    pub handshake_timeout: Duration,
+    /// Serve over TLS with this certificate and key instead of plain TCP.
+    pub tls: Option<ServerTls>,
+    /// Accounts, published keys, queued messages and receipts.
+    pub storage: Arc<Storage>,
+    /// Where account password hashes are kept, if not in `storage`.
+    pub credentials: Option<Arc<dyn CredentialStore>>,
+    /// Argon2id costs for new password hashes. Stored hashes made with other
+    /// costs are rehashed on the account's next successful login.
+    pub password_policy: PasswordPolicy,
+    /// Session tokens issued by the server. Keep a clone to revoke sessions
+    /// while the server runs.
+    pub sessions: Arc<Sessions>,
 }
 
-impl RateLimiter {
-    pub fn new(time_frame: std::time::Duration, request_limit: usize) -> Self {
-        RateLimiter {
-            requests: HashMap::new(),
-            time_frame,
-            request_limit,
-        }
-    }
-
-    pub fn check(&mut self, addr: SocketAddr) -> bool {
-        let (count, timestamp) = self.requests.entry(addr).or_insert((0, Instant::now()));
-        if timestamp.elapsed() > self.time_frame {
-            *count = 0;
-            *timestamp = Instant::now();
-        }
-        if *count > self.request_limit {
-            false
-        } else {
-            *count += 1;
-            true
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            replay_window: Duration::from_secs(5 * 60),
+            peer_rate_limit: Some(RateLimitPolicy::new(300, Duration::from_secs(60))),
+            identity_rate_limit: Some(RateLimitPolicy::new(120, Duration::from_secs(60))),
+            handshake_timeout: Duration::from_secs(10),
+            tls: None,
+            storage: Arc::new(Storage::in_memory().expect("in-memory databases always open")),
+            credentials: None,
+            password_policy: PasswordPolicy::default(),
+            sessions: Arc::new(Sessions::default()),
         }
     }
 }
 
+/// Runs a server that keeps its state in `storage`.
 pub async fn run_server(
     addr: &str,
     server_secret_key: &SecretKey,
-) -> Result<(), Box<dyn std::error::Error>> {
+    storage: Arc<Storage>,
+) -> Result<()> {
+    let config = ServerConfig {
+        storage,
+        ..ServerConfig::default()
+    };
+    run_server_with_config(addr, server_secret_key, config).await
+}
+
+pub async fn run_server_with_config(
+    addr: &str,
+    server_secret_key: &SecretKey,
+    config: ServerConfig,
+) -> Result<()> {
     let listener = TcpListener::bind(addr).await?;
+    serve_with_config(listener, server_secret_key, config).await
+}
+
+/// Shared state for every connection handled by one server.
+struct ServerState {
+    secret_key: SecretKey,
+    router: Router,
+    directory: KeyDirectory,
+    accounts: Accounts,
+    sessions: Arc<Sessions>,
+    logins: Logins,
+    replay: ReplayCache,
+    peer_limiter: Option<RateLimiter<IpAddr>>,
+    identity_limiter: Option<RateLimiter<String>>,
+    handshake_timeout: Duration,
+}
+
+/// Accepts connections on an already bound listener.
+///
+/// Every connection starts with a transport handshake that proves the server
+/// holds `server_secret_key` and the client holds the key it identifies with;
+/// everything after that, metadata included, is encrypted.
+/// Each connection stays open and may carry any number of messages until the
+/// client closes it, stops answering heartbeats or sends something unparsable.
+/// A connection must log in to an account, or register one, before it can
+/// identify as that name or send messages from it. Logging in issues a
+/// session token that later connections can present instead of the password.
+/// Messages are end-to-end encrypted, so the server only relays them: they are
+/// routed to the connection identified as their recipient, or queued until that
+/// recipient connects. Each message is only relayed once, and only while its
+/// timestamp is fresh.
+pub async fn serve(listener: TcpListener, server_secret_key: &SecretKey) -> Result<()> {
+    serve_with_config(listener, server_secret_key, ServerConfig::default()).await
+}
+
+/// Like `serve`, with explicit configuration.
+///
+/// With `config.tls` set, every connection is TLS first and the transport
+/// handshake runs inside it.
+pub async fn serve_with_config(
+    listener: TcpListener,
+    server_secret_key: &SecretKey,
+    config: ServerConfig,
+) -> Result<()> {
+    let acceptor = config.tls.as_ref().map(ServerTls::acceptor).transpose()?;
+    let credentials = match config.credentials {
+        Some(credentials) => credentials,
+        None => Arc::clone(&config.storage) as Arc<dyn CredentialStore>,
+    };
+    let state = Arc::new(ServerState {
+        secret_key: server_secret_key.clone(),
+        router: Router::new(Arc::clone(&config.storage)),
+        directory: KeyDirectory::new(config.storage),
+        accounts: Accounts::new(credentials, config.password_policy)?,
+        sessions: config.sessions,
+        logins: Logins::default(),
+        replay: ReplayCache::new(config.replay_window),
+        peer_limiter: config.peer_rate_limit.map(RateLimiter::new),
+        identity_limiter: config.identity_rate_limit.map(RateLimiter::new),
+        handshake_timeout: config.handshake_timeout,
+    });
 
     loop {
-        let (mut socket, _) = listener.accept().await?;
+        let (socket, peer_addr) = listener.accept().await?;
 
-        let server_secret_key = server_secret_key.clone();
+        let state = Arc::clone(&state);
+        let acceptor = acceptor.clone();
 
         spawn(async move {
-            let mut buf = vec![0; 1024];
-            match socket.read(&mut buf).await {
-                Ok(0) => {
-                    eprintln!("Connection closed.");
-                }
-
-                Ok(n) => {
-                    match serde_json::from_slice::<Message>(&buf[..n]) {
-                        Ok(msg) => {
-                            println!("### Encrypted message recived:\n{:?}\n", &msg.content);
-
-                            // And then you get the sender's public key here
-                            let sender_public_key = msg.public_key;
-
-                            // Then you decrypt using the secret key this way
-                            match decrypt_message(
-                                &msg.content,
-                                &sender_public_key,
-                                &server_secret_key,
-                            ) {
-                                Ok(content) => {
-                                    println!(
-                                        "## Decrypted message: \n\
-                                        sender: {}\n\
-                                        recipient: {}\n\
-                                        content: {}\n\
-                                        timestamp: {}\n",
-                                        msg.sender, msg.recipient, content, msg.timestamp,
-                                    );
-                                    let response = "Message received.";
-                                    if let Err(e) = socket.write_all(response.as_bytes()).await {
-                                        eprintln!("Failed to send response: {}", e);
-                                    }
-                                }
-                                Err(e) => {
-                                    eprintln!("Error decrypting message: {}", e);
-                                }
-                            }
+            let mut stream: BoxedStream = match acceptor {
+                Some(acceptor) => {
+                    match timeout(state.handshake_timeout, acceptor.accept(socket)).await {
+                        Ok(Ok(stream)) => Box::new(stream),
+                        Ok(Err(e)) => {
+                            eprintln!("TLS handshake with {} failed: {}", peer_addr, e);
+                            return;
                         }
-                        Err(e) => {
-                            eprintln!("Error deserializing message: {}", e);
+                        Err(_) => {
+                            eprintln!("TLS handshake with {} timed out", peer_addr);
+                            return;
                         }
                     }
                 }
-                Err(e) => {
-                    eprintln!("Error reading from socket: {}", e);
+                None => Box::new(socket),
+            };
+
+            let handshake = transport::accept(&mut stream, &state.secret_key);
+            let (channel, peer_key) = match timeout(state.handshake_timeout, handshake).await {
+                Ok(Ok(accepted)) => accepted,
+                Ok(Err(e)) => {
+                    eprintln!("Handshake with {} failed: {}", peer_addr, e);
+                    return;
+                }
+                Err(_) => {
+                    eprintln!("Handshake with {} timed out", peer_addr);
+                    return;
                 }
+            };
+
+            let mut connection =
+                Connection::with_channel(stream, ConnectionConfig::default(), channel);
+            let peer = Peer {
+                addr: peer_addr.ip(),
+                public_key: peer_key,
+            };
+            if let Err(e) = handle_connection(&mut connection, &peer, &state).await {
+                eprintln!("Connection with {} ended with error: {}", peer_addr, e);
             }
         });
     }
 }
+
+/// Who is on the other end of a connection, as established before any
+/// request is read.
+struct Peer {
+    addr: IpAddr,
+    /// The static key the client proved it holds during the handshake.
+    public_key: PublicKey,
+}
+
+/// The identity a connection registered with `Identify`.
+struct ConnectedIdentity {
+    name: String,
+    public_key: PublicKey,
+    registration: u64,
+}
+
+/// What a connection has established about itself so far.
+struct Session {
+    /// The account the connection logged in to.
+    account: Option<String>,
+    /// The session token issued to, or presented by, the connection.
+    token: Option<String>,
+    identity: Option<ConnectedIdentity>,
+    /// This connection's entry in `Logins` while it is logged in.
+    login: Option<u64>,
+    /// Where `Logins` tells the connection it has been logged out.
+    logout_tx: mpsc::UnboundedSender<u64>,
+}
+
+impl Session {
+    fn new(logout_tx: mpsc::UnboundedSender<u64>) -> Self {
+        Session {
+            account: None,
+            token: None,
+            identity: None,
+            login: None,
+            logout_tx,
+        }
+    }
+}
+
+/// The connections logged in to each account, so that changing or resetting
+/// the password can log all the others out.
+#[derive(Default)]
+struct Logins {
+    next_login: AtomicU64,
+    accounts: Mutex<HashMap<String, HashMap<u64, mpsc::UnboundedSender<u64>>>>,
+}
+
+impl Logins {
+    /// Records a connection logging in to `account`, returning the login's ID.
+    fn add(&self, account: &str, logout_tx: &mpsc::UnboundedSender<u64>) -> u64 {
+        let login = self.next_login.fetch_add(1, Ordering::Relaxed);
+        self.accounts
+            .lock()
+            .unwrap()
+            .entry(account.to_owned())
+            .or_default()
+            .insert(login, logout_tx.clone());
+        login
+    }
+
+    fn remove(&self, account: &str, login: u64) {
+        let mut accounts = self.accounts.lock().unwrap();
+        if let Some(logins) = accounts.get_mut(account) {
+            logins.remove(&login);
+            if logins.is_empty() {
+                accounts.remove(account);
+            }
+        }
+    }
+
+    /// Tells every connection logged in to `account`, other than the one
+    /// holding `keep`, that it has been logged out.
+    fn log_out_others(&self, account: &str, keep: Option<u64>) {
+        let mut accounts = self.accounts.lock().unwrap();
+        if let Some(logins) = accounts.get_mut(account) {
+            logins.retain(|login, logout_tx| {
+                if Some(*login) == keep {
+                    return true;
+                }
+                // A closed channel means the connection is already gone
+                let _ = logout_tx.send(*login);
+                false
+            });
+        }
+    }
+}
+
+async fn handle_connection(
+    connection: &mut Connection<BoxedStream>,
+    peer: &Peer,
+    state: &Arc<ServerState>,
+) -> std::io::Result<()> {
+    if !handshake(connection).await? {
+        return Ok(());
+    }
+
+    let (outbound_tx, mut outbound_rx) = mpsc::unbounded_channel();
+    let (logout_tx, mut logout_rx) = mpsc::unbounded_channel();
+    let mut session = Session::new(logout_tx);
+    let mut unwritten = Vec::new();
+
+    let result = loop {
+        let packet = tokio::select! {
+            // Checked first, so no request is served on a login that has ended
+            biased;
+            Some(login) = logout_rx.recv() => {
+                if session.login == Some(login) {
+                    log_out(&mut session, state);
+                }
+                continue;
+            }
+            packet = connection.recv() => packet,
+            Some(outbound) = outbound_rx.recv() => {
+                if let Err(e) = connection.send(&outbound).await {
+                    unwritten.push(outbound);
+                    break Err(e);
+                }
+                // Only a message that made it onto the wire counts as delivered
+                if let Packet::Message(msg) = &outbound {
+                    if let Err(e) = state.router.delivered(msg).await {
+                        eprintln!("## Could not record delivery of message {}: {}", msg.id, e);
+                    }
+                }
+                continue;
+            }
+        };
+
+        let request = match packet {
+            Ok(Some(request)) => request,
+            Ok(None) => {
+                eprintln!("Connection closed.");
+                break Ok(());
+            }
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                // The stream can no longer be trusted to be in sync; say why and hang up
+                let reply = ServerResponse::error(ErrorCode::MalformedPacket, e.to_string());
+                let _ = connection.send(&Packet::Response(reply)).await;
+                break Err(e);
+            }
+            Err(e) => break Err(e),
+        };
+
+        let reply = match check_rate_limits(&request, peer.addr, &session, state) {
+            Err(retry_after) => ServerResponse::RateLimited { retry_after },
+            Ok(()) => handle_request(request, peer, &mut session, &outbound_tx, state).await,
+        };
+
+        if let Err(e) = connection.send(&Packet::Response(reply)).await {
+            break Err(e);
+        }
+    };
+
+    if let (Some(account), Some(login)) = (&session.account, session.login) {
+        state.logins.remove(account, login);
+    }
+    let identity = session.identity.map(|identity| {
+        state
+            .router
+            .disconnect(&identity.name, identity.registration);
+        identity.name
+    });
+
+    // Anything routed here but not yet written goes back to the router
+    outbound_rx.close();
+    while let Ok(packet) = outbound_rx.try_recv() {
+        unwritten.push(packet);
+    }
+    for packet in unwritten {
+        match (packet, &identity) {
+            (Packet::Message(msg), _) => {
+                let message_id = msg.id.clone();
+                if let Err(e) = state.router.route(msg).await {
+                    eprintln!("## Could not requeue message {}: {}", message_id, e);
+                }
+            }
+            (Packet::Delivered(receipt), Some(name)) => {
+                let message_id = receipt.message_id.clone();
+                if let Err(e) = state.router.send_receipt(name, receipt).await {
+                    eprintln!("## Could not keep receipt for {}: {}", message_id, e);
+                }
+            }
+            _ => {}
+        }
+    }
+    result
+}
+
+/// Waits for the client's `Hello` and answers it.
+///
+/// Returns `false` if the client was turned away, after telling it why.
+async fn handshake(connection: &mut Connection<BoxedStream>) -> std::io::Result<bool> {
+    let reply = match connection.recv().await {
+        Ok(Some(Packet::Hello {
+            min_version,
+            max_version,
+            capabilities,
+            client,
+        })) => match negotiate(min_version, max_version, &capabilities) {
+            Ok((version, capabilities)) => {
+                println!("## {} connected using protocol version {}", client, version);
+                connection
+                    .send(&Packet::Response(ServerResponse::Welcome {
+                        version,
+                        capabilities,
+                    }))
+                    .await?;
+                return Ok(true);
+            }
+            Err(reason) => {
+                eprintln!("## Turning away {}: {}", client, reason);
+                ServerResponse::error(ErrorCode::IncompatibleVersion, reason)
+            }
+        },
+        Ok(Some(_)) => ServerResponse::error(
+            ErrorCode::HandshakeRequired,
+            "The first packet on a connection must be Hello",
+        ),
+        Ok(None) => return Ok(false),
+        Err(e) if e.kind() == ErrorKind::InvalidData => {
+            let reply = ServerResponse::error(ErrorCode::MalformedPacket, e.to_string());
+            let _ = connection.send(&Packet::Response(reply)).await;
+            return Err(e);
+        }
+        Err(e) => return Err(e),
+    };
+
+    connection.send(&Packet::Response(reply)).await?;
+    Ok(false)
+}
+
+/// Carries out one client request and builds the response to it.
+///
+/// Password hashing and database queries run on the blocking thread pool,
+/// so slow requests do not hold up other connections.
+async fn handle_request(
+    request: Packet,
+    peer: &Peer,
+    session: &mut Session,
+    outbound_tx: &mpsc::UnboundedSender<Packet>,
+    state: &Arc<ServerState>,
+) -> ServerResponse {
+    match request {
+        Packet::Register { name, password } => {
+            if !is_valid_account_name(&name) {
+                return ServerResponse::error(
+                    ErrorCode::InvalidName,
+                    "Names must not be empty, contain / or \\, or start with a dot",
+                );
+            }
+            if let Some(refusal) = check_password_strength(&password) {
+                return refusal;
+            }
+            let account = name.clone();
+            let registered = blocking(state, move |state| {
+                state.accounts.register(&account, &password)
+            })
+            .await;
+            match registered {
+                Ok(Some(recovery_code)) => {
+                    println!("## Registered account {}", name);
+                    let issued = state.sessions.issue(&name);
+                    log_in(session, name, issued.token.clone(), state);
+                    ServerResponse::Registered {
+                        session: issued,
+                        recovery_code,
+                    }
+                }
+                Ok(None) => {
+                    ServerResponse::error(ErrorCode::NameTaken, "That name is already registered")
+                }
+                Err(e) => {
+                    eprintln!("## Registering {} failed: {}", name, e);
+                    ServerResponse::error(ErrorCode::Internal, "Could not create the account")
+                }
+            }
+        }
+        Packet::Login { name, password } => {
+            let account = name.clone();
+            let verified = blocking(state, move |state| {
+                state.accounts.verify(&account, &password)
+            })
+            .await;
+            match verified {
+                Ok(true) => {
+                    let issued = state.sessions.issue(&name);
+                    log_in(session, name, issued.token.clone(), state);
+                    ServerResponse::LoggedIn { session: issued }
+                }
+                Ok(false) => {
+                    eprintln!("## Failed login for {}", name);
+                    ServerResponse::error(
+                        ErrorCode::InvalidCredentials,
+                        "Unknown account or wrong password",
+                    )
+                }
+                Err(e) => {
+                    eprintln!("## Checking the password for {} failed: {}", name, e);
+                    ServerResponse::error(ErrorCode::Internal, "Could not check the password")
+                }
+            }
+        }
+        Packet::Resume { token } => match state.sessions.validate(&token) {
+            Some((account, expires_in)) => {
+                log_in(session, account, token.clone(), state);
+                ServerResponse::LoggedIn {
+                    session: SessionToken { token, expires_in },
+                }
+            }
+            None => invalid_session(),
+        },
+        Packet::RefreshSession => {
+            if session.account.is_none() {
+                return ServerResponse::AuthRequired;
+            }
+            let refreshed = session
+                .token
+                .as_deref()
+                .and_then(|token| state.sessions.refresh(token));
+            match refreshed {
+                Some((_, issued)) => {
+                    session.token = Some(issued.token.clone());
+                    ServerResponse::LoggedIn { session: issued }
+                }
+                None => invalid_session(),
+            }
+        }
+        Packet::Logout => {
+            if let Some(token) = session.token.take() {
+                state.sessions.revoke(&token);
+            }
+            log_out(session, state);
+            ServerResponse::LoggedOut
+        }
+        Packet::ChangePassword {
+            old_password,
+            new_password,
+        } => {
+            let name = match &session.account {
+                Some(name) => name.clone(),
+                None => return ServerResponse::AuthRequired,
+            };
+            if let Some(refusal) = check_password_strength(&new_password) {
+                return refusal;
+            }
+            let account = name.clone();
+            let changed = blocking(state, move |state| {
+                state
+                    .accounts
+                    .change_password(&account, &old_password, &new_password)
+            })
+            .await;
+            match changed {
+                Ok(true) => {
+                    println!("## Changed the password of {}", name);
+                    let issued = replace_sessions(session, name, state);
+                    ServerResponse::PasswordChanged { session: issued }
+                }
+                Ok(false) => ServerResponse::error(ErrorCode::InvalidCredentials, "Wrong password"),
+                Err(e) => {
+                    eprintln!("## Changing the password of {} failed: {}", name, e);
+                    ServerResponse::error(ErrorCode::Internal, "Could not change the password")
+                }
+            }
+        }
+        Packet::ResetPassword {
+            name,
+            recovery_code,
+            new_password,
+        } => {
+            if let Some(refusal) = check_password_strength(&new_password) {
+                return refusal;
+            }
+            let account = name.clone();
+            let reset = blocking(state, move |state| {
+                state
+                    .accounts
+                    .reset_password(&account, &recovery_code, &new_password)
+            })
+            .await;
+            match reset {
+                Ok(Some(recovery_code)) => {
+                    println!("## Reset the password of {}", name);
+                    let issued = replace_sessions(session, name, state);
+                    ServerResponse::PasswordReset {
+                        session: issued,
+                        recovery_code,
+                    }
+                }
+                Ok(None) => {
+                    eprintln!("## Failed password reset for {}", name);
+                    ServerResponse::error(
+                        ErrorCode::InvalidCredentials,
+                        "Unknown account or wrong recovery code",
+                    )
+                }
+                Err(e) => {
+                    eprintln!("## Resetting the password of {} failed: {}", name, e);
+                    ServerResponse::error(ErrorCode::Internal, "Could not reset the password")
+                }
+            }
+        }
+        Packet::Identify {
+            name,
+            public_key,
+            signing_key,
+        } => {
+            if session.account.as_deref() != Some(name.as_str()) {
+                return ServerResponse::AuthRequired;
+            }
+            if public_key != peer.public_key {
+                return ServerResponse::error(
+                    ErrorCode::KeyMismatch,
+                    "Identify must publish the key this connection was opened with",
+                );
+            }
+            if let Some(previous) = session.identity.take() {
+                state
+                    .router
+                    .disconnect(&previous.name, previous.registration);
+            }
+            let account = name.clone();
+            let published = blocking(state, move |state| {
+                state.directory.publish(&account, public_key, signing_key)
+            })
+            .await;
+            let connected = match published {
+                Ok(()) => state.router.connect(&name, outbound_tx.clone()).await,
+                Err(e) => Err(e),
+            };
+            let registration = match connected {
+                Ok(registration) => registration,
+                Err(e) => {
+                    eprintln!("## Identifying {} failed: {}", name, e);
+                    return ServerResponse::error(ErrorCode::Internal, "Could not identify");
+                }
+            };
+            println!("## {} connected", name);
+            session.identity = Some(ConnectedIdentity {
+                name,
+                public_key,
+                registration,
+            });
+            ServerResponse::Identified
+        }
+        Packet::LookupKey { identity: wanted } => {
+            let requester = match &session.identity {
+                Some(requester) => requester,
+                None => return ServerResponse::AuthRequired,
+            };
+            let lookup = wanted.clone();
+            let record = match blocking(state, move |state| state.directory.record(&lookup)).await {
+                Ok(record) => record,
+                Err(e) => {
+                    eprintln!("## Looking up the key of {} failed: {}", wanted, e);
+                    return ServerResponse::error(ErrorCode::Internal, "Could not look up the key");
+                }
+            };
+            ServerResponse::PublicKey {
+                identity: wanted,
+                sealed: record.seal(&requester.public_key, &state.secret_key),
+            }
+        }
+        Packet::Message(msg) => {
+            let account = match &session.account {
+                Some(account) => account,
+                None => return ServerResponse::AuthRequired,
+            };
+            if Uuid::parse_str(&msg.id).is_err() {
+                return ServerResponse::error(
+                    ErrorCode::InvalidMessageId,
+                    "Message IDs must be UUIDs",
+                );
+            }
+            if let Err((code, reason)) = check_sender(&msg, account, state).await {
+                eprintln!("## Rejected message from {}: {}", msg.sender, reason);
+                return ServerResponse::error(code, reason);
+            }
+            // Checked after the signature, so only the real sender's
+            // messages can occupy the cache
+            let pending = match state.replay.check(&msg) {
+                Ok(Admission::New(pending)) => pending,
+                // The sender lost the acknowledgement of the first copy
+                Ok(Admission::Duplicate) => return ServerResponse::Ack { message_id: msg.id },
+                Err(rejection) => {
+                    eprintln!("## Rejected message from {}: {}", msg.sender, rejection);
+                    return ServerResponse::error(rejection.into(), rejection.to_string());
+                }
+            };
+            let message_id = msg.id.clone();
+            // The content is sealed to the recipient; only metadata is visible here
+            println!(
+                "## Relaying message {}: \n\
+                sender: {}\n\
+                recipient: {}\n\
+                size: {} bytes\n\
+                timestamp: {}",
+                message_id,
+                msg.sender,
+                msg.recipient,
+                msg.content.len(),
+                msg.timestamp,
+            );
+            match state.router.route(msg).await {
+                Ok(delivery) => {
+                    state.replay.accept(pending);
+                    match delivery {
+                        Delivery::Forwarded => println!("## Forwarded to recipient\n"),
+                        Delivery::Queued => println!("## Recipient offline, message queued\n"),
+                    }
+                }
+                Err(e) => {
+                    // Not relayed, so the sender may try the same message again
+                    state.replay.forget(pending);
+                    eprintln!("## Could not relay message {}: {}", message_id, e);
+                    return ServerResponse::error(
+                        ErrorCode::Internal,
+                        "Could not relay the message",
+                    );
+                }
+            }
+            ServerResponse::Ack { message_id }
+        }
+        other => {
+            eprintln!("Refusing unexpected packet: {:?}", other);
+            ServerResponse::error(
+                ErrorCode::UnexpectedPacket,
+                "Clients may not send this packet",
+            )
+        }
+    }
+}
+
+/// Runs `work` on the blocking thread pool with the server's state.
+async fn blocking<T, F>(state: &Arc<ServerState>, work: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&ServerState) -> Result<T> + Send + 'static,
+{
+    let state = Arc::clone(state);
+    tokio::task::spawn_blocking(move || work(&state))
+        .await
+        .map_err(std::io::Error::other)?
+}
+
+/// Binds the connection to `name` and `token`, dropping any identity
+/// registered under a different name and revoking any token it held before.
+fn log_in(session: &mut Session, name: String, token: String, state: &ServerState) {
+    if let Some(previous) = session.token.replace(token) {
+        if session.token.as_ref() != Some(&previous) {
+            state.sessions.revoke(&previous);
+        }
+    }
+    if let Some(previous) = session.identity.take() {
+        if previous.name == name {
+            session.identity = Some(previous);
+        } else {
+            state
+                .router
+                .disconnect(&previous.name, previous.registration);
+        }
+    }
+    if let (Some(account), Some(login)) = (&session.account, session.login) {
+        state.logins.remove(account, login);
+    }
+    session.login = Some(state.logins.add(&name, &session.logout_tx));
+    session.account = Some(name);
+}
+
+/// Ends the connection's login, leaving it connected but anonymous.
+fn log_out(session: &mut Session, state: &ServerState) {
+    session.token = None;
+    if let Some(identity) = session.identity.take() {
+        state
+            .router
+            .disconnect(&identity.name, identity.registration);
+    }
+    if let (Some(account), Some(login)) = (session.account.take(), session.login.take()) {
+        state.logins.remove(&account, login);
+    }
+}
+
+/// Revokes every session of `name` after a password change, logs out every
+/// other connection logged in to it, and logs this connection in with a new
+/// session.
+fn replace_sessions(session: &mut Session, name: String, state: &ServerState) -> SessionToken {
+    state.sessions.revoke_account(&name);
+    let issued = state.sessions.issue(&name);
+    log_in(session, name.clone(), issued.token.clone(), state);
+    state.logins.log_out_others(&name, session.login);
+    issued
+}
+
+/// The refusal for a password that is too short, if it is.
+fn check_password_strength(password: &str) -> Option<ServerResponse> {
+    (password.chars().count() < MIN_PASSWORD_LENGTH).then(|| {
+        ServerResponse::error(
+            ErrorCode::WeakPassword,
+            format!(
+                "Passwords must be at least {} characters long",
+                MIN_PASSWORD_LENGTH
+            ),
+        )
+    })
+}
+
+fn invalid_session() -> ServerResponse {
+    ServerResponse::error(
+        ErrorCode::InvalidSession,
+        "Session expired or was revoked; log in with a password",
+    )
+}
+
+/// Checks that a message comes from the logged-in account and is signed by
+/// the key published for its sender.
+///
+/// Senders that have never published keys cannot be checked here;
+/// recipients still verify the signature against the key they look up.
+async fn check_sender(
+    msg: &Message,
+    account: &str,
+    state: &Arc<ServerState>,
+) -> std::result::Result<(), (ErrorCode, String)> {
+    msg.verify_signature()
+        .map_err(|e| (ErrorCode::InvalidSignature, e.to_string()))?;
+    if account != msg.sender {
+        return Err((
+            ErrorCode::SenderMismatch,
+            "Sender does not match the logged-in account".to_owned(),
+        ));
+    }
+    let sender = msg.sender.clone();
+    let published = blocking(state, move |state| {
+        state.directory.lookup_signing_key(&sender)
+    })
+    .await
+    .map_err(|e| {
+        (
+            ErrorCode::Internal,
+            format!("Could not look up the sender's key: {}", e),
+        )
+    })?;
+    match published {
+        Some(signing_key) if signing_key != msg.signing_key => Err((
+            ErrorCode::SenderMismatch,
+            "Message is not signed with the sender's published key".to_owned(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Counts a request against the peer's limit and, for messages from a
+/// logged-in connection, against the account's limit.
+fn check_rate_limits(
+    request: &Packet,
+    peer: IpAddr,
+    session: &Session,
+    state: &ServerState,
+) -> std::result::Result<(), Duration> {
+    if let Some(limiter) = &state.peer_limiter {
+        limiter.check(peer)?;
+    }
+    if let (Packet::Message(_), Some(account), Some(limiter)) =
+        (request, &session.account, &state.identity_limiter)
+    {
+        limiter.check(account.clone())?;
+    }
+    Ok(())
+}
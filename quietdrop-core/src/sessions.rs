@@ -0,0 +1,169 @@
+use crate::error::Result;
+use crate::keystore::{create_private_dir, write_private_file};
+use crate::protocol::SessionToken;
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a session token stays valid unless it is refreshed.
+pub const DEFAULT_SESSION_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Bytes of randomness in a token.
+const TOKEN_BYTES: usize = 32;
+
+/// The file a client keeps its `SavedSession` in, next to its keystore.
+pub const SESSION_TOKEN_FILE: &str = "session.token";
+
+/// Opaque session tokens issued to logged-in clients.
+///
+/// A token lets a client log in again on a new connection without sending
+/// its password. Tokens live only in server memory: they expire after the
+/// configured time to live, and can be revoked one at a time or all at once
+/// for an account. Revoking a token stops it being presented again; it does
+/// not by itself log out connections that already logged in with it. The
+/// server does that when an account's password is changed or reset.
+pub struct Sessions {
+    ttl: Duration,
+    tokens: Mutex<HashMap<String, SessionRecord>>,
+}
+
+struct SessionRecord {
+    account: String,
+    expires_at: Instant,
+}
+
+impl Sessions {
+    pub fn new(ttl: Duration) -> Self {
+        Sessions {
+            ttl,
+            tokens: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Issues a new token for `account`.
+    pub fn issue(&self, account: &str) -> SessionToken {
+        let mut bytes = [0u8; TOKEN_BYTES];
+        sodiumoxide::randombytes::randombytes_into(&mut bytes);
+        let token: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+
+        let now = Instant::now();
+        let mut tokens = self.tokens.lock().unwrap();
+        // Expired tokens are dropped whenever a new one is issued
+        tokens.retain(|_, record| record.expires_at > now);
+        tokens.insert(
+            token.clone(),
+            SessionRecord {
+                account: account.to_owned(),
+                expires_at: now + self.ttl,
+            },
+        );
+        SessionToken {
+            token,
+            expires_in: self.ttl,
+        }
+    }
+
+    /// The account `token` belongs to and how long it has left, if it is
+    /// still valid.
+    pub fn validate(&self, token: &str) -> Option<(String, Duration)> {
+        let now = Instant::now();
+        let mut tokens = self.tokens.lock().unwrap();
+        let record = tokens.get(token)?;
+        if record.expires_at <= now {
+            tokens.remove(token);
+            return None;
+        }
+        Some((record.account.clone(), record.expires_at - now))
+    }
+
+    /// Replaces a valid `token` with a fresh one for the same account.
+    ///
+    /// The old token stops working straight away.
+    pub fn refresh(&self, token: &str) -> Option<(String, SessionToken)> {
+        let (account, _) = self.validate(token)?;
+        self.revoke(token);
+        let session = self.issue(&account);
+        Some((account, session))
+    }
+
+    /// Revokes one token. Returns whether it was known.
+    pub fn revoke(&self, token: &str) -> bool {
+        self.tokens.lock().unwrap().remove(token).is_some()
+    }
+
+    /// Revokes every token issued for `account`, returning how many there
+    /// were.
+    pub fn revoke_account(&self, account: &str) -> usize {
+        let mut tokens = self.tokens.lock().unwrap();
+        let before = tokens.len();
+        tokens.retain(|_, record| record.account != account);
+        before - tokens.len()
+    }
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Sessions::new(DEFAULT_SESSION_TTL)
+    }
+}
+
+impl std::fmt::Debug for Sessions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Never print the tokens themselves
+        f.debug_struct("Sessions")
+            .field("ttl", &self.ttl)
+            .field("active", &self.tokens.lock().unwrap().len())
+            .finish()
+    }
+}
+
+/// A client's copy of its session token, kept on disk so the next run can
+/// log in without the password.
+///
+/// Anyone holding the token can log in as the account, so the file is
+/// written like a key file, readable only by the current user.
+pub struct SavedSession {
+    path: PathBuf,
+}
+
+impl SavedSession {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        SavedSession { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The saved token, if there is one.
+    pub fn token(&self) -> Result<Option<String>> {
+        match fs::read_to_string(&self.path) {
+            Ok(token) => Ok(Some(token.trim().to_owned())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Saves `token`, replacing any token saved before.
+    pub fn save(&self, token: &str) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            create_private_dir(dir)?;
+        }
+        write_private_file(&self.path, token.as_bytes())
+    }
+
+    /// Deletes the saved token, if any.
+    pub fn clear(&self) -> Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,449 @@
+use crate::authentication::{Credential, CredentialStore};
+use crate::encryption::{PublicKey, SigningPublicKey};
+use crate::error::{QuietDropError, Result};
+use crate::keystore::write_private_file;
+use crate::message::Message;
+use crate::protocol::DeliveryReceipt;
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+/// Schema changes, oldest first. A database's `user_version` is the number
+/// of migrations already applied to it; append new ones, never edit old ones.
+const MIGRATIONS: &[&str] = &[
+    // 1: accounts, published keys, offline queue and receipts
+    "CREATE TABLE users (
+        name TEXT PRIMARY KEY NOT NULL,
+        created_at TEXT NOT NULL
+    );
+    CREATE TABLE credentials (
+        user TEXT PRIMARY KEY NOT NULL REFERENCES users (name) ON DELETE CASCADE,
+        password_hash TEXT NOT NULL,
+        recovery_hash TEXT
+    );
+    CREATE TABLE public_keys (
+        identity TEXT PRIMARY KEY NOT NULL,
+        public_key BLOB NOT NULL,
+        signing_key BLOB NOT NULL,
+        published_at TEXT NOT NULL
+    );
+    CREATE TABLE queued_messages (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        message_id TEXT NOT NULL UNIQUE,
+        recipient TEXT NOT NULL,
+        message TEXT NOT NULL,
+        queued_at TEXT NOT NULL
+    );
+    CREATE INDEX queued_messages_by_recipient ON queued_messages (recipient, id);
+    CREATE TABLE receipts (
+        message_id TEXT NOT NULL,
+        recipient TEXT NOT NULL,
+        status TEXT NOT NULL,
+        recorded_at TEXT NOT NULL,
+        PRIMARY KEY (message_id, status)
+    );",
+    // 2: delivery receipts waiting for their sender to connect
+    "CREATE TABLE undelivered_receipts (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        sender TEXT NOT NULL,
+        message_id TEXT NOT NULL,
+        recipient TEXT NOT NULL,
+        delivered_at TEXT NOT NULL
+    );
+    CREATE INDEX undelivered_receipts_by_sender ON undelivered_receipts (sender, id);",
+    // 3: message IDs are chosen by clients, so they are only unique per
+    // sender and recipient
+    "CREATE TABLE scoped_queued_messages (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        sender TEXT NOT NULL,
+        message_id TEXT NOT NULL,
+        recipient TEXT NOT NULL,
+        message TEXT NOT NULL,
+        queued_at TEXT NOT NULL,
+        UNIQUE (sender, recipient, message_id)
+    );
+    INSERT INTO scoped_queued_messages (id, sender, message_id, recipient, message, queued_at)
+        SELECT id, json_extract(message, '$.sender'), message_id, recipient, message, queued_at
+        FROM queued_messages;
+    CREATE TABLE scoped_receipts (
+        sender TEXT NOT NULL,
+        message_id TEXT NOT NULL,
+        recipient TEXT NOT NULL,
+        status TEXT NOT NULL,
+        recorded_at TEXT NOT NULL,
+        PRIMARY KEY (sender, recipient, message_id, status)
+    );
+    INSERT INTO scoped_receipts (sender, message_id, recipient, status, recorded_at)
+        SELECT COALESCE(
+            (SELECT json_extract(queued.message, '$.sender') FROM queued_messages queued
+             WHERE queued.message_id = receipts.message_id),
+            ''
+        ), message_id, recipient, status, recorded_at
+        FROM receipts ORDER BY rowid;
+    DROP TABLE queued_messages;
+    DROP TABLE receipts;
+    ALTER TABLE scoped_queued_messages RENAME TO queued_messages;
+    ALTER TABLE scoped_receipts RENAME TO receipts;
+    CREATE INDEX queued_messages_by_recipient ON queued_messages (recipient, id);",
+];
+
+/// How far a relayed message has got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReceiptStatus {
+    /// Stored until the recipient connects.
+    Queued,
+    /// Handed to a connection open for the recipient.
+    Delivered,
+}
+
+impl ReceiptStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            ReceiptStatus::Queued => "queued",
+            ReceiptStatus::Delivered => "delivered",
+        }
+    }
+
+    fn parse(status: &str) -> Result<Self> {
+        match status {
+            "queued" => Ok(ReceiptStatus::Queued),
+            "delivered" => Ok(ReceiptStatus::Delivered),
+            other => Err(storage_error(format!("unknown receipt status {}", other))),
+        }
+    }
+}
+
+/// One step in the delivery of a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Receipt {
+    pub sender: String,
+    pub message_id: String,
+    pub recipient: String,
+    pub status: ReceiptStatus,
+    pub recorded_at: DateTime<Utc>,
+}
+
+/// The server's SQLite database: accounts and their credentials, published
+/// public keys, messages waiting for offline recipients and delivery receipts,
+/// including those waiting for an offline sender.
+///
+/// Opening a database brings its schema up to date. Messages are stored as
+/// relayed, so their content stays sealed to the recipient.
+pub struct Storage {
+    path: Option<PathBuf>,
+    connection: Mutex<Connection>,
+}
+
+impl Storage {
+    /// Opens the database at `path`, creating it with owner-only permissions
+    /// if it does not exist.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        if !path.exists() {
+            write_private_file(&path, &[])?;
+        }
+        let connection = Connection::open(&path)?;
+        Storage::init(Some(path), connection)
+    }
+
+    /// A private database that lives only as long as the returned handle.
+    pub fn in_memory() -> Result<Self> {
+        Storage::init(None, Connection::open_in_memory()?)
+    }
+
+    fn init(path: Option<PathBuf>, mut connection: Connection) -> Result<Self> {
+        connection.pragma_update(None, "foreign_keys", true)?;
+        connection.busy_timeout(Duration::from_secs(5))?;
+        migrate(&mut connection)?;
+        Ok(Storage {
+            path,
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Where the database lives, or `None` for an in-memory one.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// The number of migrations applied to the database.
+    pub fn schema_version(&self) -> Result<usize> {
+        schema_version(&self.connection())
+    }
+
+    /// Records the keys `identity` published, replacing any earlier ones.
+    pub fn publish_keys(
+        &self,
+        identity: &str,
+        public_key: &PublicKey,
+        signing_key: &SigningPublicKey,
+    ) -> Result<()> {
+        self.connection().execute(
+            "INSERT INTO public_keys (identity, public_key, signing_key, published_at)
+             VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (identity) DO UPDATE SET
+                public_key = excluded.public_key,
+                signing_key = excluded.signing_key,
+                published_at = excluded.published_at",
+            params![
+                identity,
+                public_key.as_ref(),
+                signing_key.as_ref(),
+                Utc::now()
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The keys last published by `identity`.
+    pub fn public_keys(&self, identity: &str) -> Result<Option<(PublicKey, SigningPublicKey)>> {
+        let keys = self
+            .connection()
+            .query_row(
+                "SELECT public_key, signing_key FROM public_keys WHERE identity = ?1",
+                params![identity],
+                |row| Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, Vec<u8>>(1)?)),
+            )
+            .optional()?;
+        keys.map(|(public_key, signing_key)| {
+            let public_key = PublicKey::from_slice(&public_key);
+            let signing_key = SigningPublicKey::from_slice(&signing_key);
+            public_key
+                .zip(signing_key)
+                .ok_or_else(|| storage_error(format!("stored keys for {} are malformed", identity)))
+        })
+        .transpose()
+    }
+
+    /// Stores a message until its recipient connects. A message whose sender
+    /// already queued one with the same ID for the same recipient is a resend
+    /// and is ignored.
+    pub fn enqueue(&self, message_id: &str, message: &Message) -> Result<()> {
+        self.connection().execute(
+            "INSERT OR IGNORE INTO queued_messages (sender, message_id, recipient, message, queued_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                message.sender,
+                message_id,
+                message.recipient,
+                serde_json::to_string(message)?,
+                Utc::now()
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Removes and returns the messages queued for `recipient`, oldest first,
+    /// with their message IDs.
+    pub fn take_queued(&self, recipient: &str) -> Result<Vec<(String, Message)>> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        let rows = transaction
+            .prepare(
+                "SELECT message_id, message FROM queued_messages
+                 WHERE recipient = ?1 ORDER BY id",
+            )?
+            .query_map(params![recipient], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        transaction.execute(
+            "DELETE FROM queued_messages WHERE recipient = ?1",
+            params![recipient],
+        )?;
+        transaction.commit()?;
+
+        rows.into_iter()
+            .map(|(message_id, json)| Ok((message_id, serde_json::from_str(&json)?)))
+            .collect()
+    }
+
+    /// How many messages are waiting for `recipient`.
+    pub fn queued_count(&self, recipient: &str) -> Result<usize> {
+        let count: i64 = self.connection().query_row(
+            "SELECT COUNT(*) FROM queued_messages WHERE recipient = ?1",
+            params![recipient],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    /// Notes that the message `sender` sent to `recipient` as `message_id`
+    /// reached `status`. Recording the same status twice keeps the first
+    /// time it was reached.
+    pub fn record_receipt(
+        &self,
+        sender: &str,
+        message_id: &str,
+        recipient: &str,
+        status: ReceiptStatus,
+    ) -> Result<()> {
+        self.connection().execute(
+            "INSERT OR IGNORE INTO receipts (sender, message_id, recipient, status, recorded_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![sender, message_id, recipient, status.as_str(), Utc::now()],
+        )?;
+        Ok(())
+    }
+
+    /// Every receipt recorded for a message `sender` sent, in the order they
+    /// were reached.
+    pub fn receipts(&self, sender: &str, message_id: &str) -> Result<Vec<Receipt>> {
+        let connection = self.connection();
+        let rows = connection
+            .prepare(
+                "SELECT recipient, status, recorded_at FROM receipts
+                 WHERE sender = ?1 AND message_id = ?2 ORDER BY recorded_at, rowid",
+            )?
+            .query_map(params![sender, message_id], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, DateTime<Utc>>(2)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        rows.into_iter()
+            .map(|(recipient, status, recorded_at)| {
+                Ok(Receipt {
+                    sender: sender.to_owned(),
+                    message_id: message_id.to_owned(),
+                    recipient,
+                    status: ReceiptStatus::parse(&status)?,
+                    recorded_at,
+                })
+            })
+            .collect()
+    }
+
+    /// Keeps a delivery receipt until `sender` connects to collect it.
+    pub fn enqueue_delivery_receipt(&self, sender: &str, receipt: &DeliveryReceipt) -> Result<()> {
+        self.connection().execute(
+            "INSERT INTO undelivered_receipts (sender, message_id, recipient, delivered_at)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![
+                sender,
+                receipt.message_id,
+                receipt.recipient,
+                receipt.delivered_at
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Removes and returns the delivery receipts kept for `sender`, oldest
+    /// first.
+    pub fn take_delivery_receipts(&self, sender: &str) -> Result<Vec<DeliveryReceipt>> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        let receipts = transaction
+            .prepare(
+                "SELECT message_id, recipient, delivered_at FROM undelivered_receipts
+                 WHERE sender = ?1 ORDER BY id",
+            )?
+            .query_map(params![sender], |row| {
+                Ok(DeliveryReceipt {
+                    message_id: row.get(0)?,
+                    recipient: row.get(1)?,
+                    delivered_at: row.get(2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        transaction.execute(
+            "DELETE FROM undelivered_receipts WHERE sender = ?1",
+            params![sender],
+        )?;
+        transaction.commit()?;
+        Ok(receipts)
+    }
+
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        self.connection.lock().unwrap()
+    }
+}
+
+/// Account names live in `users`; each has one row in `credentials`.
+impl CredentialStore for Storage {
+    fn get(&self, name: &str) -> Result<Option<Credential>> {
+        let credential = self
+            .connection()
+            .query_row(
+                "SELECT password_hash, recovery_hash FROM credentials WHERE user = ?1",
+                params![name],
+                |row| {
+                    Ok(Credential {
+                        password_hash: row.get(0)?,
+                        recovery_hash: row.get(1)?,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(credential)
+    }
+
+    fn insert(&self, name: &str, credential: &Credential) -> Result<bool> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        let created = transaction.execute(
+            "INSERT OR IGNORE INTO users (name, created_at) VALUES (?1, ?2)",
+            params![name, Utc::now()],
+        )?;
+        if created == 0 {
+            return Ok(false);
+        }
+        transaction.execute(
+            "INSERT INTO credentials (user, password_hash, recovery_hash) VALUES (?1, ?2, ?3)",
+            params![name, credential.password_hash, credential.recovery_hash],
+        )?;
+        transaction.commit()?;
+        Ok(true)
+    }
+
+    fn update(&self, name: &str, credential: &Credential) -> Result<bool> {
+        let updated = self.connection().execute(
+            "UPDATE credentials SET password_hash = ?2, recovery_hash = ?3 WHERE user = ?1",
+            params![name, credential.password_hash, credential.recovery_hash],
+        )?;
+        Ok(updated > 0)
+    }
+}
+
+impl fmt::Debug for Storage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Storage").field("path", &self.path).finish()
+    }
+}
+
+/// Applies every migration the database has not seen yet, each in its own
+/// transaction.
+fn migrate(connection: &mut Connection) -> Result<()> {
+    let applied = schema_version(connection)?;
+    if applied > MIGRATIONS.len() {
+        return Err(storage_error(format!(
+            "database schema version {} is newer than this server supports ({})",
+            applied,
+            MIGRATIONS.len()
+        )));
+    }
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(applied) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", index + 1)?;
+        transaction.commit()?;
+    }
+    Ok(())
+}
+
+fn schema_version(connection: &Connection) -> Result<usize> {
+    let version: i64 = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    Ok(version as usize)
+}
+
+fn storage_error(message: impl Into<String>) -> QuietDropError {
+    QuietDropError::Storage(message.into())
+}
@@ -0,0 +1,158 @@
+use crate::error::{QuietDropError, Result};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{
+    self, internal::pemfile, Certificate, NoClientAuth, PrivateKey, RootCertStore,
+    ServerCertVerified, ServerCertVerifier, TLSError,
+};
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// Any byte stream a `Connection` can run over, with or without TLS.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T> Stream for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
+
+pub type BoxedStream = Box<dyn Stream>;
+
+/// Certificate and private key the server presents to TLS clients.
+///
+/// Both files are PEM. The certificate file may hold a whole chain, leaf
+/// first; the key may be PKCS#8 or RSA.
+#[derive(Debug, Clone)]
+pub struct ServerTls {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl ServerTls {
+    pub fn new(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        ServerTls {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+        }
+    }
+
+    pub(crate) fn acceptor(&self) -> Result<TlsAcceptor> {
+        let certs = read_certs(&self.cert_path)?;
+        let key = read_private_key(&self.key_path)?;
+
+        let mut config = rustls::ServerConfig::new(NoClientAuth::new());
+        config
+            .set_single_cert(certs, key)
+            .map_err(|e| tls_error(&self.cert_path, e))?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+/// How a client checks the server's TLS certificate.
+///
+/// `server_name` is sent to the server and checked against the certificate,
+/// so it must be a DNS name rather than an IP address.
+#[derive(Clone)]
+pub struct ClientTls {
+    server_name: String,
+    config: Arc<rustls::ClientConfig>,
+}
+
+impl ClientTls {
+    /// Accepts certificates for `server_name` issued by a CA in the PEM file
+    /// at `ca_path`.
+    pub fn with_ca_file(server_name: &str, ca_path: &Path) -> Result<ClientTls> {
+        let mut roots = RootCertStore::empty();
+        for cert in read_certs(ca_path)? {
+            roots
+                .add(&cert)
+                .map_err(|e| tls_error(ca_path, format!("{:?}", e)))?;
+        }
+
+        let mut config = rustls::ClientConfig::new();
+        config.root_store = roots;
+        ClientTls::build(server_name, config)
+    }
+
+    /// Accepts only the exact certificate in the PEM file at `cert_path`,
+    /// whoever issued it. Suits self-signed server certificates.
+    pub fn pinned(server_name: &str, cert_path: &Path) -> Result<ClientTls> {
+        let pinned = read_certs(cert_path)?.swap_remove(0);
+
+        let mut config = rustls::ClientConfig::new();
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(PinnedCertificate(pinned)));
+        ClientTls::build(server_name, config)
+    }
+
+    fn build(server_name: &str, config: rustls::ClientConfig) -> Result<ClientTls> {
+        DNSNameRef::try_from_ascii_str(server_name)
+            .map_err(|_| QuietDropError::Tls(format!("{} is not a valid DNS name", server_name)))?;
+        Ok(ClientTls {
+            server_name: server_name.to_owned(),
+            config: Arc::new(config),
+        })
+    }
+
+    pub(crate) async fn connect(&self, stream: TcpStream) -> Result<BoxedStream> {
+        let name = DNSNameRef::try_from_ascii_str(&self.server_name)
+            .expect("server name was checked when the config was built");
+        let stream = TlsConnector::from(Arc::clone(&self.config))
+            .connect(name, stream)
+            .await?;
+        Ok(Box::new(stream))
+    }
+}
+
+/// Trusts one specific certificate and nothing else.
+struct PinnedCertificate(Certificate);
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        _dns_name: DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> std::result::Result<ServerCertVerified, TLSError> {
+        match presented_certs.first() {
+            Some(cert) if *cert == self.0 => Ok(ServerCertVerified::assertion()),
+            _ => Err(TLSError::General(
+                "Server certificate does not match the pinned certificate".to_owned(),
+            )),
+        }
+    }
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let certs = pemfile::certs(&mut open(path)?)
+        .map_err(|_| tls_error(path, "not a PEM certificate file"))?;
+    if certs.is_empty() {
+        return Err(tls_error(path, "no certificates found"));
+    }
+    Ok(certs)
+}
+
+fn read_private_key(path: &Path) -> Result<PrivateKey> {
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(path)?)
+        .map_err(|_| tls_error(path, "not a PEM key file"))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(path)?)
+            .map_err(|_| tls_error(path, "not a PEM key file"))?;
+    }
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| tls_error(path, "no private key found"))
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| tls_error(path, e))
+}
+
+fn tls_error(path: &Path, reason: impl std::fmt::Display) -> QuietDropError {
+    QuietDropError::Tls(format!("{}: {}", path.display(), reason))
+}
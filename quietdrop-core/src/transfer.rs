@@ -0,0 +1,1179 @@
+use crate::client::Client;
+use crate::encryption::{
+    decrypt_bytes, encrypt_bytes, Identity, PublicKey, SigningPublicKey, StreamDecryptor,
+    StreamEncryptor, StreamKey, STREAM_FRAME_OVERHEAD,
+};
+use crate::error::{QuietDropError, Result};
+use crate::keystore::write_private_file;
+use crate::message::{Message, MessageType};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::randombytes::randombytes;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Plaintext bytes carried by each chunk. Chunks are sent as ordinary
+/// messages, so this is also what a transfer costs against rate limits.
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Random bytes at the front of every chunk. The server's replay cache
+/// remembers messages by them, so a chunk can be sent again on request.
+const CHUNK_NONCE_LEN: usize = 24;
+
+/// The transfer ID and chunk index that follow the nonce.
+const CHUNK_PREFIX_LEN: usize = 16 + 8;
+
+/// Encrypted size of every chunk but the last.
+const SEALED_CHUNK_SIZE: u64 = (CHUNK_SIZE + STREAM_FRAME_OVERHEAD) as u64;
+
+/// The recipient confirms its progress every this many chunks, and once the
+/// file is complete.
+const ACK_INTERVAL: u64 = 8;
+
+const TRANSFER_FILE_VERSION: u8 = 1;
+
+/// The directory a client's `OutgoingFiles` keep unfinished uploads in, next
+/// to its keystore.
+pub const TRANSFERS_DIR: &str = "transfers";
+
+/// What the recipient learns about a file before any of it arrives.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileHeader {
+    /// The file's name, without any directories.
+    pub name: String,
+    pub size: u64,
+    pub mime_type: String,
+    /// Hex-encoded SHA-256 of the file's contents.
+    pub sha256: String,
+    pub chunk_count: u64,
+}
+
+/// The content of a `File` message, sealed to the recipient like any other
+/// message content. The stream key never travels outside it.
+#[derive(Serialize, Deserialize)]
+struct FileOffer {
+    header: FileHeader,
+    stream_key: StreamKey,
+}
+
+/// The content of a `TransferAck` message, sealed to the file's sender.
+#[derive(Serialize, Deserialize)]
+struct TransferAck {
+    transfer_id: String,
+    /// Chunks the recipient has stored, which are always the first ones.
+    received: u64,
+    /// Asks the sender to send everything after `received` again.
+    resume: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransferDirection {
+    Upload,
+    Download,
+}
+
+/// How far a transfer has come, in chunks.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransferProgress {
+    pub transfer_id: String,
+    pub direction: TransferDirection,
+    /// The recipient of an upload, or the sender of a download.
+    pub peer: String,
+    pub name: String,
+    pub size: u64,
+    pub chunk_count: u64,
+    /// Chunks the server accepted, for uploads, or that were stored, for
+    /// downloads.
+    pub chunks_done: u64,
+    /// Chunks the recipient confirmed. The same as `chunks_done` for
+    /// downloads.
+    pub chunks_confirmed: u64,
+}
+
+impl TransferProgress {
+    pub fn bytes_done(&self) -> u64 {
+        (self.chunks_done * CHUNK_SIZE as u64).min(self.size)
+    }
+
+    /// Whether the whole file has arrived.
+    pub fn is_complete(&self) -> bool {
+        self.chunks_confirmed == self.chunk_count
+    }
+}
+
+/// Sends a file as a `File` message followed by `FileChunk` messages.
+///
+/// The `File` message is the offer: the file's name, size, type and hash,
+/// and a `StreamKey`, all sealed to the recipient. The file itself follows
+/// in chunks encrypted as the frames of that stream, each signed by the
+/// sender. The transfer ID is the offer's message ID.
+///
+/// A `FileSender` lives only as long as the connection it sends over; see
+/// `OutgoingFiles` for transfers that survive disconnects.
+pub struct FileSender {
+    offer: Message,
+    header: FileHeader,
+    file: File,
+    stream: StreamEncryptor,
+    next_chunk: u64,
+    identity: Identity,
+}
+
+impl FileSender {
+    /// Reads `path` once to hash it and prepares the offer. The file is read
+    /// again chunk by chunk as it is sent, and must not change in between.
+    pub fn open(
+        path: &Path,
+        sender: &str,
+        recipient: &str,
+        recipient_public_key: &PublicKey,
+        identity: &Identity,
+    ) -> Result<FileSender> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| transfer_error(format!("{} is not a file", path.display())))?
+            .to_owned();
+        let mut file = File::open(path)?;
+        let (size, sha256) = hash_file(&mut file)?;
+        file.seek(SeekFrom::Start(0))?;
+
+        let header = FileHeader {
+            name,
+            size,
+            mime_type: guess_mime_type(path).to_owned(),
+            sha256,
+            chunk_count: chunk_count(size),
+        };
+        let (stream, stream_key) = StreamEncryptor::new()?;
+        let offer = FileOffer {
+            header: header.clone(),
+            stream_key,
+        };
+
+        let mut message = Message {
+            id: Message::new_id(),
+            timestamp: Utc::now(),
+            message_type: MessageType::File,
+            sender: sender.to_owned(),
+            recipient: recipient.to_owned(),
+            content: vec![],
+            public_key: *identity.public_key(),
+            signing_key: *identity.signing_key(),
+            signature: vec![],
+        };
+        message.encrypt_content_bytes(
+            &serde_json::to_vec(&offer)?,
+            recipient_public_key,
+            identity.secret_key(),
+        );
+        message.sign(identity.signing_secret_key());
+
+        Ok(FileSender {
+            offer: message,
+            header,
+            file,
+            stream,
+            next_chunk: 0,
+            identity: identity.clone(),
+        })
+    }
+
+    /// The offer's message ID, which every chunk carries.
+    pub fn transfer_id(&self) -> &str {
+        &self.offer.id
+    }
+
+    pub fn header(&self) -> &FileHeader {
+        &self.header
+    }
+
+    /// The `File` message to send before any chunk.
+    pub fn offer(&self) -> &Message {
+        &self.offer
+    }
+
+    /// Reads and encrypts the next chunk, or returns `None` once the last
+    /// one has been produced.
+    pub fn next_chunk(&mut self) -> Result<Option<Message>> {
+        let index = self.next_chunk;
+        let sealed = match self.seal_next_chunk()? {
+            Some(sealed) => sealed,
+            None => return Ok(None),
+        };
+        chunk_message(&self.offer, index, &sealed, &self.identity).map(Some)
+    }
+
+    /// The next chunk's ciphertext, without the message around it.
+    fn seal_next_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        if self.next_chunk == self.header.chunk_count {
+            return Ok(None);
+        }
+        let index = self.next_chunk;
+        let expected = chunk_len(self.header.size, index);
+        let mut plaintext = Vec::with_capacity(expected);
+        (&mut self.file)
+            .take(expected as u64)
+            .read_to_end(&mut plaintext)?;
+        if plaintext.len() != expected {
+            return Err(transfer_error(format!(
+                "{} changed while it was being sent",
+                self.header.name
+            )));
+        }
+
+        let last = index + 1 == self.header.chunk_count;
+        let prefix = chunk_prefix(&self.offer.id, index)?;
+        let sealed = self.stream.push(&plaintext, Some(&prefix), last)?;
+        self.next_chunk += 1;
+        Ok(Some(sealed))
+    }
+}
+
+/// Sends the offer and every chunk of `file` over `client`, waiting out
+/// rate limits along the way.
+pub async fn send_file(client: &mut Client, file: &mut FileSender) -> Result<()> {
+    send_patiently(client, file.offer()).await?;
+    while let Some(chunk) = file.next_chunk()? {
+        send_patiently(client, &chunk).await?;
+    }
+    Ok(())
+}
+
+async fn send_patiently(client: &mut Client, message: &Message) -> Result<String> {
+    loop {
+        match client.send(message).await {
+            Err(QuietDropError::RateLimited { retry_after }) => {
+                tokio::time::sleep(retry_after).await
+            }
+            result => return result,
+        }
+    }
+}
+
+/// An upload as it is kept on disk, next to the encrypted chunks.
+#[derive(Serialize, Deserialize)]
+struct Upload {
+    version: u8,
+    offer: Message,
+    header: FileHeader,
+    offer_sent: bool,
+    /// Chunks before this one were accepted by the server.
+    next_chunk: u64,
+    /// Chunks the recipient confirmed.
+    confirmed: u64,
+}
+
+impl Upload {
+    fn progress(&self) -> TransferProgress {
+        TransferProgress {
+            transfer_id: self.offer.id.clone(),
+            direction: TransferDirection::Upload,
+            peer: self.offer.recipient.clone(),
+            name: self.header.name.clone(),
+            size: self.header.size,
+            chunk_count: self.header.chunk_count,
+            chunks_done: self.next_chunk,
+            chunks_confirmed: self.confirmed,
+        }
+    }
+}
+
+/// Files being sent, kept on disk until their recipients confirm them.
+///
+/// Starting an upload encrypts the whole file into a spool in the transfer
+/// directory, so the file may change or go away afterwards. Sending picks up
+/// after the last chunk the server accepted. The recipient confirms chunks
+/// as they arrive with `TransferAck` messages, and may ask for everything
+/// after its last stored chunk again, for instance after it crashed; the
+/// upload is finished once every chunk is confirmed.
+///
+/// Resent chunks and offers carry a fresh timestamp and signature, so an
+/// upload can resume after the server's replay window has passed.
+pub struct OutgoingFiles {
+    dir: PathBuf,
+    name: String,
+    identity: Identity,
+    uploads: BTreeMap<String, Upload>,
+}
+
+impl OutgoingFiles {
+    /// Opens the uploads `name` left unfinished in `dir`, which is created
+    /// when the first upload starts.
+    pub fn open(dir: impl Into<PathBuf>, name: &str, identity: &Identity) -> Result<Self> {
+        let dir = dir.into();
+        let mut uploads = BTreeMap::new();
+        for path in state_files(&dir)? {
+            let upload: Upload = serde_json::from_slice(&fs::read(&path)?)?;
+            check_version(&path, upload.version)?;
+            uploads.insert(upload.offer.id.clone(), upload);
+        }
+        Ok(OutgoingFiles {
+            dir,
+            name: name.to_owned(),
+            identity: identity.clone(),
+            uploads,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Encrypts the file at `path` for `recipient` and queues it for
+    /// sending. Returns the transfer ID.
+    pub fn start(
+        &mut self,
+        path: &Path,
+        recipient: &str,
+        recipient_public_key: &PublicKey,
+    ) -> Result<String> {
+        let mut file = FileSender::open(
+            path,
+            &self.name,
+            recipient,
+            recipient_public_key,
+            &self.identity,
+        )?;
+        let transfer_id = file.transfer_id().to_owned();
+        fs::create_dir_all(&self.dir)?;
+
+        let spool_path = spool_path(&self.dir, &transfer_id);
+        let spooled = (|| -> Result<()> {
+            let mut spool = create_private_file(&spool_path)?;
+            while let Some(sealed) = file.seal_next_chunk()? {
+                spool.write_all(&sealed)?;
+            }
+            spool.sync_all()?;
+            Ok(())
+        })();
+        if let Err(e) = spooled {
+            let _ = fs::remove_file(&spool_path);
+            return Err(e);
+        }
+
+        let upload = Upload {
+            version: TRANSFER_FILE_VERSION,
+            offer: file.offer,
+            header: file.header,
+            offer_sent: false,
+            next_chunk: 0,
+            confirmed: 0,
+        };
+        self.save(&upload)?;
+        self.uploads.insert(transfer_id.clone(), upload);
+        Ok(transfer_id)
+    }
+
+    /// Every unfinished upload.
+    pub fn transfers(&self) -> Vec<TransferProgress> {
+        self.uploads.values().map(Upload::progress).collect()
+    }
+
+    /// Whether any upload has chunks the server has not accepted yet.
+    pub fn has_unsent(&self) -> bool {
+        self.uploads
+            .values()
+            .any(|upload| !upload.offer_sent || upload.next_chunk < upload.header.chunk_count)
+    }
+
+    /// Sends whatever the server has not accepted yet, upload by upload,
+    /// waiting out rate limits. `on_progress` is called after every chunk.
+    ///
+    /// Progress is saved as it is made, so an error, such as a lost
+    /// connection, loses nothing; call this again once reconnected.
+    pub async fn send_pending(
+        &mut self,
+        client: &mut Client,
+        mut on_progress: impl FnMut(&TransferProgress),
+    ) -> Result<()> {
+        let transfer_ids: Vec<String> = self.uploads.keys().cloned().collect();
+        for transfer_id in transfer_ids {
+            self.send_upload(client, &transfer_id, &mut on_progress)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn send_upload(
+        &mut self,
+        client: &mut Client,
+        transfer_id: &str,
+        on_progress: &mut impl FnMut(&TransferProgress),
+    ) -> Result<()> {
+        let mut spool = File::open(spool_path(&self.dir, transfer_id))?;
+        loop {
+            let upload = &self.uploads[transfer_id];
+            let message = if !upload.offer_sent {
+                let mut offer = upload.offer.clone();
+                offer.timestamp = Utc::now();
+                offer.sign(self.identity.signing_secret_key());
+                offer
+            } else if upload.next_chunk < upload.header.chunk_count {
+                let sealed = read_sealed_chunk(&mut spool, &upload.header, upload.next_chunk)?;
+                chunk_message(&upload.offer, upload.next_chunk, &sealed, &self.identity)?
+            } else {
+                return Ok(());
+            };
+
+            let message_id = send_patiently(client, &message).await?;
+            if message_id != message.id {
+                return Err(transfer_error(format!(
+                    "The server acknowledged {} instead of {}",
+                    message_id, message.id
+                )));
+            }
+
+            let upload = self.uploads.get_mut(transfer_id).unwrap();
+            if upload.offer_sent {
+                upload.next_chunk += 1;
+            } else {
+                upload.offer_sent = true;
+            }
+            let progress = upload.progress();
+            self.save(&self.uploads[transfer_id])?;
+            on_progress(&progress);
+        }
+    }
+
+    /// Takes in a `TransferAck` from a recipient, finishing the upload once
+    /// every chunk is confirmed. Returns the upload's progress, or `None` if
+    /// it is not one of ours or already finished.
+    pub fn handle(&mut self, message: &Message) -> Result<Option<TransferProgress>> {
+        if message.message_type != MessageType::TransferAck {
+            return Err(transfer_error("Not a transfer acknowledgement"));
+        }
+        let ack: TransferAck =
+            serde_json::from_slice(&message.decrypt_content_bytes(self.identity.secret_key())?)?;
+        let upload = match self.uploads.get_mut(&ack.transfer_id) {
+            Some(upload) => upload,
+            None => return Ok(None),
+        };
+        if message.sender != upload.offer.recipient {
+            return Err(transfer_error(format!(
+                "{} acknowledged a file sent to {}",
+                message.sender, upload.offer.recipient
+            )));
+        }
+        if ack.received > upload.header.chunk_count {
+            return Err(transfer_error("Acknowledged more chunks than were sent"));
+        }
+
+        upload.confirmed = upload.confirmed.max(ack.received);
+        if ack.resume {
+            upload.offer_sent = true;
+            upload.next_chunk = upload.next_chunk.min(ack.received);
+        }
+        let progress = upload.progress();
+        if progress.is_complete() {
+            self.remove(&ack.transfer_id)?;
+        } else {
+            self.save(&self.uploads[&ack.transfer_id])?;
+        }
+        Ok(Some(progress))
+    }
+
+    /// Gives up on an upload. Returns whether there was one to give up.
+    pub fn cancel(&mut self, transfer_id: &str) -> Result<bool> {
+        if !self.uploads.contains_key(transfer_id) {
+            return Ok(false);
+        }
+        self.remove(transfer_id)?;
+        Ok(true)
+    }
+
+    fn remove(&mut self, transfer_id: &str) -> Result<()> {
+        self.uploads.remove(transfer_id);
+        remove_if_present(&spool_path(&self.dir, transfer_id))?;
+        remove_if_present(&state_path(&self.dir, transfer_id))
+    }
+
+    fn save(&self, upload: &Upload) -> Result<()> {
+        let path = state_path(&self.dir, &upload.offer.id);
+        write_private_file(&path, &serde_json::to_vec(upload)?)
+    }
+}
+
+/// A download as it is kept on disk, next to the chunks stored so far.
+///
+/// It holds the stream key, so it is sealed to the recipient's own keys
+/// before it is written.
+#[derive(Serialize, Deserialize)]
+struct Download {
+    version: u8,
+    transfer_id: String,
+    sender: String,
+    /// The sender's encryption key, which acknowledgements are sealed to.
+    public_key: PublicKey,
+    signing_key: SigningPublicKey,
+    header: FileHeader,
+    stream_key: StreamKey,
+    /// Chunks stored so far.
+    next_chunk: u64,
+}
+
+impl Download {
+    fn progress(&self) -> TransferProgress {
+        TransferProgress {
+            transfer_id: self.transfer_id.clone(),
+            direction: TransferDirection::Download,
+            peer: self.sender.clone(),
+            name: self.header.name.clone(),
+            size: self.header.size,
+            chunk_count: self.header.chunk_count,
+            chunks_done: self.next_chunk,
+            chunks_confirmed: self.next_chunk,
+        }
+    }
+
+    /// A fresh stream for this download's chunks.
+    fn stream(&self) -> Result<StreamDecryptor> {
+        StreamDecryptor::new(&self.stream_key)
+    }
+
+    /// Decrypts one chunk, checking it ends the stream if and only if it is
+    /// the last.
+    fn open_chunk(
+        &self,
+        stream: &mut StreamDecryptor,
+        index: u64,
+        sealed: &[u8],
+    ) -> Result<Vec<u8>> {
+        let prefix = chunk_prefix(&self.transfer_id, index)?;
+        let (plaintext, last) = stream
+            .pull(sealed, Some(&prefix))
+            .map_err(|_| transfer_error(format!("Chunk {} failed to decrypt", index)))?;
+        if last != (index + 1 == self.header.chunk_count) {
+            return Err(transfer_error("The stream ended at the wrong chunk"));
+        }
+        Ok(plaintext)
+    }
+}
+
+/// What a chunk did to its download.
+enum ChunkOutcome {
+    /// The chunk was stored already.
+    Duplicate,
+    /// Chunks before it are missing.
+    Gap,
+    Stored,
+    Complete(PathBuf),
+}
+
+/// Receives one file into a directory.
+///
+/// Chunks are checked and stored, still encrypted, in a hidden spool as they
+/// arrive, with the download's state next to it, so a download picks up
+/// where it left off after a restart. Once the final chunk is in, the file
+/// is decrypted, its size and hash are checked against the offer, and only
+/// then is it moved to its real name.
+struct FileReceiver {
+    download: Download,
+    /// Positioned after the last stored chunk.
+    stream: StreamDecryptor,
+    spool: File,
+    dir: PathBuf,
+}
+
+impl FileReceiver {
+    /// Opens the offer in a `File` message and prepares to write the file
+    /// into `dir`, which is created if needed.
+    fn accept(offer: &Message, identity: &Identity, dir: &Path) -> Result<FileReceiver> {
+        let transfer_id = parse_transfer_id(&offer.id)?;
+        let FileOffer { header, stream_key } =
+            serde_json::from_slice(&offer.decrypt_content_bytes(identity.secret_key())?)?;
+        if header.chunk_count != chunk_count(header.size) {
+            return Err(transfer_error(
+                "The offer's chunk count does not fit its size",
+            ));
+        }
+        let download = Download {
+            version: TRANSFER_FILE_VERSION,
+            transfer_id,
+            sender: offer.sender.clone(),
+            public_key: offer.public_key,
+            signing_key: offer.signing_key,
+            header,
+            stream_key,
+            next_chunk: 0,
+        };
+        let stream = download.stream()?;
+
+        fs::create_dir_all(dir)?;
+        let spool = create_private_file(&spool_path(dir, &download.transfer_id))?;
+        let receiver = FileReceiver {
+            download,
+            stream,
+            spool,
+            dir: dir.to_owned(),
+        };
+        receiver.save(identity)?;
+        Ok(receiver)
+    }
+
+    /// Picks up a download from its saved state, checking the chunks stored
+    /// so far on the way.
+    fn resume(download: Download, dir: &Path) -> Result<FileReceiver> {
+        let mut spool = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(spool_path(dir, &download.transfer_id))?;
+        // Anything past the last saved chunk was cut off mid-write
+        spool.set_len(spool_offset(download.next_chunk))?;
+        let mut stream = download.stream()?;
+        for index in 0..download.next_chunk {
+            let sealed = read_sealed_chunk(&mut spool, &download.header, index)?;
+            download.open_chunk(&mut stream, index, &sealed)?;
+        }
+        spool.seek(SeekFrom::End(0))?;
+        Ok(FileReceiver {
+            download,
+            stream,
+            spool,
+            dir: dir.to_owned(),
+        })
+    }
+
+    /// Checks and stores a chunk, ignoring ones that are already stored or
+    /// that arrive ahead of missing ones. Once the last chunk is in and the
+    /// file has been verified, moves it into place and returns its path.
+    ///
+    /// The file is saved under the name from the offer, stripped of any
+    /// directories, with a number added if that name is taken.
+    fn receive(&mut self, chunk: &Message, identity: &Identity) -> Result<ChunkOutcome> {
+        let download = &self.download;
+        let index = chunk_index(chunk).ok_or_else(|| transfer_error("Malformed file chunk"))?;
+        if index < download.next_chunk {
+            return Ok(ChunkOutcome::Duplicate);
+        }
+        if index > download.next_chunk {
+            return Ok(ChunkOutcome::Gap);
+        }
+        if index >= download.header.chunk_count {
+            return Err(transfer_error("Chunk is past the end of the file"));
+        }
+
+        let sealed = &chunk.content[CHUNK_NONCE_LEN + CHUNK_PREFIX_LEN..];
+        if sealed.len() != sealed_chunk_len(&download.header, index) {
+            return Err(transfer_error(format!(
+                "Chunk {} has the wrong size",
+                index
+            )));
+        }
+        download.open_chunk(&mut self.stream, index, sealed)?;
+        self.spool.write_all(sealed)?;
+        self.spool.sync_data()?;
+        self.download.next_chunk += 1;
+
+        if self.download.next_chunk == self.download.header.chunk_count {
+            return self.finish().map(ChunkOutcome::Complete);
+        }
+        self.save(identity)?;
+        Ok(ChunkOutcome::Stored)
+    }
+
+    /// Whether `chunk` comes from the same sender and signing key as the
+    /// offer. The caller is expected to have checked the offer's signing key
+    /// against the sender's published one.
+    fn is_from_sender(&self, chunk: &Message) -> bool {
+        chunk.sender == self.download.sender && chunk.signing_key == self.download.signing_key
+    }
+
+    /// A signed acknowledgement of the chunks stored so far, for the sender.
+    fn ack(&self, name: &str, identity: &Identity, resume: bool) -> Result<Message> {
+        let ack = TransferAck {
+            transfer_id: self.download.transfer_id.clone(),
+            received: self.download.next_chunk,
+            resume,
+        };
+        let mut message = Message {
+            id: Message::new_id(),
+            timestamp: Utc::now(),
+            message_type: MessageType::TransferAck,
+            sender: name.to_owned(),
+            recipient: self.download.sender.clone(),
+            content: vec![],
+            public_key: *identity.public_key(),
+            signing_key: *identity.signing_key(),
+            signature: vec![],
+        };
+        message.encrypt_content_bytes(
+            &serde_json::to_vec(&ack)?,
+            &self.download.public_key,
+            identity.secret_key(),
+        );
+        message.sign(identity.signing_secret_key());
+        Ok(message)
+    }
+
+    /// Gives up on the download and removes everything it stored.
+    fn abort(self) {
+        let transfer_id = self.download.transfer_id;
+        drop(self.spool);
+        let _ = fs::remove_file(spool_path(&self.dir, &transfer_id));
+        let _ = fs::remove_file(state_path(&self.dir, &transfer_id));
+        let _ = fs::remove_file(output_path(&self.dir, &transfer_id));
+    }
+
+    fn finish(&mut self) -> Result<PathBuf> {
+        let download = &self.download;
+        let output = output_path(&self.dir, &download.transfer_id);
+        let mut file = create_private_file(&output)?;
+        let mut stream = download.stream()?;
+        let mut hasher = sha256::State::new();
+        let mut size = 0;
+        for index in 0..download.header.chunk_count {
+            let sealed = read_sealed_chunk(&mut self.spool, &download.header, index)?;
+            let plaintext = download.open_chunk(&mut stream, index, &sealed)?;
+            hasher.update(&plaintext);
+            size += plaintext.len() as u64;
+            file.write_all(&plaintext)?;
+        }
+        if size != download.header.size || hex(hasher.finalize().as_ref()) != download.header.sha256
+        {
+            return Err(transfer_error(format!(
+                "{} does not match the size and hash it was sent with",
+                download.header.name
+            )));
+        }
+        file.sync_all()?;
+
+        let path = unused_path(&self.dir, &safe_file_name(&download.header.name))?;
+        fs::rename(&output, &path)?;
+        remove_if_present(&spool_path(&self.dir, &download.transfer_id))?;
+        remove_if_present(&state_path(&self.dir, &download.transfer_id))?;
+        Ok(path)
+    }
+
+    fn save(&self, identity: &Identity) -> Result<()> {
+        let path = state_path(&self.dir, &self.download.transfer_id);
+        let sealed = encrypt_bytes(
+            &serde_json::to_vec(&self.download)?,
+            identity.public_key(),
+            identity.secret_key(),
+        );
+        write_private_file(&path, &sealed)
+    }
+}
+
+/// A file that arrived completely and intact.
+#[derive(Debug, Clone)]
+pub struct ReceivedFile {
+    pub transfer_id: String,
+    pub sender: String,
+    pub header: FileHeader,
+    pub path: PathBuf,
+}
+
+/// Every file being received by one identity, keyed by transfer ID.
+///
+/// Downloads are kept in the download directory until they complete, so
+/// they survive disconnects and restarts. Along the way this collects the
+/// `TransferAck` messages owed to senders, which the caller sends with
+/// `take_replies`, and progress updates, which it collects with
+/// `take_progress`.
+pub struct IncomingFiles {
+    dir: PathBuf,
+    name: String,
+    identity: Identity,
+    transfers: HashMap<String, FileReceiver>,
+    /// Where each transfer last asked its sender to resume from, so a gap
+    /// is only reported once.
+    requested: HashMap<String, u64>,
+    replies: Vec<Message>,
+    progress: Vec<TransferProgress>,
+}
+
+impl IncomingFiles {
+    /// Received files are saved in `dir`. Downloads `name` left unfinished
+    /// there are picked up again.
+    pub fn open(dir: impl Into<PathBuf>, name: &str, identity: &Identity) -> Result<Self> {
+        let dir = dir.into();
+        let mut transfers = HashMap::new();
+        for path in state_files(&dir)? {
+            let json = decrypt_bytes(
+                &fs::read(&path)?,
+                identity.public_key(),
+                identity.secret_key(),
+            )?;
+            let download: Download = serde_json::from_slice(&json)?;
+            check_version(&path, download.version)?;
+            let transfer_id = parse_transfer_id(&download.transfer_id)?;
+            transfers.insert(transfer_id, FileReceiver::resume(download, &dir)?);
+        }
+        Ok(IncomingFiles {
+            dir,
+            name: name.to_owned(),
+            identity: identity.clone(),
+            transfers,
+            requested: HashMap::new(),
+            replies: Vec::new(),
+            progress: Vec::new(),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Starts a download for a `File` offer or adds a `FileChunk` to one.
+    /// Returns the file once its last chunk is in.
+    ///
+    /// Offers for downloads already under way are ignored, as are chunks
+    /// already stored. A chunk that arrives after missing ones is dropped
+    /// and the sender is asked to resume from the first missing chunk. A
+    /// chunk from the sender that fails its checks aborts the download.
+    /// Chunks of unknown transfers, chunks from anyone else and messages of
+    /// other types are errors.
+    pub fn handle(&mut self, message: &Message) -> Result<Option<ReceivedFile>> {
+        match message.message_type {
+            MessageType::File => {
+                let transfer_id = parse_transfer_id(&message.id)?;
+                if !self.transfers.contains_key(&transfer_id) {
+                    let receiver = FileReceiver::accept(message, &self.identity, &self.dir)?;
+                    self.progress.push(receiver.download.progress());
+                    self.transfers.insert(transfer_id, receiver);
+                }
+                Ok(None)
+            }
+            MessageType::FileChunk => {
+                let transfer_id = chunk_transfer_id(message)
+                    .ok_or_else(|| transfer_error("Malformed file chunk"))?;
+                let receiver = self.transfers.get_mut(&transfer_id).ok_or_else(|| {
+                    transfer_error(format!("Chunk for unknown transfer {}", transfer_id))
+                })?;
+                if !receiver.is_from_sender(message) {
+                    return Err(transfer_error("Chunk does not belong to this transfer"));
+                }
+                let stored = match receiver.receive(message, &self.identity) {
+                    Ok(stored) => stored,
+                    Err(e @ QuietDropError::Transfer(_)) => {
+                        self.requested.remove(&transfer_id);
+                        self.transfers.remove(&transfer_id).unwrap().abort();
+                        return Err(e);
+                    }
+                    Err(e) => return Err(e),
+                };
+
+                match stored {
+                    ChunkOutcome::Duplicate => Ok(None),
+                    ChunkOutcome::Gap => {
+                        let next_chunk = receiver.download.next_chunk;
+                        if self.requested.get(&transfer_id) != Some(&next_chunk) {
+                            let ack = receiver.ack(&self.name, &self.identity, true)?;
+                            self.replies.push(ack);
+                            self.requested.insert(transfer_id, next_chunk);
+                        }
+                        Ok(None)
+                    }
+                    ChunkOutcome::Stored => {
+                        let download = &receiver.download;
+                        self.progress.push(download.progress());
+                        if download.next_chunk % ACK_INTERVAL == 0 {
+                            let ack = receiver.ack(&self.name, &self.identity, false)?;
+                            self.replies.push(ack);
+                        }
+                        Ok(None)
+                    }
+                    ChunkOutcome::Complete(path) => {
+                        self.requested.remove(&transfer_id);
+                        let receiver = self.transfers.remove(&transfer_id).unwrap();
+                        self.progress.push(receiver.download.progress());
+                        self.replies
+                            .push(receiver.ack(&self.name, &self.identity, false)?);
+                        Ok(Some(ReceivedFile {
+                            transfer_id,
+                            sender: receiver.download.sender,
+                            header: receiver.download.header,
+                            path,
+                        }))
+                    }
+                }
+            }
+            _ => Err(transfer_error("Not part of a file transfer")),
+        }
+    }
+
+    /// Every unfinished download.
+    pub fn transfers(&self) -> Vec<TransferProgress> {
+        self.transfers
+            .values()
+            .map(|receiver| receiver.download.progress())
+            .collect()
+    }
+
+    /// Asks the sender of every unfinished download to resend what is
+    /// missing, for after a reconnect. The requests are sent with the other
+    /// replies.
+    pub fn request_resume(&mut self) -> Result<()> {
+        for (transfer_id, receiver) in &self.transfers {
+            self.replies
+                .push(receiver.ack(&self.name, &self.identity, true)?);
+            self.requested
+                .insert(transfer_id.clone(), receiver.download.next_chunk);
+        }
+        Ok(())
+    }
+
+    /// The `TransferAck` messages owed to senders since the last call.
+    pub fn take_replies(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.replies)
+    }
+
+    /// Progress made since the last call, oldest first.
+    pub fn take_progress(&mut self) -> Vec<TransferProgress> {
+        std::mem::take(&mut self.progress)
+    }
+
+    /// Gives up on a download. Returns whether there was one to give up.
+    pub fn cancel(&mut self, transfer_id: &str) -> bool {
+        self.requested.remove(transfer_id);
+        match self.transfers.remove(transfer_id) {
+            Some(receiver) => {
+                receiver.abort();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// The transfer a `FileChunk` message belongs to.
+pub fn chunk_transfer_id(message: &Message) -> Option<String> {
+    if message.message_type != MessageType::FileChunk
+        || message.content.len() < CHUNK_NONCE_LEN + CHUNK_PREFIX_LEN
+    {
+        return None;
+    }
+    Uuid::from_slice(&message.content[CHUNK_NONCE_LEN..CHUNK_NONCE_LEN + 16])
+        .ok()
+        .map(|id| id.to_string())
+}
+
+fn chunk_index(message: &Message) -> Option<u64> {
+    let start = CHUNK_NONCE_LEN + 16;
+    let bytes = message.content.get(start..start + 8)?;
+    Some(u64::from_be_bytes(bytes.try_into().ok()?))
+}
+
+/// A MIME type for `path` based on its extension.
+pub fn guess_mime_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("txt") => "text/plain",
+        Some("md") => "text/markdown",
+        Some("html" | "htm") => "text/html",
+        Some("csv") => "text/csv",
+        Some("json") => "application/json",
+        Some("pdf") => "application/pdf",
+        Some("zip") => "application/zip",
+        Some("gz") => "application/gzip",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("svg") => "image/svg+xml",
+        Some("mp3") => "audio/mpeg",
+        Some("wav") => "audio/wav",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+/// A signed `FileChunk` message carrying the `index`th chunk of the file
+/// `offer` offered. Every call gives the chunk a new nonce.
+fn chunk_message(
+    offer: &Message,
+    index: u64,
+    sealed: &[u8],
+    identity: &Identity,
+) -> Result<Message> {
+    let prefix = chunk_prefix(&offer.id, index)?;
+    let mut message = Message {
+        id: Message::new_id(),
+        timestamp: Utc::now(),
+        message_type: MessageType::FileChunk,
+        sender: offer.sender.clone(),
+        recipient: offer.recipient.clone(),
+        content: [&randombytes(CHUNK_NONCE_LEN)[..], &prefix, sealed].concat(),
+        public_key: *identity.public_key(),
+        signing_key: *identity.signing_key(),
+        signature: vec![],
+    };
+    message.sign(identity.signing_secret_key());
+    Ok(message)
+}
+
+/// Reads the `index`th chunk's ciphertext from a spool of chunks.
+fn read_sealed_chunk(spool: &mut File, header: &FileHeader, index: u64) -> Result<Vec<u8>> {
+    let mut sealed = vec![0; sealed_chunk_len(header, index)];
+    spool.seek(SeekFrom::Start(spool_offset(index)))?;
+    spool.read_exact(&mut sealed)?;
+    Ok(sealed)
+}
+
+fn hash_file(file: &mut File) -> Result<(u64, String)> {
+    let mut hasher = sha256::State::new();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut size = 0;
+    loop {
+        let read = match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        hasher.update(&buffer[..read]);
+        size += read as u64;
+    }
+    Ok((size, hex(hasher.finalize().as_ref())))
+}
+
+/// Every file has at least one chunk, so an empty file still ends its stream.
+fn chunk_count(size: u64) -> u64 {
+    size.div_ceil(CHUNK_SIZE as u64).max(1)
+}
+
+fn chunk_len(size: u64, index: u64) -> usize {
+    let start = index * CHUNK_SIZE as u64;
+    size.saturating_sub(start).min(CHUNK_SIZE as u64) as usize
+}
+
+fn sealed_chunk_len(header: &FileHeader, index: u64) -> usize {
+    chunk_len(header.size, index) + STREAM_FRAME_OVERHEAD
+}
+
+/// Where the `index`th chunk starts in a spool. Only the last chunk is
+/// short, so this is the same for every transfer.
+fn spool_offset(index: u64) -> u64 {
+    index * SEALED_CHUNK_SIZE
+}
+
+/// Also the chunk's additional data, so a chunk cannot be moved to another
+/// transfer or position.
+fn chunk_prefix(transfer_id: &str, index: u64) -> Result<Vec<u8>> {
+    let transfer_id =
+        Uuid::parse_str(transfer_id).map_err(|_| transfer_error("Transfer IDs must be UUIDs"))?;
+    Ok([transfer_id.as_bytes().as_slice(), &index.to_be_bytes()].concat())
+}
+
+/// A transfer ID from a message in its canonical form. Transfer IDs name
+/// files in the download directory, so anything but a UUID is refused.
+fn parse_transfer_id(transfer_id: &str) -> Result<String> {
+    Uuid::parse_str(transfer_id)
+        .map(|id| id.to_string())
+        .map_err(|_| transfer_error("Transfer IDs must be UUIDs"))
+}
+
+/// Hidden, so a download's state and spool do not look like received files.
+fn state_path(dir: &Path, transfer_id: &str) -> PathBuf {
+    dir.join(format!(".{}.json", transfer_id))
+}
+
+fn spool_path(dir: &Path, transfer_id: &str) -> PathBuf {
+    dir.join(format!(".{}.part", transfer_id))
+}
+
+/// Where a download is decrypted before it is checked and renamed.
+fn output_path(dir: &Path, transfer_id: &str) -> PathBuf {
+    dir.join(format!(".{}.out", transfer_id))
+}
+
+/// The transfer state files in `dir`, which need not exist. Other files in
+/// a download directory are left alone.
+fn state_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut paths = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let transfer_id = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix('.')?.strip_suffix(".json"));
+        if transfer_id.is_some_and(|id| Uuid::parse_str(id).is_ok()) {
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+fn check_version(path: &Path, version: u8) -> Result<()> {
+    if version != TRANSFER_FILE_VERSION {
+        return Err(transfer_error(format!(
+            "{} has unsupported version {}",
+            path.display(),
+            version
+        )));
+    }
+    Ok(())
+}
+
+fn create_private_file(path: &Path) -> Result<File> {
+    let mut options = OpenOptions::new();
+    options.read(true).write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    Ok(options.open(path)?)
+}
+
+fn remove_if_present(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Strips directories and anything else a sender could use to write outside
+/// the download directory.
+fn safe_file_name(name: &str) -> String {
+    let name = name
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .trim_start_matches('.');
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+    if name.is_empty() {
+        "received-file".to_owned()
+    } else {
+        name
+    }
+}
+
+/// `dir/name`, or `dir/name (n)` with the first free `n`.
+fn unused_path(dir: &Path, name: &str) -> Result<PathBuf> {
+    let candidate = dir.join(name);
+    if !candidate.exists() {
+        return Ok(candidate);
+    }
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (name, String::new()),
+    };
+    (1..1000)
+        .map(|n| dir.join(format!("{} ({}){}", stem, n, extension)))
+        .find(|candidate| !candidate.exists())
+        .ok_or_else(|| transfer_error(format!("Too many files named {}", name)))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn transfer_error(message: impl Into<String>) -> QuietDropError {
+    QuietDropError::Transfer(message.into())
+}
@@ -0,0 +1,220 @@
+use crate::encryption::{PublicKey, SecretKey};
+use crate::error::{QuietDropError, Result};
+use crate::framing::{read_frame, write_frame};
+use sodiumoxide::crypto::{box_, generichash, kx, secretbox};
+use std::io::{Error, ErrorKind};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Mixed into every derived key and exchanged as proof that both sides
+/// derived the same keys.
+const TRANSPORT_CONTEXT: &[u8] = b"quietdrop-transport-v1";
+
+/// Largest handshake message either side will read.
+const MAX_HANDSHAKE_SIZE: usize = 256;
+
+/// Keys and counters for one encrypted, authenticated connection.
+///
+/// Every frame is sealed with `secretbox` under a per-direction key, using a
+/// counter as the nonce. Frames that are forged, replayed, dropped or
+/// reordered fail to open.
+pub struct SecureChannel {
+    send_key: secretbox::Key,
+    recv_key: secretbox::Key,
+    sent: u64,
+    received: u64,
+}
+
+impl SecureChannel {
+    fn new(send_key: secretbox::Key, recv_key: secretbox::Key) -> Self {
+        SecureChannel {
+            send_key,
+            recv_key,
+            sent: 0,
+            received: 0,
+        }
+    }
+
+    /// Encrypts the next outgoing frame.
+    pub fn seal(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = counter_nonce(self.sent);
+        self.sent += 1;
+        secretbox::seal(plaintext, &nonce, &self.send_key)
+    }
+
+    /// Decrypts the next incoming frame.
+    pub fn open(&mut self, ciphertext: &[u8]) -> std::io::Result<Vec<u8>> {
+        let nonce = counter_nonce(self.received);
+        let plaintext = secretbox::open(ciphertext, &nonce, &self.recv_key).map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidData,
+                "Frame failed to decrypt; it was forged, replayed or reordered",
+            )
+        })?;
+        self.received += 1;
+        Ok(plaintext)
+    }
+}
+
+/// Runs the client side of the transport handshake.
+///
+/// `server_public_key` is the pinned key from `server_public_key.key`; the
+/// handshake only completes if the peer holds the matching secret key.
+/// `secret_key` is the client's own static key, which the server learns and
+/// authenticates. It is encrypted on the wire, so a passive observer cannot
+/// tell who is connecting.
+///
+/// Session keys combine three `kx` exchanges: client ephemeral with server
+/// static, client ephemeral with server ephemeral, and client static with
+/// server ephemeral.
+pub async fn connect<S>(
+    stream: &mut S,
+    server_public_key: &PublicKey,
+    secret_key: &SecretKey,
+) -> Result<SecureChannel>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let server_static = kx_public_key(server_public_key);
+    let (static_public, static_secret) = kx_keypair(secret_key);
+    let (ephemeral_public, ephemeral_secret) = kx::gen_keypair();
+
+    let (rx1, tx1) = kx::client_session_keys(&ephemeral_public, &ephemeral_secret, &server_static)
+        .map_err(|_| handshake_error("Pinned server key is not usable"))?;
+
+    let mut hello = ephemeral_public.0.to_vec();
+    hello.extend(secretbox::seal(
+        &static_public.0,
+        &counter_nonce(0),
+        &derive_key(&[&tx1]),
+    ));
+    write_frame(stream, &hello).await?;
+
+    let reply = read_handshake(stream).await.map_err(|e| match e {
+        QuietDropError::ConnectionClosed => handshake_error(
+            "Server hung up during the handshake; the pinned server key may be out of date",
+        ),
+        e => e,
+    })?;
+    let server_ephemeral = read_public_key(&reply)?;
+    let (rx2, tx2) =
+        kx::client_session_keys(&ephemeral_public, &ephemeral_secret, &server_ephemeral)
+            .map_err(|_| handshake_error("Server sent an unusable ephemeral key"))?;
+    let (rx3, tx3) = kx::client_session_keys(&static_public, &static_secret, &server_ephemeral)
+        .map_err(|_| handshake_error("Server sent an unusable ephemeral key"))?;
+
+    let mut channel = SecureChannel::new(
+        derive_key(&[&tx1, &tx2, &tx3]),
+        derive_key(&[&rx1, &rx2, &rx3]),
+    );
+    match channel.open(&reply[kx::PUBLICKEYBYTES..]) {
+        Ok(proof) if proof == TRANSPORT_CONTEXT => {}
+        _ => {
+            return Err(handshake_error(
+                "Server does not hold the pinned server key",
+            ))
+        }
+    }
+
+    write_frame(stream, &channel.seal(TRANSPORT_CONTEXT)).await?;
+    Ok(channel)
+}
+
+/// Runs the server side of the transport handshake.
+///
+/// Returns the channel and the static public key the client proved it holds.
+pub async fn accept<S>(
+    stream: &mut S,
+    server_secret_key: &SecretKey,
+) -> Result<(SecureChannel, PublicKey)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (static_public, static_secret) = kx_keypair(server_secret_key);
+
+    let hello = read_handshake(stream).await?;
+    let client_ephemeral = read_public_key(&hello)?;
+    let (rx1, tx1) = kx::server_session_keys(&static_public, &static_secret, &client_ephemeral)
+        .map_err(|_| handshake_error("Client sent an unusable ephemeral key"))?;
+    let client_static = secretbox::open(
+        &hello[kx::PUBLICKEYBYTES..],
+        &counter_nonce(0),
+        &derive_key(&[&rx1]),
+    )
+    .ok()
+    .and_then(|bytes| kx::PublicKey::from_slice(&bytes))
+    .ok_or_else(|| handshake_error("Client did not encrypt to this server's key"))?;
+
+    let (ephemeral_public, ephemeral_secret) = kx::gen_keypair();
+    let (rx2, tx2) =
+        kx::server_session_keys(&ephemeral_public, &ephemeral_secret, &client_ephemeral)
+            .map_err(|_| handshake_error("Client sent an unusable ephemeral key"))?;
+    let (rx3, tx3) = kx::server_session_keys(&ephemeral_public, &ephemeral_secret, &client_static)
+        .map_err(|_| handshake_error("Client sent an unusable static key"))?;
+
+    let mut channel = SecureChannel::new(
+        derive_key(&[&tx1, &tx2, &tx3]),
+        derive_key(&[&rx1, &rx2, &rx3]),
+    );
+    let mut reply = ephemeral_public.0.to_vec();
+    reply.extend(channel.seal(TRANSPORT_CONTEXT));
+    write_frame(stream, &reply).await?;
+
+    let confirmation = read_handshake(stream).await?;
+    match channel.open(&confirmation) {
+        Ok(proof) if proof == TRANSPORT_CONTEXT => {}
+        _ => return Err(handshake_error("Client does not hold the key it presented")),
+    }
+
+    Ok((channel, box_::PublicKey(client_static.0)))
+}
+
+fn handshake_error(reason: &str) -> QuietDropError {
+    QuietDropError::Handshake(reason.to_owned())
+}
+
+async fn read_handshake<S>(stream: &mut S) -> Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    read_frame(stream, MAX_HANDSHAKE_SIZE)
+        .await?
+        .ok_or(QuietDropError::ConnectionClosed)
+}
+
+/// Reads the public key at the front of a handshake message.
+fn read_public_key(message: &[u8]) -> Result<kx::PublicKey> {
+    message
+        .get(..kx::PUBLICKEYBYTES)
+        .and_then(kx::PublicKey::from_slice)
+        .ok_or_else(|| handshake_error("Handshake message is too short"))
+}
+
+/// `box_` and `kx` keys are both X25519 keys, so identity and server keys can
+/// take part in the key exchange directly.
+fn kx_public_key(public_key: &PublicKey) -> kx::PublicKey {
+    kx::PublicKey(public_key.0)
+}
+
+fn kx_keypair(secret_key: &SecretKey) -> (kx::PublicKey, kx::SecretKey) {
+    (
+        kx_public_key(&secret_key.public_key()),
+        kx::SecretKey(secret_key.0),
+    )
+}
+
+fn derive_key(session_keys: &[&kx::SessionKey]) -> secretbox::Key {
+    let mut state = generichash::State::new(Some(secretbox::KEYBYTES), None)
+        .expect("secretbox keys are a valid digest length");
+    state.update(TRANSPORT_CONTEXT).unwrap();
+    for key in session_keys {
+        state.update(&key.0).unwrap();
+    }
+    let digest = state.finalize().unwrap();
+    secretbox::Key::from_slice(digest.as_ref()).expect("digest has the length of a key")
+}
+
+fn counter_nonce(counter: u64) -> secretbox::Nonce {
+    let mut nonce = [0u8; secretbox::NONCEBYTES];
+    nonce[..8].copy_from_slice(&counter.to_be_bytes());
+    secretbox::Nonce(nonce)
+}
@@ -0,0 +1,415 @@
+use chrono::Utc;
+use quietdrop_core::accounts::Accounts;
+use quietdrop_core::authentication::{
+    hash_password_with, needs_rehash, Credential, CredentialStore, MemoryCredentialStore,
+    PasswordPolicy,
+};
+use quietdrop_core::client::Client;
+use quietdrop_core::encryption::{generate_keypair, Identity, PublicKey};
+use quietdrop_core::error::QuietDropError;
+use quietdrop_core::message::{Message, MessageType};
+use quietdrop_core::protocol::ErrorCode;
+use quietdrop_core::server::{self, ServerConfig};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+const PASSWORD: &str = "correct horse battery";
+
+async fn start_server() -> (String, PublicKey) {
+    quietdrop_core::initialize();
+
+    let (server_public_key, server_secret_key) = generate_keypair();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+    tokio::spawn(async move {
+        let _ = server::serve(listener, &server_secret_key).await;
+    });
+
+    (addr, server_public_key)
+}
+
+fn signed_message(sender: &str, identity: &Identity) -> Message {
+    let (recipient_public_key, _) = generate_keypair();
+    let mut msg = Message {
+        id: Message::new_id(),
+        timestamp: Utc::now(),
+        message_type: MessageType::Text,
+        sender: sender.to_owned(),
+        recipient: "Bob".to_owned(),
+        content: vec![],
+        public_key: *identity.public_key(),
+        signing_key: *identity.signing_key(),
+        signature: vec![],
+    };
+    msg.encrypt_content("Hello, Bob", &recipient_public_key, identity.secret_key());
+    msg.sign(identity.signing_secret_key());
+    msg
+}
+
+fn assert_server_error(err: QuietDropError, expected: ErrorCode) {
+    match err {
+        QuietDropError::Server { code, .. } => assert_eq!(code, expected),
+        other => panic!("Expected a {} error, got {}", expected, other),
+    }
+}
+
+#[test]
+fn test_accounts_store_only_hashes() {
+    quietdrop_core::initialize();
+
+    let accounts = Accounts::new(
+        Arc::new(MemoryCredentialStore::default()),
+        PasswordPolicy::default(),
+    )
+    .unwrap();
+    assert!(accounts.register("Alice", PASSWORD).unwrap().is_some());
+    assert!(accounts
+        .register("Alice", "another password")
+        .unwrap()
+        .is_none());
+    assert!(accounts.contains("Alice").unwrap());
+
+    assert!(accounts.verify("Alice", PASSWORD).unwrap());
+    assert!(!accounts.verify("Alice", "another password").unwrap());
+    assert!(!accounts.verify("Bob", PASSWORD).unwrap());
+}
+
+#[test]
+fn test_accounts_rehash_outdated_hashes_on_login() {
+    quietdrop_core::initialize();
+
+    let old_policy = PasswordPolicy::new(4096, 3, 1);
+    let store = Arc::new(MemoryCredentialStore::default());
+    let (old_hash, _) = hash_password_with(PASSWORD, &old_policy).unwrap();
+    store.insert("Alice", &Credential::new(&old_hash)).unwrap();
+    let accounts = Accounts::new(store.clone(), PasswordPolicy::default()).unwrap();
+
+    // A wrong password leaves the hash alone
+    assert!(!accounts.verify("Alice", "not the password").unwrap());
+    assert_eq!(store.get("Alice").unwrap().unwrap().password_hash, old_hash);
+
+    assert!(accounts.verify("Alice", PASSWORD).unwrap());
+    let upgraded = store.get("Alice").unwrap().unwrap().password_hash;
+    assert_ne!(upgraded, old_hash);
+    assert!(!needs_rehash(&upgraded, &PasswordPolicy::default()).unwrap());
+    assert!(accounts.verify("Alice", PASSWORD).unwrap());
+}
+
+#[tokio::test]
+async fn test_server_applies_configured_password_policy() {
+    quietdrop_core::initialize();
+
+    let policy = PasswordPolicy::new(8 * 1024, 2, 1);
+    let store = Arc::new(MemoryCredentialStore::default());
+    let (server_public_key, server_secret_key) = generate_keypair();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let config = ServerConfig {
+        credentials: Some(store.clone()),
+        password_policy: policy,
+        ..ServerConfig::default()
+    };
+    tokio::spawn(async move {
+        let _ = server::serve_with_config(listener, &server_secret_key, config).await;
+    });
+
+    let mut client = Client::connect(&addr, &server_public_key, &Identity::generate())
+        .await
+        .unwrap();
+    client.register("Alice", PASSWORD).await.unwrap();
+    let stored = store.get("Alice").unwrap().unwrap().password_hash;
+    assert!(stored.contains("m=8192,t=2,p=1"), "{}", stored);
+    assert!(!needs_rehash(&stored, &policy).unwrap());
+}
+
+#[tokio::test]
+async fn test_register_then_login() {
+    let (addr, server_public_key) = start_server().await;
+    let identity = Identity::generate();
+
+    let mut first = Client::connect(&addr, &server_public_key, &identity)
+        .await
+        .unwrap();
+    first.register("Alice", PASSWORD).await.unwrap();
+    first.identify("Alice", &identity).await.unwrap();
+    first.close().await.unwrap();
+
+    let mut second = Client::connect(&addr, &server_public_key, &identity)
+        .await
+        .unwrap();
+    second.login("Alice", PASSWORD).await.unwrap();
+    second.identify("Alice", &identity).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_register_rejects_taken_name_and_weak_password() {
+    let (addr, server_public_key) = start_server().await;
+    let identity = Identity::generate();
+
+    let mut client = Client::connect(&addr, &server_public_key, &identity)
+        .await
+        .unwrap();
+    let err = client.register("Alice", "short").await.unwrap_err();
+    assert_server_error(err, ErrorCode::WeakPassword);
+
+    client.register("Alice", PASSWORD).await.unwrap();
+    let mut other = Client::connect(&addr, &server_public_key, &Identity::generate())
+        .await
+        .unwrap();
+    let err = other.register("Alice", PASSWORD).await.unwrap_err();
+    assert_server_error(err, ErrorCode::NameTaken);
+}
+
+#[tokio::test]
+async fn test_register_rejects_names_clients_cannot_store() {
+    let (addr, server_public_key) = start_server().await;
+    let identity = Identity::generate();
+
+    let mut client = Client::connect(&addr, &server_public_key, &identity)
+        .await
+        .unwrap();
+    for name in ["", "/", "../Alice", "Alice\\Bob", ".Alice"] {
+        let err = client.register(name, PASSWORD).await.unwrap_err();
+        assert_server_error(err, ErrorCode::InvalidName);
+    }
+    client.register("Alice", PASSWORD).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_login_rejects_wrong_password_and_unknown_name() {
+    let (addr, server_public_key) = start_server().await;
+    let identity = Identity::generate();
+
+    let mut client = Client::connect(&addr, &server_public_key, &identity)
+        .await
+        .unwrap();
+    client.register("Alice", PASSWORD).await.unwrap();
+
+    let mut other = Client::connect(&addr, &server_public_key, &identity)
+        .await
+        .unwrap();
+    let err = other.login("Alice", "not the password").await.unwrap_err();
+    assert_server_error(err, ErrorCode::InvalidCredentials);
+    let err = other.login("Nobody", PASSWORD).await.unwrap_err();
+    assert_server_error(err, ErrorCode::InvalidCredentials);
+
+    // A failed login leaves the connection logged out
+    let err = other.identify("Alice", &identity).await.unwrap_err();
+    assert!(matches!(err, QuietDropError::AuthRequired), "{}", err);
+}
+
+#[tokio::test]
+async fn test_message_requires_login() {
+    let (addr, server_public_key) = start_server().await;
+    let identity = Identity::generate();
+
+    let mut client = Client::connect(&addr, &server_public_key, &identity)
+        .await
+        .unwrap();
+    let err = client
+        .send(&signed_message("Alice", &identity))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, QuietDropError::AuthRequired), "{}", err);
+
+    client.register("Alice", PASSWORD).await.unwrap();
+    client
+        .send(&signed_message("Alice", &identity))
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_logged_in_connection_cannot_claim_other_names() {
+    let (addr, server_public_key) = start_server().await;
+    let identity = Identity::generate();
+
+    let mut client = Client::connect(&addr, &server_public_key, &identity)
+        .await
+        .unwrap();
+    client.register("Mallory", PASSWORD).await.unwrap();
+
+    let err = client.identify("Alice", &identity).await.unwrap_err();
+    assert!(matches!(err, QuietDropError::AuthRequired), "{}", err);
+
+    let err = client
+        .send(&signed_message("Alice", &identity))
+        .await
+        .unwrap_err();
+    assert_server_error(err, ErrorCode::SenderMismatch);
+}
+
+#[test]
+fn test_accounts_change_and_reset_password() {
+    quietdrop_core::initialize();
+
+    let accounts = Accounts::new(
+        Arc::new(MemoryCredentialStore::default()),
+        PasswordPolicy::default(),
+    )
+    .unwrap();
+    let recovery_code = accounts.register("Alice", PASSWORD).unwrap().unwrap();
+
+    assert!(!accounts
+        .change_password("Alice", "not the password", "a new password")
+        .unwrap());
+    assert!(accounts
+        .change_password("Alice", PASSWORD, "a new password")
+        .unwrap());
+    assert!(!accounts.verify("Alice", PASSWORD).unwrap());
+    assert!(accounts.verify("Alice", "a new password").unwrap());
+
+    assert!(accounts
+        .reset_password("Alice", "0000-0000", "another password")
+        .unwrap()
+        .is_none());
+    assert!(accounts
+        .reset_password("Nobody", &recovery_code, "another password")
+        .unwrap()
+        .is_none());
+
+    // Case and separators do not matter when typing the code back in
+    let typed = recovery_code.to_uppercase().replace('-', " ");
+    let new_code = accounts
+        .reset_password("Alice", &typed, "another password")
+        .unwrap()
+        .expect("The recovery code should reset the password");
+    assert_ne!(new_code, recovery_code);
+    assert!(accounts.verify("Alice", "another password").unwrap());
+
+    // The used code is gone; its replacement works
+    assert!(accounts
+        .reset_password("Alice", &recovery_code, "yet another password")
+        .unwrap()
+        .is_none());
+    assert!(accounts
+        .reset_password("Alice", &new_code, "yet another password")
+        .unwrap()
+        .is_some());
+}
+
+#[tokio::test]
+async fn test_change_password_revokes_sessions() {
+    let (addr, server_public_key) = start_server().await;
+    let identity = Identity::generate();
+
+    let mut client = Client::connect(&addr, &server_public_key, &identity)
+        .await
+        .unwrap();
+    let err = client
+        .change_password(PASSWORD, "a new password")
+        .await
+        .unwrap_err();
+    assert!(matches!(err, QuietDropError::AuthRequired), "{}", err);
+
+    let old_session = client.register("Alice", PASSWORD).await.unwrap().session;
+    let err = client
+        .change_password("not the password", "a new password")
+        .await
+        .unwrap_err();
+    assert_server_error(err, ErrorCode::InvalidCredentials);
+    let err = client.change_password(PASSWORD, "short").await.unwrap_err();
+    assert_server_error(err, ErrorCode::WeakPassword);
+
+    let new_session = client
+        .change_password(PASSWORD, "a new password")
+        .await
+        .unwrap();
+
+    let mut other = Client::connect(&addr, &server_public_key, &identity)
+        .await
+        .unwrap();
+    let err = other.resume(&old_session.token).await.unwrap_err();
+    assert_server_error(err, ErrorCode::InvalidSession);
+    let err = other.login("Alice", PASSWORD).await.unwrap_err();
+    assert_server_error(err, ErrorCode::InvalidCredentials);
+    other.resume(&new_session.token).await.unwrap();
+    other.login("Alice", "a new password").await.unwrap();
+}
+
+#[tokio::test]
+async fn test_reset_password_with_recovery_code() {
+    let (addr, server_public_key) = start_server().await;
+    let identity = Identity::generate();
+
+    let mut client = Client::connect(&addr, &server_public_key, &identity)
+        .await
+        .unwrap();
+    let registered = client.register("Alice", PASSWORD).await.unwrap();
+
+    let mut other = Client::connect(&addr, &server_public_key, &identity)
+        .await
+        .unwrap();
+    let reset = other
+        .reset_password("Alice", &registered.recovery_code, "a new password")
+        .await
+        .unwrap();
+    assert_ne!(reset.recovery_code, registered.recovery_code);
+    // The reset logs the connection in
+    other.identify("Alice", &identity).await.unwrap();
+
+    let mut third = Client::connect(&addr, &server_public_key, &identity)
+        .await
+        .unwrap();
+    let err = third.resume(&registered.session.token).await.unwrap_err();
+    assert_server_error(err, ErrorCode::InvalidSession);
+    let err = third
+        .reset_password("Alice", &registered.recovery_code, "another password")
+        .await
+        .unwrap_err();
+    assert_server_error(err, ErrorCode::InvalidCredentials);
+    third.login("Alice", "a new password").await.unwrap();
+}
+
+#[tokio::test]
+async fn test_password_change_logs_out_other_connections() {
+    let (addr, server_public_key) = start_server().await;
+    let identity = Identity::generate();
+
+    let mut client = Client::connect(&addr, &server_public_key, &identity)
+        .await
+        .unwrap();
+    let registered = client.register("Alice", PASSWORD).await.unwrap();
+    client.identify("Alice", &identity).await.unwrap();
+    let mut other = Client::connect(&addr, &server_public_key, &identity)
+        .await
+        .unwrap();
+    other.login("Alice", PASSWORD).await.unwrap();
+    other.identify("Alice", &identity).await.unwrap();
+
+    client
+        .change_password(PASSWORD, "a new password")
+        .await
+        .unwrap();
+
+    // The other connection no longer speaks for the account
+    let err = other
+        .send(&signed_message("Alice", &identity))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, QuietDropError::AuthRequired), "{}", err);
+    let err = other.identify("Alice", &identity).await.unwrap_err();
+    assert!(matches!(err, QuietDropError::AuthRequired), "{}", err);
+    // The connection that made the change stays logged in
+    client
+        .send(&signed_message("Alice", &identity))
+        .await
+        .unwrap();
+
+    // A reset does the same, even to the connection that changed the password
+    other.login("Alice", "a new password").await.unwrap();
+    other
+        .reset_password("Alice", &registered.recovery_code, "another password")
+        .await
+        .unwrap();
+    let err = client
+        .send(&signed_message("Alice", &identity))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, QuietDropError::AuthRequired), "{}", err);
+    other
+        .send(&signed_message("Alice", &identity))
+        .await
+        .unwrap();
+}
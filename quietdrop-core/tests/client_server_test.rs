@@ -1,6 +1,16 @@
 use chrono::Utc;
-use quietdrop_core::encryption::generate_keypair;
+use quietdrop_core::client;
+use quietdrop_core::encryption::{
+    decrypt_message, generate_keypair, generate_signing_keypair, Identity,
+};
+use quietdrop_core::error::QuietDropError;
 use quietdrop_core::message::{Message, MessageType};
+use quietdrop_core::protocol::ErrorCode;
+use quietdrop_core::server;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+const PASSWORD: &str = "correct horse battery";
 
 #[test]
 fn test_client_message_construction() {
@@ -10,12 +20,15 @@ fn test_client_message_construction() {
 
     // Create a message
     let mut msg = Message {
+        id: Message::new_id(),
         timestamp: Utc::now(),
         message_type: MessageType::Text,
         sender: "TestClient".to_owned(),
         recipient: "TestRecipient".to_owned(),
         content: vec![],
         public_key: client_public_key,
+        signing_key: generate_signing_keypair().0,
+        signature: vec![],
     };
 
     // Add encrypted content
@@ -39,9 +52,8 @@ fn test_client_message_construction() {
     assert!(diff.num_seconds() < 60, "Timestamp should be recent");
 }
 
-// The following tests would involve actual client-server communication
-// They are ignored by default as they require running a server
-// You can run them with: cargo test -- --ignored
+// Server initialization is still a placeholder and ignored by default
+// You can run it with: cargo test -- --ignored
 
 #[test]
 #[ignore]
@@ -50,9 +62,273 @@ fn test_server_initialization() {
     // Requires tokio runtime setup
 }
 
-#[test]
-#[ignore]
-fn test_client_server_communication() {
-    // This would test full client-server message exchange
-    // Requires both client and server components running
+#[tokio::test]
+async fn test_client_server_communication() {
+    quietdrop_core::initialize();
+
+    let (server_public_key, server_secret_key) = generate_keypair();
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Should bind to an ephemeral port");
+    let addr = listener.local_addr().unwrap().to_string();
+
+    tokio::spawn(async move {
+        let _ = server::serve(listener, &server_secret_key).await;
+    });
+
+    let client_identity = Identity::generate();
+    let (recipient_public_key, _) = generate_keypair();
+    let mut registration = client::Client::connect(&addr, &server_public_key, &client_identity)
+        .await
+        .unwrap();
+    registration.register("TestClient", PASSWORD).await.unwrap();
+    registration.close().await.unwrap();
+
+    // A message well over the old 1 KiB read buffer
+    let long_message = "QuietDrop ".repeat(1000);
+    let mut msg = Message {
+        id: Message::new_id(),
+        timestamp: Utc::now(),
+        message_type: MessageType::Text,
+        sender: "TestClient".to_owned(),
+        recipient: "TestRecipient".to_owned(),
+        content: vec![],
+        public_key: *client_identity.public_key(),
+        signing_key: *client_identity.signing_key(),
+        signature: vec![],
+    };
+    msg.encrypt_content(
+        &long_message,
+        &recipient_public_key,
+        client_identity.secret_key(),
+    );
+    msg.sign(client_identity.signing_secret_key());
+
+    client::send_message(&msg, &addr, &server_public_key, &client_identity, PASSWORD)
+        .await
+        .expect("Long message should be acknowledged by the server");
+}
+
+#[tokio::test]
+async fn test_client_sends_many_messages_on_one_connection() {
+    quietdrop_core::initialize();
+
+    let (server_public_key, server_secret_key) = generate_keypair();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+    tokio::spawn(async move {
+        let _ = server::serve(listener, &server_secret_key).await;
+    });
+
+    let client_identity = Identity::generate();
+    let (recipient_public_key, _) = generate_keypair();
+    let mut client = client::Client::connect(&addr, &server_public_key, &client_identity)
+        .await
+        .expect("Client should connect");
+    client.register("TestClient", PASSWORD).await.unwrap();
+
+    let mut message_ids = std::collections::HashSet::new();
+    for i in 0..5 {
+        let mut msg = Message {
+            id: Message::new_id(),
+            timestamp: Utc::now(),
+            message_type: MessageType::Text,
+            sender: "TestClient".to_owned(),
+            recipient: "TestRecipient".to_owned(),
+            content: vec![],
+            public_key: *client_identity.public_key(),
+            signing_key: *client_identity.signing_key(),
+            signature: vec![],
+        };
+        msg.encrypt_content(
+            &format!("Message number {}", i),
+            &recipient_public_key,
+            client_identity.secret_key(),
+        );
+        msg.sign(client_identity.signing_secret_key());
+        let message_id = client
+            .send(&msg)
+            .await
+            .expect("Every message should be acknowledged");
+        assert!(
+            message_ids.insert(message_id),
+            "Message IDs should be unique"
+        );
+    }
+
+    client.close().await.expect("Client should close cleanly");
+}
+
+#[tokio::test]
+async fn test_server_cannot_read_relayed_messages() {
+    quietdrop_core::initialize();
+
+    let (server_public_key, server_secret_key) = generate_keypair();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+    let relay_key = server_secret_key.clone();
+    tokio::spawn(async move {
+        let _ = server::serve(listener, &relay_key).await;
+    });
+
+    let alice_identity = Identity::generate();
+    let bob_identity = Identity::generate();
+
+    let mut bob = client::Client::connect(&addr, &server_public_key, &bob_identity)
+        .await
+        .unwrap();
+    bob.register("Bob", PASSWORD).await.unwrap();
+    bob.identify("Bob", &bob_identity).await.unwrap();
+    let mut alice = client::Client::connect(&addr, &server_public_key, &alice_identity)
+        .await
+        .unwrap();
+    alice.register("Alice", PASSWORD).await.unwrap();
+    alice.identify("Alice", &alice_identity).await.unwrap();
+
+    let bob_public_key = alice
+        .lookup_key("Bob", &server_public_key)
+        .await
+        .unwrap()
+        .expect("Bob's key should be published");
+    assert_eq!(&bob_public_key, bob_identity.public_key());
+
+    let mut msg = Message {
+        id: Message::new_id(),
+        timestamp: Utc::now(),
+        message_type: MessageType::Text,
+        sender: "Alice".to_owned(),
+        recipient: "Bob".to_owned(),
+        content: vec![],
+        public_key: *alice_identity.public_key(),
+        signing_key: *alice_identity.signing_key(),
+        signature: vec![],
+    };
+    msg.encrypt_content("For Bob only", &bob_public_key, alice_identity.secret_key());
+    msg.sign(alice_identity.signing_secret_key());
+    alice.send(&msg).await.unwrap();
+
+    let received = bob
+        .try_recv_message(Duration::from_secs(5))
+        .await
+        .unwrap()
+        .expect("Bob should receive the message");
+
+    // The server's key does not open what it relays; Bob's does
+    assert!(decrypt_message(&received.content, &received.public_key, &server_secret_key).is_err());
+    assert_eq!(
+        received.decrypt_content(bob_identity.secret_key()).unwrap(),
+        "For Bob only"
+    );
+}
+
+#[tokio::test]
+async fn test_key_lookup_rejects_wrong_server_key() {
+    quietdrop_core::initialize();
+
+    let (server_public_key, server_secret_key) = generate_keypair();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+    tokio::spawn(async move {
+        let _ = server::serve(listener, &server_secret_key).await;
+    });
+
+    let alice_identity = Identity::generate();
+    let mut alice = client::Client::connect(&addr, &server_public_key, &alice_identity)
+        .await
+        .unwrap();
+    alice.register("Alice", PASSWORD).await.unwrap();
+    alice.identify("Alice", &alice_identity).await.unwrap();
+
+    // Unknown identities have no key
+    let missing = alice
+        .lookup_key("Nobody", &server_public_key)
+        .await
+        .unwrap();
+    assert!(missing.is_none());
+
+    // An answer that does not open with the pinned server key is refused
+    let (impostor_public_key, _) = generate_keypair();
+    assert!(alice
+        .lookup_key("Alice", &impostor_public_key)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_server_rejects_spoofed_sender() {
+    quietdrop_core::initialize();
+
+    let (server_public_key, server_secret_key) = generate_keypair();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+    tokio::spawn(async move {
+        let _ = server::serve(listener, &server_secret_key).await;
+    });
+
+    let alice_identity = Identity::generate();
+    let bob_identity = Identity::generate();
+    let mallory_identity = Identity::generate();
+
+    let mut alice = client::Client::connect(&addr, &server_public_key, &alice_identity)
+        .await
+        .unwrap();
+    alice.register("Alice", PASSWORD).await.unwrap();
+    alice.identify("Alice", &alice_identity).await.unwrap();
+    let mut bob = client::Client::connect(&addr, &server_public_key, &bob_identity)
+        .await
+        .unwrap();
+    bob.register("Bob", PASSWORD).await.unwrap();
+    bob.identify("Bob", &bob_identity).await.unwrap();
+    let bob_public_key = bob
+        .lookup_key("Bob", &server_public_key)
+        .await
+        .unwrap()
+        .unwrap();
+
+    let mut mallory = client::Client::connect(&addr, &server_public_key, &mallory_identity)
+        .await
+        .unwrap();
+    mallory.register("Mallory", PASSWORD).await.unwrap();
+
+    // Mallory claims to be Alice but can only sign with her own key
+    let mut forged = Message {
+        id: Message::new_id(),
+        timestamp: Utc::now(),
+        message_type: MessageType::Text,
+        sender: "Alice".to_owned(),
+        recipient: "Bob".to_owned(),
+        content: vec![],
+        public_key: *mallory_identity.public_key(),
+        signing_key: *mallory_identity.signing_key(),
+        signature: vec![],
+    };
+    forged.encrypt_content(
+        "Hi Bob, it's Alice",
+        &bob_public_key,
+        mallory_identity.secret_key(),
+    );
+    forged.sign(mallory_identity.signing_secret_key());
+    let err = mallory.send(&forged).await.unwrap_err();
+    assert!(
+        matches!(
+            err,
+            QuietDropError::Server {
+                code: ErrorCode::SenderMismatch,
+                ..
+            }
+        ),
+        "{}",
+        err
+    );
+
+    // Nothing reaches Bob
+    assert!(bob
+        .try_recv_message(Duration::from_millis(200))
+        .await
+        .unwrap()
+        .is_none());
 }
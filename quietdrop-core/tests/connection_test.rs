@@ -0,0 +1,96 @@
+use chrono::Utc;
+use quietdrop_core::connection::{Connection, ConnectionConfig};
+use quietdrop_core::encryption::{generate_keypair, generate_signing_keypair};
+use quietdrop_core::message::{Message, MessageType};
+use quietdrop_core::protocol::Packet;
+use std::io::ErrorKind;
+use std::time::Duration;
+use tokio::io::duplex;
+
+fn test_message(sender: &str, recipient: &str) -> Message {
+    Message {
+        id: Message::new_id(),
+        timestamp: Utc::now(),
+        message_type: MessageType::Text,
+        sender: sender.to_owned(),
+        recipient: recipient.to_owned(),
+        content: vec![1, 2, 3, 4],
+        public_key: generate_keypair().0,
+        signing_key: generate_signing_keypair().0,
+        signature: vec![],
+    }
+}
+
+fn fast_heartbeat() -> ConnectionConfig {
+    ConnectionConfig {
+        heartbeat_interval: Duration::from_millis(50),
+        idle_timeout: Duration::from_millis(200),
+        ..ConnectionConfig::default()
+    }
+}
+
+#[tokio::test]
+async fn test_messages_flow_in_both_directions() {
+    let (a, b) = duplex(64 * 1024);
+    let mut alice = Connection::new(a);
+    let mut bob = Connection::new(b);
+
+    for i in 0..10 {
+        let msg = test_message("Alice", &format!("Bob{}", i));
+        alice.send(&Packet::Message(msg)).await.unwrap();
+    }
+    for i in 0..10 {
+        match bob.recv().await.unwrap() {
+            Some(Packet::Message(msg)) => assert_eq!(msg.recipient, format!("Bob{}", i)),
+            other => panic!("Expected a message, got {:?}", other),
+        }
+    }
+
+    bob.send(&Packet::Message(test_message("Bob", "Alice")))
+        .await
+        .unwrap();
+    match alice.recv().await.unwrap() {
+        Some(Packet::Message(msg)) => assert_eq!(msg.sender, "Bob"),
+        other => panic!("Expected a message, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_clean_close_is_reported() {
+    let (a, b) = duplex(1024);
+    let mut alice = Connection::new(a);
+    let mut bob = Connection::new(b);
+
+    alice.close().await.unwrap();
+    drop(alice);
+
+    assert!(bob.recv().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_heartbeats_keep_quiet_connection_alive() {
+    let (a, b) = duplex(1024);
+    let mut alice = Connection::with_config(a, fast_heartbeat());
+    let mut bob = Connection::with_config(b, fast_heartbeat());
+
+    // Bob only answers heartbeats while Alice waits well past the idle timeout
+    let bob_task = tokio::spawn(async move {
+        let _ = bob.recv().await;
+    });
+
+    let result = tokio::time::timeout(Duration::from_millis(600), alice.recv()).await;
+    assert!(
+        result.is_err(),
+        "Alice should still be waiting, not timed out or closed"
+    );
+    bob_task.abort();
+}
+
+#[tokio::test]
+async fn test_silent_peer_times_out() {
+    let (a, _silent) = duplex(1024);
+    let mut alice = Connection::with_config(a, fast_heartbeat());
+
+    let err = alice.recv().await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+}
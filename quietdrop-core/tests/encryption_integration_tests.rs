@@ -1,4 +1,27 @@
-use quietdrop_core::encryption::{decrypt_message, encrypt_message, generate_keypair};
+use chrono::{Duration, Utc};
+use quietdrop_core::encryption::{
+    decrypt_bytes, decrypt_message, decrypt_stream, encrypt_bytes, encrypt_message, encrypt_stream,
+    generate_keypair, Identity, PublicKey, SecretKey, STREAM_CHUNK_SIZE,
+};
+use quietdrop_core::error::QuietDropError;
+use quietdrop_core::message::{Message, MessageType};
+
+fn signed_message(sender: &Identity, recipient: &Identity, text: &str) -> Message {
+    let mut message = Message {
+        id: Message::new_id(),
+        timestamp: Utc::now(),
+        message_type: MessageType::Text,
+        sender: "Alice".to_owned(),
+        recipient: "Bob".to_owned(),
+        content: vec![],
+        public_key: *sender.public_key(),
+        signing_key: *sender.signing_key(),
+        signature: vec![],
+    };
+    message.encrypt_content(text, recipient.public_key(), sender.secret_key());
+    message.sign(sender.signing_secret_key());
+    message
+}
 
 #[test]
 fn test_end_to_end_encryption() {
@@ -64,7 +87,7 @@ fn test_message_tampering_detection() {
 
     // Verify that tampering is detected
     assert!(
-        decryption_result.is_err(),
+        matches!(decryption_result, Err(QuietDropError::Crypto(_))),
         "Tampered message should fail authentication"
     );
 }
@@ -122,3 +145,183 @@ fn test_key_specificity() {
         decrypt_message(&encrypted_for_bob, &alice_public_key, &charlie_secret_key);
     assert!(charlie_decrypting_bobs_message.is_err());
 }
+
+#[test]
+fn test_signed_message_verifies() {
+    quietdrop_core::initialize();
+    let alice = Identity::generate();
+    let bob = Identity::generate();
+
+    let message = signed_message(&alice, &bob, "Signed by Alice");
+    assert!(message.verify_signature().is_ok());
+
+    // A message that was never signed does not verify
+    let mut unsigned = message.clone();
+    unsigned.signature.clear();
+    assert!(unsigned.verify_signature().is_err());
+}
+
+#[test]
+fn test_signature_detects_tampering() {
+    quietdrop_core::initialize();
+    let alice = Identity::generate();
+    let bob = Identity::generate();
+    let message = signed_message(&alice, &bob, "Meet at noon");
+
+    // Every signed field is covered: changing any of them breaks the signature
+    let mut tampered = message.clone();
+    tampered.content[30] ^= 0x01;
+    assert!(tampered.verify_signature().is_err(), "ciphertext");
+
+    let mut tampered = message.clone();
+    tampered.sender = "Mallory".to_owned();
+    assert!(tampered.verify_signature().is_err(), "sender");
+
+    let mut tampered = message.clone();
+    tampered.recipient = "Carol".to_owned();
+    assert!(tampered.verify_signature().is_err(), "recipient");
+
+    let mut tampered = message.clone();
+    tampered.timestamp = message.timestamp + Duration::seconds(1);
+    assert!(tampered.verify_signature().is_err(), "timestamp");
+
+    let mut tampered = message.clone();
+    tampered.message_type = MessageType::File;
+    assert!(tampered.verify_signature().is_err(), "message type");
+
+    let mut tampered = message;
+    tampered.signature[0] ^= 0x01;
+    assert!(tampered.verify_signature().is_err(), "signature");
+}
+
+#[test]
+fn test_signature_rejects_wrong_signer() {
+    quietdrop_core::initialize();
+    let alice = Identity::generate();
+    let bob = Identity::generate();
+    let mallory = Identity::generate();
+
+    // Mallory signs with her own key but claims Alice's signing key
+    let mut forged = signed_message(&alice, &bob, "Send Mallory the files");
+    forged.sign(mallory.signing_secret_key());
+    assert!(matches!(
+        forged.verify_signature(),
+        Err(QuietDropError::InvalidSignature(_))
+    ));
+
+    // Swapping in her own signing key makes the signature verify, but it no
+    // longer matches the key Alice published, which receivers check
+    forged.signing_key = *mallory.signing_key();
+    forged.sign(mallory.signing_secret_key());
+    assert!(forged.verify_signature().is_ok());
+    assert_ne!(&forged.signing_key, alice.signing_key());
+}
+
+#[test]
+fn test_bytes_round_trip_without_utf8() {
+    let (alice_public_key, alice_secret_key) = generate_keypair();
+    let (bob_public_key, bob_secret_key) = generate_keypair();
+
+    let payload: Vec<u8> = vec![0xff, 0x00, 0xfe, 0x80, 0x7f];
+    let encrypted = encrypt_bytes(&payload, &bob_public_key, &alice_secret_key);
+    assert_eq!(
+        decrypt_bytes(&encrypted, &alice_public_key, &bob_secret_key).unwrap(),
+        payload
+    );
+
+    // The text wrapper still insists on UTF-8
+    let err = decrypt_message(&encrypted, &alice_public_key, &bob_secret_key).unwrap_err();
+    assert!(matches!(err, QuietDropError::Crypto(_)), "{}", err);
+
+    // Text and bytes share one format
+    let encrypted = encrypt_message("héllo", &bob_public_key, &alice_secret_key);
+    assert_eq!(
+        decrypt_bytes(&encrypted, &alice_public_key, &bob_secret_key).unwrap(),
+        "héllo".as_bytes()
+    );
+}
+
+async fn encrypt_to_vec(plaintext: &[u8], to: &PublicKey, from: &SecretKey) -> Vec<u8> {
+    let mut encrypted = Vec::new();
+    let written = encrypt_stream(&mut &plaintext[..], &mut encrypted, to, from)
+        .await
+        .unwrap();
+    assert_eq!(written, plaintext.len() as u64);
+    encrypted
+}
+
+async fn decrypt_to_vec(
+    encrypted: &[u8],
+    from: &PublicKey,
+    to: &SecretKey,
+) -> Result<Vec<u8>, QuietDropError> {
+    let mut decrypted = Vec::new();
+    decrypt_stream(&mut &encrypted[..], &mut decrypted, from, to).await?;
+    Ok(decrypted)
+}
+
+#[tokio::test]
+async fn test_stream_round_trip() {
+    let (alice_public_key, alice_secret_key) = generate_keypair();
+    let (bob_public_key, bob_secret_key) = generate_keypair();
+
+    for len in [0, 1, STREAM_CHUNK_SIZE, 2 * STREAM_CHUNK_SIZE + 5] {
+        let plaintext: Vec<u8> = (0..len).map(|_| rand::random::<u8>()).collect();
+        let encrypted = encrypt_to_vec(&plaintext, &bob_public_key, &alice_secret_key).await;
+        let decrypted = decrypt_to_vec(&encrypted, &alice_public_key, &bob_secret_key)
+            .await
+            .unwrap();
+        assert_eq!(decrypted, plaintext, "round trip of {} bytes", len);
+    }
+}
+
+#[tokio::test]
+async fn test_stream_rejects_tampering_and_truncation() {
+    let (alice_public_key, alice_secret_key) = generate_keypair();
+    let (bob_public_key, bob_secret_key) = generate_keypair();
+    let (_, eve_secret_key) = generate_keypair();
+
+    let plaintext = vec![7u8; STREAM_CHUNK_SIZE + 100];
+    let encrypted = encrypt_to_vec(&plaintext, &bob_public_key, &alice_secret_key).await;
+
+    // Only the recipient can open it
+    assert!(
+        decrypt_to_vec(&encrypted, &alice_public_key, &eve_secret_key)
+            .await
+            .is_err()
+    );
+
+    // A flipped byte in a frame
+    let mut tampered = encrypted.clone();
+    tampered[encrypted.len() / 2] ^= 1;
+    assert!(
+        decrypt_to_vec(&tampered, &alice_public_key, &bob_secret_key)
+            .await
+            .is_err()
+    );
+
+    // Dropping the final frame leaves whole frames that still fail
+    let final_frame = 4 + 100 + sodiumoxide::crypto::secretstream::ABYTES;
+    let truncated = &encrypted[..encrypted.len() - final_frame];
+    let err = decrypt_to_vec(truncated, &alice_public_key, &bob_secret_key)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, QuietDropError::Crypto(_)), "{}", err);
+
+    // Cut mid-frame
+    let truncated = &encrypted[..encrypted.len() - 1];
+    assert!(
+        decrypt_to_vec(truncated, &alice_public_key, &bob_secret_key)
+            .await
+            .is_err()
+    );
+
+    // Trailing data after the final frame
+    let mut extended = encrypted.clone();
+    extended.push(0);
+    assert!(
+        decrypt_to_vec(&extended, &alice_public_key, &bob_secret_key)
+            .await
+            .is_err()
+    );
+}
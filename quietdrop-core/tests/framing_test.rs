@@ -0,0 +1,90 @@
+use quietdrop_core::framing::{read_frame, write_frame, MAX_FRAME_SIZE};
+use std::io::ErrorKind;
+use tokio::io::{duplex, AsyncWriteExt};
+
+#[tokio::test]
+async fn test_frame_round_trip() {
+    let (mut client, mut server) = duplex(64 * 1024);
+
+    write_frame(&mut client, b"hello frame")
+        .await
+        .expect("Writing a frame should succeed");
+
+    let frame = read_frame(&mut server, MAX_FRAME_SIZE)
+        .await
+        .expect("Reading a frame should succeed")
+        .expect("A frame should be available");
+    assert_eq!(frame, b"hello frame");
+}
+
+#[tokio::test]
+async fn test_large_frame_survives_small_pipe() {
+    // A pipe much smaller than the payload forces the frame to arrive in pieces
+    let (mut client, mut server) = duplex(512);
+    let payload: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+
+    let expected = payload.clone();
+    let writer = tokio::spawn(async move {
+        write_frame(&mut client, &payload)
+            .await
+            .expect("Writing a large frame should succeed");
+    });
+
+    let frame = read_frame(&mut server, MAX_FRAME_SIZE)
+        .await
+        .expect("Reading a large frame should succeed")
+        .expect("A frame should be available");
+    writer.await.unwrap();
+
+    assert_eq!(frame, expected, "Large frame should arrive intact");
+}
+
+#[tokio::test]
+async fn test_multiple_frames_on_one_stream() {
+    let (mut client, mut server) = duplex(64 * 1024);
+
+    for i in 0..5 {
+        let payload = format!("message number {}", i);
+        write_frame(&mut client, payload.as_bytes()).await.unwrap();
+    }
+    drop(client);
+
+    for i in 0..5 {
+        let frame = read_frame(&mut server, MAX_FRAME_SIZE)
+            .await
+            .unwrap()
+            .expect("Each frame should be read separately");
+        assert_eq!(frame, format!("message number {}", i).as_bytes());
+    }
+
+    // A clean close between frames is not an error
+    let end = read_frame(&mut server, MAX_FRAME_SIZE).await.unwrap();
+    assert!(end.is_none(), "Closed stream should yield no more frames");
+}
+
+#[tokio::test]
+async fn test_oversized_frame_is_rejected() {
+    let (mut client, mut server) = duplex(1024);
+
+    write_frame(&mut client, &[0u8; 100]).await.unwrap();
+
+    let result = read_frame(&mut server, 64).await;
+    assert_eq!(
+        result.unwrap_err().kind(),
+        ErrorKind::InvalidData,
+        "Frames above the limit should be rejected"
+    );
+}
+
+#[tokio::test]
+async fn test_truncated_frame_is_an_error() {
+    let (mut client, mut server) = duplex(1024);
+
+    // Announce 10 bytes but only send 3
+    client.write_all(&10u32.to_be_bytes()).await.unwrap();
+    client.write_all(b"abc").await.unwrap();
+    drop(client);
+
+    let result = read_frame(&mut server, MAX_FRAME_SIZE).await;
+    assert_eq!(result.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+}
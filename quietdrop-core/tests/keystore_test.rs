@@ -0,0 +1,283 @@
+use quietdrop_core::authentication::{derive_key, PasswordPolicy};
+use quietdrop_core::encryption::generate_keypair;
+use quietdrop_core::error::QuietDropError;
+use quietdrop_core::keystore::{Keystore, ServerKeyFiles};
+use sodiumoxide::crypto::secretbox;
+use std::path::PathBuf;
+
+fn temp_keystore_dir(name: &str) -> PathBuf {
+    let mut dir = std::env::temp_dir();
+    dir.push(format!(
+        "quietdrop-keystore-{}-{}-{}",
+        name,
+        std::process::id(),
+        rand::random::<u32>()
+    ));
+    dir
+}
+
+#[test]
+fn test_keystore_create_and_load() {
+    quietdrop_core::initialize();
+    let dir = temp_keystore_dir("roundtrip");
+    let keystore = Keystore::new(&dir);
+
+    assert!(!keystore.exists());
+    let identity = keystore
+        .create("correct horse battery staple")
+        .expect("Creating a keystore should succeed");
+    assert!(keystore.exists());
+
+    // Loading with the same passphrase returns the same identity
+    let loaded = keystore
+        .load("correct horse battery staple")
+        .expect("Loading with the right passphrase should succeed");
+    assert_eq!(identity.keypair, loaded.keypair);
+    assert_eq!(identity.signing_keypair, loaded.signing_keypair);
+
+    // The public key can be read without the passphrase
+    assert_eq!(&keystore.public_key().unwrap(), identity.public_key());
+
+    // Creating again must not silently replace the identity
+    assert!(keystore.create("another passphrase").is_err());
+
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_keystore_load_or_create_is_stable() {
+    quietdrop_core::initialize();
+    let dir = temp_keystore_dir("stable");
+    let keystore = Keystore::new(&dir);
+
+    let first = keystore.load_or_create("passphrase").unwrap();
+    let second = keystore.load_or_create("passphrase").unwrap();
+    assert_eq!(
+        first.keypair, second.keypair,
+        "Identity should persist between runs"
+    );
+    assert_eq!(first.signing_keypair, second.signing_keypair);
+
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_keystore_rejects_wrong_passphrase() {
+    quietdrop_core::initialize();
+    let dir = temp_keystore_dir("wrong");
+    let keystore = Keystore::new(&dir);
+
+    let _ = keystore.create("right passphrase").unwrap();
+    assert!(
+        matches!(
+            keystore.load("wrong passphrase"),
+            Err(QuietDropError::Auth(_))
+        ),
+        "Wrong passphrase should not unlock the keystore"
+    );
+
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_keystore_secret_key_is_encrypted_at_rest() {
+    quietdrop_core::initialize();
+    let dir = temp_keystore_dir("at-rest");
+    let keystore = Keystore::new(&dir);
+
+    let identity = keystore.create("passphrase").unwrap();
+    let on_disk = std::fs::read(dir.join("identity.key")).unwrap();
+
+    for secret_bytes in [
+        identity.secret_key().as_ref(),
+        identity.signing_secret_key().as_ref(),
+    ] {
+        assert!(
+            !on_disk
+                .windows(secret_bytes.len())
+                .any(|window| window == secret_bytes),
+            "Secret keys must not be stored in the clear"
+        );
+    }
+
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_keystore_change_passphrase() {
+    quietdrop_core::initialize();
+    let dir = temp_keystore_dir("rekey");
+    let keystore = Keystore::new(&dir);
+
+    let identity = keystore.create("old passphrase").unwrap();
+    keystore
+        .change_passphrase("old passphrase", "new passphrase")
+        .unwrap();
+
+    assert!(keystore.load("old passphrase").is_err());
+    let reloaded = keystore.load("new passphrase").unwrap();
+    assert_eq!(reloaded.keypair, identity.keypair);
+    assert_eq!(reloaded.signing_keypair, identity.signing_keypair);
+
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_keystore_upgrades_version_1() {
+    quietdrop_core::initialize();
+    let dir = temp_keystore_dir("v1");
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // A version 1 keystore holds only the encryption secret key
+    let (public_key, secret_key) = generate_keypair();
+    let salt = [7u8; 16];
+    let mut key_bytes = [0u8; secretbox::KEYBYTES];
+    derive_key(
+        "passphrase",
+        &salt,
+        &PasswordPolicy::new(4096, 3, 1),
+        &mut key_bytes,
+    )
+    .unwrap();
+    let key = secretbox::Key::from_slice(&key_bytes).unwrap();
+    let nonce = secretbox::gen_nonce();
+    let stored = serde_json::json!({
+        "version": 1,
+        "salt": salt.to_vec(),
+        "nonce": nonce.as_ref().to_vec(),
+        "ciphertext": secretbox::seal(secret_key.as_ref(), &nonce, &key),
+    });
+    std::fs::write(dir.join("identity.pub"), public_key.as_ref()).unwrap();
+    std::fs::write(dir.join("identity.key"), stored.to_string()).unwrap();
+
+    // Loading keeps the encryption key and adds a signing key
+    let keystore = Keystore::new(&dir);
+    let upgraded = keystore.load("passphrase").unwrap();
+    assert_eq!(upgraded.keypair, (public_key, secret_key));
+
+    // The signing key is saved, so it stays the same from then on
+    let reloaded = keystore.load("passphrase").unwrap();
+    assert_eq!(reloaded.signing_keypair, upgraded.signing_keypair);
+
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_keystore_reads_key_derivation_costs_from_file() {
+    quietdrop_core::initialize();
+    let dir = temp_keystore_dir("costs");
+    let keystore = Keystore::new(&dir);
+    let identity = keystore.create("passphrase").unwrap();
+
+    // Re-encrypt the secret keys under cheaper costs than the default
+    let cheap = PasswordPolicy::new(1024, 1, 1);
+    let salt = [9u8; 16];
+    let mut key_bytes = [0u8; secretbox::KEYBYTES];
+    derive_key("passphrase", &salt, &cheap, &mut key_bytes).unwrap();
+    let key = secretbox::Key::from_slice(&key_bytes).unwrap();
+    let nonce = secretbox::gen_nonce();
+    let secret_bytes = [
+        identity.secret_key().as_ref(),
+        identity.signing_secret_key().as_ref(),
+    ]
+    .concat();
+    let stored = serde_json::json!({
+        "version": 3,
+        "key_derivation": cheap,
+        "salt": salt.to_vec(),
+        "nonce": nonce.as_ref().to_vec(),
+        "ciphertext": secretbox::seal(&secret_bytes, &nonce, &key),
+    });
+    std::fs::write(dir.join("identity.key"), stored.to_string()).unwrap();
+
+    // The stored costs unlock it
+    let loaded = keystore.load("passphrase").unwrap();
+    assert_eq!(loaded.keypair, identity.keypair);
+    assert_eq!(loaded.signing_keypair, identity.signing_keypair);
+
+    // And it is rewritten under the current costs
+    let rewritten: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(dir.join("identity.key")).unwrap()).unwrap();
+    assert_eq!(
+        rewritten["key_derivation"],
+        serde_json::to_value(PasswordPolicy::default()).unwrap()
+    );
+    assert!(keystore.load("passphrase").is_ok());
+
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_keystore_files_are_private() {
+    use std::os::unix::fs::PermissionsExt;
+
+    quietdrop_core::initialize();
+    let dir = temp_keystore_dir("perms");
+    let keystore = Keystore::new(&dir);
+    let _ = keystore.create("passphrase").unwrap();
+
+    let mode = |path: PathBuf| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+    assert_eq!(mode(dir.clone()), 0o700);
+    assert_eq!(mode(dir.join("identity.key")), 0o600);
+    assert_eq!(mode(dir.join("identity.pub")), 0o600);
+
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_server_keys_generated_once_and_reloaded() {
+    quietdrop_core::initialize();
+    let dir = temp_keystore_dir("server");
+    let server_keys = ServerKeyFiles::new(&dir);
+
+    let (first, generated) = server_keys.load_or_generate().unwrap();
+    assert!(generated, "First run should generate keys");
+
+    let (second, generated) = server_keys.load_or_generate().unwrap();
+    assert!(!generated, "Later runs should load the existing keys");
+    assert_eq!(first.0, second.0, "Server key should survive a restart");
+    assert_eq!(server_keys.public_key().unwrap(), first.0);
+
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_server_keys_report_missing_or_corrupted_files() {
+    quietdrop_core::initialize();
+    let dir = temp_keystore_dir("server-broken");
+    let server_keys = ServerKeyFiles::new(&dir);
+    let _ = server_keys.load_or_generate().unwrap();
+
+    // A corrupted secret key is an error, not a reason to regenerate
+    std::fs::write(server_keys.secret_key_path(), b"garbage").unwrap();
+    let err = server_keys.load_or_generate().unwrap_err();
+    assert!(matches!(err, QuietDropError::Keystore(_)));
+    assert!(err.to_string().contains("corrupted"));
+
+    // So is a missing half of the keypair
+    std::fs::remove_file(server_keys.secret_key_path()).unwrap();
+    assert!(server_keys.load_or_generate().is_err());
+    assert!(
+        !server_keys.secret_key_path().exists(),
+        "No new secret key should have been written"
+    );
+
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_server_keys_rotate() {
+    quietdrop_core::initialize();
+    let dir = temp_keystore_dir("server-rotate");
+    let server_keys = ServerKeyFiles::new(&dir);
+
+    let (old, _) = server_keys.load_or_generate().unwrap();
+    let new = server_keys.rotate().unwrap();
+    assert_ne!(old.0, new.0, "Rotation should produce a new keypair");
+    assert_eq!(server_keys.load().unwrap().0, new.0);
+    assert!(dir.join("server_public_key.key.old").exists());
+    assert!(dir.join("server_secret_key.key.old").exists());
+
+    std::fs::remove_dir_all(&dir).ok();
+}
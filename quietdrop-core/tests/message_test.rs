@@ -1,7 +1,6 @@
 use chrono::Utc;
-use quietdrop_core::encryption::generate_keypair;
+use quietdrop_core::encryption::{generate_keypair, generate_signing_keypair};
 use quietdrop_core::message::{Message, MessageType};
-use serde_json;
 
 #[test]
 fn test_message_creation_and_encryption() {
@@ -11,12 +10,15 @@ fn test_message_creation_and_encryption() {
 
     // Create a message
     let mut message = Message {
+        id: Message::new_id(),
         timestamp: Utc::now(),
         message_type: MessageType::Text,
         sender: "Alice".to_owned(),
         recipient: "Bob".to_owned(),
         content: vec![],
-        public_key: sender_public_key.clone(),
+        public_key: sender_public_key,
+        signing_key: generate_signing_keypair().0,
+        signature: vec![],
     };
 
     // Original message content
@@ -43,19 +45,24 @@ fn test_message_creation_and_encryption() {
 fn test_message_serialization() {
     // Generate a keypair for the message
     let (public_key, secret_key) = generate_keypair();
+    let (signing_key, signing_secret_key) = generate_signing_keypair();
 
     // Create a message
     let mut message = Message {
+        id: Message::new_id(),
         timestamp: Utc::now(),
         message_type: MessageType::Text,
         sender: "TestSender".to_owned(),
         recipient: "TestRecipient".to_owned(),
         content: vec![],
-        public_key: public_key.clone(),
+        public_key,
+        signing_key,
+        signature: vec![],
     };
 
-    // Add some content
+    // Add some content and sign it
     message.encrypt_content("Test content", &public_key, &secret_key);
+    message.sign(&signing_secret_key);
 
     // Serialize to JSON
     let serialized = serde_json::to_string(&message).expect("Message should serialize to JSON");
@@ -75,6 +82,9 @@ fn test_message_serialization() {
     assert_eq!(message.recipient, deserialized.recipient);
     assert_eq!(message.content, deserialized.content);
 
+    // The signature still verifies after a round trip
+    assert!(deserialized.verify_signature().is_ok());
+
     // Check message_type manually using pattern matching instead of direct comparison
     match (message.message_type, deserialized.message_type) {
         (MessageType::Text, MessageType::Text) => {}
@@ -132,21 +142,27 @@ fn test_message_types() {
 
     // Test that message types are preserved in message structures
     let text_message = Message {
+        id: Message::new_id(),
         timestamp: Utc::now(),
         message_type: MessageType::Text,
         sender: "Sender".to_owned(),
         recipient: "Recipient".to_owned(),
         content: vec![1, 2, 3, 4], // Dummy content
         public_key: generate_keypair().0,
+        signing_key: generate_signing_keypair().0,
+        signature: vec![],
     };
 
     let file_message = Message {
+        id: Message::new_id(),
         timestamp: Utc::now(),
         message_type: MessageType::File,
         sender: "Sender".to_owned(),
         recipient: "Recipient".to_owned(),
         content: vec![1, 2, 3, 4], // Dummy content
         public_key: generate_keypair().0,
+        signing_key: generate_signing_keypair().0,
+        signature: vec![],
     };
 
     // Verify message types in structures using pattern matching
@@ -0,0 +1,343 @@
+use chrono::Utc;
+use quietdrop_core::client::Client;
+use quietdrop_core::encryption::{generate_keypair, Identity, PublicKey};
+use quietdrop_core::error::QuietDropError;
+use quietdrop_core::message::{Message, MessageType};
+use quietdrop_core::outbox::{Outbox, OutboxStatus, RetryPolicy};
+use quietdrop_core::server::{self, ServerConfig};
+use quietdrop_core::storage::Storage;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+const PASSWORD: &str = "correct horse battery";
+
+fn temp_outbox(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "quietdrop-outbox-{}-{}-{}.json",
+        name,
+        std::process::id(),
+        rand::random::<u32>()
+    ))
+}
+
+fn quick_retries() -> RetryPolicy {
+    RetryPolicy::new(Duration::from_millis(10), Duration::from_millis(40), 5)
+}
+
+fn signed_message(sender: &Identity, recipient: &str, text: &str) -> Message {
+    let mut msg = Message {
+        id: Message::new_id(),
+        timestamp: Utc::now(),
+        message_type: MessageType::Text,
+        sender: "Alice".to_owned(),
+        recipient: recipient.to_owned(),
+        content: vec![],
+        public_key: *sender.public_key(),
+        signing_key: *sender.signing_key(),
+        signature: vec![],
+    };
+    msg.encrypt_content(text, &generate_keypair().0, sender.secret_key());
+    msg.sign(sender.signing_secret_key());
+    msg
+}
+
+async fn start_server(listener: TcpListener) -> PublicKey {
+    let (server_public_key, server_secret_key) = generate_keypair();
+    tokio::spawn(async move {
+        let _ = server::serve(listener, &server_secret_key).await;
+    });
+    server_public_key
+}
+
+async fn logged_in_client(
+    addr: &str,
+    server_public_key: &PublicKey,
+    identity: &Identity,
+) -> Client {
+    let mut client = Client::connect(addr, server_public_key, identity)
+        .await
+        .unwrap();
+    client.register("Alice", PASSWORD).await.unwrap();
+    client.identify("Alice", identity).await.unwrap();
+    client
+}
+
+#[test]
+fn test_retry_policy_doubles_up_to_max_delay() {
+    let policy = RetryPolicy::new(Duration::from_secs(1), Duration::from_secs(10), 8);
+    let delays: Vec<u64> = (1..=6).map(|n| policy.delay(n).as_secs()).collect();
+    assert_eq!(delays, [1, 2, 4, 8, 10, 10]);
+}
+
+#[test]
+fn test_outbox_persists_pending_messages() {
+    quietdrop_core::initialize();
+
+    let path = temp_outbox("persist");
+    let identity = Identity::generate();
+    let outbox = Outbox::open(&path, quick_retries()).unwrap();
+    let id = outbox
+        .push(signed_message(&identity, "Bob", "Hi Bob"))
+        .unwrap();
+    drop(outbox);
+
+    let reopened = Outbox::open(&path, quick_retries()).unwrap();
+    assert_eq!(reopened.status(&id), Some(OutboxStatus::Pending));
+    assert_eq!(reopened.pending_count(), 1);
+    let entries = reopened.entries();
+    assert_eq!(entries[0].message.recipient, "Bob");
+    entries[0].message.verify_signature().unwrap();
+
+    std::fs::remove_file(&path).ok();
+}
+
+#[test]
+fn test_outbox_rejects_unsupported_file_version() {
+    let path = temp_outbox("version");
+    std::fs::write(&path, r#"{"version":99,"entries":[]}"#).unwrap();
+
+    assert!(matches!(
+        Outbox::open(&path, quick_retries()),
+        Err(QuietDropError::Storage(_))
+    ));
+
+    std::fs::remove_file(&path).ok();
+}
+
+#[test]
+fn test_outbox_backs_off_and_gives_up() {
+    quietdrop_core::initialize();
+
+    let path = temp_outbox("backoff");
+    let policy = RetryPolicy::new(Duration::from_millis(50), Duration::from_millis(200), 5);
+    let outbox = Outbox::open(&path, policy).unwrap();
+    let id = outbox
+        .push(signed_message(&Identity::generate(), "Bob", "Hi Bob"))
+        .unwrap();
+    assert_eq!(outbox.next_attempt_in(), Some(Duration::ZERO));
+
+    let unreachable = QuietDropError::ConnectionClosed;
+    outbox.record_failure(&unreachable).unwrap();
+    let wait = outbox.next_attempt_in().unwrap();
+    assert!(wait > Duration::ZERO && wait <= Duration::from_millis(50));
+
+    // Messages that are not due yet are left alone
+    outbox.record_failure(&unreachable).unwrap();
+    assert_eq!(outbox.entries()[0].attempts, 1);
+
+    for _ in 1..5 {
+        std::thread::sleep(outbox.next_attempt_in().unwrap());
+        outbox.record_failure(&unreachable).unwrap();
+    }
+    match outbox.status(&id) {
+        Some(OutboxStatus::Failed { reason }) => assert!(reason.contains("5 attempts")),
+        other => panic!("Expected the message to fail, got {:?}", other),
+    }
+    assert_eq!(outbox.next_attempt_in(), None);
+
+    std::fs::remove_file(&path).ok();
+}
+
+#[tokio::test]
+async fn test_outbox_flush_sends_and_reports_status() {
+    quietdrop_core::initialize();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let server_public_key = start_server(listener).await;
+    let identity = Identity::generate();
+    let mut client = logged_in_client(&addr, &server_public_key, &identity).await;
+
+    let path = temp_outbox("flush");
+    let outbox = Outbox::open(&path, quick_retries()).unwrap();
+    let first = outbox
+        .push(signed_message(&identity, "Bob", "one"))
+        .unwrap();
+    let second = outbox
+        .push(signed_message(&identity, "Bob", "two"))
+        .unwrap();
+    let mut invalid = signed_message(&identity, "Bob", "three");
+    invalid.id = "not-a-uuid".to_owned();
+    let rejected = outbox.push(invalid).unwrap();
+
+    assert_eq!(outbox.flush(&mut client, &identity).await.unwrap(), 2);
+    for id in [&first, &second] {
+        assert!(matches!(outbox.status(id), Some(OutboxStatus::Sent { .. })));
+    }
+    // A refusal from the server is final
+    assert!(matches!(
+        outbox.status(&rejected),
+        Some(OutboxStatus::Failed { .. })
+    ));
+    assert_eq!(outbox.next_attempt_in(), None);
+
+    let finished = outbox.remove_finished().unwrap();
+    assert_eq!(finished.len(), 3);
+    assert!(outbox.entries().is_empty());
+
+    std::fs::remove_file(&path).ok();
+}
+
+#[tokio::test]
+async fn test_outbox_retries_once_server_is_back() {
+    quietdrop_core::initialize();
+
+    // Reserve an address, then let it go so nothing is listening on it
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    drop(listener);
+
+    let identity = Identity::generate();
+    let path = temp_outbox("retry");
+    let outbox = Outbox::open(&path, quick_retries()).unwrap();
+    let id = outbox
+        .push(signed_message(&identity, "Bob", "Hi Bob"))
+        .unwrap();
+
+    let (server_public_key, _) = generate_keypair();
+    let error = Client::connect(&addr, &server_public_key, &identity)
+        .await
+        .err()
+        .expect("Nothing should be listening yet");
+    outbox.record_failure(&error).unwrap();
+    assert_eq!(outbox.status(&id), Some(OutboxStatus::Pending));
+    assert!(outbox.entries()[0].last_error.is_some());
+
+    let listener = TcpListener::bind(&addr).await.unwrap();
+    let server_public_key = start_server(listener).await;
+    let mut client = logged_in_client(&addr, &server_public_key, &identity).await;
+
+    tokio::time::sleep(outbox.next_attempt_in().unwrap()).await;
+    assert_eq!(outbox.flush(&mut client, &identity).await.unwrap(), 1);
+    assert!(matches!(
+        outbox.status(&id),
+        Some(OutboxStatus::Sent { .. })
+    ));
+
+    std::fs::remove_file(&path).ok();
+}
+
+#[tokio::test]
+async fn test_outbox_acknowledges_retry_after_lost_ack() {
+    quietdrop_core::initialize();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let server_public_key = start_server(listener).await;
+    let identity = Identity::generate();
+    let mut client = logged_in_client(&addr, &server_public_key, &identity).await;
+
+    // The server accepts the message, but the acknowledgement never arrives
+    let msg = signed_message(&identity, "Bob", "Hi Bob");
+    let message_id = client.send(&msg).await.unwrap();
+    let path = temp_outbox("lost-ack");
+    let outbox = Outbox::open(&path, quick_retries()).unwrap();
+    let id = outbox.push(msg).unwrap();
+    outbox
+        .record_failure(&QuietDropError::ConnectionClosed)
+        .unwrap();
+
+    tokio::time::sleep(outbox.next_attempt_in().unwrap()).await;
+    assert_eq!(outbox.flush(&mut client, &identity).await.unwrap(), 1);
+    assert_eq!(outbox.status(&id), Some(OutboxStatus::Sent { message_id }));
+
+    std::fs::remove_file(&path).ok();
+}
+
+#[tokio::test]
+async fn test_outbox_retries_message_the_server_failed_to_route() {
+    quietdrop_core::initialize();
+
+    let db = temp_outbox("route-db").with_extension("sqlite");
+    let storage = Arc::new(Storage::open(&db).unwrap());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let (server_public_key, server_secret_key) = generate_keypair();
+    let config = ServerConfig {
+        storage,
+        ..ServerConfig::default()
+    };
+    tokio::spawn(async move {
+        let _ = server::serve_with_config(listener, &server_secret_key, config).await;
+    });
+
+    let bob_identity = Identity::generate();
+    let mut bob = Client::connect(&addr, &server_public_key, &bob_identity)
+        .await
+        .unwrap();
+    bob.register("Bob", PASSWORD).await.unwrap();
+    bob.close().await.unwrap();
+
+    let identity = Identity::generate();
+    let mut client = logged_in_client(&addr, &server_public_key, &identity).await;
+    let path = temp_outbox("route");
+    let outbox = Outbox::open(&path, quick_retries()).unwrap();
+    let id = outbox
+        .push(signed_message(&identity, "Bob", "Hi Bob"))
+        .unwrap();
+
+    // Bob is offline and his queue cannot be written to
+    let sabotage = rusqlite::Connection::open(&db).unwrap();
+    sabotage
+        .execute_batch("ALTER TABLE queued_messages RENAME TO hidden_queue")
+        .unwrap();
+    assert_eq!(outbox.flush(&mut client, &identity).await.unwrap(), 0);
+    assert_eq!(outbox.status(&id), Some(OutboxStatus::Pending));
+
+    // The retry is routed, not taken for a replay of the failed attempt
+    sabotage
+        .execute_batch("ALTER TABLE hidden_queue RENAME TO queued_messages")
+        .unwrap();
+    tokio::time::sleep(outbox.next_attempt_in().unwrap()).await;
+    assert_eq!(outbox.flush(&mut client, &identity).await.unwrap(), 1);
+    assert_eq!(
+        outbox.status(&id),
+        Some(OutboxStatus::Sent {
+            message_id: id.clone()
+        })
+    );
+
+    let mut bob = Client::connect(&addr, &server_public_key, &bob_identity)
+        .await
+        .unwrap();
+    bob.login("Bob", PASSWORD).await.unwrap();
+    bob.identify("Bob", &bob_identity).await.unwrap();
+    let received = bob
+        .try_recv_message(Duration::from_secs(5))
+        .await
+        .unwrap()
+        .expect("The retried message should be delivered");
+    assert_eq!(received.id, id);
+
+    std::fs::remove_file(&path).ok();
+    std::fs::remove_file(&db).ok();
+}
+
+#[tokio::test]
+async fn test_outbox_sends_messages_older_than_replay_window() {
+    quietdrop_core::initialize();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let server_public_key = start_server(listener).await;
+    let identity = Identity::generate();
+    let mut client = logged_in_client(&addr, &server_public_key, &identity).await;
+
+    // Queued before an outage that outlasted the server's replay window
+    let mut msg = signed_message(&identity, "Bob", "Hi Bob");
+    msg.timestamp = Utc::now() - chrono::Duration::minutes(30);
+    msg.sign(identity.signing_secret_key());
+    let path = temp_outbox("outage");
+    let outbox = Outbox::open(&path, quick_retries()).unwrap();
+    let id = outbox.push(msg).unwrap();
+
+    assert_eq!(outbox.flush(&mut client, &identity).await.unwrap(), 1);
+    assert!(matches!(
+        outbox.status(&id),
+        Some(OutboxStatus::Sent { .. })
+    ));
+
+    std::fs::remove_file(&path).ok();
+}
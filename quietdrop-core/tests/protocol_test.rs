@@ -0,0 +1,268 @@
+use quietdrop_core::client::Client;
+use quietdrop_core::encryption::{generate_keypair, Identity, PublicKey};
+use quietdrop_core::error::QuietDropError;
+use quietdrop_core::framing::{read_frame, write_frame, MAX_FRAME_SIZE};
+use quietdrop_core::protocol::{
+    self, negotiate, Capability, ErrorCode, Packet, ServerResponse, PROTOCOL_VERSION,
+};
+use quietdrop_core::server;
+use quietdrop_core::transport::{self, SecureChannel};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+
+async fn start_server() -> (String, PublicKey) {
+    quietdrop_core::initialize();
+
+    let (server_public_key, server_secret_key) = generate_keypair();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+    tokio::spawn(async move {
+        let _ = server::serve(listener, &server_secret_key).await;
+    });
+
+    (addr, server_public_key)
+}
+
+/// Speaks the wire protocol by hand over the secure transport, so tests can
+/// send things a well-behaved `Client` never would.
+struct RawClient {
+    stream: TcpStream,
+    channel: SecureChannel,
+}
+
+impl RawClient {
+    async fn connect(addr: &str, server_public_key: &PublicKey) -> RawClient {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let identity = Identity::generate();
+        let channel = transport::connect(&mut stream, server_public_key, identity.secret_key())
+            .await
+            .unwrap();
+        RawClient { stream, channel }
+    }
+
+    async fn send_bytes(&mut self, bytes: &[u8]) {
+        let sealed = self.channel.seal(bytes);
+        write_frame(&mut self.stream, &sealed).await.unwrap();
+    }
+
+    async fn recv(&mut self) -> Option<Packet> {
+        let frame = read_frame(&mut self.stream, MAX_FRAME_SIZE)
+            .await
+            .unwrap()?;
+        let bytes = self.channel.open(&frame).unwrap();
+        Some(Packet::from_bytes(&bytes).unwrap())
+    }
+
+    async fn request(&mut self, packet: &Packet) -> Option<Packet> {
+        self.send_bytes(&packet.to_bytes().unwrap()).await;
+        self.recv().await
+    }
+}
+
+/// Opens a raw connection and completes the version handshake on it.
+async fn connect_raw(addr: &str, server_public_key: &PublicKey) -> RawClient {
+    let mut raw = RawClient::connect(addr, server_public_key).await;
+    match raw.request(&protocol::hello("protocol-test")).await {
+        Some(Packet::Response(ServerResponse::Welcome { .. })) => raw,
+        other => panic!("Expected a welcome, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_negotiate_picks_newest_shared_version() {
+    let (version, capabilities) = negotiate(
+        1,
+        PROTOCOL_VERSION + 5,
+        &[Capability::SignedMessages, Capability::Unknown],
+    )
+    .unwrap();
+    assert_eq!(version, PROTOCOL_VERSION);
+    assert_eq!(capabilities, vec![Capability::SignedMessages]);
+
+    assert!(negotiate(PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 3, &[]).is_err());
+    assert!(negotiate(3, 2, &[]).is_err());
+}
+
+#[test]
+fn test_unknown_capability_still_parses() {
+    let json = br#"{"Hello":{"min_version":1,"max_version":9,"capabilities":["KeyDirectory","Telepathy"],"client":"future"}}"#;
+    match Packet::from_bytes(json).unwrap() {
+        Packet::Hello { capabilities, .. } => assert_eq!(
+            capabilities,
+            vec![Capability::KeyDirectory, Capability::Unknown]
+        ),
+        other => panic!("Expected a hello, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_server_response_round_trip() {
+    let responses = [
+        ServerResponse::Welcome {
+            version: PROTOCOL_VERSION,
+            capabilities: vec![Capability::OfflineDelivery],
+        },
+        ServerResponse::Identified,
+        ServerResponse::Ack {
+            message_id: "42".to_owned(),
+        },
+        ServerResponse::error(ErrorCode::Replayed, "already delivered"),
+        ServerResponse::RateLimited {
+            retry_after: Duration::from_millis(1500),
+        },
+        ServerResponse::AuthRequired,
+    ];
+
+    for response in responses {
+        let bytes = Packet::Response(response.clone()).to_bytes().unwrap();
+        match Packet::from_bytes(&bytes).unwrap() {
+            Packet::Response(decoded) => {
+                assert_eq!(format!("{:?}", decoded), format!("{:?}", response))
+            }
+            other => panic!("Expected a response, got {:?}", other),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_malformed_packet_gets_error_response() {
+    let (addr, server_public_key) = start_server().await;
+
+    let mut raw = connect_raw(&addr, &server_public_key).await;
+    raw.send_bytes(b"this is not a packet").await;
+
+    match raw.recv().await {
+        Some(Packet::Response(ServerResponse::Error { code, .. })) => {
+            assert_eq!(code, ErrorCode::MalformedPacket)
+        }
+        other => panic!("Expected an error response, got {:?}", other),
+    }
+
+    // Then the connection is closed
+    assert!(raw.recv().await.is_none());
+}
+
+#[tokio::test]
+async fn test_lookup_before_identify_requires_auth() {
+    let (addr, server_public_key) = start_server().await;
+
+    let mut raw = connect_raw(&addr, &server_public_key).await;
+    let lookup = Packet::LookupKey {
+        identity: "Bob".to_owned(),
+    };
+    assert!(matches!(
+        raw.request(&lookup).await,
+        Some(Packet::Response(ServerResponse::AuthRequired))
+    ));
+}
+
+#[tokio::test]
+async fn test_unexpected_packet_gets_error_response() {
+    let (addr, server_public_key) = start_server().await;
+
+    // Clients may not send responses; the server says so
+    let mut raw = connect_raw(&addr, &server_public_key).await;
+    let bogus = Packet::Response(ServerResponse::Identified);
+    assert!(matches!(
+        raw.request(&bogus).await,
+        Some(Packet::Response(ServerResponse::Error {
+            code: ErrorCode::UnexpectedPacket,
+            ..
+        }))
+    ));
+
+    // The connection is still usable afterwards
+    let lookup = Packet::LookupKey {
+        identity: "Bob".to_owned(),
+    };
+    assert!(matches!(
+        raw.request(&lookup).await,
+        Some(Packet::Response(ServerResponse::AuthRequired))
+    ));
+}
+
+#[tokio::test]
+async fn test_server_rejects_incompatible_version() {
+    let (addr, server_public_key) = start_server().await;
+
+    let mut raw = RawClient::connect(&addr, &server_public_key).await;
+    let hello = Packet::Hello {
+        min_version: PROTOCOL_VERSION + 1,
+        max_version: PROTOCOL_VERSION + 2,
+        capabilities: vec![],
+        client: "from-the-future".to_owned(),
+    };
+    match raw.request(&hello).await {
+        Some(Packet::Response(ServerResponse::Error { code, reason })) => {
+            assert_eq!(code, ErrorCode::IncompatibleVersion);
+            assert!(reason.contains(&PROTOCOL_VERSION.to_string()), "{}", reason);
+        }
+        other => panic!("Expected an error response, got {:?}", other),
+    }
+
+    // Then the connection is closed
+    assert!(raw.recv().await.is_none());
+}
+
+#[tokio::test]
+async fn test_server_requires_hello_first() {
+    let (addr, server_public_key) = start_server().await;
+
+    let mut raw = RawClient::connect(&addr, &server_public_key).await;
+    let lookup = Packet::LookupKey {
+        identity: "Bob".to_owned(),
+    };
+    assert!(matches!(
+        raw.request(&lookup).await,
+        Some(Packet::Response(ServerResponse::Error {
+            code: ErrorCode::HandshakeRequired,
+            ..
+        }))
+    ));
+    assert!(raw.recv().await.is_none());
+}
+
+#[tokio::test]
+async fn test_client_negotiates_version() {
+    let (addr, server_public_key) = start_server().await;
+
+    let client = Client::connect(&addr, &server_public_key, &Identity::generate())
+        .await
+        .unwrap();
+    assert_eq!(client.protocol_version(), PROTOCOL_VERSION);
+    assert!(client.capabilities().contains(&Capability::SignedMessages));
+}
+
+#[tokio::test]
+async fn test_client_reports_incompatible_server() {
+    quietdrop_core::initialize();
+
+    // A server that only speaks a protocol this build has never heard of
+    let (server_public_key, server_secret_key) = generate_keypair();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let (mut channel, _) = transport::accept(&mut stream, &server_secret_key)
+            .await
+            .unwrap();
+        let hello = read_frame(&mut stream, MAX_FRAME_SIZE)
+            .await
+            .unwrap()
+            .unwrap();
+        channel.open(&hello).unwrap();
+        let reply = Packet::Response(ServerResponse::error(
+            ErrorCode::IncompatibleVersion,
+            "This server only speaks protocol version 99",
+        ));
+        let sealed = channel.seal(&reply.to_bytes().unwrap());
+        write_frame(&mut stream, &sealed).await.unwrap();
+    });
+
+    match Client::connect(&addr, &server_public_key, &Identity::generate()).await {
+        Err(QuietDropError::IncompatibleVersion(reason)) => assert!(reason.contains("99")),
+        Err(other) => panic!("Expected an incompatible version error, got {}", other),
+        Ok(_) => panic!("Connecting to an incompatible server should fail"),
+    }
+}
@@ -0,0 +1,158 @@
+use chrono::Utc;
+use quietdrop_core::client::Client;
+use quietdrop_core::encryption::{generate_keypair, Identity, PublicKey};
+use quietdrop_core::error::QuietDropError;
+use quietdrop_core::message::{Message, MessageType};
+use quietdrop_core::rate_limit::{RateLimitPolicy, RateLimiter};
+use quietdrop_core::server::{self, ServerConfig};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+
+const PASSWORD: &str = "correct horse battery";
+
+fn signed_message(sender: &Identity, recipient: &Identity, text: &str) -> Message {
+    let mut message = Message {
+        id: Message::new_id(),
+        timestamp: Utc::now(),
+        message_type: MessageType::Text,
+        sender: "Alice".to_owned(),
+        recipient: "Bob".to_owned(),
+        content: vec![],
+        public_key: *sender.public_key(),
+        signing_key: *sender.signing_key(),
+        signature: vec![],
+    };
+    message.encrypt_content(text, recipient.public_key(), sender.secret_key());
+    message.sign(sender.signing_secret_key());
+    message
+}
+
+async fn start_server(config: ServerConfig) -> (String, PublicKey) {
+    quietdrop_core::initialize();
+
+    let (server_public_key, server_secret_key) = generate_keypair();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let _ = server::serve_with_config(listener, &server_secret_key, config).await;
+    });
+    (addr, server_public_key)
+}
+
+#[test]
+fn test_rate_limiter_allows_exactly_the_limit() {
+    let limiter = RateLimiter::new(RateLimitPolicy::new(3, Duration::from_secs(10)));
+    let start = Instant::now();
+
+    for _ in 0..3 {
+        assert!(limiter.check_at("peer", start).is_ok());
+    }
+    let retry_after = limiter
+        .check_at("peer", start + Duration::from_secs(4))
+        .unwrap_err();
+    assert_eq!(retry_after, Duration::from_secs(6));
+
+    // Other keys have their own budget
+    assert!(limiter.check_at("other", start).is_ok());
+}
+
+#[test]
+fn test_rate_limiter_resets_after_window() {
+    let limiter = RateLimiter::new(RateLimitPolicy::new(1, Duration::from_secs(10)));
+    let start = Instant::now();
+
+    assert!(limiter.check_at("peer", start).is_ok());
+    assert!(limiter.check_at("peer", start).is_err());
+    assert!(limiter
+        .check_at("peer", start + Duration::from_secs(10))
+        .is_ok());
+}
+
+#[test]
+fn test_rate_limiter_expires_stale_entries() {
+    let limiter = RateLimiter::new(RateLimitPolicy::new(5, Duration::from_secs(10)));
+    let start = Instant::now();
+
+    for peer in 0..100 {
+        limiter.check_at(peer, start).unwrap();
+    }
+    assert_eq!(limiter.tracked_keys(), 100);
+
+    // After a full window, only keys that are still active remain
+    limiter
+        .check_at(1000, start + Duration::from_secs(11))
+        .unwrap();
+    assert_eq!(limiter.tracked_keys(), 1);
+}
+
+#[tokio::test]
+async fn test_server_rate_limits_identity() {
+    let (addr, server_public_key) = start_server(ServerConfig {
+        identity_rate_limit: Some(RateLimitPolicy::new(2, Duration::from_secs(60))),
+        ..ServerConfig::default()
+    })
+    .await;
+
+    let (alice_identity, bob_identity) = (Identity::generate(), Identity::generate());
+    let mut alice = Client::connect(&addr, &server_public_key, &alice_identity)
+        .await
+        .unwrap();
+    alice.register("Alice", PASSWORD).await.unwrap();
+    alice.identify("Alice", &alice_identity).await.unwrap();
+
+    for text in ["one", "two"] {
+        alice
+            .send(&signed_message(&alice_identity, &bob_identity, text))
+            .await
+            .unwrap();
+    }
+    let err = alice
+        .send(&signed_message(&alice_identity, &bob_identity, "three"))
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err, QuietDropError::RateLimited { retry_after } if retry_after > Duration::ZERO),
+        "{}",
+        err
+    );
+
+    // The limit follows the account, not the connection
+    let mut second = Client::connect(&addr, &server_public_key, &alice_identity)
+        .await
+        .unwrap();
+    second.login("Alice", PASSWORD).await.unwrap();
+    second.identify("Alice", &alice_identity).await.unwrap();
+    assert!(matches!(
+        second
+            .send(&signed_message(&alice_identity, &bob_identity, "four"))
+            .await,
+        Err(QuietDropError::RateLimited { .. })
+    ));
+}
+
+#[tokio::test]
+async fn test_server_rate_limits_peer() {
+    let (addr, server_public_key) = start_server(ServerConfig {
+        peer_rate_limit: Some(RateLimitPolicy::new(3, Duration::from_secs(60))),
+        ..ServerConfig::default()
+    })
+    .await;
+
+    let (alice_identity, bob_identity) = (Identity::generate(), Identity::generate());
+    let mut alice = Client::connect(&addr, &server_public_key, &alice_identity)
+        .await
+        .unwrap();
+    alice.register("Alice", PASSWORD).await.unwrap();
+    alice.identify("Alice", &alice_identity).await.unwrap();
+    alice
+        .send(&signed_message(&alice_identity, &bob_identity, "one"))
+        .await
+        .unwrap();
+
+    // Every request from the address counts, whichever connection it uses
+    let mut other = Client::connect(&addr, &server_public_key, &bob_identity)
+        .await
+        .unwrap();
+    let err = other.register("Bob", PASSWORD).await.unwrap_err();
+    assert!(matches!(err, QuietDropError::RateLimited { .. }), "{}", err);
+}
@@ -0,0 +1,219 @@
+use chrono::Utc;
+use quietdrop_core::client::Client;
+use quietdrop_core::encryption::{generate_keypair, Identity, PublicKey};
+use quietdrop_core::error::QuietDropError;
+use quietdrop_core::message::{Message, MessageType};
+use quietdrop_core::protocol::ErrorCode;
+use quietdrop_core::receipts::{MessageStatus, MessageTracker, StatusEvent};
+use quietdrop_core::server;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+const PASSWORD: &str = "correct horse battery";
+
+fn signed_message(
+    sender: &str,
+    identity: &Identity,
+    recipient: &str,
+    recipient_public_key: &PublicKey,
+) -> Message {
+    let mut msg = Message {
+        id: Message::new_id(),
+        timestamp: Utc::now(),
+        message_type: MessageType::Text,
+        sender: sender.to_owned(),
+        recipient: recipient.to_owned(),
+        content: vec![],
+        public_key: *identity.public_key(),
+        signing_key: *identity.signing_key(),
+        signature: vec![],
+    };
+    msg.encrypt_content("Hi", recipient_public_key, identity.secret_key());
+    msg.sign(identity.signing_secret_key());
+    msg
+}
+
+async fn start_server() -> (String, PublicKey) {
+    quietdrop_core::initialize();
+
+    let (server_public_key, server_secret_key) = generate_keypair();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+    tokio::spawn(async move {
+        let _ = server::serve(listener, &server_secret_key).await;
+    });
+
+    (addr, server_public_key)
+}
+
+async fn connect(
+    addr: &str,
+    server_public_key: &PublicKey,
+    name: &str,
+    identity: &Identity,
+    register: bool,
+) -> Client {
+    let mut client = Client::connect(addr, server_public_key, identity)
+        .await
+        .unwrap();
+    if register {
+        client.register(name, PASSWORD).await.unwrap();
+    } else {
+        client.login(name, PASSWORD).await.unwrap();
+    }
+    client.identify(name, identity).await.unwrap();
+    client
+}
+
+#[test]
+fn test_message_status_only_moves_forward() {
+    use MessageStatus::*;
+
+    assert_eq!(Pending.apply(StatusEvent::Accepted), Sent);
+    assert_eq!(Sent.apply(StatusEvent::Delivered), Delivered);
+    assert_eq!(Delivered.apply(StatusEvent::Read), Read);
+
+    // Receipts can overtake the acknowledgement and each other
+    assert_eq!(Pending.apply(StatusEvent::Read), Read);
+    assert_eq!(Read.apply(StatusEvent::Delivered), Read);
+    assert_eq!(Delivered.apply(StatusEvent::Accepted), Delivered);
+
+    // Only a message the server never took can fail
+    assert_eq!(Pending.apply(StatusEvent::Failed), Failed);
+    assert_eq!(Sent.apply(StatusEvent::Failed), Sent);
+    assert_eq!(Failed.apply(StatusEvent::Delivered), Delivered);
+}
+
+#[test]
+fn test_tracker_ignores_untracked_messages() {
+    let mut tracker = MessageTracker::new();
+    tracker.track("one");
+    assert_eq!(tracker.status("one"), Some(MessageStatus::Pending));
+
+    assert_eq!(
+        tracker.apply("one", StatusEvent::Delivered),
+        Some(MessageStatus::Delivered)
+    );
+    assert_eq!(tracker.apply("two", StatusEvent::Read), None);
+    assert_eq!(tracker.status("two"), None);
+
+    // Tracking again keeps the status reached so far
+    tracker.track("one");
+    assert_eq!(tracker.status("one"), Some(MessageStatus::Delivered));
+    assert_eq!(tracker.forget("one"), Some(MessageStatus::Delivered));
+    assert!(tracker.statuses().is_empty());
+}
+
+#[test]
+fn test_read_receipt_is_sealed_and_signed() {
+    quietdrop_core::initialize();
+
+    let alice = Identity::generate();
+    let bob = Identity::generate();
+    let original = signed_message("Alice", &alice, "Bob", bob.public_key());
+
+    let receipt = Message::read_receipt(&original, "Bob", &bob);
+    assert_eq!(receipt.message_type, MessageType::ReadReceipt);
+    assert_eq!(receipt.recipient, "Alice");
+    assert_ne!(receipt.id, original.id);
+    receipt.verify_signature().unwrap();
+    assert_eq!(
+        receipt.read_message_id(alice.secret_key()).unwrap(),
+        Some(original.id.clone())
+    );
+    assert!(receipt.read_message_id(bob.secret_key()).is_err());
+
+    // Ordinary messages are not receipts
+    assert_eq!(original.read_message_id(bob.secret_key()).unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_sender_is_told_of_delivery_and_read() {
+    let (addr, server_public_key) = start_server().await;
+    let alice_identity = Identity::generate();
+    let bob_identity = Identity::generate();
+    let mut alice = connect(&addr, &server_public_key, "Alice", &alice_identity, true).await;
+    let mut bob = connect(&addr, &server_public_key, "Bob", &bob_identity, true).await;
+
+    let msg = signed_message("Alice", &alice_identity, "Bob", bob_identity.public_key());
+    assert_eq!(alice.send(&msg).await.unwrap(), msg.id);
+
+    let received = bob
+        .try_recv_message(Duration::from_secs(5))
+        .await
+        .unwrap()
+        .expect("Bob should receive the message");
+    bob.send_read_receipt(&received, "Bob", &bob_identity)
+        .await
+        .unwrap();
+
+    let receipt = alice
+        .try_recv_message(Duration::from_secs(5))
+        .await
+        .unwrap()
+        .expect("Alice should receive the read receipt");
+    assert_eq!(
+        receipt
+            .read_message_id(alice_identity.secret_key())
+            .unwrap(),
+        Some(msg.id.clone())
+    );
+    let delivered = alice.take_delivery_receipts();
+    assert_eq!(delivered.len(), 1);
+    assert_eq!(delivered[0].message_id, msg.id);
+    assert_eq!(delivered[0].recipient, "Bob");
+
+    // The read receipt itself is not acknowledged with a delivery receipt
+    assert!(bob
+        .try_recv_message(Duration::from_millis(200))
+        .await
+        .unwrap()
+        .is_none());
+    assert!(bob.take_delivery_receipts().is_empty());
+}
+
+#[tokio::test]
+async fn test_delivery_receipt_waits_for_offline_sender() {
+    let (addr, server_public_key) = start_server().await;
+    let alice_identity = Identity::generate();
+    let bob_identity = Identity::generate();
+    let bob = connect(&addr, &server_public_key, "Bob", &bob_identity, true).await;
+    bob.close().await.unwrap();
+
+    let mut alice = connect(&addr, &server_public_key, "Alice", &alice_identity, true).await;
+    let msg = signed_message("Alice", &alice_identity, "Bob", bob_identity.public_key());
+    alice.send(&msg).await.unwrap();
+    alice.close().await.unwrap();
+
+    // Bob comes online while Alice is away
+    let mut bob = connect(&addr, &server_public_key, "Bob", &bob_identity, false).await;
+    bob.try_recv_message(Duration::from_secs(5))
+        .await
+        .unwrap()
+        .expect("Queued message should be delivered on connect");
+
+    let mut alice = connect(&addr, &server_public_key, "Alice", &alice_identity, false).await;
+    alice
+        .try_recv_message(Duration::from_millis(200))
+        .await
+        .unwrap();
+    let delivered = alice.take_delivery_receipts();
+    assert_eq!(delivered.len(), 1);
+    assert_eq!(delivered[0].message_id, msg.id);
+}
+
+#[tokio::test]
+async fn test_server_rejects_invalid_message_id() {
+    let (addr, server_public_key) = start_server().await;
+    let identity = Identity::generate();
+    let mut alice = connect(&addr, &server_public_key, "Alice", &identity, true).await;
+
+    let mut msg = signed_message("Alice", &identity, "Bob", &generate_keypair().0);
+    msg.id = "message-1".to_owned();
+    msg.sign(identity.signing_secret_key());
+    match alice.send(&msg).await.unwrap_err() {
+        QuietDropError::Server { code, .. } => assert_eq!(code, ErrorCode::InvalidMessageId),
+        other => panic!("Expected an invalid message id error, got {}", other),
+    }
+}
@@ -0,0 +1,229 @@
+use chrono::{Duration as TimeDelta, Utc};
+use quietdrop_core::client::Client;
+use quietdrop_core::encryption::{generate_keypair, Identity};
+use quietdrop_core::error::QuietDropError;
+use quietdrop_core::message::{Message, MessageType};
+use quietdrop_core::protocol::ErrorCode;
+use quietdrop_core::replay::{Admission, Rejection, ReplayCache};
+use quietdrop_core::server::{self, ServerConfig};
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+const PASSWORD: &str = "correct horse battery";
+
+fn signed_message(sender: &Identity, recipient: &Identity, text: &str) -> Message {
+    let mut message = Message {
+        id: Message::new_id(),
+        timestamp: Utc::now(),
+        message_type: MessageType::Text,
+        sender: "Alice".to_owned(),
+        recipient: "Bob".to_owned(),
+        content: vec![],
+        public_key: *sender.public_key(),
+        signing_key: *sender.signing_key(),
+        signature: vec![],
+    };
+    message.encrypt_content(text, recipient.public_key(), sender.secret_key());
+    message.sign(sender.signing_secret_key());
+    message
+}
+
+#[test]
+fn test_replay_cache_rejects_repeated_nonce() {
+    quietdrop_core::initialize();
+    let cache = ReplayCache::new(Duration::from_secs(60));
+    let (alice, bob) = (Identity::generate(), Identity::generate());
+
+    let message = signed_message(&alice, &bob, "once");
+    assert!(matches!(cache.check(&message), Ok(Admission::New(_))));
+    assert_eq!(cache.check(&message), Err(Rejection::Replayed));
+
+    // A new message with a fresh nonce is fine
+    let another = signed_message(&alice, &bob, "once");
+    assert!(matches!(cache.check(&another), Ok(Admission::New(_))));
+    assert_eq!(cache.len(), 2);
+}
+
+#[test]
+fn test_replay_cache_frees_unrouted_and_acknowledges_resigned_messages() {
+    quietdrop_core::initialize();
+    let cache = ReplayCache::new(Duration::from_secs(60));
+    let (alice, bob) = (Identity::generate(), Identity::generate());
+    let mut message = signed_message(&alice, &bob, "hello");
+
+    // A message the server failed to route can be sent again
+    let pending = match cache.check(&message) {
+        Ok(Admission::New(pending)) => pending,
+        other => panic!("Expected a new message, got {:?}", other),
+    };
+    cache.forget(pending);
+    let pending = match cache.check(&message) {
+        Ok(Admission::New(pending)) => pending,
+        other => panic!("Expected a new message, got {:?}", other),
+    };
+    cache.accept(pending);
+
+    // Once accepted, an identical copy is a replay
+    assert_eq!(cache.check(&message), Err(Rejection::Replayed));
+
+    // The sender re-signing it after a lost acknowledgement is not
+    message.timestamp += TimeDelta::seconds(1);
+    message.sign(alice.signing_secret_key());
+    assert_eq!(cache.check(&message), Ok(Admission::Duplicate));
+
+    // Nor does another message reusing the nonce get through
+    let mut other = message.clone();
+    other.id = Message::new_id();
+    other.sign(alice.signing_secret_key());
+    assert_eq!(cache.check(&other), Err(Rejection::Replayed));
+}
+
+#[test]
+fn test_replay_cache_rejects_stale_and_future_timestamps() {
+    quietdrop_core::initialize();
+    let cache = ReplayCache::new(Duration::from_secs(60));
+    let (alice, bob) = (Identity::generate(), Identity::generate());
+    let message = signed_message(&alice, &bob, "hello");
+
+    let too_late = message.timestamp + TimeDelta::seconds(61);
+    assert_eq!(cache.check_at(&message, too_late), Err(Rejection::Stale));
+
+    let too_early = message.timestamp - TimeDelta::seconds(61);
+    assert_eq!(cache.check_at(&message, too_early), Err(Rejection::Stale));
+
+    let in_window = message.timestamp + TimeDelta::seconds(59);
+    assert!(matches!(
+        cache.check_at(&message, in_window),
+        Ok(Admission::New(_))
+    ));
+}
+
+#[test]
+fn test_replay_cache_forgets_expired_entries() {
+    quietdrop_core::initialize();
+    let cache = ReplayCache::new(Duration::from_secs(60));
+    let (alice, bob) = (Identity::generate(), Identity::generate());
+
+    let old = signed_message(&alice, &bob, "old");
+    cache.check(&old).unwrap();
+
+    // Once the first message is out of the window it no longer takes up space
+    let mut newer = signed_message(&alice, &bob, "newer");
+    newer.timestamp = old.timestamp + TimeDelta::seconds(90);
+    cache
+        .check_at(&newer, old.timestamp + TimeDelta::seconds(90))
+        .unwrap();
+    assert_eq!(cache.len(), 1);
+}
+
+#[test]
+fn test_replay_cache_sweeps_once_per_window() {
+    quietdrop_core::initialize();
+    let cache = ReplayCache::new(Duration::from_secs(60));
+    let (alice, bob) = (Identity::generate(), Identity::generate());
+    let start = Utc::now();
+
+    let mut first = signed_message(&alice, &bob, "first");
+    first.timestamp = start - TimeDelta::seconds(50);
+    cache.check_at(&first, start).unwrap();
+
+    // The first message has expired, but the cache was swept less than a
+    // window ago, so it is still held
+    let mut second = signed_message(&alice, &bob, "second");
+    second.timestamp = start + TimeDelta::seconds(20);
+    cache
+        .check_at(&second, start + TimeDelta::seconds(20))
+        .unwrap();
+    assert_eq!(cache.len(), 2);
+
+    let mut third = signed_message(&alice, &bob, "third");
+    third.timestamp = start + TimeDelta::seconds(61);
+    cache
+        .check_at(&third, start + TimeDelta::seconds(61))
+        .unwrap();
+    assert_eq!(cache.len(), 2);
+}
+
+#[tokio::test]
+async fn test_server_rejects_replayed_message() {
+    quietdrop_core::initialize();
+
+    let (server_public_key, server_secret_key) = generate_keypair();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let config = ServerConfig {
+        replay_window: Duration::from_secs(60),
+        ..ServerConfig::default()
+    };
+    tokio::spawn(async move {
+        let _ = server::serve_with_config(listener, &server_secret_key, config).await;
+    });
+
+    let (alice_identity, bob_identity) = (Identity::generate(), Identity::generate());
+    let mut bob = Client::connect(&addr, &server_public_key, &bob_identity)
+        .await
+        .unwrap();
+    bob.register("Bob", PASSWORD).await.unwrap();
+    bob.identify("Bob", &bob_identity).await.unwrap();
+    let mut alice = Client::connect(&addr, &server_public_key, &alice_identity)
+        .await
+        .unwrap();
+    alice.register("Alice", PASSWORD).await.unwrap();
+    alice.identify("Alice", &alice_identity).await.unwrap();
+    let bob_public_key = alice
+        .lookup_key("Bob", &server_public_key)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&bob_public_key, bob_identity.public_key());
+
+    let message = signed_message(&alice_identity, &bob_identity, "Transfer 10 coins");
+    alice.send(&message).await.unwrap();
+
+    // Resending the captured message is refused, but the connection stays usable
+    let err = alice.send(&message).await.unwrap_err();
+    assert!(
+        matches!(
+            err,
+            QuietDropError::Server {
+                code: ErrorCode::Replayed,
+                ..
+            }
+        ),
+        "{}",
+        err
+    );
+
+    // So is a message signed too long ago
+    let mut stale = signed_message(&alice_identity, &bob_identity, "Old news");
+    stale.timestamp = Utc::now() - TimeDelta::minutes(5);
+    stale.sign(alice_identity.signing_secret_key());
+    let err = alice.send(&stale).await.unwrap_err();
+    assert!(
+        matches!(
+            err,
+            QuietDropError::Server {
+                code: ErrorCode::Stale,
+                ..
+            }
+        ),
+        "{}",
+        err
+    );
+
+    // Bob only ever sees the original once
+    let received = bob
+        .try_recv_message(Duration::from_secs(5))
+        .await
+        .unwrap()
+        .expect("The original message should be delivered");
+    assert_eq!(
+        received.decrypt_content(bob_identity.secret_key()).unwrap(),
+        "Transfer 10 coins"
+    );
+    assert!(bob
+        .try_recv_message(Duration::from_millis(200))
+        .await
+        .unwrap()
+        .is_none());
+}
@@ -0,0 +1,241 @@
+use chrono::Utc;
+use quietdrop_core::client::Client;
+use quietdrop_core::encryption::{generate_keypair, generate_signing_keypair, Identity, PublicKey};
+use quietdrop_core::message::{Message, MessageType};
+use quietdrop_core::protocol::Packet;
+use quietdrop_core::routing::{Delivery, Router};
+use quietdrop_core::server;
+use quietdrop_core::storage::{ReceiptStatus, Storage};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+const PASSWORD: &str = "correct horse battery";
+
+fn test_message(recipient: &str, content: Vec<u8>) -> Message {
+    Message {
+        id: Message::new_id(),
+        timestamp: Utc::now(),
+        message_type: MessageType::Text,
+        sender: "Alice".to_owned(),
+        recipient: recipient.to_owned(),
+        content,
+        public_key: generate_keypair().0,
+        signing_key: generate_signing_keypair().0,
+        signature: vec![],
+    }
+}
+
+async fn start_server() -> (String, PublicKey) {
+    quietdrop_core::initialize();
+
+    let (server_public_key, server_secret_key) = generate_keypair();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+    tokio::spawn(async move {
+        let _ = server::serve(listener, &server_secret_key).await;
+    });
+
+    (addr, server_public_key)
+}
+
+#[tokio::test]
+async fn test_router_queues_for_offline_recipient() {
+    let router = Router::new(Arc::new(Storage::in_memory().unwrap()));
+
+    assert_eq!(
+        router.route(test_message("Bob", vec![1])).await.unwrap(),
+        Delivery::Queued
+    );
+    assert_eq!(
+        router.route(test_message("Bob", vec![2])).await.unwrap(),
+        Delivery::Queued
+    );
+    assert_eq!(router.queued_count("Bob").await.unwrap(), 2);
+
+    // Connecting flushes the queue in order
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    router.connect("Bob", tx).await.unwrap();
+    assert_eq!(router.queued_count("Bob").await.unwrap(), 0);
+
+    for expected in [1u8, 2] {
+        match rx.try_recv() {
+            Ok(Packet::Message(msg)) => assert_eq!(msg.content, vec![expected]),
+            other => panic!("Expected a queued message, got {:?}", other),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_router_delivers_to_online_recipient() {
+    let router = Router::new(Arc::new(Storage::in_memory().unwrap()));
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let registration = router.connect("Bob", tx).await.unwrap();
+    assert!(router.is_online("Bob"));
+
+    assert_eq!(
+        router.route(test_message("Bob", vec![7])).await.unwrap(),
+        Delivery::Forwarded
+    );
+    assert!(matches!(rx.try_recv(), Ok(Packet::Message(_))));
+
+    // Once disconnected, messages are queued again
+    router.disconnect("Bob", registration);
+    assert!(!router.is_online("Bob"));
+    assert_eq!(
+        router.route(test_message("Bob", vec![8])).await.unwrap(),
+        Delivery::Queued
+    );
+}
+
+#[tokio::test]
+async fn test_router_keeps_newer_connection() {
+    let router = Router::new(Arc::new(Storage::in_memory().unwrap()));
+    let (old_tx, _old_rx) = mpsc::unbounded_channel();
+    let (new_tx, mut new_rx) = mpsc::unbounded_channel();
+
+    let old_registration = router.connect("Bob", old_tx).await.unwrap();
+    router.connect("Bob", new_tx).await.unwrap();
+
+    // The stale connection going away must not unregister the new one
+    router.disconnect("Bob", old_registration);
+    assert!(router.is_online("Bob"));
+
+    router.route(test_message("Bob", vec![9])).await.unwrap();
+    assert!(matches!(new_rx.try_recv(), Ok(Packet::Message(_))));
+}
+
+#[tokio::test]
+async fn test_router_receipts_delivery_once_written() {
+    let storage = Arc::new(Storage::in_memory().unwrap());
+    let router = Router::new(Arc::clone(&storage));
+    let (alice_tx, mut alice_rx) = mpsc::unbounded_channel();
+    let (bob_tx, mut bob_rx) = mpsc::unbounded_channel();
+    router.connect("Alice", alice_tx).await.unwrap();
+    router.connect("Bob", bob_tx).await.unwrap();
+
+    let msg = test_message("Bob", vec![1]);
+    assert_eq!(
+        router.route(msg.clone()).await.unwrap(),
+        Delivery::Forwarded
+    );
+    assert!(matches!(bob_rx.try_recv(), Ok(Packet::Message(_))));
+
+    // Handing the message to Bob's connection is not delivery yet
+    assert!(alice_rx.try_recv().is_err());
+    assert!(storage.receipts("Alice", &msg.id).unwrap().is_empty());
+
+    router.delivered(&msg).await.unwrap();
+    match alice_rx.try_recv() {
+        Ok(Packet::Delivered(receipt)) => assert_eq!(receipt.message_id, msg.id),
+        other => panic!("Expected a delivery receipt, got {:?}", other),
+    }
+    let statuses: Vec<ReceiptStatus> = storage
+        .receipts("Alice", &msg.id)
+        .unwrap()
+        .into_iter()
+        .map(|receipt| receipt.status)
+        .collect();
+    assert_eq!(statuses, [ReceiptStatus::Delivered]);
+}
+
+#[tokio::test]
+async fn test_server_delivers_to_connected_recipient() {
+    let (addr, server_public_key) = start_server().await;
+
+    let bob_identity = Identity::generate();
+    let mut bob = Client::connect(&addr, &server_public_key, &bob_identity)
+        .await
+        .unwrap();
+    bob.register("Bob", PASSWORD).await.unwrap();
+    bob.identify("Bob", &bob_identity).await.unwrap();
+
+    let alice_identity = Identity::generate();
+    let mut alice = Client::connect(&addr, &server_public_key, &alice_identity)
+        .await
+        .unwrap();
+    alice.register("Alice", PASSWORD).await.unwrap();
+    alice.identify("Alice", &alice_identity).await.unwrap();
+
+    let bob_public_key = alice
+        .lookup_key("Bob", &server_public_key)
+        .await
+        .unwrap()
+        .expect("Bob's key should be published");
+
+    let mut msg = test_message("Bob", vec![]);
+    msg.public_key = *alice_identity.public_key();
+    msg.signing_key = *alice_identity.signing_key();
+    msg.encrypt_content("Hi Bob", &bob_public_key, alice_identity.secret_key());
+    msg.sign(alice_identity.signing_secret_key());
+    alice.send(&msg).await.unwrap();
+
+    let received = bob
+        .try_recv_message(Duration::from_secs(5))
+        .await
+        .unwrap()
+        .expect("Bob should receive the message");
+    assert_eq!(received.sender, "Alice");
+    assert_eq!(
+        received.decrypt_content(bob_identity.secret_key()).unwrap(),
+        "Hi Bob"
+    );
+}
+
+#[tokio::test]
+async fn test_server_stores_and_forwards_to_offline_recipient() {
+    let (addr, server_public_key) = start_server().await;
+
+    // Carol publishes her key, then goes offline
+    let carol_identity = Identity::generate();
+    let mut carol = Client::connect(&addr, &server_public_key, &carol_identity)
+        .await
+        .unwrap();
+    carol.register("Carol", PASSWORD).await.unwrap();
+    carol.identify("Carol", &carol_identity).await.unwrap();
+    carol.close().await.unwrap();
+
+    let alice_identity = Identity::generate();
+    let mut alice = Client::connect(&addr, &server_public_key, &alice_identity)
+        .await
+        .unwrap();
+    alice.register("Alice", PASSWORD).await.unwrap();
+    alice.identify("Alice", &alice_identity).await.unwrap();
+    let carol_public_key = alice
+        .lookup_key("Carol", &server_public_key)
+        .await
+        .unwrap()
+        .expect("Carol's key should stay published while she is offline");
+
+    for text in ["first", "second"] {
+        let mut msg = test_message("Carol", vec![]);
+        msg.public_key = *alice_identity.public_key();
+        msg.signing_key = *alice_identity.signing_key();
+        msg.encrypt_content(text, &carol_public_key, alice_identity.secret_key());
+        msg.sign(alice_identity.signing_secret_key());
+        alice.send(&msg).await.unwrap();
+    }
+
+    // Carol reconnects later and receives both messages in order
+    let mut carol = Client::connect(&addr, &server_public_key, &carol_identity)
+        .await
+        .unwrap();
+    carol.login("Carol", PASSWORD).await.unwrap();
+    carol.identify("Carol", &carol_identity).await.unwrap();
+
+    for expected in ["first", "second"] {
+        let received = carol
+            .try_recv_message(Duration::from_secs(5))
+            .await
+            .unwrap()
+            .expect("Queued message should be delivered on connect");
+        assert_eq!(
+            received
+                .decrypt_content(carol_identity.secret_key())
+                .unwrap(),
+            expected
+        );
+    }
+}
@@ -0,0 +1,211 @@
+use quietdrop_core::client::Client;
+use quietdrop_core::encryption::{generate_keypair, Identity, PublicKey};
+use quietdrop_core::error::QuietDropError;
+use quietdrop_core::protocol::ErrorCode;
+use quietdrop_core::server::{self, ServerConfig};
+use quietdrop_core::sessions::{SavedSession, Sessions, SESSION_TOKEN_FILE};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+const PASSWORD: &str = "correct horse battery";
+
+async fn start_server(config: ServerConfig) -> (String, PublicKey) {
+    quietdrop_core::initialize();
+
+    let (server_public_key, server_secret_key) = generate_keypair();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+    tokio::spawn(async move {
+        let _ = server::serve_with_config(listener, &server_secret_key, config).await;
+    });
+
+    (addr, server_public_key)
+}
+
+async fn connect(addr: &str, server_public_key: &PublicKey, identity: &Identity) -> Client {
+    Client::connect(addr, server_public_key, identity)
+        .await
+        .unwrap()
+}
+
+fn assert_invalid_session(err: QuietDropError) {
+    assert!(
+        matches!(
+            err,
+            QuietDropError::Server {
+                code: ErrorCode::InvalidSession,
+                ..
+            }
+        ),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn test_sessions_issue_validate_and_revoke() {
+    quietdrop_core::initialize();
+    let sessions = Sessions::new(Duration::from_secs(60));
+
+    let first = sessions.issue("Alice");
+    let second = sessions.issue("Alice");
+    let bob = sessions.issue("Bob");
+    assert_ne!(first.token, second.token);
+    assert_eq!(first.expires_in, Duration::from_secs(60));
+
+    let (account, remaining) = sessions.validate(&first.token).unwrap();
+    assert_eq!(account, "Alice");
+    assert!(remaining <= Duration::from_secs(60));
+    assert!(sessions.validate("not a token").is_none());
+
+    assert!(sessions.revoke(&first.token));
+    assert!(!sessions.revoke(&first.token));
+    assert!(sessions.validate(&first.token).is_none());
+
+    assert_eq!(sessions.revoke_account("Alice"), 1);
+    assert!(sessions.validate(&second.token).is_none());
+    assert!(sessions.validate(&bob.token).is_some());
+}
+
+#[test]
+fn test_sessions_expire() {
+    quietdrop_core::initialize();
+    let sessions = Sessions::new(Duration::from_millis(50));
+
+    let session = sessions.issue("Alice");
+    assert!(sessions.validate(&session.token).is_some());
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(sessions.validate(&session.token).is_none());
+    assert!(sessions.refresh(&session.token).is_none());
+}
+
+#[test]
+fn test_sessions_refresh_replaces_token() {
+    quietdrop_core::initialize();
+    let sessions = Sessions::new(Duration::from_secs(60));
+
+    let old = sessions.issue("Alice");
+    let (account, new) = sessions.refresh(&old.token).unwrap();
+    assert_eq!(account, "Alice");
+    assert_ne!(old.token, new.token);
+    assert!(sessions.validate(&old.token).is_none());
+    assert!(sessions.validate(&new.token).is_some());
+}
+
+#[tokio::test]
+async fn test_client_resumes_with_token() {
+    let (addr, server_public_key) = start_server(ServerConfig::default()).await;
+    let identity = Identity::generate();
+
+    let mut first = connect(&addr, &server_public_key, &identity).await;
+    let session = first.register("Alice", PASSWORD).await.unwrap().session;
+    first.close().await.unwrap();
+
+    // A new connection logs in with the token alone
+    let mut second = connect(&addr, &server_public_key, &identity).await;
+    let resumed = second.resume(&session.token).await.unwrap();
+    assert_eq!(resumed.token, session.token);
+    assert!(resumed.expires_in <= session.expires_in);
+    second.identify("Alice", &identity).await.unwrap();
+
+    let mut third = connect(&addr, &server_public_key, &identity).await;
+    assert_invalid_session(third.resume("not a token").await.unwrap_err());
+}
+
+#[tokio::test]
+async fn test_refresh_session_over_the_wire() {
+    let (addr, server_public_key) = start_server(ServerConfig::default()).await;
+    let identity = Identity::generate();
+
+    let mut client = connect(&addr, &server_public_key, &identity).await;
+    assert!(matches!(
+        client.refresh_session().await,
+        Err(QuietDropError::AuthRequired)
+    ));
+
+    let old = client.register("Alice", PASSWORD).await.unwrap().session;
+    let new = client.refresh_session().await.unwrap();
+    assert_ne!(old.token, new.token);
+
+    let mut other = connect(&addr, &server_public_key, &identity).await;
+    assert_invalid_session(other.resume(&old.token).await.unwrap_err());
+    other.resume(&new.token).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_logout_revokes_token() {
+    let (addr, server_public_key) = start_server(ServerConfig::default()).await;
+    let identity = Identity::generate();
+
+    let mut client = connect(&addr, &server_public_key, &identity).await;
+    let session = client.register("Alice", PASSWORD).await.unwrap().session;
+    client.identify("Alice", &identity).await.unwrap();
+    client.logout().await.unwrap();
+
+    let err = client.identify("Alice", &identity).await.unwrap_err();
+    assert!(matches!(err, QuietDropError::AuthRequired), "{}", err);
+
+    let mut other = connect(&addr, &server_public_key, &identity).await;
+    assert_invalid_session(other.resume(&session.token).await.unwrap_err());
+}
+
+#[tokio::test]
+async fn test_server_side_revocation() {
+    let sessions = Arc::new(Sessions::default());
+    let (addr, server_public_key) = start_server(ServerConfig {
+        sessions: Arc::clone(&sessions),
+        ..ServerConfig::default()
+    })
+    .await;
+    let identity = Identity::generate();
+
+    let mut client = connect(&addr, &server_public_key, &identity).await;
+    let session = client.register("Alice", PASSWORD).await.unwrap().session;
+    assert_eq!(sessions.revoke_account("Alice"), 1);
+
+    let mut other = connect(&addr, &server_public_key, &identity).await;
+    assert_invalid_session(other.resume(&session.token).await.unwrap_err());
+    // The password still works
+    other.login("Alice", PASSWORD).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_expired_token_is_refused() {
+    let (addr, server_public_key) = start_server(ServerConfig {
+        sessions: Arc::new(Sessions::new(Duration::from_millis(100))),
+        ..ServerConfig::default()
+    })
+    .await;
+    let identity = Identity::generate();
+
+    let mut client = connect(&addr, &server_public_key, &identity).await;
+    let session = client.register("Alice", PASSWORD).await.unwrap().session;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let mut other = connect(&addr, &server_public_key, &identity).await;
+    assert_invalid_session(other.resume(&session.token).await.unwrap_err());
+}
+
+#[test]
+fn test_saved_session_round_trip() {
+    quietdrop_core::initialize();
+    let dir = std::env::temp_dir().join(format!(
+        "quietdrop-session-{}-{}",
+        std::process::id(),
+        rand::random::<u32>()
+    ));
+    let saved = SavedSession::new(dir.join(SESSION_TOKEN_FILE));
+
+    assert_eq!(saved.token().unwrap(), None);
+    saved.save("0123abcd").unwrap();
+    assert_eq!(saved.token().unwrap().as_deref(), Some("0123abcd"));
+
+    saved.clear().unwrap();
+    assert_eq!(saved.token().unwrap(), None);
+    // Clearing twice is fine
+    saved.clear().unwrap();
+
+    std::fs::remove_dir_all(&dir).ok();
+}
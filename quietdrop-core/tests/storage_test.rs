@@ -0,0 +1,311 @@
+use chrono::Utc;
+use quietdrop_core::authentication::{self, Credential, CredentialStore};
+use quietdrop_core::client::Client;
+use quietdrop_core::encryption::{generate_keypair, generate_signing_keypair, Identity, SecretKey};
+use quietdrop_core::error::QuietDropError;
+use quietdrop_core::message::{Message, MessageType};
+use quietdrop_core::server;
+use quietdrop_core::storage::{ReceiptStatus, Storage};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+const PASSWORD: &str = "correct horse battery";
+
+fn temp_database(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "quietdrop-storage-{}-{}-{}.db",
+        name,
+        std::process::id(),
+        rand::random::<u32>()
+    ))
+}
+
+fn test_message(recipient: &str, content: Vec<u8>) -> Message {
+    Message {
+        id: Message::new_id(),
+        timestamp: Utc::now(),
+        message_type: MessageType::Text,
+        sender: "Alice".to_owned(),
+        recipient: recipient.to_owned(),
+        content,
+        public_key: generate_keypair().0,
+        signing_key: generate_signing_keypair().0,
+        signature: vec![],
+    }
+}
+
+async fn start_server(
+    storage: Arc<Storage>,
+    server_secret_key: SecretKey,
+) -> (String, JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let config = server::ServerConfig {
+        storage,
+        ..server::ServerConfig::default()
+    };
+    let handle = tokio::spawn(async move {
+        let _ = server::serve_with_config(listener, &server_secret_key, config).await;
+    });
+    (addr, handle)
+}
+
+#[test]
+fn test_storage_migrates_new_database() {
+    let path = temp_database("migrate");
+    let storage = Storage::open(&path).unwrap();
+    let version = storage.schema_version().unwrap();
+    assert!(version >= 1);
+    assert_eq!(storage.path(), Some(path.as_path()));
+    drop(storage);
+
+    // Reopening an up-to-date database applies nothing again
+    let reopened = Storage::open(&path).unwrap();
+    assert_eq!(reopened.schema_version().unwrap(), version);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    std::fs::remove_file(&path).ok();
+}
+
+#[test]
+fn test_storage_rejects_newer_schema() {
+    let path = temp_database("newer");
+    drop(Storage::open(&path).unwrap());
+
+    let connection = rusqlite::Connection::open(&path).unwrap();
+    connection.pragma_update(None, "user_version", 999).unwrap();
+    drop(connection);
+
+    let result = Storage::open(&path);
+    assert!(matches!(result, Err(QuietDropError::Storage(_))));
+
+    std::fs::remove_file(&path).ok();
+}
+
+#[test]
+fn test_storage_persists_across_reopen() {
+    quietdrop_core::initialize();
+
+    let path = temp_database("persist");
+    let (hash, _) = authentication::hash_password("alice password").unwrap();
+    let identity = Identity::generate();
+
+    let storage = Storage::open(&path).unwrap();
+    assert!(storage.insert("Alice", &Credential::new(&hash)).unwrap());
+    storage
+        .publish_keys("Alice", identity.public_key(), identity.signing_key())
+        .unwrap();
+    storage
+        .enqueue("message-1", &test_message("Bob", vec![1]))
+        .unwrap();
+    storage
+        .enqueue("message-2", &test_message("Bob", vec![2]))
+        .unwrap();
+    storage
+        .record_receipt("Alice", "message-1", "Bob", ReceiptStatus::Queued)
+        .unwrap();
+    drop(storage);
+
+    let storage = Storage::open(&path).unwrap();
+    assert_eq!(storage.get("Alice").unwrap(), Some(Credential::new(&hash)));
+    assert_eq!(
+        storage.public_keys("Alice").unwrap(),
+        Some((*identity.public_key(), *identity.signing_key()))
+    );
+    assert_eq!(storage.public_keys("Bob").unwrap(), None);
+
+    // The queue comes back in order, and taking it empties it
+    assert_eq!(storage.queued_count("Bob").unwrap(), 2);
+    let queued = storage.take_queued("Bob").unwrap();
+    let ids: Vec<&str> = queued.iter().map(|(id, _)| id.as_str()).collect();
+    assert_eq!(ids, ["message-1", "message-2"]);
+    assert_eq!(queued[1].1.content, vec![2]);
+    assert_eq!(storage.queued_count("Bob").unwrap(), 0);
+
+    storage
+        .record_receipt("Alice", "message-1", "Bob", ReceiptStatus::Delivered)
+        .unwrap();
+    let statuses: Vec<ReceiptStatus> = storage
+        .receipts("Alice", "message-1")
+        .unwrap()
+        .into_iter()
+        .map(|receipt| receipt.status)
+        .collect();
+    assert_eq!(statuses, [ReceiptStatus::Queued, ReceiptStatus::Delivered]);
+
+    std::fs::remove_file(&path).ok();
+}
+
+#[test]
+fn test_storage_scopes_message_ids_to_sender() {
+    let storage = Storage::in_memory().unwrap();
+    let from_alice = test_message("Bob", vec![1]);
+    let mut from_mallory = test_message("Bob", vec![2]);
+    from_mallory.sender = "Mallory".to_owned();
+
+    // Another sender reusing an ID neither displaces nor blocks the message
+    storage.enqueue("message-1", &from_mallory).unwrap();
+    storage.enqueue("message-1", &from_alice).unwrap();
+    storage.enqueue("message-1", &from_alice).unwrap();
+    let queued = storage.take_queued("Bob").unwrap();
+    let senders: Vec<&str> = queued.iter().map(|(_, msg)| msg.sender.as_str()).collect();
+    assert_eq!(senders, ["Mallory", "Alice"]);
+
+    storage
+        .record_receipt("Alice", "message-1", "Bob", ReceiptStatus::Queued)
+        .unwrap();
+    storage
+        .record_receipt("Mallory", "message-1", "Bob", ReceiptStatus::Delivered)
+        .unwrap();
+    let receipts = storage.receipts("Alice", "message-1").unwrap();
+    assert_eq!(receipts.len(), 1);
+    assert_eq!(receipts[0].sender, "Alice");
+    assert_eq!(receipts[0].status, ReceiptStatus::Queued);
+}
+
+#[test]
+fn test_storage_migrates_queue_to_sender_scoped_ids() {
+    let path = temp_database("scoped-ids");
+    let msg = test_message("Bob", vec![1]);
+
+    // The queue and receipts as the second schema version left them
+    let connection = rusqlite::Connection::open(&path).unwrap();
+    connection
+        .execute_batch(
+            "CREATE TABLE queued_messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                message_id TEXT NOT NULL UNIQUE,
+                recipient TEXT NOT NULL,
+                message TEXT NOT NULL,
+                queued_at TEXT NOT NULL
+            );
+            CREATE TABLE receipts (
+                message_id TEXT NOT NULL,
+                recipient TEXT NOT NULL,
+                status TEXT NOT NULL,
+                recorded_at TEXT NOT NULL,
+                PRIMARY KEY (message_id, status)
+            );
+            PRAGMA user_version = 2;",
+        )
+        .unwrap();
+    connection
+        .execute(
+            "INSERT INTO queued_messages (message_id, recipient, message, queued_at)
+             VALUES (?1, 'Bob', ?2, ?3)",
+            rusqlite::params![msg.id, serde_json::to_string(&msg).unwrap(), Utc::now()],
+        )
+        .unwrap();
+    connection
+        .execute(
+            "INSERT INTO receipts (message_id, recipient, status, recorded_at)
+             VALUES (?1, 'Bob', 'queued', ?2)",
+            rusqlite::params![msg.id, Utc::now()],
+        )
+        .unwrap();
+    drop(connection);
+
+    let storage = Storage::open(&path).unwrap();
+    assert_eq!(storage.schema_version().unwrap(), 3);
+    let receipts = storage.receipts("Alice", &msg.id).unwrap();
+    assert_eq!(receipts.len(), 1);
+    assert_eq!(receipts[0].status, ReceiptStatus::Queued);
+    let queued = storage.take_queued("Bob").unwrap();
+    assert_eq!(queued.len(), 1);
+    assert_eq!(queued[0].0, msg.id);
+    assert_eq!(queued[0].1.sender, "Alice");
+
+    std::fs::remove_file(&path).ok();
+}
+
+#[tokio::test]
+async fn test_server_state_survives_restart() {
+    quietdrop_core::initialize();
+
+    let path = temp_database("restart");
+    let (server_public_key, server_secret_key) = generate_keypair();
+    let (addr, server_task) = start_server(
+        Arc::new(Storage::open(&path).unwrap()),
+        server_secret_key.clone(),
+    )
+    .await;
+
+    let bob_identity = Identity::generate();
+    let mut bob = Client::connect(&addr, &server_public_key, &bob_identity)
+        .await
+        .unwrap();
+    bob.register("Bob", PASSWORD).await.unwrap();
+    bob.identify("Bob", &bob_identity).await.unwrap();
+    bob.close().await.unwrap();
+
+    let alice_identity = Identity::generate();
+    let mut alice = Client::connect(&addr, &server_public_key, &alice_identity)
+        .await
+        .unwrap();
+    alice.register("Alice", PASSWORD).await.unwrap();
+    alice.identify("Alice", &alice_identity).await.unwrap();
+    let mut msg = test_message("Bob", vec![]);
+    msg.public_key = *alice_identity.public_key();
+    msg.signing_key = *alice_identity.signing_key();
+    msg.encrypt_content(
+        "Hi Bob",
+        bob_identity.public_key(),
+        alice_identity.secret_key(),
+    );
+    msg.sign(alice_identity.signing_secret_key());
+    let message_id = alice.send(&msg).await.unwrap();
+    alice.close().await.unwrap();
+
+    server_task.abort();
+    let _ = server_task.await;
+
+    // A new server on the same database knows the accounts, keys and queue
+    let storage = Arc::new(Storage::open(&path).unwrap());
+    let (addr, _server_task) = start_server(Arc::clone(&storage), server_secret_key).await;
+
+    let mut alice = Client::connect(&addr, &server_public_key, &alice_identity)
+        .await
+        .unwrap();
+    alice.login("Alice", PASSWORD).await.unwrap();
+    alice.identify("Alice", &alice_identity).await.unwrap();
+    let bob_key = alice
+        .lookup_key("Bob", &server_public_key)
+        .await
+        .unwrap()
+        .expect("Bob's key should still be published");
+    assert_eq!(bob_key, *bob_identity.public_key());
+
+    let mut bob = Client::connect(&addr, &server_public_key, &bob_identity)
+        .await
+        .unwrap();
+    bob.login("Bob", PASSWORD).await.unwrap();
+    bob.identify("Bob", &bob_identity).await.unwrap();
+    let received = bob
+        .try_recv_message(Duration::from_secs(5))
+        .await
+        .unwrap()
+        .expect("Bob should receive the message queued before the restart");
+    assert_eq!(
+        received.decrypt_content(bob_identity.secret_key()).unwrap(),
+        "Hi Bob"
+    );
+
+    let statuses: Vec<ReceiptStatus> = storage
+        .receipts("Alice", &message_id)
+        .unwrap()
+        .into_iter()
+        .map(|receipt| receipt.status)
+        .collect();
+    assert_eq!(statuses, [ReceiptStatus::Queued, ReceiptStatus::Delivered]);
+
+    std::fs::remove_file(&path).ok();
+}
@@ -0,0 +1,168 @@
+use quietdrop_core::client::Client;
+use quietdrop_core::encryption::{generate_keypair, Identity, PublicKey};
+use quietdrop_core::error::QuietDropError;
+use quietdrop_core::server::{self, ServerConfig};
+use quietdrop_core::tls::{ClientTls, ServerTls};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio::net::TcpListener;
+
+const PASSWORD: &str = "correct horse battery";
+
+fn temp_tls_dir(name: &str) -> PathBuf {
+    let mut dir = std::env::temp_dir();
+    dir.push(format!(
+        "quietdrop_tls_test_{}_{}",
+        name,
+        uuid::Uuid::new_v4()
+    ));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Writes a certificate for `localhost` and its key, signed by `issuer` or
+/// self-signed, and returns their paths.
+fn write_certificate(dir: &Path, name: &str, issuer: Option<&Certificate>) -> ServerTls {
+    let cert =
+        Certificate::from_params(CertificateParams::new(vec!["localhost".to_owned()])).unwrap();
+    let pem = match issuer {
+        Some(issuer) => cert.serialize_pem_with_signer(issuer).unwrap(),
+        None => cert.serialize_pem().unwrap(),
+    };
+
+    let tls = ServerTls::new(
+        dir.join(format!("{}.crt", name)),
+        dir.join(format!("{}.key", name)),
+    );
+    fs::write(&tls.cert_path, pem).unwrap();
+    fs::write(&tls.key_path, cert.serialize_private_key_pem()).unwrap();
+    tls
+}
+
+async fn start_tls_server(tls: ServerTls) -> (String, PublicKey) {
+    quietdrop_core::initialize();
+
+    let (server_public_key, server_secret_key) = generate_keypair();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let config = ServerConfig {
+        tls: Some(tls),
+        ..ServerConfig::default()
+    };
+    tokio::spawn(async move {
+        let _ = server::serve_with_config(listener, &server_secret_key, config).await;
+    });
+
+    (addr, server_public_key)
+}
+
+#[tokio::test]
+async fn test_client_connects_with_pinned_certificate() {
+    let dir = temp_tls_dir("pinned");
+    let server_tls = write_certificate(&dir, "server", None);
+    let client_tls = ClientTls::pinned("localhost", &server_tls.cert_path).unwrap();
+    let (addr, server_public_key) = start_tls_server(server_tls).await;
+
+    let alice_identity = Identity::generate();
+    let mut alice = Client::connect_tls(&addr, &client_tls, &server_public_key, &alice_identity)
+        .await
+        .expect("Client should connect over TLS");
+    alice.register("Alice", PASSWORD).await.unwrap();
+    alice.identify("Alice", &alice_identity).await.unwrap();
+    let key = alice.lookup_key("Alice", &server_public_key).await.unwrap();
+    assert_eq!(key.as_ref(), Some(alice_identity.public_key()));
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[tokio::test]
+async fn test_client_trusts_certificate_from_ca_file() {
+    let dir = temp_tls_dir("ca");
+    let mut ca_params = CertificateParams::new(vec![]);
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = Certificate::from_params(ca_params).unwrap();
+    let ca_path = dir.join("ca.crt");
+    fs::write(&ca_path, ca.serialize_pem().unwrap()).unwrap();
+
+    let server_tls = write_certificate(&dir, "server", Some(&ca));
+    let (addr, server_public_key) = start_tls_server(server_tls).await;
+
+    let client_tls = ClientTls::with_ca_file("localhost", &ca_path).unwrap();
+    Client::connect_tls(
+        &addr,
+        &client_tls,
+        &server_public_key,
+        &Identity::generate(),
+    )
+    .await
+    .expect("A certificate issued by the trusted CA should be accepted");
+
+    // The name must match the certificate too
+    let wrong_name = ClientTls::with_ca_file("quietdrop.example", &ca_path).unwrap();
+    assert!(Client::connect_tls(
+        &addr,
+        &wrong_name,
+        &server_public_key,
+        &Identity::generate()
+    )
+    .await
+    .is_err());
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[tokio::test]
+async fn test_client_rejects_unpinned_certificate() {
+    let dir = temp_tls_dir("mismatch");
+    let server_tls = write_certificate(&dir, "server", None);
+    let other = write_certificate(&dir, "other", None);
+    let (addr, server_public_key) = start_tls_server(server_tls).await;
+
+    let client_tls = ClientTls::pinned("localhost", &other.cert_path).unwrap();
+    let result = Client::connect_tls(
+        &addr,
+        &client_tls,
+        &server_public_key,
+        &Identity::generate(),
+    )
+    .await;
+    assert!(result.is_err(), "A different certificate must be refused");
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[tokio::test]
+async fn test_plain_client_cannot_reach_tls_server() {
+    let dir = temp_tls_dir("plain");
+    let server_tls = write_certificate(&dir, "server", None);
+    let (addr, server_public_key) = start_tls_server(server_tls).await;
+
+    assert!(
+        Client::connect(&addr, &server_public_key, &Identity::generate())
+            .await
+            .is_err()
+    );
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[tokio::test]
+async fn test_server_reports_missing_certificate() {
+    quietdrop_core::initialize();
+
+    let dir = temp_tls_dir("missing");
+    let (_, server_secret_key) = generate_keypair();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let config = ServerConfig {
+        tls: Some(ServerTls::new(dir.join("none.crt"), dir.join("none.key"))),
+        ..ServerConfig::default()
+    };
+
+    let err = server::serve_with_config(listener, &server_secret_key, config)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, QuietDropError::Tls(_)), "{}", err);
+
+    let _ = fs::remove_dir_all(dir);
+}
@@ -0,0 +1,487 @@
+use quietdrop_core::client::Client;
+use quietdrop_core::encryption::{generate_keypair, Identity, PublicKey};
+use quietdrop_core::error::QuietDropError;
+use quietdrop_core::message::{Message, MessageType};
+use quietdrop_core::server;
+use quietdrop_core::transfer::{
+    self, guess_mime_type, FileSender, IncomingFiles, OutgoingFiles, ReceivedFile,
+    TransferDirection, CHUNK_SIZE,
+};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+const PASSWORD: &str = "correct horse battery";
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "quietdrop-transfer-{}-{}-{}",
+        name,
+        std::process::id(),
+        rand::random::<u32>()
+    ));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn write_file(dir: &Path, name: &str, len: usize) -> (PathBuf, Vec<u8>) {
+    let bytes: Vec<u8> = (0..len).map(|_| rand::random::<u8>()).collect();
+    let path = dir.join(name);
+    fs::write(&path, &bytes).unwrap();
+    (path, bytes)
+}
+
+/// The offer followed by every chunk of the file at `path`, from Alice to Bob.
+fn file_messages(path: &Path, alice: &Identity, bob: &Identity) -> Vec<Message> {
+    let mut file = FileSender::open(path, "Alice", "Bob", bob.public_key(), alice).unwrap();
+    let mut messages = vec![file.offer().clone()];
+    while let Some(chunk) = file.next_chunk().unwrap() {
+        messages.push(chunk);
+    }
+    messages
+}
+
+fn receive_all(files: &mut IncomingFiles, messages: &[Message]) -> Option<ReceivedFile> {
+    let mut received = None;
+    for message in messages {
+        received = files.handle(message).unwrap();
+    }
+    received
+}
+
+#[test]
+fn test_file_round_trip_in_chunks() {
+    quietdrop_core::initialize();
+    let alice = Identity::generate();
+    let bob = Identity::generate();
+    let dir = temp_dir("round-trip");
+    let (path, bytes) = write_file(&dir, "report.pdf", 2 * CHUNK_SIZE + 100);
+
+    let messages = file_messages(&path, &alice, &bob);
+    assert_eq!(messages.len(), 4);
+    assert_eq!(messages[0].message_type, MessageType::File);
+    for chunk in &messages[1..] {
+        assert_eq!(chunk.message_type, MessageType::FileChunk);
+        assert_eq!(
+            transfer::chunk_transfer_id(chunk).as_deref(),
+            Some(messages[0].id.as_str())
+        );
+        chunk.verify_signature().unwrap();
+    }
+
+    let mut files = IncomingFiles::open(dir.join("downloads"), "Bob", &bob).unwrap();
+    let received = receive_all(&mut files, &messages).expect("The file should be complete");
+    assert_eq!(received.transfer_id, messages[0].id);
+    assert_eq!(received.sender, "Alice");
+    assert_eq!(received.header.name, "report.pdf");
+    assert_eq!(received.header.size, bytes.len() as u64);
+    assert_eq!(received.header.mime_type, "application/pdf");
+    assert_eq!(received.header.chunk_count, 3);
+    assert_eq!(fs::read(&received.path).unwrap(), bytes);
+
+    // Only the finished file is left behind
+    assert_eq!(fs::read_dir(dir.join("downloads")).unwrap().count(), 1);
+    assert!(files.transfers().is_empty());
+
+    // Progress was reported for the offer and every chunk
+    let progress = files.take_progress();
+    let done: Vec<u64> = progress.iter().map(|p| p.chunks_done).collect();
+    assert_eq!(done, [0, 1, 2, 3]);
+    assert_eq!(progress[3].direction, TransferDirection::Download);
+    assert_eq!(progress[3].bytes_done(), bytes.len() as u64);
+    assert!(progress[3].is_complete());
+
+    // Alice is told the file arrived
+    let replies = files.take_replies();
+    assert_eq!(replies.len(), 1);
+    assert_eq!(replies[0].message_type, MessageType::TransferAck);
+    assert_eq!(replies[0].recipient, "Alice");
+    replies[0].verify_signature().unwrap();
+
+    fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_empty_file_and_name_clash() {
+    quietdrop_core::initialize();
+    let alice = Identity::generate();
+    let bob = Identity::generate();
+    let dir = temp_dir("empty");
+    let (path, _) = write_file(&dir, "empty.txt", 0);
+    let downloads = dir.join("downloads");
+    fs::create_dir_all(&downloads).unwrap();
+    fs::write(downloads.join("empty.txt"), b"already here").unwrap();
+
+    let messages = file_messages(&path, &alice, &bob);
+    assert_eq!(messages.len(), 2);
+
+    let mut files = IncomingFiles::open(&downloads, "Bob", &bob).unwrap();
+    let received = receive_all(&mut files, &messages).expect("The file should be complete");
+    assert_eq!(received.path, downloads.join("empty (1).txt"));
+    assert!(fs::read(&received.path).unwrap().is_empty());
+    assert_eq!(
+        fs::read(downloads.join("empty.txt")).unwrap(),
+        b"already here"
+    );
+
+    fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_tampered_chunks_are_rejected() {
+    quietdrop_core::initialize();
+    let alice = Identity::generate();
+    let bob = Identity::generate();
+    let dir = temp_dir("tampered");
+    let (path, _) = write_file(&dir, "data.bin", CHUNK_SIZE + 10);
+    let downloads = dir.join("downloads");
+
+    // A flipped byte fails to decrypt and abandons the transfer
+    let mut messages = file_messages(&path, &alice, &bob);
+    let last = messages[2].content.len() - 1;
+    messages[2].content[last] ^= 1;
+    let mut files = IncomingFiles::open(&downloads, "Bob", &bob).unwrap();
+    files.handle(&messages[0]).unwrap();
+    files.handle(&messages[1]).unwrap();
+    let err = files.handle(&messages[2]).unwrap_err();
+    assert!(matches!(err, QuietDropError::Transfer(_)), "{}", err);
+    assert!(files.transfers().is_empty());
+    assert_eq!(fs::read_dir(&downloads).unwrap().count(), 0);
+
+    // Chunks of a transfer nobody offered are refused
+    let err = files.handle(&messages[1]).unwrap_err();
+    assert!(matches!(err, QuietDropError::Transfer(_)), "{}", err);
+
+    // Someone else cannot slip chunks into the transfer, nor end it
+    let messages = file_messages(&path, &alice, &bob);
+    let forged = file_messages(&path, &Identity::generate(), &bob);
+    let mut chunk = forged[1].clone();
+    chunk.content[24..40].copy_from_slice(&messages[1].content[24..40]);
+    files.handle(&messages[0]).unwrap();
+    assert!(files.handle(&chunk).is_err());
+    assert_eq!(files.transfers().len(), 1);
+
+    files.cancel(&messages[0].id);
+    assert_eq!(fs::read_dir(&downloads).unwrap().count(), 0);
+    fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_gaps_ask_the_sender_to_resume() {
+    quietdrop_core::initialize();
+    let alice = Identity::generate();
+    let bob = Identity::generate();
+    let dir = temp_dir("gap");
+    let (path, bytes) = write_file(&dir, "data.bin", 2 * CHUNK_SIZE + 10);
+    let messages = file_messages(&path, &alice, &bob);
+
+    let mut files = IncomingFiles::open(dir.join("downloads"), "Bob", &bob).unwrap();
+    files.handle(&messages[0]).unwrap();
+    files.handle(&messages[1]).unwrap();
+
+    // Chunk 2 arriving before chunk 1 is dropped, and asked for once
+    assert!(files.handle(&messages[3]).unwrap().is_none());
+    assert!(files.handle(&messages[3]).unwrap().is_none());
+    let replies = files.take_replies();
+    assert_eq!(replies.len(), 1);
+    assert_eq!(replies[0].message_type, MessageType::TransferAck);
+    assert!(replies[0].decrypt_content(alice.secret_key()).is_ok());
+    assert_eq!(files.transfers()[0].chunks_done, 1);
+
+    // Resent chunks fill the gap; repeats are ignored
+    assert!(files.handle(&messages[1]).unwrap().is_none());
+    assert!(files.handle(&messages[2]).unwrap().is_none());
+    let received = files
+        .handle(&messages[3])
+        .unwrap()
+        .expect("The file should be complete");
+    assert_eq!(fs::read(&received.path).unwrap(), bytes);
+
+    fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_download_resumes_after_restart() {
+    quietdrop_core::initialize();
+    let alice = Identity::generate();
+    let bob = Identity::generate();
+    let dir = temp_dir("restart");
+    let downloads = dir.join("downloads");
+    let (path, bytes) = write_file(&dir, "video.mp4", 2 * CHUNK_SIZE + 10);
+    let messages = file_messages(&path, &alice, &bob);
+
+    let mut files = IncomingFiles::open(&downloads, "Bob", &bob).unwrap();
+    receive_all(&mut files, &messages[..2]);
+    drop(files);
+
+    // The saved state holds the stream key, so only Bob can read it
+    let state = fs::read(downloads.join(format!(".{}.json", messages[0].id))).unwrap();
+    assert!(!state.windows(5).any(|window| window == b"\"key\""));
+    assert!(IncomingFiles::open(&downloads, "Eve", &Identity::generate()).is_err());
+
+    // Half-written data past the last stored chunk is discarded
+    let spool = downloads.join(format!(".{}.part", messages[0].id));
+    fs::OpenOptions::new()
+        .append(true)
+        .open(&spool)
+        .unwrap()
+        .write_all(b"torn write")
+        .unwrap();
+
+    let mut files = IncomingFiles::open(&downloads, "Bob", &bob).unwrap();
+    let transfers = files.transfers();
+    assert_eq!(transfers.len(), 1);
+    assert_eq!(transfers[0].transfer_id, messages[0].id);
+    assert_eq!(transfers[0].peer, "Alice");
+    assert_eq!(transfers[0].chunks_done, 1);
+
+    // Alice is asked to resend everything after the first chunk
+    files.request_resume().unwrap();
+    let replies = files.take_replies();
+    assert_eq!(replies.len(), 1);
+    assert_eq!(replies[0].recipient, "Alice");
+
+    let received = receive_all(&mut files, &messages[1..]).expect("The file should be complete");
+    assert_eq!(fs::read(&received.path).unwrap(), bytes);
+    assert_eq!(fs::read_dir(&downloads).unwrap().count(), 1);
+
+    fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_offers_without_uuid_ids_are_refused() {
+    quietdrop_core::initialize();
+    let alice = Identity::generate();
+    let bob = Identity::generate();
+    let dir = temp_dir("offer-id");
+    let (path, _) = write_file(&dir, "notes.txt", 100);
+    let downloads = dir.join("a").join("downloads");
+
+    let mut offer = file_messages(&path, &alice, &bob).remove(0);
+    // `.{id}.part` would be `./../escaped.part`, next to the download directory
+    offer.id = "/../escaped".to_owned();
+    offer.sign(alice.signing_secret_key());
+
+    let mut files = IncomingFiles::open(&downloads, "Bob", &bob).unwrap();
+    match files.handle(&offer) {
+        Err(QuietDropError::Transfer(_)) => {}
+        other => panic!("Expected the offer to be refused, got {:?}", other),
+    }
+    assert!(files.transfers().is_empty());
+    assert!(!downloads.exists());
+    assert!(!dir.join("a").join("escaped.part").exists());
+
+    fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_guess_mime_type() {
+    assert_eq!(guess_mime_type(Path::new("photo.JPG")), "image/jpeg");
+    assert_eq!(guess_mime_type(Path::new("notes.txt")), "text/plain");
+    assert_eq!(
+        guess_mime_type(Path::new("archive")),
+        "application/octet-stream"
+    );
+}
+
+async fn start_server() -> (String, PublicKey) {
+    quietdrop_core::initialize();
+
+    let (server_public_key, server_secret_key) = generate_keypair();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+    tokio::spawn(async move {
+        let _ = server::serve(listener, &server_secret_key).await;
+    });
+
+    (addr, server_public_key)
+}
+
+#[tokio::test]
+async fn test_send_file_through_server() {
+    let (addr, server_public_key) = start_server().await;
+    let dir = temp_dir("server");
+    let (path, bytes) = write_file(&dir, "photo.png", CHUNK_SIZE * 3 / 2);
+
+    let bob_identity = Identity::generate();
+    let mut bob = Client::connect(&addr, &server_public_key, &bob_identity)
+        .await
+        .unwrap();
+    bob.register("Bob", PASSWORD).await.unwrap();
+    bob.identify("Bob", &bob_identity).await.unwrap();
+
+    let alice_identity = Identity::generate();
+    let mut alice = Client::connect(&addr, &server_public_key, &alice_identity)
+        .await
+        .unwrap();
+    alice.register("Alice", PASSWORD).await.unwrap();
+    alice.identify("Alice", &alice_identity).await.unwrap();
+
+    let mut file = FileSender::open(
+        &path,
+        "Alice",
+        "Bob",
+        bob_identity.public_key(),
+        &alice_identity,
+    )
+    .unwrap();
+    transfer::send_file(&mut alice, &mut file).await.unwrap();
+
+    let mut files = IncomingFiles::open(dir.join("downloads"), "Bob", &bob_identity).unwrap();
+    let received = loop {
+        let message = bob
+            .try_recv_message(Duration::from_secs(5))
+            .await
+            .unwrap()
+            .expect("Bob should receive the whole file");
+        if let Some(received) = files.handle(&message).unwrap() {
+            break received;
+        }
+    };
+    assert_eq!(received.transfer_id, file.transfer_id());
+    assert_eq!(received.header.mime_type, "image/png");
+    assert_eq!(fs::read(&received.path).unwrap(), bytes);
+
+    // Only the offer is acknowledged with a delivery receipt
+    alice
+        .try_recv_message(Duration::from_millis(200))
+        .await
+        .unwrap();
+    let delivered = alice.take_delivery_receipts();
+    assert_eq!(delivered.len(), 1);
+    assert_eq!(delivered[0].message_id, file.transfer_id());
+
+    fs::remove_dir_all(&dir).ok();
+}
+
+async fn connect(
+    addr: &str,
+    server_public_key: &PublicKey,
+    name: &str,
+    identity: &Identity,
+) -> Client {
+    let mut client = Client::connect(addr, server_public_key, identity)
+        .await
+        .unwrap();
+    client.register(name, PASSWORD).await.unwrap();
+    client.identify(name, identity).await.unwrap();
+    client
+}
+
+/// Hands every message Bob receives within `timeout` to `files`, sending
+/// the acknowledgements it owes.
+async fn bob_receives(
+    bob: &mut Client,
+    files: &mut IncomingFiles,
+    timeout: Duration,
+) -> Option<ReceivedFile> {
+    let mut received = None;
+    while let Some(message) = bob.try_recv_message(timeout).await.unwrap() {
+        if let Some(file) = files.handle(&message).unwrap() {
+            received = Some(file);
+        }
+    }
+    for reply in files.take_replies() {
+        bob.send(&reply).await.unwrap();
+    }
+    received
+}
+
+#[tokio::test]
+async fn test_upload_resumes_from_last_confirmed_chunk() {
+    let (addr, server_public_key) = start_server().await;
+    let dir = temp_dir("resume");
+    let transfers = dir.join("transfers");
+    let downloads = dir.join("downloads");
+    let (path, bytes) = write_file(&dir, "backup.zip", 3 * CHUNK_SIZE);
+
+    let alice_identity = Identity::generate();
+    let bob_identity = Identity::generate();
+    let mut alice = connect(&addr, &server_public_key, "Alice", &alice_identity).await;
+    let mut bob = connect(&addr, &server_public_key, "Bob", &bob_identity).await;
+
+    let mut outgoing = OutgoingFiles::open(&transfers, "Alice", &alice_identity).unwrap();
+    let transfer_id = outgoing
+        .start(&path, "Bob", bob_identity.public_key())
+        .unwrap();
+    let mut updates = Vec::new();
+    outgoing
+        .send_pending(&mut alice, |progress| updates.push(progress.chunks_done))
+        .await
+        .unwrap();
+    assert_eq!(updates, [0, 1, 2, 3]);
+    assert!(!outgoing.has_unsent());
+
+    // Alice restarts: everything was sent, nothing confirmed yet
+    drop(outgoing);
+    let mut outgoing = OutgoingFiles::open(&transfers, "Alice", &alice_identity).unwrap();
+    let pending = outgoing.transfers();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].transfer_id, transfer_id);
+    assert_eq!(pending[0].direction, TransferDirection::Upload);
+    assert_eq!(pending[0].chunks_done, 3);
+    assert_eq!(pending[0].chunks_confirmed, 0);
+
+    // Bob stores the offer and the first chunk, then loses the rest
+    let mut files = IncomingFiles::open(&downloads, "Bob", &bob_identity).unwrap();
+    for _ in 0..2 {
+        let message = bob
+            .try_recv_message(Duration::from_secs(5))
+            .await
+            .unwrap()
+            .expect("Bob should receive the offer and first chunk");
+        files.handle(&message).unwrap();
+    }
+    drop(files);
+    bob.close().await.unwrap();
+
+    // On reconnecting, Bob asks Alice to resume after the first chunk
+    let mut bob = Client::connect(&addr, &server_public_key, &bob_identity)
+        .await
+        .unwrap();
+    bob.login("Bob", PASSWORD).await.unwrap();
+    bob.identify("Bob", &bob_identity).await.unwrap();
+    let mut files = IncomingFiles::open(&downloads, "Bob", &bob_identity).unwrap();
+    files.request_resume().unwrap();
+    for reply in files.take_replies() {
+        bob.send(&reply).await.unwrap();
+    }
+
+    let ack = alice
+        .try_recv_message(Duration::from_secs(5))
+        .await
+        .unwrap()
+        .expect("Alice should receive the resume request");
+    let progress = outgoing.handle(&ack).unwrap().unwrap();
+    assert_eq!(progress.chunks_done, 1);
+    assert_eq!(progress.chunks_confirmed, 1);
+    assert!(outgoing.has_unsent());
+
+    // Resent chunks get past the server's replay cache
+    outgoing.send_pending(&mut alice, |_| {}).await.unwrap();
+    let received = bob_receives(&mut bob, &mut files, Duration::from_millis(500))
+        .await
+        .expect("Bob should receive the whole file");
+    assert_eq!(received.transfer_id, transfer_id);
+    assert_eq!(fs::read(&received.path).unwrap(), bytes);
+
+    // Bob's final acknowledgement finishes the upload
+    let mut finished = None;
+    while let Some(message) = alice
+        .try_recv_message(Duration::from_millis(500))
+        .await
+        .unwrap()
+    {
+        if message.message_type == MessageType::TransferAck {
+            finished = outgoing.handle(&message).unwrap();
+        }
+    }
+    assert!(finished.expect("Alice should hear back").is_complete());
+    assert!(outgoing.transfers().is_empty());
+    assert_eq!(fs::read_dir(&transfers).unwrap().count(), 0);
+
+    fs::remove_dir_all(&dir).ok();
+}
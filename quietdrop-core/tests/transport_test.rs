@@ -0,0 +1,177 @@
+use quietdrop_core::client::Client;
+use quietdrop_core::connection::{Connection, ConnectionConfig};
+use quietdrop_core::encryption::{generate_keypair, Identity};
+use quietdrop_core::error::QuietDropError;
+use quietdrop_core::framing::{read_frame, write_frame, MAX_FRAME_SIZE};
+use quietdrop_core::protocol::{ErrorCode, Packet};
+use quietdrop_core::server;
+use quietdrop_core::transport::{self, SecureChannel};
+use tokio::io::{duplex, DuplexStream};
+use tokio::net::TcpListener;
+
+/// Runs both sides of the handshake over an in-memory pipe.
+async fn handshake_pair(
+    identity: &Identity,
+) -> (DuplexStream, SecureChannel, DuplexStream, SecureChannel) {
+    quietdrop_core::initialize();
+
+    let (server_public_key, server_secret_key) = generate_keypair();
+    let (mut client_end, mut server_end) = duplex(64 * 1024);
+    let server = tokio::spawn(async move {
+        let (channel, client_key) = transport::accept(&mut server_end, &server_secret_key)
+            .await
+            .unwrap();
+        (server_end, channel, client_key)
+    });
+
+    let client_channel =
+        transport::connect(&mut client_end, &server_public_key, identity.secret_key())
+            .await
+            .unwrap();
+    let (server_end, server_channel, client_key) = server.await.unwrap();
+    assert_eq!(&client_key, identity.public_key());
+
+    (client_end, client_channel, server_end, server_channel)
+}
+
+#[tokio::test]
+async fn test_handshake_establishes_shared_keys() {
+    let identity = Identity::generate();
+    let (_, mut client, _, mut server) = handshake_pair(&identity).await;
+
+    let sealed = client.seal(b"to the server");
+    assert_ne!(&sealed[..], b"to the server");
+    assert_eq!(server.open(&sealed).unwrap(), b"to the server");
+
+    let sealed = server.seal(b"to the client");
+    assert_eq!(client.open(&sealed).unwrap(), b"to the client");
+}
+
+#[tokio::test]
+async fn test_channel_rejects_replayed_and_reordered_frames() {
+    let identity = Identity::generate();
+    let (_, mut client, _, mut server) = handshake_pair(&identity).await;
+
+    let first = client.seal(b"first");
+    let second = client.seal(b"second");
+
+    assert!(server.open(&second).is_err());
+    assert_eq!(server.open(&first).unwrap(), b"first");
+    assert!(server.open(&first).is_err());
+    assert_eq!(server.open(&second).unwrap(), b"second");
+
+    // A frame sealed for the other direction does not open either
+    let reflected = server.seal(b"echo");
+    assert!(server.open(&reflected).is_err());
+}
+
+#[tokio::test]
+async fn test_connection_hides_metadata() {
+    let identity = Identity::generate();
+    let (client_end, client, mut server_end, mut server) = handshake_pair(&identity).await;
+
+    let mut connection = Connection::with_channel(client_end, ConnectionConfig::default(), client);
+    assert!(connection.is_secure());
+    connection
+        .send(&Packet::Identify {
+            name: "Alice".to_owned(),
+            public_key: *identity.public_key(),
+            signing_key: *identity.signing_key(),
+        })
+        .await
+        .unwrap();
+
+    // On the wire, neither the name nor the packet structure is visible
+    let frame = read_frame(&mut server_end, MAX_FRAME_SIZE)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(!frame.windows(5).any(|window| window == b"Alice"));
+    assert!(!frame.windows(8).any(|window| window == b"Identify"));
+
+    match Packet::from_bytes(&server.open(&frame).unwrap()).unwrap() {
+        Packet::Identify { name, .. } => assert_eq!(name, "Alice"),
+        other => panic!("Expected Identify, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_client_rejects_impostor_server() {
+    quietdrop_core::initialize();
+
+    // Someone answering on the server's address without its secret key
+    let (pinned_public_key, _) = generate_keypair();
+    let (impostor_public_key, _) = generate_keypair();
+    let (mut client_end, mut impostor_end) = duplex(64 * 1024);
+    tokio::spawn(async move {
+        read_frame(&mut impostor_end, MAX_FRAME_SIZE).await.unwrap();
+        let mut reply = impostor_public_key.0.to_vec();
+        reply.extend_from_slice(&[0u8; 40]);
+        write_frame(&mut impostor_end, &reply).await.unwrap();
+        // Keep the pipe open until the client has given up
+        let _ = read_frame(&mut impostor_end, MAX_FRAME_SIZE).await;
+    });
+
+    let identity = Identity::generate();
+    let result =
+        transport::connect(&mut client_end, &pinned_public_key, identity.secret_key()).await;
+    assert!(
+        matches!(result, Err(QuietDropError::Handshake(_))),
+        "An impostor must not complete the handshake"
+    );
+}
+
+#[tokio::test]
+async fn test_server_refuses_client_with_wrong_pin() {
+    quietdrop_core::initialize();
+
+    let (_, server_secret_key) = generate_keypair();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let _ = server::serve(listener, &server_secret_key).await;
+    });
+
+    // A stale server_public_key.key cannot be used to talk to the server
+    let (stale_public_key, _) = generate_keypair();
+    let result = Client::connect(&addr, &stale_public_key, &Identity::generate()).await;
+    assert!(matches!(result, Err(QuietDropError::Handshake(_))));
+}
+
+#[tokio::test]
+async fn test_server_rejects_identify_with_other_key() {
+    quietdrop_core::initialize();
+
+    let (server_public_key, server_secret_key) = generate_keypair();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let _ = server::serve(listener, &server_secret_key).await;
+    });
+
+    let mallory = Identity::generate();
+    let alice = Identity::generate();
+    let mut client = Client::connect(&addr, &server_public_key, &mallory)
+        .await
+        .unwrap();
+
+    // Mallory cannot publish a key she did not authenticate with, even
+    // under an account she holds
+    client
+        .register("Alice", "correct horse battery")
+        .await
+        .unwrap();
+    let err = client.identify("Alice", &alice).await.unwrap_err();
+    assert!(
+        matches!(
+            err,
+            QuietDropError::Server {
+                code: ErrorCode::KeyMismatch,
+                ..
+            }
+        ),
+        "{}",
+        err
+    );
+    client.identify("Alice", &mallory).await.unwrap();
+}
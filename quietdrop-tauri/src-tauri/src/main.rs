@@ -1,143 +1,926 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+use quietdrop_core::accounts::is_valid_account_name;
 use quietdrop_core::client;
-use quietdrop_core::encryption::generate_keypair;
+use quietdrop_core::encryption::Identity;
+use quietdrop_core::error::QuietDropError;
+use quietdrop_core::keystore::{Keystore, ServerKeyFiles};
 use quietdrop_core::message::{Message, MessageType};
+use quietdrop_core::outbox::{Outbox, OutboxStatus, RetryPolicy, OUTBOX_FILE};
+use quietdrop_core::protocol::ErrorCode;
+use quietdrop_core::receipts::{MessageStatus, MessageTracker, StatusEvent};
+use quietdrop_core::transfer::{
+    IncomingFiles, OutgoingFiles, ReceivedFile, TransferProgress, TRANSFERS_DIR,
+};
 use serde::{Deserialize, Serialize};
 use sodiumoxide::crypto::box_;
-use std::fs::File;
-use std::io::prelude::*;
-use std::sync::Mutex;
-use tauri::State;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager, State};
 
 #[derive(Default)]
 struct AppState {
     server_address: Mutex<String>,
+    connection: tokio::sync::Mutex<Option<OpenConnection>>,
+    // Unlocked keystore identity, kept so the passphrase is only needed once
+    identity: Mutex<Option<(String, Identity)>>,
+    // Session token per name, presented instead of the password on reconnect
+    session: Mutex<Option<(String, String)>>,
+    // Unsent messages of the name last used, kept on disk between runs
+    outbox: Mutex<Option<(String, Arc<Outbox>)>>,
+    // Status of every message sent in this run, updated as receipts arrive
+    tracker: Mutex<MessageTracker>,
+    // Files being received by the name last used, kept on disk until complete
+    files: Mutex<Option<(String, IncomingFiles)>>,
+    // Files being sent by the name last used, kept on disk until confirmed
+    uploads: tokio::sync::Mutex<Option<(String, OutgoingFiles)>>,
+}
+
+// Connection reused across sends while the server address and name stay the same
+struct OpenConnection {
+    server_addr: String,
+    name: String,
+    identity: Identity,
+    client: client::Client,
+    // Recovery code of an account created while connecting, shown once
+    recovery_code: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ReceivedMessage {
+    id: String,
+    sender: String,
+    content: String,
+    timestamp: String,
+    file: Option<ReceivedFileInfo>,
+}
+
+#[derive(Serialize)]
+struct ReceivedFileInfo {
+    name: String,
+    size: u64,
+    mime_type: String,
+    path: String,
 }
 
 #[derive(Serialize)]
 struct MessageResponse {
     status: String,
     message: String,
+    // ID of the message sent, to look up in `message_statuses`
+    message_id: Option<String>,
+}
+
+#[derive(Serialize)]
+struct OutboxReport {
+    id: String,
+    recipient: String,
+    status: OutboxStatus,
+    attempts: u32,
+    last_error: Option<String>,
 }
 
 #[derive(Deserialize)]
 struct MessageRequest {
     name: String,
+    passphrase: String,
+    password: String,
     content: String,
     recipient: String,
 }
 
 #[tauri::command]
 async fn send_message(
+    app: AppHandle,
     app_state: State<'_, AppState>,
     name: String,
+    passphrase: String,
+    password: String,
     content: String,
     recipient: String,
 ) -> Result<MessageResponse, String> {
-    // Log the incoming request
-    println!("===Tauri send_message called ===");
-    println!("Received message request from: {}", name);
-    println!("Message content: {}", content);
-    println!("Recipient: {}", recipient);
-
-    // Initialize sodiumoxide
-    println!("Initializing sodiumoxide...");
-    sodiumoxide::init().expect("Failed to initialize sodiumoxide");
-    println!("Sodiumoxide initialized successfully");
-
-    // Get server address from state
-    println!("Getting server address from state...");
-    let server_addr = {
-        let server_address = app_state.server_address.lock().map_err(|e| e.to_string())?;
-        if server_address.is_empty() {
-            "127.0.0.1:8080".to_string()
-        } else {
-            server_address.clone()
-        }
-    };
-    println!("Using server address: {}", server_addr);
+    quietdrop_core::initialize();
+    let server_addr = current_server_address(&app_state)?;
+    let server_public_key = read_server_public_key()?;
 
-    // Try to read the server's public key
-    println!("Attempting to read server public key...");
-    let server_key_result = read_server_public_key();
-    match &server_key_result {
-        Ok(_) => println!("Successfully read server public key"),
-        Err(e) => println!("Error reading server public key: {}", e),
-    }
+    // Unlock (or create) the long-term identity for this name
+    let identity = load_identity(&app, &app_state, &name, &passphrase)?;
+    let outbox = open_outbox(&app, &app_state, &name)?;
 
-    let server_public_key = server_key_result?;
-    println!("Server public key loaded successfully");
+    // Reuse the persistent connection, opening it if needed
+    let mut connection = app_state.connection.lock().await;
+    let open = ensure_connection(
+        &mut connection,
+        &server_addr,
+        &server_public_key,
+        &name,
+        &password,
+        &app_state.session,
+        identity,
+    )
+    .await?;
 
-    // Generate client keypair
-    println!("Generating client keypair...");
-    let (public_key, secret_key) = generate_keypair();
-    println!("Client keypair generated successfully");
+    // Messages are sealed to the recipient's published key
+    let recipient_public_key = match open.client.lookup_key(&recipient, &server_public_key).await {
+        Ok(Some(key)) => key,
+        Ok(None) => return Err(format!("{} has not published a key yet", recipient)),
+        Err(e) => {
+            return Err(command_error(
+                "Failed to look up recipient key",
+                e,
+                &mut connection,
+            ))
+        }
+    };
 
     // Create and encrypt the message
-    println!("Creating message...");
+    let identity = &open.identity;
     let mut msg = Message {
+        id: Message::new_id(),
         timestamp: chrono::Utc::now(),
         message_type: MessageType::Text,
         sender: name,
         recipient: recipient,
         content: vec![],
-        public_key,
+        public_key: *identity.public_key(),
+        signing_key: *identity.signing_key(),
+        signature: vec![],
     };
-    println!("Message object created successfully");
 
-    println!("Encrypting message...");
-    msg.encrypt_content(&content, &server_public_key, &secret_key);
-    println!("Message encrypted successfully");
+    msg.encrypt_content(&content, &recipient_public_key, identity.secret_key());
+    msg.sign(identity.signing_secret_key());
+
+    // The outbox keeps the message until the server accepts it, and sends
+    // anything still waiting from earlier attempts first
+    let id = outbox
+        .push(msg)
+        .map_err(|e| format!("Unable to save the message to the outbox: {}", e))?;
+    app_state
+        .tracker
+        .lock()
+        .map_err(|e| e.to_string())?
+        .track(&id);
+    let error = match outbox.flush(&mut open.client, &open.identity).await {
+        Ok(_) => None,
+        Err(e) => Some(command_error("Failed to send message", e, &mut connection)),
+    };
 
-    // Send the message to the server
-    println!("Sending message to server...");
-    match client::send_message(&msg, &server_addr).await {
-        Ok(_) => {
-            println!("Message sent successfully!");
+    let status = outbox.status(&id);
+    if let Some(event) = status.as_ref().and_then(status_event) {
+        app_state
+            .tracker
+            .lock()
+            .map_err(|e| e.to_string())?
+            .apply(&id, event);
+    }
+    match status {
+        Some(OutboxStatus::Sent { .. }) => {
+            let recovery_code = connection
+                .as_mut()
+                .and_then(|open| open.recovery_code.take());
+            let message = match recovery_code {
+                Some(code) => format!(
+                    "Message sent successfully. Your new account's recovery code is {}; \
+                    keep it safe, it is not shown again",
+                    code
+                ),
+                None => "Message sent successfully".to_string(),
+            };
             Ok(MessageResponse {
                 status: "success".to_string(),
-                message: "Message sent successfully".to_string(),
+                message,
+                message_id: Some(id),
             })
         }
+        Some(OutboxStatus::Failed { reason }) => {
+            Err(format!("Message was not delivered: {}", reason))
+        }
+        _ => Ok(MessageResponse {
+            status: "queued".to_string(),
+            message: format!(
+                "{}. The message is kept in the outbox and will be retried",
+                error.unwrap_or_else(|| "The server is busy".to_string())
+            ),
+            message_id: Some(id),
+        }),
+    }
+}
+
+/// Retries the messages waiting in the outbox over the open connection and
+/// reports the status of each. Sent and failed messages are reported once,
+/// then dropped from the outbox.
+#[tauri::command]
+async fn flush_outbox(app_state: State<'_, AppState>) -> Result<Vec<OutboxReport>, String> {
+    let outbox = match &*app_state.outbox.lock().map_err(|e| e.to_string())? {
+        Some((_, outbox)) => Arc::clone(outbox),
+        None => return Ok(Vec::new()),
+    };
+
+    let mut connection = app_state.connection.lock().await;
+    if let Some(open) = connection.as_mut() {
+        if let Err(e) = outbox.flush(&mut open.client, &open.identity).await {
+            log::warn!(
+                "{}",
+                command_error("Failed to flush the outbox", e, &mut connection)
+            );
+        }
+    }
+
+    let pending = outbox
+        .entries()
+        .into_iter()
+        .filter(|entry| entry.status == OutboxStatus::Pending);
+    let finished = outbox
+        .remove_finished()
+        .map_err(|e| format!("Unable to update the outbox: {}", e))?;
+    let mut tracker = app_state.tracker.lock().map_err(|e| e.to_string())?;
+    for entry in &finished {
+        if let Some(event) = status_event(&entry.status) {
+            tracker.apply(&entry.id, event);
+        }
+    }
+    Ok(finished
+        .into_iter()
+        .chain(pending)
+        .map(|entry| OutboxReport {
+            id: entry.id,
+            recipient: entry.message.recipient,
+            status: entry.status,
+            attempts: entry.attempts,
+            last_error: entry.last_error,
+        })
+        .collect())
+}
+
+/// Receives the messages waiting on the open connection and records any
+/// delivery and read receipts among them. Each message shown is answered
+/// with a read receipt.
+///
+/// File transfers move along too: downloads are acknowledged, uploads pick
+/// up any chunks their recipients asked for again, and both report their
+/// progress as `transfer-progress` events.
+#[tauri::command]
+async fn fetch_messages(
+    app: AppHandle,
+    app_state: State<'_, AppState>,
+) -> Result<Vec<ReceivedMessage>, String> {
+    let mut connection = app_state.connection.lock().await;
+    let open = match connection.as_mut() {
+        Some(open) => open,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut received = Vec::new();
+    loop {
+        match open
+            .client
+            .try_recv_message(Duration::from_millis(50))
+            .await
+        {
+            Ok(Some(msg)) => {
+                // File chunks are checked against the offer they belong to
+                if msg.message_type == MessageType::FileChunk {
+                    received.extend(receive_file(&app, &app_state, &msg, open)?);
+                    continue;
+                }
+                // Acknowledgements only count from the recipient of the file
+                if msg.message_type == MessageType::TransferAck {
+                    let mut uploads = app_state.uploads.lock().await;
+                    let uploads = outgoing_files(&app, &mut uploads, open)?;
+                    match uploads.handle(&msg) {
+                        Ok(Some(progress)) => emit_progress(&app, &progress),
+                        Ok(None) => {}
+                        Err(e) => log::warn!("Ignoring a transfer acknowledgement: {}", e),
+                    }
+                    continue;
+                }
+                // Only trust messages signed with the key the sender published
+                let server_public_key = read_server_public_key()?;
+                let published = open
+                    .client
+                    .lookup_keys(&msg.sender, &server_public_key)
+                    .await
+                    .ok()
+                    .and_then(|record| record.signing_key);
+                if published.as_ref() != Some(&msg.signing_key) {
+                    log::warn!("Dropping a message not signed with its sender's published key");
+                    continue;
+                }
+                if msg.message_type == MessageType::ReadReceipt {
+                    match msg.read_message_id(open.identity.secret_key()) {
+                        Ok(Some(message_id)) => {
+                            app_state
+                                .tracker
+                                .lock()
+                                .map_err(|e| e.to_string())?
+                                .apply(&message_id, StatusEvent::Read);
+                        }
+                        Ok(None) => {}
+                        Err(e) => log::warn!("Ignoring unreadable read receipt: {}", e),
+                    }
+                    continue;
+                }
+                if msg.message_type == MessageType::File {
+                    received.extend(receive_file(&app, &app_state, &msg, open)?);
+                    continue;
+                }
+                let content = msg
+                    .decrypt_content(open.identity.secret_key())
+                    .unwrap_or_else(|e| format!("<could not decrypt: {}>", e));
+                if let Err(e) = open
+                    .client
+                    .send_read_receipt(&msg, &open.name, &open.identity)
+                    .await
+                {
+                    log::warn!("Failed to send a read receipt: {}", e);
+                }
+                received.push(ReceivedMessage {
+                    id: msg.id,
+                    sender: msg.sender,
+                    content,
+                    timestamp: msg.timestamp.to_rfc3339(),
+                    file: None,
+                });
+            }
+            Ok(None) => break,
+            Err(e) => {
+                return Err(command_error(
+                    "Failed to receive messages",
+                    e,
+                    &mut connection,
+                ))
+            }
+        }
+    }
+
+    let mut tracker = app_state.tracker.lock().map_err(|e| e.to_string())?;
+    for receipt in open.client.take_delivery_receipts() {
+        tracker.apply(&receipt.message_id, StatusEvent::Delivered);
+    }
+    drop(tracker);
+
+    if let Err(e) = continue_transfers(&app, &app_state, open).await {
+        return Err(command_error(
+            "Failed to continue file transfers",
+            e,
+            &mut connection,
+        ));
+    }
+    Ok(received)
+}
+
+/// Encrypts the file at `path` and sends it to `recipient` chunk by chunk,
+/// reporting progress as `transfer-progress` events. The transfer is
+/// followed in `message_statuses` under the returned ID.
+///
+/// The file is kept, encrypted, until the recipient confirms it, so an
+/// upload cut short by a lost connection carries on in `resume_transfers`.
+#[tauri::command]
+async fn send_file(
+    app: AppHandle,
+    app_state: State<'_, AppState>,
+    name: String,
+    passphrase: String,
+    password: String,
+    recipient: String,
+    path: String,
+) -> Result<MessageResponse, String> {
+    quietdrop_core::initialize();
+    let server_addr = current_server_address(&app_state)?;
+    let server_public_key = read_server_public_key()?;
+    let identity = load_identity(&app, &app_state, &name, &passphrase)?;
+
+    let mut connection = app_state.connection.lock().await;
+    let open = ensure_connection(
+        &mut connection,
+        &server_addr,
+        &server_public_key,
+        &name,
+        &password,
+        &app_state.session,
+        identity,
+    )
+    .await?;
+
+    let recipient_public_key = match open.client.lookup_key(&recipient, &server_public_key).await {
+        Ok(Some(key)) => key,
+        Ok(None) => return Err(format!("{} has not published a key yet", recipient)),
         Err(e) => {
-            println!("Failed to send message: {}", e);
-            Err(format!("Failed to send message: {}", e))
+            return Err(command_error(
+                "Failed to look up recipient key",
+                e,
+                &mut connection,
+            ))
         }
+    };
+
+    let mut uploads = app_state.uploads.lock().await;
+    let uploads = outgoing_files(&app, &mut uploads, open)?;
+    let transfer_id = uploads
+        .start(Path::new(&path), &recipient, &recipient_public_key)
+        .map_err(|e| format!("Unable to read {}: {}", path, e))?;
+    app_state
+        .tracker
+        .lock()
+        .map_err(|e| e.to_string())?
+        .track(&transfer_id);
+
+    let result = uploads
+        .send_pending(&mut open.client, |progress| emit_progress(&app, progress))
+        .await;
+    if let Err(e) = result {
+        return Err(command_error("Failed to send file", e, &mut connection));
+    }
+    app_state
+        .tracker
+        .lock()
+        .map_err(|e| e.to_string())?
+        .apply(&transfer_id, StatusEvent::Accepted);
+
+    Ok(MessageResponse {
+        status: "success".to_string(),
+        message: format!("Sent {}", path),
+        message_id: Some(transfer_id),
+    })
+}
+
+/// Picks up file transfers left unfinished by a lost connection or an
+/// earlier run: asks the senders of downloads to resend what is missing and
+/// sends the rest of uploads. Call after connecting. Returns every transfer
+/// still under way.
+#[tauri::command]
+async fn resume_transfers(
+    app: AppHandle,
+    app_state: State<'_, AppState>,
+) -> Result<Vec<TransferProgress>, String> {
+    let mut connection = app_state.connection.lock().await;
+    let open = match connection.as_mut() {
+        Some(open) => open,
+        None => return Ok(Vec::new()),
+    };
+
+    {
+        let mut files = app_state.files.lock().map_err(|e| e.to_string())?;
+        incoming_files(&app, &mut files, open)?
+            .request_resume()
+            .map_err(|e| format!("Unable to resume downloads: {}", e))?;
+    }
+    if let Err(e) = continue_transfers(&app, &app_state, open).await {
+        return Err(command_error(
+            "Failed to resume file transfers",
+            e,
+            &mut connection,
+        ));
+    }
+
+    let mut transfers = {
+        let mut files = app_state.files.lock().map_err(|e| e.to_string())?;
+        incoming_files(&app, &mut files, open)?.transfers()
+    };
+    let mut uploads = app_state.uploads.lock().await;
+    transfers.extend(outgoing_files(&app, &mut uploads, open)?.transfers());
+    Ok(transfers)
+}
+
+/// Sends the acknowledgements owed to the senders of files, then any upload
+/// chunks the server has not accepted yet.
+async fn continue_transfers(
+    app: &AppHandle,
+    app_state: &AppState,
+    open: &mut OpenConnection,
+) -> Result<(), QuietDropError> {
+    let replies = match app_state.files.lock() {
+        Ok(mut files) => match files.as_mut() {
+            Some((_, files)) => files.take_replies(),
+            None => Vec::new(),
+        },
+        Err(e) => return Err(QuietDropError::Transfer(e.to_string())),
+    };
+    for reply in replies {
+        open.client.send(&reply).await?;
+    }
+
+    let mut uploads = app_state.uploads.lock().await;
+    let uploads = outgoing_files(app, &mut uploads, open).map_err(QuietDropError::Transfer)?;
+    if uploads.has_unsent() {
+        uploads
+            .send_pending(&mut open.client, |progress| emit_progress(app, progress))
+            .await?;
+    }
+    Ok(())
+}
+
+/// Adds a file offer or chunk to its download, returning the finished file
+/// as a message once its last chunk is in.
+fn receive_file(
+    app: &AppHandle,
+    app_state: &AppState,
+    msg: &Message,
+    open: &OpenConnection,
+) -> Result<Option<ReceivedMessage>, String> {
+    let mut files = app_state.files.lock().map_err(|e| e.to_string())?;
+    let files = incoming_files(app, &mut files, open)?;
+    let result = files.handle(msg);
+    for progress in files.take_progress() {
+        emit_progress(app, &progress);
+    }
+    match result {
+        Ok(Some(file)) => Ok(Some(file_message(file))),
+        Ok(None) => Ok(None),
+        Err(e) => {
+            log::warn!("Could not receive a file: {}", e);
+            Ok(None)
+        }
+    }
+}
+
+/// The downloads of the connection's name, opened on first use.
+fn incoming_files<'a>(
+    app: &AppHandle,
+    files: &'a mut Option<(String, IncomingFiles)>,
+    open: &OpenConnection,
+) -> Result<&'a mut IncomingFiles, String> {
+    if !matches!(files.as_ref(), Some((name, _)) if *name == open.name) {
+        let opened = IncomingFiles::open(downloads_dir(app)?, &open.name, &open.identity)
+            .map_err(|e| format!("Unable to open unfinished downloads: {}", e))?;
+        *files = Some((open.name.clone(), opened));
+    }
+    Ok(&mut files.as_mut().expect("downloads were just opened").1)
+}
+
+/// The uploads of the connection's name, opened on first use.
+fn outgoing_files<'a>(
+    app: &AppHandle,
+    uploads: &'a mut Option<(String, OutgoingFiles)>,
+    open: &OpenConnection,
+) -> Result<&'a mut OutgoingFiles, String> {
+    if !matches!(uploads.as_ref(), Some((name, _)) if *name == open.name) {
+        let dir = keystore_dir(app, &open.name)?.join(TRANSFERS_DIR);
+        let opened = OutgoingFiles::open(dir, &open.name, &open.identity)
+            .map_err(|e| format!("Unable to open unfinished uploads: {}", e))?;
+        *uploads = Some((open.name.clone(), opened));
+    }
+    Ok(&mut uploads.as_mut().expect("uploads were just opened").1)
+}
+
+/// Tells the frontend how far a transfer has come.
+fn emit_progress(app: &AppHandle, progress: &TransferProgress) {
+    if let Err(e) = app.emit("transfer-progress", progress) {
+        log::warn!("Failed to report transfer progress: {}", e);
+    }
+}
+
+fn file_message(file: ReceivedFile) -> ReceivedMessage {
+    ReceivedMessage {
+        id: file.transfer_id,
+        sender: file.sender,
+        content: format!("Sent a file: {}", file.header.name),
+        timestamp: chrono::Utc::now().to_rfc3339(),
+        file: Some(ReceivedFileInfo {
+            name: file.header.name,
+            size: file.header.size,
+            mime_type: file.header.mime_type,
+            path: file.path.display().to_string(),
+        }),
+    }
+}
+
+/// The status of every message sent in this run, by message ID.
+#[tauri::command]
+fn message_statuses(app_state: State<AppState>) -> Result<HashMap<String, MessageStatus>, String> {
+    Ok(app_state
+        .tracker
+        .lock()
+        .map_err(|e| e.to_string())?
+        .statuses()
+        .clone())
+}
+
+/// How a finished outbox entry moves its message's status along.
+fn status_event(status: &OutboxStatus) -> Option<StatusEvent> {
+    match status {
+        OutboxStatus::Pending => None,
+        OutboxStatus::Sent { .. } => Some(StatusEvent::Accepted),
+        OutboxStatus::Failed { .. } => Some(StatusEvent::Failed),
+    }
+}
+
+/// Turns a core error into a message for the frontend.
+///
+/// Only failures that leave the connection unusable drop it, so the next
+/// command reconnects; throttling and rejections keep it open.
+fn command_error(
+    context: &str,
+    error: QuietDropError,
+    connection: &mut Option<OpenConnection>,
+) -> String {
+    match error {
+        QuietDropError::RateLimited { retry_after } => format!(
+            "Sending too fast; try again in {} seconds",
+            retry_after.as_secs().max(1)
+        ),
+        QuietDropError::Server { reason, .. } => {
+            format!("Message was not delivered: {}", reason)
+        }
+        QuietDropError::IncompatibleVersion(reason) => format!(
+            "This version of QuietDrop cannot talk to the server: {}",
+            reason
+        ),
+        QuietDropError::Io(_)
+        | QuietDropError::ConnectionClosed
+        | QuietDropError::AuthRequired
+        | QuietDropError::Protocol(_)
+        | QuietDropError::Serialization(_) => {
+            *connection = None;
+            format!("{}: {}", context, error)
+        }
+        error => format!("{}: {}", context, error),
+    }
+}
+
+async fn ensure_connection<'a>(
+    connection: &'a mut Option<OpenConnection>,
+    server_addr: &str,
+    server_public_key: &box_::PublicKey,
+    name: &str,
+    password: &str,
+    saved_session: &Mutex<Option<(String, String)>>,
+    identity: Identity,
+) -> Result<&'a mut OpenConnection, String> {
+    let reusable = matches!(
+        connection.as_ref(),
+        Some(open) if open.server_addr == server_addr && open.name == name
+    );
+    if !reusable {
+        let mut client = client::Client::connect(server_addr, server_public_key, &identity)
+            .await
+            .map_err(|e| format!("Failed to connect to server: {}", e))?;
+        let recovery_code = log_in(&mut client, name, password, saved_session).await?;
+        client
+            .identify(name, &identity)
+            .await
+            .map_err(|e| format!("Failed to identify with server: {}", e))?;
+        *connection = Some(OpenConnection {
+            server_addr: server_addr.to_string(),
+            name: name.to_string(),
+            identity,
+            client,
+            recovery_code,
+        });
+    }
+
+    Ok(connection.as_mut().expect("connection was just opened"))
+}
+
+/// Logs in with the saved session token for `name` if it is still valid,
+/// and otherwise with the password, saving the token that login issues.
+/// Returns the recovery code if the account had to be created.
+async fn log_in(
+    client: &mut client::Client,
+    name: &str,
+    password: &str,
+    saved_session: &Mutex<Option<(String, String)>>,
+) -> Result<Option<String>, String> {
+    let saved_token = match &*saved_session.lock().map_err(|e| e.to_string())? {
+        Some((saved_name, token)) if saved_name == name => Some(token.clone()),
+        _ => None,
+    };
+    if let Some(token) = saved_token {
+        match client.resume(&token).await {
+            Ok(_) => return Ok(None),
+            Err(QuietDropError::Server {
+                code: ErrorCode::InvalidSession,
+                ..
+            }) => log::info!("Saved session expired; logging in again"),
+            Err(e) => return Err(format!("Failed to resume session: {}", e)),
+        }
+    }
+
+    let (session, recovery_code) = match client.login(name, password).await {
+        Ok(session) => (session, None),
+        // The first use of a name creates its account
+        Err(QuietDropError::Server {
+            code: ErrorCode::InvalidCredentials,
+            ..
+        }) => {
+            let credentials = client.register(name, password).await.map_err(|e| match e {
+                QuietDropError::Server {
+                    code: ErrorCode::NameTaken,
+                    ..
+                } => format!("Wrong account password for {}", name),
+                e => format!("Failed to register {}: {}", name, e),
+            })?;
+            (credentials.session, Some(credentials.recovery_code))
+        }
+        Err(e) => return Err(format!("Failed to log in: {}", e)),
+    };
+    *saved_session.lock().map_err(|e| e.to_string())? = Some((name.to_string(), session.token));
+    Ok(recovery_code)
+}
+
+fn load_identity(
+    app: &AppHandle,
+    app_state: &AppState,
+    name: &str,
+    passphrase: &str,
+) -> Result<Identity, String> {
+    let mut unlocked = app_state.identity.lock().map_err(|e| e.to_string())?;
+    if let Some((unlocked_name, identity)) = unlocked.as_ref() {
+        if unlocked_name == name {
+            return Ok(identity.clone());
+        }
+    }
+
+    let dir = keystore_dir(app, name)?;
+    if passphrase.is_empty() {
+        return Err("A keystore passphrase is required".to_string());
+    }
+
+    let identity = Keystore::new(dir)
+        .load_or_create(passphrase)
+        .map_err(|e| match e {
+            QuietDropError::Auth(_) => format!("Wrong passphrase for {}", name),
+            e => format!("Unable to open keystore: {}", e),
+        })?;
+
+    *unlocked = Some((name.to_string(), identity.clone()));
+    Ok(identity)
+}
+
+fn open_outbox(app: &AppHandle, app_state: &AppState, name: &str) -> Result<Arc<Outbox>, String> {
+    let mut opened = app_state.outbox.lock().map_err(|e| e.to_string())?;
+    if let Some((opened_name, outbox)) = opened.as_ref() {
+        if opened_name == name {
+            return Ok(Arc::clone(outbox));
+        }
+    }
+
+    let path = keystore_dir(app, name)?.join(OUTBOX_FILE);
+    let outbox = Outbox::open(path, RetryPolicy::default())
+        .map(Arc::new)
+        .map_err(|e| format!("Unable to open the outbox: {}", e))?;
+    *opened = Some((name.to_string(), Arc::clone(&outbox)));
+    Ok(outbox)
+}
+
+/// Where `name`'s keystore lives, refusing names that would not stay
+/// inside the identities directory.
+fn keystore_dir(app: &AppHandle, name: &str) -> Result<PathBuf, String> {
+    if !is_valid_account_name(name) {
+        return Err(format!("Invalid name: {}", name));
+    }
+    Ok(app
+        .path()
+        .app_data_dir()
+        .map_err(|e| format!("Unable to locate app data directory: {}", e))?
+        .join("identities")
+        .join(name))
+}
+
+fn downloads_dir(app: &AppHandle) -> Result<PathBuf, String> {
+    Ok(app
+        .path()
+        .download_dir()
+        .map_err(|e| format!("Unable to locate downloads directory: {}", e))?
+        .join("QuietDrop"))
+}
+
+fn current_server_address(app_state: &AppState) -> Result<String, String> {
+    let server_address = app_state.server_address.lock().map_err(|e| e.to_string())?;
+    if server_address.is_empty() {
+        Ok("127.0.0.1:8080".to_string())
+    } else {
+        Ok(server_address.clone())
     }
 }
 
 fn read_server_public_key() -> Result<box_::PublicKey, String> {
     // Try to find the key in the current directory and parent directories
-    let possible_paths = [
-        "server_public_key.key",
-        "../server_public_key.key",
-        "../../server_public_key.key",
-    ];
-
-    for path in possible_paths {
-        println!("Trying to open key file: {}", path);
-        match File::open(path) {
-            Ok(mut file) => {
-                let mut server_public_key_bytes = Vec::new();
-                match file.read_to_end(&mut server_public_key_bytes) {
-                    Ok(_) => match box_::PublicKey::from_slice(&server_public_key_bytes) {
-                        Some(key) => return Ok(key),
-                        None => println!("Invalid key format in {}", path),
-                    },
-                    Err(e) => println!("Error reading {}: {}", path, e),
-                }
-            }
-            Err(e) => println!("Could not open {}: {}", path, e),
+    let possible_dirs = [".", "..", "../.."];
+
+    let mut errors = Vec::new();
+    for dir in possible_dirs {
+        match ServerKeyFiles::new(dir).public_key() {
+            Ok(key) => return Ok(key),
+            Err(e) => errors.push(e.to_string()),
         }
     }
 
-    Err("Could not find server public key in any expected location".to_string())
+    Err(format!(
+        "Could not find server public key in any expected location: {}",
+        errors.join("; ")
+    ))
+}
+
+#[tauri::command]
+async fn change_password(
+    app: AppHandle,
+    app_state: State<'_, AppState>,
+    name: String,
+    passphrase: String,
+    old_password: String,
+    new_password: String,
+) -> Result<MessageResponse, String> {
+    quietdrop_core::initialize();
+    let server_addr = current_server_address(&app_state)?;
+    let server_public_key = read_server_public_key()?;
+    let identity = load_identity(&app, &app_state, &name, &passphrase)?;
+
+    let mut connection = app_state.connection.lock().await;
+    let open = ensure_connection(
+        &mut connection,
+        &server_addr,
+        &server_public_key,
+        &name,
+        &old_password,
+        &app_state.session,
+        identity,
+    )
+    .await?;
+    let session = match open
+        .client
+        .change_password(&old_password, &new_password)
+        .await
+    {
+        Ok(session) => session,
+        Err(e) => {
+            return Err(command_error(
+                "Failed to change password",
+                e,
+                &mut connection,
+            ))
+        }
+    };
+    *app_state.session.lock().map_err(|e| e.to_string())? = Some((name.clone(), session.token));
+
+    // A keystore protected by the old password follows it to the new one
+    let keystore = Keystore::new(keystore_dir(&app, &name)?);
+    let message = if keystore.load(&old_password).is_ok() {
+        keystore
+            .change_passphrase(&old_password, &new_password)
+            .map_err(|e| format!("Password changed, but the keystore was not: {}", e))?;
+        "Password and keystore passphrase changed"
+    } else {
+        "Password changed"
+    };
+    Ok(MessageResponse {
+        status: "success".to_string(),
+        message: message.to_string(),
+        message_id: None,
+    })
+}
+
+#[tauri::command]
+async fn reset_password(
+    app_state: State<'_, AppState>,
+    name: String,
+    recovery_code: String,
+    new_password: String,
+) -> Result<MessageResponse, String> {
+    quietdrop_core::initialize();
+    let server_addr = current_server_address(&app_state)?;
+    let server_public_key = read_server_public_key()?;
+
+    // The account's keystore may be locked too, so a throwaway identity
+    // carries this one request
+    let mut client =
+        client::Client::connect(&server_addr, &server_public_key, &Identity::generate())
+            .await
+            .map_err(|e| format!("Failed to connect to server: {}", e))?;
+    let credentials = client
+        .reset_password(&name, &recovery_code, &new_password)
+        .await
+        .map_err(|e| format!("Failed to reset password: {}", e))?;
+    let _ = client.close().await;
+
+    *app_state.session.lock().map_err(|e| e.to_string())? = Some((name, credentials.session.token));
+    Ok(MessageResponse {
+        status: "success".to_string(),
+        message: format!(
+            "Password reset. Your new recovery code is {}; keep it safe, it is not shown again",
+            credentials.recovery_code
+        ),
+        message_id: None,
+    })
+}
+
+#[tauri::command]
+async fn logout(app_state: State<'_, AppState>) -> Result<(), String> {
+    let mut connection = app_state.connection.lock().await;
+    if let Some(mut open) = connection.take() {
+        // The token is forgotten locally even if the server cannot be told
+        if let Err(e) = open.client.logout().await {
+            log::warn!("Failed to revoke session on the server: {}", e);
+        }
+    }
+    *app_state.session.lock().map_err(|e| e.to_string())? = None;
+    Ok(())
 }
 
 #[tauri::command]
 fn set_server_address(app_state: State<AppState>, address: String) -> Result<(), String> {
-    println!("Setting server address to: {}", address);
     if let Ok(mut server_address) = app_state.server_address.lock() {
         *server_address = address;
         Ok(())
@@ -148,18 +931,31 @@ fn set_server_address(app_state: State<AppState>, address: String) -> Result<(),
 
 #[tauri::command]
 fn test_command() {
-    println!("=== TEST COMMAND CALLED ===");
+    log::debug!("test_command called");
 }
 
 fn main() {
-    println!("Starting QuietDrop Tauri application...");
-
     tauri::Builder::default()
         .manage(AppState {
             server_address: Mutex::new("127.0.0.1:8080".to_string()),
+            connection: tokio::sync::Mutex::new(None),
+            identity: Mutex::new(None),
+            session: Mutex::new(None),
+            outbox: Mutex::new(None),
+            tracker: Mutex::new(MessageTracker::new()),
+            files: Mutex::new(None),
+            uploads: tokio::sync::Mutex::new(None),
         })
         .invoke_handler(tauri::generate_handler![
             send_message,
+            fetch_messages,
+            flush_outbox,
+            send_file,
+            resume_transfers,
+            message_statuses,
+            logout,
+            change_password,
+            reset_password,
             set_server_address,
             test_command
         ])
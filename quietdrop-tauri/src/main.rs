@@ -14,6 +14,8 @@ extern "C" {
 #[derive(Serialize, Debug)]
 struct MessageRequest {
     name: String,
+    passphrase: String,
+    password: String,
     content: String,
     recipient: String,
 }
@@ -27,6 +29,8 @@ struct MessageResponse {
 #[function_component(App)]
 fn app() -> Html {
     let name = use_state(|| String::from(""));
+    let passphrase = use_state(|| String::from(""));
+    let password = use_state(|| String::from(""));
     let message = use_state(|| String::from(""));
     let recipient = use_state(|| String::from("Bob"));
     let status = use_state(|| String::from(""));
@@ -39,6 +43,22 @@ fn app() -> Html {
         })
     };
 
+    let onchange_passphrase = {
+        let passphrase = passphrase.clone();
+        Callback::from(move |e: Event| {
+            let input: web_sys::HtmlInputElement = e.target_unchecked_into();
+            passphrase.set(input.value());
+        })
+    };
+
+    let onchange_password = {
+        let password = password.clone();
+        Callback::from(move |e: Event| {
+            let input: web_sys::HtmlInputElement = e.target_unchecked_into();
+            password.set(input.value());
+        })
+    };
+
     let onchange_message = {
         let message = message.clone();
         Callback::from(move |e: Event| {
@@ -57,6 +77,8 @@ fn app() -> Html {
 
     let onsubmit = {
         let name = name.clone();
+        let passphrase = passphrase.clone();
+        let password = password.clone();
         let message = message.clone();
         let recipient = recipient.clone();
         let status = status.clone();
@@ -65,11 +87,17 @@ fn app() -> Html {
         Callback::from(move |e: SubmitEvent| {
             e.prevent_default();
             let name_val = (*name).clone();
+            let passphrase_val = (*passphrase).clone();
+            let password_val = (*password).clone();
             let message_val = (*message).clone();
             let recipient_val = (*recipient).clone();
 
-            if name_val.is_empty() || message_val.is_empty() {
-                status.set("Name and message cannot be empty".to_string());
+            if name_val.is_empty()
+                || passphrase_val.is_empty()
+                || password_val.is_empty()
+                || message_val.is_empty()
+            {
+                status.set("Name, passphrase, password and message cannot be empty".to_string());
                 return;
             }
 
@@ -79,6 +107,8 @@ fn app() -> Html {
             spawn_local(async move {
                 let request = MessageRequest {
                     name: name_val,
+                    passphrase: passphrase_val,
+                    password: password_val,
                     content: message_val,
                     recipient: recipient_val,
                 };
@@ -138,8 +168,9 @@ fn app() -> Html {
                             &format!("Frontend: Success response: {:?}", response).into(),
                         );
                         status.set(response.message);
-                        if response.status == "success" {
-                            msg_state.set(String::new()); // Clear message input on success
+                        // A queued message is kept in the outbox, so it is not lost either
+                        if response.status == "success" || response.status == "queued" {
+                            msg_state.set(String::new()); // Clear message input once it is safe
                         }
                     }
                     Err(e) => {
@@ -178,6 +209,22 @@ fn app() -> Html {
                         onchange={onchange_name}
                     />
                 </div>
+                <div class="message-input">
+                    <input
+                        type="password"
+                        placeholder="Keystore passphrase"
+                        value={(*passphrase).clone()}
+                        onchange={onchange_passphrase}
+                    />
+                </div>
+                <div class="message-input">
+                    <input
+                        type="password"
+                        placeholder="Account password"
+                        value={(*password).clone()}
+                        onchange={onchange_password}
+                    />
+                </div>
                 <div class="message-input">
                     <input
                         type="text"